@@ -1,7 +1,62 @@
-use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg()]
-    pub torrent_file: String,
-}
\ No newline at end of file
+    pub torrent_file: Option<String>,
+
+    /// Keep seeding once the download completes, until interrupted
+    #[arg(long)]
+    pub seed: bool,
+
+    /// Download incomplete files with this suffix, renamed once complete
+    #[arg(long, value_name = "SUFFIX", num_args = 0..=1, default_missing_value = ".part")]
+    pub part: Option<String>,
+
+    /// Start even if the files don't fit on the disk
+    #[arg(long)]
+    pub skip_space_check: bool,
+
+    /// Connect to peers and trackers only from this address, and accept peers only on it
+    #[arg(long, value_name = "IP")]
+    pub bind: Option<IpAddr>,
+
+    /// Connect to peers and http trackers through this SOCKS5 proxy
+    #[arg(long, value_name = "ADDRESS")]
+    pub proxy: Option<SocketAddr>,
+
+    /// Credentials for the proxy, as the username and password separated by a colon
+    #[arg(long, value_name = "USER:PASSWORD", requires = "proxy")]
+    pub proxy_auth: Option<String>,
+
+    /// How download progress is shown
+    #[arg(long, value_enum, default_value_t = Progress::Auto)]
+    pub progress: Progress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Progress {
+    /// A bar when stdout is a terminal, lines otherwise
+    Auto,
+    Bar,
+    /// A line every time more pieces are complete
+    Lines,
+    None,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Re-hashes the downloaded files against the torrent without downloading
+    Verify {
+        #[arg()]
+        torrent_file: String,
+    },
+    /// Lists the addresses of the network interfaces, any of them can be passed to `--bind`
+    Interfaces,
+}
@@ -1,20 +1,75 @@
 use std::{str::from_utf8, collections::BTreeMap};
 
+/// Structural errors carry the byte offset of the value they were found in, counted from
+/// the start of the decoded bytes
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Error {
-    EmptyInteger,
+    EmptyInteger(usize),
     NotEnoughBytes,
-    NotAnInteger,
-    UnclosedInteger,
-    UnclosedList,
-    UnclosedMap,
-    NegativeZero,
-    LeadingZero,
-    MissingColon,
+    NotAnInteger(usize),
+    UnclosedInteger(usize),
+    UnclosedList(usize),
+    UnclosedMap(usize),
+    /// Offset of the key missing its value
+    MissingValue(usize),
+    NegativeZero(usize),
+    LeadingZero(usize),
+    MissingColon(usize),
     ExpectedMap,
     ExpectedString,
     ExpectedInteger,
     ExpectedList,
+    /// `error` happened in the value of a dictionary key starting at `offset`
+    InKey { key: String, offset: usize, error: Box<Error> },
+}
+
+impl Error {
+    /// Adds the key being decoded and where its value starts, see `offset`
+    pub fn in_key(self, key: &[u8], offset: usize) -> Self {
+        Error::InKey { key: String::from_utf8_lossy(key).into_owned(), offset, error: Box::new(self) }
+    }
+}
+
+/// Offset of `value` inside `bytes`, for values such as the raw slice of a `Type` decoded
+/// from them. 0 if it isn't part of them
+pub fn offset(bytes: &[u8], value: &[u8]) -> usize {
+    let start = value.as_ptr() as usize;
+
+    match start.checked_sub(bytes.as_ptr() as usize) {
+        Some(offset) if offset <= bytes.len() => offset,
+        _ => 0,
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyInteger(offset) => write!(f, "Integer at byte {} has no digits", offset),
+            Self::NotEnoughBytes => write!(f, "Not enough bytes to decode"),
+            Self::NotAnInteger(offset) => write!(f, "Integer at byte {} contains a non digit character", offset),
+            Self::UnclosedInteger(offset) => write!(f, "Integer at byte {} is missing its closing 'e'", offset),
+            Self::UnclosedList(offset) => write!(f, "List at byte {} is missing its closing 'e'", offset),
+            Self::UnclosedMap(offset) => write!(f, "Dictionary at byte {} is missing its closing 'e'", offset),
+            Self::MissingValue(offset) => write!(f, "Dictionary key at byte {} has no value", offset),
+            Self::NegativeZero(offset) => write!(f, "Negative zero at byte {} is not a valid integer", offset),
+            Self::LeadingZero(offset) => write!(f, "Integer at byte {} has leading zeros", offset),
+            Self::MissingColon(offset) => write!(f, "String length at byte {} is missing its colon", offset),
+            Self::ExpectedMap => write!(f, "Expected a dictionary"),
+            Self::ExpectedString => write!(f, "Expected a byte string"),
+            Self::ExpectedInteger => write!(f, "Expected an integer"),
+            Self::ExpectedList => write!(f, "Expected a list"),
+            Self::InKey { key, offset, .. } => write!(f, "Invalid value of key {} at byte {}", key, offset),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InKey { error, .. } => Some(error),
+            _ => None,
+        }
+    }
 }
 
 /// Contains the value and the raw bencode of the type
@@ -54,6 +109,13 @@ impl<'a> Type<'a> {
             _ => Err(Error::ExpectedString),
         }
     }
+
+    /// The exact bencode this value was decoded from
+    pub const fn raw(&self) -> &'a [u8] {
+        match self {
+            Type::String(_, raw) | Type::Integer(_, raw) | Type::List(_, raw) | Type::Map(_, raw) => raw,
+        }
+    }
 }
 
 pub trait FromBencodeType {
@@ -87,18 +149,26 @@ impl<'a> Iterator for Iter<'a> {
                     self.current += 1;
                 }
 
-                if self.raw[self.current] != b':' {
-                    return Some(Err(Error::MissingColon));
+                if self.raw.get(self.current) != Some(&b':') {
+                    return Some(Err(Error::MissingColon(begin)));
                 }
 
                 // consume colon
                 self.current += 1;
 
-                let length: usize = from_utf8(&self.raw[begin..(self.current - 1)]).unwrap().parse().unwrap();
-
                 let str_begin = self.current;
 
-                self.current += length;
+                // a length too big to parse is past the end of the input anyway
+                let str_end = from_utf8(&self.raw[begin..(str_begin - 1)]).ok()
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .and_then(|length| str_begin.checked_add(length))
+                    .filter(|&end| end <= self.raw.len());
+
+                let Some(str_end) = str_end else {
+                    return Some(Err(Error::NotEnoughBytes));
+                };
+
+                self.current = str_end;
 
                 let str = &self.raw[str_begin..self.current];
 
@@ -107,7 +177,7 @@ impl<'a> Iterator for Iter<'a> {
             b'i' => {
                 // can't have "ie"
                 if self.raw[self.current] == b'e' {
-                    return Some(Err(Error::EmptyInteger));
+                    return Some(Err(Error::EmptyInteger(begin)));
                 }
 
                 let mut negative = false;
@@ -115,11 +185,11 @@ impl<'a> Iterator for Iter<'a> {
                 // first character may be a negative sign
                 if self.raw[self.current] == b'-' {
                     negative = true;
-                } else if self.raw[self.current] == b'0' && len > 3 {
+                } else if self.raw[self.current] == b'0' && self.raw.get(self.current + 1).is_some_and(u8::is_ascii_digit) {
                     // leading zeros are not allowed
-                    return Some(Err(Error::LeadingZero));
+                    return Some(Err(Error::LeadingZero(begin)));
                 } else if !self.raw[self.current].is_ascii_digit() {
-                    return Some(Err(Error::NotAnInteger));
+                    return Some(Err(Error::NotAnInteger(begin)));
                 }
 
                 if negative {
@@ -127,8 +197,8 @@ impl<'a> Iterator for Iter<'a> {
                 }
 
                 // negative zero is not allowed
-                if negative && self.raw[self.current] == b'0' {
-                    return Some(Err(Error::NegativeZero));
+                if negative && self.raw.get(self.current) == Some(&b'0') {
+                    return Some(Err(Error::NegativeZero(begin)));
                 }
 
                 // all characters except last one have to be digits
@@ -137,8 +207,8 @@ impl<'a> Iterator for Iter<'a> {
                 }
 
                 // last character needs to close the integer
-                if self.raw[self.current] != b'e' {
-                    return Some(Err(Error::UnclosedInteger));
+                if self.raw.get(self.current) != Some(&b'e') {
+                    return Some(Err(Error::UnclosedInteger(begin)));
                 }
 
                 let str = from_utf8(&self.raw[(begin + 1)..self.current]).unwrap();
@@ -159,8 +229,8 @@ impl<'a> Iterator for Iter<'a> {
                     vec.push(object)
                 }
 
-                if self.raw[self.current] != b'e' {
-                    return Some(Err(Error::UnclosedList))
+                if self.raw.get(self.current) != Some(&b'e') {
+                    return Some(Err(Error::UnclosedList(begin)))
                 }
 
                 self.current += 1;
@@ -170,22 +240,32 @@ impl<'a> Iterator for Iter<'a> {
             b'd' => {
                 let mut map = BTreeMap::new();
 
-                while let (Some(key), Some(val)) = (self.next(), self.next()) {
+                // the value is only read once the key is, so an error in either stops right there
+                loop {
+                    let key_begin = self.current;
+
+                    let Some(key) = self.next() else {
+                        break;
+                    };
+
                     let key = match key {
                         Ok(key) => key,
                         err => return Some(err),
                     };
 
-                    let val = match val {
-                        Ok(val) => val,
-                        err => return Some(err),
+                    // an odd number of elements, unless the input ends before the map does
+                    let val = match self.next() {
+                        Some(Ok(val)) => val,
+                        Some(err) => return Some(err),
+                        None if self.current >= self.raw.len() => return Some(Err(Error::UnclosedMap(begin))),
+                        None => return Some(Err(Error::MissingValue(key_begin))),
                     };
 
                     map.insert(key, val);
                 }
 
-                if self.raw[self.current] != b'e' {
-                    return Some(Err(Error::UnclosedMap))
+                if self.raw.get(self.current) != Some(&b'e') {
+                    return Some(Err(Error::UnclosedMap(begin)))
                 }
 
                 self.current += 1;
@@ -274,8 +354,57 @@ impl<'a, const N: usize> Bedecode<'a> for &'a [u8; N] {
 mod test {
     use std::collections::BTreeMap;
 
+    use rand::Rng;
+
     use crate::bencode::{Type, Error, Bedecode};
 
+    /// Random valid bencode nested at most `depth` levels
+    fn random_bencode(rng: &mut impl Rng, depth: u32) -> Vec<u8> {
+        let kind = if depth == 0 { rng.gen_range(0..2) } else { rng.gen_range(0..4) };
+
+        match kind {
+            0 => {
+                let string: Vec<u8> = (0..rng.gen_range(0..8)).map(|_| rng.gen()).collect();
+                let mut bytes = format!("{}:", string.len()).into_bytes();
+                bytes.extend(string);
+                bytes
+            }
+            1 => format!("i{}e", rng.gen::<i32>()).into_bytes(),
+            2 => {
+                let mut bytes = vec![b'l'];
+                (0..rng.gen_range(0..4)).for_each(|_| bytes.extend(random_bencode(rng, depth - 1)));
+                bytes.push(b'e');
+                bytes
+            }
+            _ => {
+                let mut bytes = vec![b'd'];
+
+                for _ in 0..rng.gen_range(0..4) {
+                    let key = format!("k{}", rng.gen::<u8>());
+                    bytes.extend(format!("{}:{}", key.len(), key).into_bytes());
+                    bytes.extend(random_bencode(rng, depth - 1));
+                }
+
+                bytes.push(b'e');
+                bytes
+            }
+        }
+    }
+
+    /// Decoding the raw slice of `value` and of everything nested in it gives the same value back
+    fn assert_raw_round_trip(value: &Type) {
+        assert_eq!(value.raw().bedecode().as_ref(), Ok(value));
+
+        match value {
+            Type::List(list, _) => list.iter().for_each(assert_raw_round_trip),
+            Type::Map(map, _) => map.iter().for_each(|(key, value)| {
+                assert_raw_round_trip(key);
+                assert_raw_round_trip(value);
+            }),
+            _ => (),
+        }
+    }
+
     #[test]
     fn bedecode_string() {
         let str = b"4:spam";
@@ -285,6 +414,10 @@ mod test {
         assert_eq!(str.bedecode(), Ok(Type::String(b"spam", str)));
         assert_eq!(empty_str.bedecode(), Ok(Type::String(b"", empty_str)));
         assert_eq!(empty.bedecode(), Err(Error::NotEnoughBytes));
+
+        assert_eq!(b"5:spam".bedecode(), Err(Error::NotEnoughBytes));
+        assert_eq!(b"99999999999999999999:spam".bedecode(), Err(Error::NotEnoughBytes));
+        assert_eq!(b"l44".bedecode(), Err(Error::MissingColon(1)));
     }
 
     #[test]
@@ -296,6 +429,7 @@ mod test {
         assert_eq!(positive.bedecode(), Ok(Type::Integer("10", positive)));
         assert_eq!(negative.bedecode(), Ok(Type::Integer("-10", negative)));
         assert_eq!(zero.bedecode(), Ok(Type::Integer("0", zero)));
+        assert_eq!(b"li0ee".bedecode(), Ok(Type::List(vec![Type::Integer("0", b"i0e")], b"li0ee")));
 
         let empty_integer = b"ie";
         let unclosed_integer = b"i10";
@@ -303,11 +437,13 @@ mod test {
         let leading_zero = b"i03e";
         let negative_leading_zero = b"i-03e";
         
-        assert_eq!(empty_integer.bedecode(), Err(Error::EmptyInteger));
-        assert_eq!(unclosed_integer.bedecode(), Err(Error::UnclosedInteger));
-        assert_eq!(negative_zero.bedecode(), Err(Error::NegativeZero));
-        assert_eq!(leading_zero.bedecode(), Err(Error::LeadingZero));
-        assert_eq!(negative_leading_zero.bedecode(), Err(Error::NegativeZero));
+        assert_eq!(empty_integer.bedecode(), Err(Error::EmptyInteger(0)));
+        assert_eq!(unclosed_integer.bedecode(), Err(Error::UnclosedInteger(0)));
+        assert_eq!(negative_zero.bedecode(), Err(Error::NegativeZero(0)));
+        assert_eq!(leading_zero.bedecode(), Err(Error::LeadingZero(0)));
+        assert_eq!(negative_leading_zero.bedecode(), Err(Error::NegativeZero(0)));
+        assert_eq!(b"li1ei03ee".bedecode(), Err(Error::LeadingZero(4)));
+        assert_eq!(b"li-".bedecode(), Err(Error::UnclosedInteger(1)));
     }
 
     #[test]
@@ -336,4 +472,66 @@ mod test {
         assert_eq!(map_str2.bedecode(), Ok(Type::Map(map2, map_str2)));
         assert_eq!(empty.bedecode(), Ok(Type::Map(BTreeMap::new(), empty)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bedecode_key_without_value() {
+        assert_eq!(b"d3:keye".bedecode(), Err(Error::MissingValue(1)));
+        assert_eq!(b"d3:cow3:moo4:spame".bedecode(), Err(Error::MissingValue(11)));
+        assert_eq!(b"d3:cow3:moo4:spam4:eggs3:key".bedecode(), Err(Error::UnclosedMap(0)));
+
+        // nested maps leave the cursor right after themselves, the outer value still parses
+        let nested = b"ld1:ad1:bi1eee3:endi2ee";
+        let (list, _) = nested.try_into_list().unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0].raw(), b"d1:ad1:bi1eee");
+        assert_eq!(list[2], Type::Integer("2", b"i2e"));
+
+        // the error of a nested map isn't swallowed by the one containing it
+        assert_eq!(b"ld3:keyei1ee".bedecode(), Err(Error::MissingValue(2)));
+        assert_eq!(b"d1:ad3:keyee".bedecode(), Err(Error::MissingValue(5)));
+    }
+
+    #[test]
+    fn bedecode_odd_map() {
+        // dangling keys anywhere a map ends, and a map cut short, never parse into a partial one
+        assert_eq!(b"d1:a1:b1:ce".bedecode(), Err(Error::MissingValue(7)));
+        assert_eq!(b"d1:ad1:b1:c1:dee".bedecode(), Err(Error::MissingValue(11)));
+        assert_eq!(b"ld1:a1:b1:ceei1ee".bedecode(), Err(Error::MissingValue(8)));
+        assert_eq!(b"d1:a1:b".bedecode(), Err(Error::UnclosedMap(0)));
+        assert_eq!(b"d1:a".bedecode(), Err(Error::UnclosedMap(0)));
+        assert_eq!(b"l4:spam".bedecode(), Err(Error::UnclosedList(0)));
+        assert_eq!(b"d1:al4:spam".bedecode(), Err(Error::UnclosedList(4)));
+
+        // pairs of any kind still parse
+        let even = b"d1:ai1e1:bl1:ce1:cd1:d1:eee";
+        let (map, raw) = even.try_into_dict().unwrap();
+        assert_eq!(raw, even);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&Type::String(b"c", b"1:c")].raw(), b"d1:d1:ee");
+    }
+
+    #[test]
+    fn raw_slice_nested() {
+        let nested = b"d4:infod5:filesld6:lengthi3e4:pathl1:a1:beee4:name1:xe4:listlli1eeldeee3:zzzi0ee";
+        let value = nested.bedecode().unwrap();
+
+        assert_eq!(value.raw(), nested);
+        assert_raw_round_trip(&value);
+
+        let info = &value.try_into_dict().unwrap().0[&Type::String(b"info", b"4:info")];
+        assert_eq!(info.raw(), b"d5:filesld6:lengthi3e4:pathl1:a1:beee4:name1:xe");
+    }
+
+    #[test]
+    fn raw_slice_random() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..1000 {
+            let bytes = random_bencode(&mut rng, 4);
+            let value = bytes.as_slice().bedecode().unwrap();
+
+            assert_eq!(value.raw(), bytes.as_slice());
+            assert_raw_round_trip(&value);
+        }
+    }
+}
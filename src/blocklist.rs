@@ -0,0 +1,225 @@
+use std::{fmt, fs, io};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Ip filter entries with a bigger access level allow their range instead of blocking it
+const MAX_BLOCKED_ACCESS: u32 = 127;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidRange(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRange(range) => write!(f, "Invalid ip range {}", range),
+            Self::Io(_) => write!(f, "Could not read the ip filter"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Addresses from `first` to `last`, both included. Ipv4 addresses are kept as
+/// ipv4-mapped ipv6 ones so a single comparison covers both families
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    first: u128,
+    last: u128,
+}
+
+impl IpRange {
+    pub fn new(first: IpAddr, last: IpAddr) -> Self {
+        IpRange { first: to_bits(first), last: to_bits(last) }
+    }
+
+    /// Parses `address/prefix`, a lone address is a range of itself
+    pub fn from_cidr(cidr: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidRange(cidr.to_string());
+
+        let (address, prefix) = match cidr.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (cidr.trim(), None),
+        };
+
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+
+        // ipv4 prefixes count from the start of the mapped address
+        let (max, offset) = if address.is_ipv4() { (32, 96) } else { (128, 0) };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|prefix| *prefix <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+
+        let host_bits = 128 - offset - prefix;
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        let bits = to_bits(address);
+
+        Ok(IpRange { first: bits & mask, last: bits | !mask })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        (self.first..=self.last).contains(&to_bits(address))
+    }
+}
+
+fn to_bits(address: IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(address) => u128::from(address.to_ipv6_mapped()),
+        IpAddr::V6(address) => u128::from(address),
+    }
+}
+
+/// Ranges of peers that are never connected to, set in `ClientConfig::blocklist`
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    ranges: Vec<IpRange>,
+}
+
+impl Blocklist {
+    pub const fn new() -> Self {
+        Blocklist { ranges: Vec::new() }
+    }
+
+    pub fn add(&mut self, range: IpRange) {
+        self.ranges.push(range);
+    }
+
+    pub fn ranges(&self) -> &[IpRange] {
+        &self.ranges
+    }
+
+    pub fn is_blocked(&self, address: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(address))
+    }
+
+    /// Reads an ip filter in the p2p (`name:first-last`) or dat (`first - last , access , name`)
+    /// format, chosen per line. Empty lines and comments starting with `#` are skipped
+    pub fn from_filter(filter: &str) -> Result<Self, Error> {
+        let mut blocklist = Blocklist::new();
+
+        for line in filter.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            if let Some(range) = parse_filter_line(line)? {
+                blocklist.add(range);
+            }
+        }
+
+        Ok(blocklist)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Blocklist::from_filter(&fs::read_to_string(path)?)
+    }
+}
+
+/// `None` for dat entries that allow their range
+fn parse_filter_line(line: &str) -> Result<Option<IpRange>, Error> {
+    let invalid = || Error::InvalidRange(line.to_string());
+
+    let mut fields = line.split(',');
+    let range = fields.next().ok_or_else(invalid)?;
+
+    let (range, access) = match fields.next() {
+        Some(access) => (range, Some(access.trim().parse::<u32>().map_err(|_| invalid())?)),
+        // names of p2p entries can contain colons, the range is after the last one
+        None => (range.rsplit_once(':').map_or(range, |(_, range)| range), None),
+    };
+
+    if access.is_some_and(|access| access > MAX_BLOCKED_ACCESS) {
+        return Ok(None);
+    }
+
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+
+    Ok(Some(IpRange::new(parse_filter_address(first).ok_or_else(invalid)?, parse_filter_address(last).ok_or_else(invalid)?)))
+}
+
+/// Dat files pad every ipv4 octet to three digits, which `Ipv4Addr` refuses as octal
+fn parse_filter_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+
+    if let Ok(address) = address.parse::<Ipv6Addr>() {
+        return Some(IpAddr::V6(address));
+    }
+
+    let mut octets = [0u8; 4];
+    let mut parts = address.split('.');
+
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+
+    parts.next().is_none().then_some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::blocklist::{Blocklist, IpRange};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges() {
+        let mut blocklist = Blocklist::new();
+
+        for cidr in ["10.0.0.0/8", "192.168.1.0/24", "203.0.113.7", "2001:db8::/32", "172.16.5.9/12"] {
+            blocklist.add(IpRange::from_cidr(cidr).unwrap());
+        }
+
+        for blocked in ["10.0.0.0", "10.255.255.255", "192.168.1.77", "203.0.113.7", "2001:db8:ffff::1", "172.31.0.1", "::ffff:10.1.2.3"] {
+            assert!(blocklist.is_blocked(ip(blocked)), "{}", blocked);
+        }
+
+        for allowed in ["11.0.0.0", "192.168.2.1", "203.0.113.8", "2001:db9::1", "172.32.0.0", "127.0.0.1"] {
+            assert!(!blocklist.is_blocked(ip(allowed)), "{}", allowed);
+        }
+
+        assert_eq!(IpRange::from_cidr("0.0.0.0/0").unwrap(), IpRange::new(ip("0.0.0.0"), ip("255.255.255.255")));
+        assert!(IpRange::from_cidr("::/0").unwrap().contains(ip("ffff::1")));
+
+        assert!(IpRange::from_cidr("10.0.0.0/33").is_err());
+        assert!(IpRange::from_cidr("10.0.0/8").is_err());
+        assert!(IpRange::from_cidr("10.0.0.0/").is_err());
+    }
+
+    #[test]
+    fn filter_formats() {
+        let filter = "\
+            # comment\n\
+            \n\
+            Bad peers: the sequel:1.2.3.0-1.2.3.255\n\
+            001.002.004.000 - 001.002.004.255 , 000 , dat entry\n\
+            005.000.000.000 - 005.255.255.255 , 200 , allowed\n";
+
+        let blocklist = Blocklist::from_filter(filter).unwrap();
+        assert_eq!(blocklist.ranges().len(), 2);
+
+        assert!(blocklist.is_blocked(ip("1.2.3.9")));
+        assert!(blocklist.is_blocked(ip("1.2.4.255")));
+        assert!(!blocklist.is_blocked(ip("1.2.5.0")));
+        assert!(!blocklist.is_blocked(ip("5.1.1.1")));
+
+        assert!(Blocklist::from_filter("not a range").is_err());
+        assert!(Blocklist::from_filter("1.2.3.4 - 1.2.3.5 , high , name").is_err());
+    }
+}
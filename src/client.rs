@@ -1,4 +1,10 @@
+use std::future::Future;
+
+use bit_vec::BitVec;
+use tokio::runtime::Handle;
+
 use crate::{metainfo, torrent};
+use crate::config::ClientConfig;
 use crate::torrent::Torrent;
 
 #[derive(Debug)]
@@ -8,6 +14,26 @@ pub enum Error {
     JoinError(tokio::task::JoinError),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MetaInfoError(_) => write!(f, "Invalid metainfo"),
+            Self::TorrentError(_) => write!(f, "Torrent failed"),
+            Self::JoinError(_) => write!(f, "Download task failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::MetaInfoError(err) => Some(err),
+            Self::TorrentError(err) => Some(err),
+            Self::JoinError(err) => Some(err),
+        }
+    }
+}
+
 impl From<metainfo::Error> for Error {
     fn from(value: metainfo::Error) -> Self {
         Self::MetaInfoError(value)
@@ -26,23 +52,54 @@ impl From<tokio::task::JoinError> for Error {
     }
 }
 
-pub struct Client { }
+pub struct Client {
+    config: ClientConfig,
+}
 
 impl Client {
     pub const fn new() -> Self {
-        Client { }
+        Client { config: ClientConfig::new() }
+    }
+
+    pub const fn with_config(config: ClientConfig) -> Self {
+        Client { config }
+    }
+
+    pub const fn config(&self) -> &ClientConfig {
+        &self.config
     }
 
-    /// `torrent_file` may be passed as a magnet link or path to file
+    /// `torrent_file` may be passed as a magnet link or path to file, the download
+    /// runs on the runtime this is called from
     pub async fn download(&self, torrent: &str) -> Result<(), Error> {
+        self.download_on(&Handle::current(), torrent).await
+    }
+
+    /// Like `download` but spawned on `handle`, which can belong to a runtime other
+    /// than the one this is awaited on. Peer tasks are spawned on it as well
+    pub async fn download_on(&self, handle: &Handle, torrent: &str) -> Result<(), Error> {
+        handle.spawn(self.download_future(torrent)).await?
+    }
+
+    /// The whole download as a future for the caller to drive, it has to be polled
+    /// inside a tokio runtime of any flavor since it spawns a task for every peer
+    pub fn download_future(&self, torrent: &str) -> impl Future<Output = Result<(), Error>> + Send + 'static {
         let torrent = torrent.to_string();
+        let config = self.config.clone();
 
-        tokio::spawn(async move {
-            let mut torrent = Torrent::new(&torrent).await?;
-            torrent.download().await;
+        async move {
+            let torrent = Torrent::new(&torrent, config).await?;
+            torrent.download().await?;
 
             Ok(())
-        }).await?
+        }
+    }
+
+    /// Checks which pieces of `torrent` are already on disk and valid
+    pub async fn verify(&self, torrent: &str) -> Result<BitVec, Error> {
+        let torrent = Torrent::new(torrent, self.config.clone()).await?;
+
+        Ok(torrent.recheck().await?)
     }
 }
 
@@ -50,4 +107,31 @@ impl Default for Client {
     fn default() -> Self {
         Client::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Builder;
+
+    use crate::client::{Client, Error};
+    use crate::torrent;
+
+    #[test]
+    fn download_future_on_current_thread_runtime() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let result = runtime.block_on(Client::new().download_future("does_not_exist.torrent"));
+
+        assert!(matches!(result, Err(Error::TorrentError(torrent::Error::InvalidTorrent(_)))));
+    }
+
+    #[tokio::test]
+    async fn download_on_other_runtime() {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let result = Client::new().download_on(runtime.handle(), "does_not_exist.torrent").await;
+
+        assert!(matches!(result, Err(Error::TorrentError(torrent::Error::InvalidTorrent(_)))));
+
+        // dropping a runtime isn't allowed from async code
+        runtime.shutdown_background();
+    }
+}
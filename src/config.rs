@@ -0,0 +1,129 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::blocklist::Blocklist;
+use crate::metainfo::DEFAULT_MAX_PIECE_LENGTH;
+use crate::proxy::ProxyConfig;
+use crate::scheduler::{RarestFirst, RequestScheduler};
+use crate::peer::{DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_READ_BUFFER};
+
+/// Settings shared by every torrent downloaded by a `Client`
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Torrents with a bigger piece length are rejected when parsing their metainfo,
+    /// since pieces are allocated in memory from untrusted data
+    pub max_piece_length: u32,
+    /// Times a dropped peer that still has pieces we need is reconnected to
+    pub peer_retries: u32,
+    /// Wait before the first reconnection, doubled after every failed one
+    pub peer_retry_backoff: Duration,
+    /// Source address of every connection to peers and trackers and the address peers are
+    /// accepted on, so traffic can't leave through another interface. `None` lets the
+    /// routing table pick. See `interface::interfaces` for the addresses there are
+    pub bind_address: Option<IpAddr>,
+    /// SOCKS5 proxy every peer and http tracker connection goes through, announcing to
+    /// other trackers fails while it's set
+    pub proxy: Option<ProxyConfig>,
+    /// Bytes read from each peer connection at a time
+    pub peer_read_buffer: usize,
+    /// Connections open at once to the ports of a single ip, so one host can't take every
+    /// slot with many fake peers
+    pub max_connections_per_ip: usize,
+    /// Peers that don't complete the handshake in this long are dropped
+    pub handshake_timeout: Duration,
+    /// Bytes of recently read and written blocks kept in memory, so pieces many peers ask
+    /// for aren't read from disk every time. 0 disables the cache
+    pub piece_cache_size: usize,
+    /// Advertise pieces one at a time with `seed::SuperSeed` when seeding, only worth it
+    /// while we are the single seed of a torrent
+    pub super_seed: bool,
+    /// Find peers on the LAN with Local Service Discovery (BEP 14), never done for private torrents
+    pub local_peer_discovery: bool,
+    /// Announce to every tracker of `announce-list` at once instead of only the main one,
+    /// each on its own interval, and connect to all the peers they return
+    pub announce_to_all: bool,
+    /// Peers in these ranges are never connected to, whoever returned them
+    pub blocklist: Blocklist,
+    /// Sync the files of every piece to disk before marking it complete, so a crash can't
+    /// lose a piece that a resume would skip. Off is faster, files are still synced at the end
+    pub sync_pieces: bool,
+    /// Peers that sent this many pieces failing verification are banned for the session
+    pub max_bad_pieces: u32,
+    /// A piece failing verification this many times bans the peer that sent it last,
+    /// however few bad pieces it sent before
+    pub max_piece_failures: u32,
+    /// Keep announcing and uploading once every piece is downloaded, until the download
+    /// future is dropped, instead of returning
+    pub seed_after_complete: bool,
+    /// Stop seeding once uploaded this many times what was downloaded, or the whole torrent
+    /// if it was complete from the start. `stopped` is announced and the download returns
+    pub seed_ratio_limit: Option<f64>,
+    /// Stop seeding after this long, counted from the moment the download completed
+    pub seed_time_limit: Option<Duration>,
+    /// Directory the files are stored in, the current one when `None`
+    pub download_dir: Option<PathBuf>,
+    /// Write incomplete files as their name followed by this, `.part` for example, and
+    /// rename them once complete so other programs never see half of a file
+    pub part_suffix: Option<String>,
+    /// Fail before writing anything if the files that are left don't fit on the disk
+    pub check_disk_space: bool,
+    /// How long `Torrent::read_range` waits for the missing pieces of its range, which are
+    /// downloaded first meanwhile. `None` fails with the first missing piece right away
+    pub read_range_wait: Option<Duration>,
+    /// Resolving the host of a tracker failing after this counts as a failed announce
+    pub dns_timeout: Duration,
+    /// How often the peers we upload to are chosen again
+    pub choke_interval: Duration,
+    /// Interested peers uploaded to at once, the ones sending to us the fastest or, once
+    /// complete, the ones we send to the fastest. At least 1
+    pub upload_slots: usize,
+    /// Picks the piece each peer downloads next, `RarestFirst` by default. `Sequential`
+    /// requests in an order tests can predict
+    pub scheduler: &'static dyn RequestScheduler,
+    /// Ids of the messages never sent to peers, see `Peer::set_disabled_messages`. Only in debug builds
+    #[cfg(debug_assertions)]
+    pub disabled_messages: Vec<u8>,
+}
+
+impl ClientConfig {
+    pub const fn new() -> Self {
+        ClientConfig {
+            max_piece_length: DEFAULT_MAX_PIECE_LENGTH,
+            peer_retries: 3,
+            peer_retry_backoff: Duration::from_secs(1),
+            bind_address: None,
+            proxy: None,
+            peer_read_buffer: DEFAULT_READ_BUFFER,
+            max_connections_per_ip: 3,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            piece_cache_size: 0,
+            super_seed: false,
+            local_peer_discovery: false,
+            announce_to_all: false,
+            blocklist: Blocklist::new(),
+            sync_pieces: true,
+            max_bad_pieces: 3,
+            max_piece_failures: 5,
+            seed_after_complete: false,
+            seed_ratio_limit: None,
+            seed_time_limit: None,
+            download_dir: None,
+            part_suffix: None,
+            check_disk_space: true,
+            read_range_wait: None,
+            dns_timeout: Duration::from_secs(10),
+            choke_interval: Duration::from_secs(10),
+            upload_slots: 4,
+            scheduler: &RarestFirst,
+            #[cfg(debug_assertions)]
+            disabled_messages: Vec::new(),
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig::new()
+    }
+}
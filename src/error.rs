@@ -0,0 +1,14 @@
+use std::error::Error;
+
+/// Formats `error` followed by every error in its `source` chain
+pub fn report(error: &dyn Error) -> String {
+    let mut report = error.to_string();
+    let mut source = error.source();
+
+    while let Some(err) = source {
+        report.push_str(&format!("\n  caused by: {}", err));
+        source = err.source();
+    }
+
+    report
+}
@@ -0,0 +1,770 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bit_vec::BitVec;
+use tokio::fs;
+use tokio::sync::{RwLock, broadcast, mpsc};
+
+use crate::hash::PieceHasher;
+use crate::metainfo::{Info, MetaInfo};
+use crate::peer::WriteMessage;
+use crate::progress::{self, ProgressEvent, FileProgress};
+use crate::state::{DownloadState, PieceStatus};
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::BLOCK_SIZE;
+
+/// Path of the file at `path` while it's incomplete, with `suffix` appended to its name
+pub fn part_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
+
+/// Where the file at `path` is stored while downloading with `suffix`: its part file
+/// unless only the final one exists, left complete by an earlier download
+pub async fn stored_path(path: &Path, suffix: Option<&str>) -> Result<PathBuf, io::Error> {
+    let Some(suffix) = suffix else {
+        return Ok(path.to_path_buf());
+    };
+
+    let part = part_path(path, suffix);
+
+    if !fs::try_exists(&part).await? && fs::try_exists(path).await? {
+        return Ok(path.to_path_buf());
+    }
+
+    Ok(part)
+}
+
+/// Bytes an unprivileged user can still write to the disk `path` is on, or would be created
+/// on. `None` where that can't be queried
+pub async fn available_space(path: &Path) -> Result<Option<u64>, io::Error> {
+    let mut existing = Path::new(".");
+
+    for ancestor in path.ancestors().filter(|ancestor| !ancestor.as_os_str().is_empty()) {
+        if fs::try_exists(ancestor).await? {
+            existing = ancestor;
+            break;
+        }
+    }
+
+    let existing = existing.to_path_buf();
+
+    tokio::task::spawn_blocking(move || statvfs_available(&existing)).await?
+}
+
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)] // the fields are u32 on some platforms
+fn statvfs_available(path: &Path) -> Result<Option<u64>, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    // safety: path is nul terminated and stats is only read once statvfs filled it
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let stats = unsafe { stats.assume_init() };
+
+    Ok(Some(u64::from(stats.f_bavail) * u64::from(stats.f_frsize)))
+}
+
+#[cfg(not(unix))]
+fn statvfs_available(_path: &Path) -> Result<Option<u64>, io::Error> {
+    Ok(None)
+}
+
+/// Writes blocks sent by the peer tasks straight to disk, so partially downloaded pieces
+/// survive the peer that was downloading them, and verifies each piece once all of its
+/// blocks are stored
+pub struct PieceWriter<S = FileStorage> {
+    metainfo: Arc<MetaInfo>,
+    hasher: PieceHasher,
+    storage: S,
+    received_blocks: Vec<BitVec>,
+    buffer: Vec<u8>,
+    files_progress: Vec<FileProgress>,
+    state: Arc<RwLock<DownloadState>>,
+    events: broadcast::Sender<ProgressEvent>,
+    /// Whether the files of a piece are synced to disk before it's marked complete
+    sync_pieces: bool,
+    max_bad_pieces: u32,
+    max_piece_failures: u32,
+}
+
+impl<S: Storage> PieceWriter<S> {
+    pub async fn new(
+        metainfo: Arc<MetaInfo>,
+        storage: S,
+        state: Arc<RwLock<DownloadState>>,
+        events: broadcast::Sender<ProgressEvent>,
+    ) -> Self {
+        let info = metainfo.info();
+
+        let received_blocks = (0..info.piece_count() as u32)
+            .map(|index| BitVec::from_elem(progress::piece_size(info, index).div_ceil(BLOCK_SIZE as u64) as usize, false))
+            .collect();
+
+        let files_progress = progress::file_progress(info, &state.read().await.bitfield());
+
+        PieceWriter {
+            buffer: Vec::with_capacity(info.piece_length() as usize),
+            hasher: PieceHasher::new(&metainfo),
+            metainfo,
+            storage,
+            received_blocks,
+            files_progress,
+            state,
+            events,
+            sync_pieces: true,
+            max_bad_pieces: u32::MAX,
+            max_piece_failures: u32::MAX,
+        }
+    }
+
+    /// On by default. Without it a crash can lose recent pieces that were already
+    /// reported complete, since files are only synced once all senders are dropped
+    pub fn set_sync_pieces(&mut self, sync_pieces: bool) {
+        self.sync_pieces = sync_pieces;
+    }
+
+    /// Bans the peer a piece failing verification was requested from once it sent
+    /// `max_bad_pieces` of them, or once that piece failed `max_piece_failures` times.
+    /// Nobody is banned until this is called
+    pub fn set_ban_limits(&mut self, max_bad_pieces: u32, max_piece_failures: u32) {
+        self.max_bad_pieces = max_bad_pieces;
+        self.max_piece_failures = max_piece_failures;
+    }
+
+    /// Writes every received block until all senders are dropped, then syncs every file.
+    /// The first error storing a block ends it early, sent as `ProgressEvent::WriteFailed`
+    /// too, and sending to the dropped receiver fails from then on
+    pub async fn run(mut self, mut receiver: mpsc::Receiver<WriteMessage>) -> Result<(), io::Error> {
+        let result = self.write_all(&mut receiver).await;
+
+        if let Err(err) = &result {
+            let _ = self.events.send(ProgressEvent::WriteFailed(err.to_string()));
+        }
+
+        result
+    }
+
+    async fn write_all(&mut self, receiver: &mut mpsc::Receiver<WriteMessage>) -> Result<(), io::Error> {
+        while let Some(write_message) = receiver.recv().await {
+            self.write(&write_message).await?;
+        }
+
+        self.sync_all().await
+    }
+
+    /// Waits until everything written is stored on disk, metadata included
+    pub async fn sync_all(&mut self) -> Result<(), io::Error> {
+        for file in 0..self.metainfo.info().file_count() {
+            self.storage.flush(file).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores a block and verifies its piece once every block arrived. Blocks of complete
+    /// pieces are ignored, two peers can both deliver the last one in endgame
+    pub async fn write(&mut self, write_message: &WriteMessage) -> Result<(), io::Error> {
+        let index = write_message.index();
+        let info = self.metainfo.info();
+
+        if self.state.read().await.status(index) == Some(PieceStatus::Complete) {
+            return Ok(());
+        }
+
+        let offset = index as u64 * info.piece_length() as u64 + write_message.begin() as u64;
+        let mut written = 0;
+
+        for (file_index, file_offset, length) in info.files_in_range(offset, write_message.block().len() as u64) {
+            let block = &write_message.block()[written..written + length as usize];
+            self.storage.write_block(file_index, file_offset, block).await?;
+
+            written += length as usize;
+        }
+
+        let block_index = (write_message.begin() / BLOCK_SIZE) as usize;
+        self.received_blocks[index as usize].set(block_index, true);
+
+        if self.received_blocks[index as usize].all() {
+            self.verify(index).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the whole piece back from disk, if the hash doesn't match its blocks
+    /// are discarded and the piece becomes available to download from scratch
+    async fn verify(&mut self, index: u32) -> Result<(), io::Error> {
+        let info = self.metainfo.info();
+
+        let valid = read_piece(info, index, &mut self.storage, &mut self.buffer).await?
+            && self.hasher.verify(&self.metainfo, index, &self.buffer);
+
+        if !valid {
+            self.received_blocks[index as usize].clear();
+
+            let mut state = self.state.write().await;
+            let peers = state.fail(index);
+
+            let banned: Vec<SocketAddr> = peers.iter().copied()
+                .filter(|peer| state.bad_pieces(peer.ip()) >= self.max_bad_pieces || state.failures(index) >= self.max_piece_failures)
+                .collect();
+
+            let _ = self.events.send(ProgressEvent::PieceFailed { index, peers });
+
+            for peer in banned {
+                state.ban(peer.ip());
+                let _ = self.events.send(ProgressEvent::PeerBanned(peer));
+            }
+
+            return Ok(());
+        }
+
+        let piece_begin = index as u64 * info.piece_length() as u64;
+        let piece_files = info.files_in_range(piece_begin, progress::piece_size(info, index));
+
+        // a resume trusts complete pieces, so they have to be on disk first
+        if self.sync_pieces {
+            for &(file_index, _, _) in &piece_files {
+                self.storage.flush(file_index).await?;
+            }
+        }
+
+        self.state.write().await.complete(index);
+
+        // sending only fails when nobody is subscribed
+        let _ = self.events.send(ProgressEvent::PieceCompleted(index));
+
+        for (file_index, _, length) in piece_files {
+            let file_progress = &mut self.files_progress[file_index];
+            *file_progress = FileProgress::new(file_progress.downloaded() + length, file_progress.total());
+
+            let _ = self.events.send(ProgressEvent::FileProgress { file_index, progress: *file_progress });
+
+            if file_progress.downloaded() == file_progress.total() {
+                self.storage.complete(file_index).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads blocks of complete pieces back from storage to upload them
+pub struct PieceReader<S = FileStorage> {
+    metainfo: Arc<MetaInfo>,
+    storage: S,
+}
+
+impl<S: Storage> PieceReader<S> {
+    pub fn new(metainfo: Arc<MetaInfo>, storage: S) -> Self {
+        PieceReader { metainfo, storage }
+    }
+
+    /// Reads `length` bytes at `begin` of the piece at `index`, which has to be on disk
+    pub async fn read_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, io::Error> {
+        let start = index as u64 * self.metainfo.info().piece_length() as u64 + begin as u64;
+
+        self.read_range(start, length as usize).await
+    }
+
+    /// Reads `length` bytes at `start` of the whole torrent, across pieces and files
+    pub async fn read_range(&mut self, start: u64, length: usize) -> Result<Vec<u8>, io::Error> {
+        let info = self.metainfo.info();
+        let mut block = Vec::with_capacity(length);
+
+        for (file_index, offset, length) in info.files_in_range(start, length as u64) {
+            let start = block.len();
+            block.resize(start + length as usize, 0);
+
+            self.storage.read_block(file_index, offset, &mut block[start..]).await?;
+        }
+
+        // past the end of the torrent
+        if block.len() != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(block)
+    }
+}
+
+/// Hashes every piece stored in `paths` and returns the bitfield of the valid ones
+pub async fn recheck_files(metainfo: &MetaInfo, paths: &[PathBuf], events: &broadcast::Sender<ProgressEvent>) -> Result<BitVec, io::Error> {
+    recheck(metainfo, &mut FileStorage::new(paths.to_vec()), events).await
+}
+
+/// Hashes every piece in `storage` and returns the bitfield of the valid ones
+pub async fn recheck(metainfo: &MetaInfo, storage: &mut impl Storage, events: &broadcast::Sender<ProgressEvent>) -> Result<BitVec, io::Error> {
+    let info = metainfo.info();
+    let hasher = PieceHasher::new(metainfo);
+    let total = info.piece_count() as u32;
+    let mut bitfield = BitVec::from_elem(total as usize, false);
+
+    // only one piece is held in memory at a time
+    let mut buffer = Vec::with_capacity(info.piece_length() as usize);
+
+    for index in 0..total {
+        if read_piece(info, index, storage, &mut buffer).await? {
+            bitfield.set(index as usize, hasher.verify(metainfo, index, &buffer));
+        }
+
+        let _ = events.send(ProgressEvent::RecheckProgress { checked: index + 1, total });
+    }
+
+    Ok(bitfield)
+}
+
+/// Reads the piece at `index` into `buffer`, returns false if part of it isn't on disk
+async fn read_piece(info: &Info, index: u32, storage: &mut impl Storage, buffer: &mut Vec<u8>) -> Result<bool, io::Error> {
+    buffer.clear();
+
+    let begin = index as u64 * info.piece_length() as u64;
+
+    for (file_index, offset, length) in info.files_in_range(begin, progress::piece_size(info, index)) {
+        let start = buffer.len();
+        buffer.resize(start + length as usize, 0);
+
+        match storage.read_block(file_index, offset, &mut buffer[start..]).await {
+            Ok(()) => (),
+            Err(err) if matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use bit_vec::BitVec;
+    use sha1::{Sha1, Digest};
+    use tokio::sync::{RwLock, broadcast, mpsc};
+
+    use crate::bencode::FromBencode;
+    use crate::file::{self, recheck_files, PieceReader, PieceWriter};
+    use crate::metainfo::MetaInfo;
+    use crate::peer::WriteMessage;
+    use crate::progress::ProgressEvent;
+    use crate::state::{DownloadState, PieceStatus};
+    use crate::storage::{FileStorage, MemStorage};
+    use crate::torrent::BLOCK_SIZE;
+
+    fn single_file_info(data: &[u8], piece_length: usize) -> Vec<u8> {
+        let mut bytes = format!("d6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces{}:", data.len(), piece_length, data.len().div_ceil(piece_length) * 20).into_bytes();
+
+        for piece in data.chunks(piece_length) {
+            bytes.extend_from_slice(&Sha1::digest(piece));
+        }
+
+        bytes.push(b'e');
+        bytes
+    }
+
+    fn single_file_torrent(data: &[u8], piece_length: usize) -> MetaInfo {
+        let mut torrent = b"d8:announce9:localhost4:info".to_vec();
+        torrent.extend_from_slice(&single_file_info(data, piece_length));
+        torrent.push(b'e');
+
+        MetaInfo::from_bencode(&torrent).unwrap()
+    }
+
+    /// Path for a test file, left over files of a previous run with the same pid are removed
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("torrent_client_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        path
+    }
+
+    /// Paths of the files of `metainfo` in the temporary directory
+    fn temp_paths(metainfo: &MetaInfo) -> Vec<PathBuf> {
+        metainfo.info().file_paths().into_iter().map(|path| std::env::temp_dir().join(path)).collect()
+    }
+
+    #[tokio::test]
+    async fn recheck() {
+        let data: Vec<u8> = (0..20).collect();
+        let metainfo = single_file_torrent(&data, 8);
+        let (events, mut receiver) = broadcast::channel(16);
+
+        let path = temp_path("recheck");
+        let paths = vec![path.clone()];
+
+        // corrupts the second piece
+        let mut corrupted = data.clone();
+        corrupted[9] = 0;
+        std::fs::write(&path, &corrupted).unwrap();
+
+        let bitfield = recheck_files(&metainfo, &paths, &events).await.unwrap();
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![true, false, true]);
+
+        // the last piece is short
+        std::fs::write(&path, &data[..18]).unwrap();
+
+        let bitfield = recheck_files(&metainfo, &paths, &events).await.unwrap();
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![true, true, false]);
+
+        std::fs::remove_file(&path).unwrap();
+
+        let bitfield = recheck_files(&metainfo, &paths, &events).await.unwrap();
+        assert!(bitfield.none());
+
+        assert_eq!(receiver.recv().await.unwrap(), ProgressEvent::RecheckProgress { checked: 1, total: 3 });
+    }
+
+    #[tokio::test]
+    async fn queries_available_space() {
+        // missing directories are looked up on the disk of their closest existing parent
+        let path = temp_path("space").join("missing/file");
+        let available = file::available_space(&path).await.unwrap();
+
+        if cfg!(unix) {
+            assert!(available.unwrap() > 0);
+        }
+
+        assert_eq!(file::available_space(&std::env::temp_dir()).await.unwrap(), available);
+    }
+
+    #[tokio::test]
+    async fn writer_persists_partial_piece() {
+        let block = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..block * 2).map(|i| i as u8).collect();
+
+        let metainfo = Arc::new(single_file_torrent(&data, block * 2));
+
+        let path = temp_path("writer");
+        let mut storage = FileStorage::new(vec![path.clone()]);
+        storage.create(&BitVec::from_elem(1, true)).await.unwrap();
+
+        let state = Arc::new(RwLock::new(DownloadState::new(1)));
+        let peer = "127.0.0.1:6881".parse().unwrap();
+        let (events, _) = broadcast::channel(16);
+
+        let mut writer = PieceWriter::new(
+            Arc::clone(&metainfo),
+            storage,
+            Arc::clone(&state),
+            events,
+        ).await;
+
+        // the first block is on disk before the piece is complete
+        writer.write(&WriteMessage::new(0, 0, &data[..block])).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), &data[..block]);
+        assert_eq!(state.read().await.status(0), Some(PieceStatus::Missing));
+
+        // a corrupted last block fails verification and the piece is downloaded again
+        {
+            let mut state = state.write().await;
+            state.request(0, peer);
+            state.release(0, BLOCK_SIZE);
+        }
+
+        writer.write(&WriteMessage::new(0, BLOCK_SIZE, &vec![0; block])).await.unwrap();
+        assert!(state.read().await.is_missing(0));
+        assert_eq!(state.read().await.partial_offset(0), None);
+
+        writer.write(&WriteMessage::new(0, 0, &data[..block])).await.unwrap();
+        writer.write(&WriteMessage::new(0, BLOCK_SIZE, &data[block..])).await.unwrap();
+        assert_eq!(state.read().await.status(0), Some(PieceStatus::Complete));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_ignores_complete_pieces() {
+        let data: Vec<u8> = (0..10).collect();
+        let metainfo = Arc::new(single_file_torrent(&data, 10));
+
+        let storage = MemStorage::new(1);
+
+        let state = Arc::new(RwLock::new(DownloadState::new(1)));
+        let (events, mut receiver) = broadcast::channel(16);
+
+        let mut writer = PieceWriter::new(Arc::clone(&metainfo), storage.clone(), Arc::clone(&state), events).await;
+
+        writer.write(&WriteMessage::new(0, 0, &data)).await.unwrap();
+        assert_eq!(state.read().await.status(0), Some(PieceStatus::Complete));
+
+        // a second peer delivering the same piece doesn't touch the file or count it again
+        writer.write(&WriteMessage::new(0, 0, &[0; 10])).await.unwrap();
+        assert_eq!(storage.file(0).unwrap(), data);
+
+        let mut completed = 0;
+
+        while let Ok(event) = receiver.try_recv() {
+            completed += matches!(event, ProgressEvent::PieceCompleted(0)) as u32;
+        }
+
+        assert_eq!(completed, 1);
+    }
+
+    #[tokio::test]
+    async fn writer_stops_on_write_error() {
+        let data: Vec<u8> = (0..10).collect();
+        let metainfo = Arc::new(single_file_torrent(&data, 10));
+
+        // a regular file where the directory of the download should be
+        let parent = temp_path("write_error");
+        std::fs::write(&parent, b"").unwrap();
+        let storage = FileStorage::new(vec![parent.join("test")]);
+
+        let state = Arc::new(RwLock::new(DownloadState::new(1)));
+        let (events, mut receiver) = broadcast::channel(16);
+
+        let writer = PieceWriter::new(Arc::clone(&metainfo), storage, Arc::clone(&state), events).await;
+        let (sender, blocks) = mpsc::channel(4);
+        let running = tokio::spawn(writer.run(blocks));
+
+        sender.send(WriteMessage::new(0, 0, &data)).await.unwrap();
+
+        assert!(running.await.unwrap().is_err());
+        assert!(matches!(receiver.try_recv(), Ok(ProgressEvent::WriteFailed(_))));
+        assert_eq!(state.read().await.status(0), Some(PieceStatus::Missing));
+
+        // peers sending more blocks find out the writer is gone
+        assert!(sender.send(WriteMessage::new(0, 0, &data)).await.is_err());
+
+        std::fs::remove_file(&parent).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_bans_bad_peers() {
+        let data: Vec<u8> = (0..20).collect();
+        let metainfo = Arc::new(single_file_torrent(&data, 10));
+
+        let state = Arc::new(RwLock::new(DownloadState::new(2)));
+        let (events, mut receiver) = broadcast::channel(16);
+
+        let mut writer = PieceWriter::new(Arc::clone(&metainfo), MemStorage::new(1), Arc::clone(&state), events).await;
+        writer.set_ban_limits(2, 2);
+
+        let bad: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        // the first bad piece only returns the piece to the pool
+        state.write().await.request(0, bad);
+        writer.write(&WriteMessage::new(0, 0, &[0; 10])).await.unwrap();
+        assert!(state.read().await.is_missing(0));
+        assert!(!state.read().await.is_banned(bad.ip()));
+        assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::PieceFailed { index: 0, peers: vec![bad] });
+
+        state.write().await.request(1, bad);
+        writer.write(&WriteMessage::new(1, 0, &[0; 10])).await.unwrap();
+        assert_eq!(state.read().await.bad_pieces(bad.ip()), 2);
+        assert!(state.read().await.is_banned(bad.ip()));
+
+        // a piece failing again bans its peer on the first bad piece
+        state.write().await.request(0, other);
+        writer.write(&WriteMessage::new(0, 0, &[1; 10])).await.unwrap();
+        assert_eq!(state.read().await.failures(0), 2);
+        assert!(state.read().await.is_banned(other.ip()));
+        assert!(state.read().await.is_missing(0));
+
+        // nobody requested it this time
+        writer.write(&WriteMessage::new(1, 0, &[1; 10])).await.unwrap();
+        let failures: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(failures.last(), Some(&ProgressEvent::PieceFailed { index: 1, peers: Vec::new() }));
+    }
+
+    #[tokio::test]
+    async fn writer_blames_every_peer_of_a_piece() {
+        let block = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..2 * block).map(|byte| byte as u8).collect();
+        let metainfo = Arc::new(single_file_torrent(&data, 2 * block));
+
+        let state = Arc::new(RwLock::new(DownloadState::new(1)));
+        let (events, mut receiver) = broadcast::channel(16);
+
+        let mut writer = PieceWriter::new(Arc::clone(&metainfo), MemStorage::new(1), Arc::clone(&state), events).await;
+        writer.set_ban_limits(1, u32::MAX);
+
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let idle: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+
+        // a peer that let go of the piece without sending anything had no part in it
+        state.write().await.request(0, idle);
+        state.write().await.release(0, 0);
+
+        // one peer starts the piece, another one finishes it
+        state.write().await.request(0, first);
+        writer.write(&WriteMessage::new(0, 0, &data[..block])).await.unwrap();
+        state.write().await.release(0, BLOCK_SIZE);
+
+        state.write().await.request(0, second);
+        writer.write(&WriteMessage::new(0, BLOCK_SIZE, &[0; BLOCK_SIZE as usize])).await.unwrap();
+
+        assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::PieceFailed { index: 0, peers: vec![first, second] });
+        assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::PeerBanned(first));
+        assert_eq!(receiver.try_recv().unwrap(), ProgressEvent::PeerBanned(second));
+
+        let state = state.read().await;
+        assert!(state.is_banned(first.ip()) && state.is_banned(second.ip()));
+        assert!(!state.is_banned(idle.ip()));
+        assert!(state.is_missing(0));
+    }
+
+    #[tokio::test]
+    async fn writer_renames_part_files() {
+        let data: Vec<u8> = (0..20).collect();
+        let path = temp_path("writer_part");
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        let mut torrent = format!("d8:announce9:localhost4:infod6:lengthi20e4:name{}:{}12:piece lengthi10e6:pieces40:", name.len(), name).into_bytes();
+
+        for piece in data.chunks(10) {
+            torrent.extend_from_slice(&Sha1::digest(piece));
+        }
+
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = Arc::new(MetaInfo::from_bencode(&torrent).unwrap());
+        let part = file::part_path(&path, ".part");
+        let _ = std::fs::remove_file(&part);
+
+        // nothing on disk yet, the download starts in the part file
+        let paths = vec![file::stored_path(&path, Some(".part")).await.unwrap()];
+        assert_eq!(paths[0], part);
+
+        let storage = FileStorage::with_stored_paths(vec![path.clone()], paths.clone());
+        let state = Arc::new(RwLock::new(DownloadState::new(2)));
+        let (events, _) = broadcast::channel(16);
+
+        let mut writer = PieceWriter::new(Arc::clone(&metainfo), storage, Arc::clone(&state), events).await;
+
+        writer.write(&WriteMessage::new(1, 0, &data[10..])).await.unwrap();
+        assert!(!path.exists());
+
+        // a resume finds the part file, complete pieces are read from it meanwhile
+        assert_eq!(file::stored_path(&path, Some(".part")).await.unwrap(), part);
+
+        let mut reader = PieceReader::new(Arc::clone(&metainfo), FileStorage::with_stored_paths(vec![path.clone()], paths));
+        assert_eq!(reader.read_block(1, 0, 10).await.unwrap(), &data[10..]);
+
+        writer.write(&WriteMessage::new(0, 0, &data[..10])).await.unwrap();
+        assert!(!part.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        // the file opened before the rename is still readable
+        assert_eq!(reader.read_block(1, 5, 5).await.unwrap(), &data[15..]);
+        assert_eq!(file::stored_path(&path, Some(".part")).await.unwrap(), path);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_skips_empty_files() {
+        // the empty files sit inside the first piece and after the last one
+        let data: Vec<u8> = (0..12).collect();
+        let dir = temp_path("writer_empty");
+        let _ = std::fs::remove_dir_all(&dir);
+        let name = dir.file_name().unwrap().to_str().unwrap();
+
+        let mut torrent = format!(
+            "d8:announce9:localhost4:infod5:filesld6:lengthi5e4:pathl1:xeed6:lengthi0e4:pathl5:emptyeed6:lengthi7e4:pathl1:yeed6:lengthi0e4:pathl4:lasteee4:name{}:{}12:piece lengthi8e6:pieces40:",
+            name.len(), name,
+        ).into_bytes();
+
+        for piece in data.chunks(8) {
+            torrent.extend_from_slice(&Sha1::digest(piece));
+        }
+
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = Arc::new(MetaInfo::from_bencode(&torrent).unwrap());
+        assert_eq!(metainfo.info().pieces().len(), 2);
+
+        let state = Arc::new(RwLock::new(DownloadState::new(2)));
+        let (events, _) = broadcast::channel(16);
+
+        let mut storage = FileStorage::new(temp_paths(&metainfo));
+        storage.create(&BitVec::from_elem(4, true)).await.unwrap();
+
+        let writer = PieceWriter::new(Arc::clone(&metainfo), storage, Arc::clone(&state), events.clone()).await;
+        let (sender, receiver) = mpsc::channel(4);
+        let running = tokio::spawn(writer.run(receiver));
+
+        sender.send(WriteMessage::new(0, 0, &data[..8])).await.unwrap();
+        sender.send(WriteMessage::new(1, 0, &data[8..])).await.unwrap();
+        drop(sender);
+
+        running.await.unwrap().unwrap();
+
+        assert!(state.read().await.is_complete());
+        assert_eq!(std::fs::read(dir.join("x")).unwrap(), &data[..5]);
+        assert_eq!(std::fs::read(dir.join("y")).unwrap(), &data[5..]);
+        assert!(std::fs::read(dir.join("empty")).unwrap().is_empty());
+        assert!(std::fs::read(dir.join("last")).unwrap().is_empty());
+
+        // no piece needs the empty files to be there
+        std::fs::remove_file(dir.join("empty")).unwrap();
+        std::fs::remove_file(dir.join("last")).unwrap();
+        assert!(recheck_files(&metainfo, &temp_paths(&metainfo), &events).await.unwrap().all());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_syncs_multiple_files() {
+        // the first piece ends 3 bytes into the second file
+        let data: Vec<u8> = (0..12).collect();
+        let dir = temp_path("writer_sync");
+        let _ = std::fs::remove_dir_all(&dir);
+        let name = dir.file_name().unwrap().to_str().unwrap();
+
+        let mut torrent = format!(
+            "d8:announce9:localhost4:infod5:filesld6:lengthi5e4:pathl1:xeed6:lengthi7e4:pathl1:yeee4:name{}:{}12:piece lengthi8e6:pieces40:",
+            name.len(), name,
+        ).into_bytes();
+
+        for piece in data.chunks(8) {
+            torrent.extend_from_slice(&Sha1::digest(piece));
+        }
+
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = Arc::new(MetaInfo::from_bencode(&torrent).unwrap());
+
+        let state = Arc::new(RwLock::new(DownloadState::new(2)));
+        let (events, _) = broadcast::channel(16);
+
+        let storage = FileStorage::new(temp_paths(&metainfo));
+        let writer = PieceWriter::new(Arc::clone(&metainfo), storage, Arc::clone(&state), events).await;
+        let (sender, receiver) = mpsc::channel(4);
+        let running = tokio::spawn(writer.run(receiver));
+
+        sender.send(WriteMessage::new(1, 0, &data[8..])).await.unwrap();
+        sender.send(WriteMessage::new(0, 0, &data[..8])).await.unwrap();
+        drop(sender);
+
+        running.await.unwrap().unwrap();
+
+        assert!(state.read().await.is_complete());
+        assert_eq!(std::fs::read(dir.join("x")).unwrap(), &data[..5]);
+        assert_eq!(std::fs::read(dir.join("y")).unwrap(), &data[5..]);
+
+        // blocks are read back across the files too
+        let mut reader = PieceReader::new(Arc::clone(&metainfo), FileStorage::new(temp_paths(&metainfo)));
+        assert_eq!(reader.read_block(0, 3, 4).await.unwrap(), &data[3..7]);
+        assert_eq!(reader.read_block(1, 2, 2).await.unwrap(), &data[10..]);
+        assert!(reader.read_block(1, 2, 3).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use sha2::{Digest, Sha256};
+
+use crate::metainfo::MetaInfo;
+use crate::torrent::BLOCK_SIZE;
+
+/// How the pieces of a torrent are verified, selected with `PieceHasher::new`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHasher {
+    /// SHA-1 of every piece, from `pieces`
+    Sha1,
+    /// Roots of SHA-256 merkle trees over 16 KiB blocks, from the file tree and `piece layers`
+    Sha256,
+}
+
+impl PieceHasher {
+    /// v2 and hybrid torrents are verified with their v2 hashes
+    pub fn new(metainfo: &MetaInfo) -> Self {
+        if metainfo.info().meta_version() == 2 {
+            PieceHasher::Sha256
+        } else {
+            PieceHasher::Sha1
+        }
+    }
+
+    /// Whether `piece` holds the data of the piece at `index`
+    pub fn verify(&self, metainfo: &MetaInfo, index: u32, piece: &[u8]) -> bool {
+        match self {
+            PieceHasher::Sha1 => metainfo.info().pieces().get(index as usize)
+                .is_some_and(|hash| <[u8; 20]>::from(sha1::Sha1::digest(piece)) == *hash),
+            PieceHasher::Sha256 => verify_v2(metainfo, index, piece),
+        }
+    }
+}
+
+/// Every v2 file starts at a piece boundary, hybrid torrents pad their v1 files to match,
+/// so a piece belongs to a single file and only its bytes of that file are hashed
+fn verify_v2(metainfo: &MetaInfo, index: u32, piece: &[u8]) -> bool {
+    let info = metainfo.info();
+    let piece_length = info.piece_length() as u64;
+    let piece_begin = index as u64 * piece_length;
+    let mut file_begin = 0;
+
+    for file in info.file_tree() {
+        let length = file.lenght();
+        let file_end = file_begin + length;
+
+        if piece_begin < file_end {
+            let Some(pieces_root) = file.pieces_root() else {
+                return false;
+            };
+
+            let Some(data) = piece.get(..(file_end - piece_begin).min(piece_length) as usize) else {
+                return false;
+            };
+
+            // files up to a piece long have no layer, their root covers just their blocks
+            if length <= piece_length {
+                return merkle_root(data, length.div_ceil(BLOCK_SIZE as u64) as usize) == *pieces_root;
+            }
+
+            let leaves = (piece_length / BLOCK_SIZE as u64) as usize;
+            let layer_index = ((piece_begin - file_begin) / piece_length) as usize;
+
+            return metainfo.piece_layer(pieces_root)
+                .and_then(|layer| layer.get(layer_index))
+                .is_some_and(|hash| merkle_root(data, leaves) == *hash);
+        }
+
+        file_begin = file_end.div_ceil(piece_length) * piece_length;
+    }
+
+    false
+}
+
+/// Root of the merkle tree over the SHA-256 of each block of `data`, padded
+/// with zeroed hashes to at least `leaves` leaves and a power of two
+fn merkle_root(data: &[u8], leaves: usize) -> [u8; 32] {
+    let mut layer: Vec<[u8; 32]> = data.chunks(BLOCK_SIZE as usize).map(|block| Sha256::digest(block).into()).collect();
+    layer.resize(leaves.max(layer.len()).next_power_of_two(), [0; 32]);
+
+    while layer.len() > 1 {
+        layer = layer.chunks(2)
+            .map(|pair| Sha256::new().chain_update(pair[0]).chain_update(pair[1]).finalize().into())
+            .collect();
+    }
+
+    layer[0]
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::{Digest, Sha256};
+
+    use crate::bencode::FromBencode;
+    use crate::hash::PieceHasher;
+    use crate::metainfo::MetaInfo;
+    use crate::torrent::BLOCK_SIZE;
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    fn pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        sha256(&[left, right].concat())
+    }
+
+    #[test]
+    fn v2_pieces() {
+        let block = BLOCK_SIZE as usize;
+
+        // `a` spans two pieces of two blocks, the second one short, `b` starts the third piece
+        let a: Vec<u8> = (0..block * 2 + 100).map(|i| i as u8).collect();
+        let b = b"hello".to_vec();
+
+        let layer = [
+            pair(sha256(&a[..block]), sha256(&a[block..block * 2])),
+            pair(sha256(&a[block * 2..]), [0; 32]),
+        ];
+        let root_a = pair(layer[0], layer[1]);
+        let root_b = sha256(&b);
+
+        let mut torrent = format!("d8:announce9:localhost4:infod9:file treed1:ad0:d6:lengthi{}e11:pieces root32:", a.len()).into_bytes();
+        torrent.extend_from_slice(&root_a);
+        torrent.extend_from_slice(b"ee1:bd0:d6:lengthi5e11:pieces root32:");
+        torrent.extend_from_slice(&root_b);
+        torrent.extend_from_slice(format!("eee12:meta versioni2e4:name4:test12:piece lengthi{}ee12:piece layersd32:", block * 2).as_bytes());
+        torrent.extend_from_slice(&root_a);
+        torrent.extend_from_slice(b"64:");
+        torrent.extend_from_slice(&layer.concat());
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        let hasher = PieceHasher::new(&metainfo);
+        assert_eq!(hasher, PieceHasher::Sha256);
+
+        assert!(hasher.verify(&metainfo, 0, &a[..block * 2]));
+        assert!(hasher.verify(&metainfo, 1, &a[block * 2..]));
+        assert!(hasher.verify(&metainfo, 2, &b));
+
+        // hybrid torrents pad the last piece of a file, the padding isn't hashed
+        assert!(hasher.verify(&metainfo, 1, &[&a[block * 2..], &vec![0; block * 2 - 100]].concat()));
+
+        assert!(!hasher.verify(&metainfo, 0, &a[1..block * 2 + 1]));
+        assert!(!hasher.verify(&metainfo, 2, b"hellO"));
+        assert!(!hasher.verify(&metainfo, 3, &b));
+    }
+
+    #[test]
+    fn v1_pieces() {
+        let mut torrent = b"d8:announce9:localhost4:infod6:lengthi5e4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        torrent.extend_from_slice(&sha1::Sha1::digest(b"hello"));
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        let hasher = PieceHasher::new(&metainfo);
+        assert_eq!(hasher, PieceHasher::Sha1);
+
+        assert!(hasher.verify(&metainfo, 0, b"hello"));
+        assert!(!hasher.verify(&metainfo, 0, b"hellO"));
+    }
+}
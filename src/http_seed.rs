@@ -0,0 +1,215 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use url::{Host, Url};
+
+use crate::info_hash::InfoHash;
+use crate::proxy::ProxyConfig;
+use crate::tracker;
+
+/// Wait before asking a busy seed again when its answer doesn't say how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reaching the seed or reading its http response failed, like it would for a tracker
+    Http(tracker::Error),
+    /// Only plain http seeds can be downloaded from
+    UnsupportedScheme(String),
+    /// The seed answered 503, it has no bandwidth for us and asks to come back after this long
+    Busy(Duration),
+    /// Any other status than 200 and 503
+    Status(u16),
+    /// The body isn't as long as the piece that was asked for
+    InvalidLength { expected: usize, received: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(_) => write!(f, "Http seed request failed"),
+            Self::UnsupportedScheme(scheme) => write!(f, "Unsupported http seed scheme: {}", scheme),
+            Self::Busy(wait) => write!(f, "Http seed is busy, retry in {}s", wait.as_secs()),
+            Self::Status(status) => write!(f, "Http seed answered with status {}", status),
+            Self::InvalidLength { expected, received } => {
+                write!(f, "Http seed sent {} bytes for a piece of {}", received, expected)
+            }
+        }
+    }
+}
+
+impl From<tracker::Error> for Error {
+    fn from(value: tracker::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Http(tracker::Error::IoError(value))
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A seed of BEP 17, a script answering each GET with a whole piece. Unlike the BEP 19
+/// servers of `url-list` it knows the pieces itself, nothing is mapped to files here
+#[derive(Debug, Clone)]
+pub struct HttpSeed {
+    url: Url,
+    bind_address: Option<IpAddr>,
+    proxy: Option<ProxyConfig>,
+    dns_timeout: Duration,
+}
+
+impl HttpSeed {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let url = Url::parse(url).map_err(tracker::Error::from)?;
+
+        // there is no tls to speak https with
+        if url.scheme() != "http" {
+            return Err(Error::UnsupportedScheme(url.scheme().to_string()));
+        }
+
+        Ok(HttpSeed { url, bind_address: None, proxy: None, dns_timeout: Duration::from_secs(10) })
+    }
+
+    pub const fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Source address of the connections to the seed, any interface when `None`
+    pub fn set_bind_address(&mut self, bind_address: Option<IpAddr>) {
+        self.bind_address = bind_address;
+    }
+
+    /// Proxy the connections go through, which resolves the host of the seed instead of us
+    pub fn set_proxy(&mut self, proxy: Option<ProxyConfig>) {
+        self.proxy = proxy;
+    }
+
+    /// Resolving the host failing after this fails the request
+    pub fn set_dns_timeout(&mut self, dns_timeout: Duration) {
+        self.dns_timeout = dns_timeout;
+    }
+
+    /// Address the pieces of the seed are requested from in `DownloadState`. Through a proxy
+    /// the host isn't resolved, so a seed given by name counts as the unspecified ip
+    pub async fn address(&self) -> Result<SocketAddr, Error> {
+        let port = self.url.port_or_known_default().unwrap_or(80);
+
+        match (&self.proxy, self.url.host()) {
+            (Some(_), Some(Host::Ipv4(ip))) => Ok(SocketAddr::new(IpAddr::V4(ip), port)),
+            (Some(_), Some(Host::Ipv6(ip))) => Ok(SocketAddr::new(IpAddr::V6(ip), port)),
+            (Some(_), _) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)),
+            (None, _) => Ok(tracker::resolve(&self.url, self.dns_timeout).await?[0]),
+        }
+    }
+
+    /// The GET for `piece` of the torrent with `info_hash`, its parameters are added
+    /// after the ones the url already has
+    pub fn request(&self, info_hash: &InfoHash, piece: u32) -> Vec<u8> {
+        let info_hash: String = url::form_urlencoded::byte_serialize(info_hash.as_bytes()).collect();
+        let separator = if self.url.query().is_some() { '&' } else { '?' };
+        let host = format!("{}:{}", self.url.host_str().unwrap_or_default(), self.url.port_or_known_default().unwrap_or(80));
+
+        format!(
+            "GET {}{}{}info_hash={}&piece={} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\nAccept-Encoding: gzip, deflate\r\nConnection: close\r\n\r\n",
+            self.url.path(), self.url.query().map_or(String::new(), |query| format!("?{}", query)), separator, info_hash, piece, host,
+        ).into_bytes()
+    }
+
+    /// Downloads the whole `piece`, which is `length` bytes long. Its hash isn't checked,
+    /// the blocks are verified by the writer like the ones of peers
+    pub async fn download_piece(&self, info_hash: &InfoHash, piece: u32, length: usize) -> Result<Vec<u8>, Error> {
+        let mut stream = match &self.proxy {
+            Some(proxy) => tracker::connect_proxied(&self.url, proxy, self.bind_address).await?,
+            None => {
+                let addresses = tracker::resolve(&self.url, self.dns_timeout).await?;
+                tracker::connect(&addresses, self.bind_address).await?
+            }
+        };
+
+        let (reader, mut writer) = stream.split();
+        writer.write_all(&self.request(info_hash, piece)).await?;
+
+        let (head, body) = tracker::read_response(&mut BufReader::new(reader)).await?;
+
+        match status(&head) {
+            Some(200) => (),
+            // the body is the number of seconds to wait
+            Some(503) => {
+                let wait = std::str::from_utf8(&body).ok().and_then(|secs| secs.trim().parse().ok());
+                return Err(Error::Busy(wait.map_or(DEFAULT_RETRY_AFTER, Duration::from_secs)));
+            }
+            Some(status) => return Err(Error::Status(status)),
+            None => return Err(tracker::Error::InvalidHttpResponse.into()),
+        }
+
+        let body = tracker::http_body(&head, body)?;
+
+        if body.len() != length {
+            return Err(Error::InvalidLength { expected: length, received: body.len() });
+        }
+
+        Ok(body)
+    }
+}
+
+/// Status code of the status line of `head`
+fn status(head: &str) -> Option<u16> {
+    head.lines().next()?.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::http_seed::{Error, HttpSeed};
+    use crate::info_hash::InfoHash;
+    use crate::tracker::mock::MockTracker;
+
+    #[test]
+    fn request_keeps_the_url_query() {
+        let info_hash = InfoHash::new([0xAB; 20]);
+        let request = |url| String::from_utf8(HttpSeed::new(url).unwrap().request(&info_hash, 3)).unwrap();
+
+        let encoded = "%AB".repeat(20);
+        assert!(request("http://seed.example/seed").starts_with(&format!("GET /seed?info_hash={}&piece=3 HTTP/1.1\r\nHost: seed.example:80\r\n", encoded)));
+        assert!(request("http://seed.example:8080/seed?key=1").starts_with(&format!("GET /seed?key=1&info_hash={}&piece=3 HTTP/1.1\r\nHost: seed.example:8080\r\n", encoded)));
+
+        assert!(matches!(HttpSeed::new("https://seed.example/seed"), Err(Error::UnsupportedScheme(scheme)) if scheme == "https"));
+    }
+
+    #[tokio::test]
+    async fn downloads_pieces() {
+        let piece: Vec<u8> = (0..100).collect();
+        let mut mock = MockTracker::start(&piece).await;
+        let seed = HttpSeed::new(mock.url().as_str()).unwrap();
+
+        assert_eq!(seed.download_piece(&InfoHash::new([1; 20]), 2, 100).await.unwrap(), piece);
+        assert!(mock.next_request().await.starts_with("GET /announce?info_hash="));
+
+        assert!(matches!(seed.download_piece(&InfoHash::new([1; 20]), 2, 50).await, Err(Error::InvalidLength { expected: 50, received: 100 })));
+    }
+
+    #[tokio::test]
+    async fn busy_seed() {
+        let busy = MockTracker::keep_alive(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\n\r\n60").await;
+        let seed = HttpSeed::new(busy.url().as_str()).unwrap();
+        assert!(matches!(seed.download_piece(&InfoHash::new([1; 20]), 0, 10).await, Err(Error::Busy(wait)) if wait == Duration::from_secs(60)));
+
+        let missing = MockTracker::keep_alive(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+        let seed = HttpSeed::new(missing.url().as_str()).unwrap();
+        assert!(matches!(seed.download_piece(&InfoHash::new([1; 20]), 0, 10).await, Err(Error::Status(404))));
+    }
+}
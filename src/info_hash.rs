@@ -0,0 +1,154 @@
+/// Letters of the base32 alphabet of RFC 4648, the one magnet links use
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// SHA-1 of the bencoded info dictionary, which identifies a torrent to trackers and peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InfoHash([u8; 20]);
+
+impl InfoHash {
+    pub const fn new(bytes: [u8; 20]) -> Self {
+        InfoHash(bytes)
+    }
+
+    /// The 40 hex digits of a hash in either case, like in magnet links and LSD announces
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        // from_str_radix would take a leading sign too
+        if hex.len() != 40 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let mut bytes = [0u8; 20];
+
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        Some(InfoHash(bytes))
+    }
+
+    /// The 32 base32 characters of a hash in either case, the older form of magnet links
+    pub fn from_base32(base32: &str) -> Option<Self> {
+        if base32.len() != 32 {
+            return None;
+        }
+
+        let mut bytes = [0u8; 20];
+        let mut buffer = 0u64;
+        let mut bits = 0;
+        let mut index = 0;
+
+        for character in base32.bytes() {
+            let value = BASE32_ALPHABET.iter().position(|&letter| letter == character.to_ascii_uppercase())?;
+
+            buffer = buffer << 5 | value as u64;
+            bits += 5;
+
+            if bits >= 8 {
+                bits -= 8;
+                bytes[index] = (buffer >> bits) as u8;
+                index += 1;
+            }
+        }
+
+        Some(InfoHash(bytes))
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Lowercase hex, how info hashes are usually shown
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Uppercase base32 without padding, 160 bits are exactly 32 characters
+    pub fn to_base32(&self) -> String {
+        let mut base32 = String::with_capacity(32);
+        let mut buffer = 0u64;
+        let mut bits = 0;
+
+        for &byte in &self.0 {
+            buffer = buffer << 8 | byte as u64;
+            bits += 8;
+
+            while bits >= 5 {
+                bits -= 5;
+                base32.push(char::from(BASE32_ALPHABET[(buffer >> bits) as usize & 31]));
+            }
+        }
+
+        base32
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(value: [u8; 20]) -> Self {
+        InfoHash(value)
+    }
+}
+
+impl TryFrom<&[u8]> for InfoHash {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(InfoHash(value.try_into()?))
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The `to_hex` of the hash
+impl std::fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::info_hash::InfoHash;
+
+    #[test]
+    fn hex_round_trip() {
+        let info_hash = InfoHash::from_hex("a77f645163215cb37575465acdf35bddae71682e").unwrap();
+
+        assert_eq!(info_hash.as_bytes()[..3], [0xa7, 0x7f, 0x64]);
+        assert_eq!(info_hash.to_hex(), "a77f645163215cb37575465acdf35bddae71682e");
+        assert_eq!(InfoHash::from_hex("A77F645163215CB37575465ACDF35BDDAE71682E"), Some(info_hash));
+        assert_eq!(info_hash.to_string(), info_hash.to_hex());
+
+        assert_eq!(InfoHash::from_hex("a77f645163215cb37575465acdf35bddae71682"), None);
+        assert_eq!(InfoHash::from_hex("g77f645163215cb37575465acdf35bddae71682e"), None);
+        assert_eq!(InfoHash::from_hex("+a7f645163215cb37575465acdf35bddae71682e"), None);
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        let info_hash = InfoHash::from_hex("a77f645163215cb37575465acdf35bddae71682e").unwrap();
+
+        assert_eq!(info_hash.to_base32(), "U57WIULDEFOLG5LVIZNM34233WXHC2BO");
+        assert_eq!(InfoHash::from_base32("U57WIULDEFOLG5LVIZNM34233WXHC2BO"), Some(info_hash));
+        assert_eq!(InfoHash::from_base32("u57wiuldefolg5lviznm34233wxhc2bo"), Some(info_hash));
+
+        assert_eq!(InfoHash::from_base32("U57WIULDEFOLG5LVIZNM34233WXHC2B"), None);
+        assert_eq!(InfoHash::from_base32("U57WIULDEFOLG5LVIZNM34233WXHC2B1"), None);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let info_hash = InfoHash::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(info_hash.as_bytes().as_slice(), bytes.as_slice());
+        assert_eq!(InfoHash::new(*info_hash.as_bytes()), info_hash);
+        assert_eq!(InfoHash::from_hex(&info_hash.to_hex()), Some(info_hash));
+        assert_eq!(InfoHash::from_base32(&info_hash.to_base32()), Some(info_hash));
+
+        assert!(InfoHash::try_from(&bytes[..19]).is_err());
+    }
+}
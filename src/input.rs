@@ -1,3 +1,5 @@
+use crate::info_hash::InfoHash;
+
 pub enum TorrentType {
     MagnetLink(String),
     InfoHash(String),
@@ -32,20 +34,20 @@ fn is_magnet_link(value: &str) -> bool {
 }
 
 fn is_info_hash(value: &str) -> bool {
-    // info hash are 20-byte SHA-1 hash represented as a hex string
-    value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit())
+    InfoHash::from_hex(value).is_some()
 }
 
 fn is_torrent_file(value: &str) -> bool {
     let path = std::path::Path::new(value);
-    path.is_file() && path.extension().map_or(false, |extension| extension == "torrent")
+    path.is_file() && path.extension().is_some_and(|extension| extension == "torrent")
 }
 
-fn is_torrent_file_url(_value: &str) -> bool {
-    todo!()
+fn is_torrent_file_url(value: &str) -> bool {
+    url::Url::parse(value).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https") && url.path().ends_with(".torrent")
+    })
 }
 
-fn is_base32_info_hash(_value: &str) -> bool {
-    // 32-byte base32 encoded representation of info hash
-    todo!()
+fn is_base32_info_hash(value: &str) -> bool {
+    InfoHash::from_base32(value).is_some()
 }
\ No newline at end of file
@@ -0,0 +1,202 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Connections waiting to be accepted, like the default of the standard library
+const LISTEN_BACKLOG: u32 = 128;
+
+/// An address assigned to a network interface of this machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    name: String,
+    address: IpAddr,
+}
+
+impl Interface {
+    pub const fn new(name: String, address: IpAddr) -> Self {
+        Interface { name, address }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub const fn address(&self) -> IpAddr {
+        self.address
+    }
+}
+
+/// Every ipv4 and ipv6 address of the interfaces of this machine, one entry per address.
+/// Empty where interfaces can't be listed
+#[cfg(unix)]
+pub fn interfaces() -> Result<Vec<Interface>, io::Error> {
+    use std::ffi::CStr;
+
+    let mut addresses = std::ptr::null_mut();
+
+    // safety: getifaddrs only writes the head of the list it allocates
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces = Vec::new();
+    let mut current = addresses;
+
+    while !current.is_null() {
+        // safety: every node of the list stays valid until freeifaddrs
+        let entry = unsafe { &*current };
+        current = entry.ifa_next;
+
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+
+        // safety: the family says which sockaddr the pointer is to
+        let address = match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                // s_addr is in network order, its bytes are the octets
+                IpAddr::from(address.sin_addr.s_addr.to_ne_bytes())
+            }
+            libc::AF_INET6 => {
+                let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::from(address.sin6_addr.s6_addr)
+            }
+            _ => continue,
+        };
+
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned();
+        interfaces.push(Interface::new(name, address));
+    }
+
+    unsafe { libc::freeifaddrs(addresses) };
+
+    Ok(interfaces)
+}
+
+#[cfg(not(unix))]
+pub fn interfaces() -> Result<Vec<Interface>, io::Error> {
+    Ok(Vec::new())
+}
+
+/// Where to listen on `port` when both families can't share a socket, every ipv4
+/// interface unless `bind` picks an address
+pub fn listen_address(bind: Option<IpAddr>, port: u16) -> SocketAddr {
+    SocketAddr::new(bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port)
+}
+
+/// Listens on `port` of `bind`, or of every interface of both families without it.
+/// Systems where `[::]` can't take ipv4 connections too only get the ipv4 ones
+pub async fn listen(bind: Option<IpAddr>, port: u16) -> Result<TcpListener, io::Error> {
+    if bind.is_none() {
+        if let Ok(listener) = listen_dual_stack(port) {
+            return Ok(listener);
+        }
+    }
+
+    TcpListener::bind(listen_address(bind, port)).await
+}
+
+fn listen_dual_stack(port: u16) -> Result<TcpListener, io::Error> {
+    let socket = TcpSocket::new_v6()?;
+    // the default differs between systems
+    set_only_v6(&socket, false)?;
+    // like TcpListener::bind, so a restarted client gets its port back right away
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))?;
+
+    socket.listen(LISTEN_BACKLOG)
+}
+
+#[cfg(unix)]
+fn set_only_v6(socket: &TcpSocket, only_v6: bool) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let value = libc::c_int::from(only_v6);
+
+    // safety: the option is an int that is only read during the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_only_v6(_socket: &TcpSocket, _only_v6: bool) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "dual stack sockets are only set up on unix"))
+}
+
+/// Connects to `address` from `bind`, or from wherever the routing table picks without it.
+/// A bound connection never falls back to another interface, so an address of the other
+/// family than `bind` always fails
+pub async fn connect(address: SocketAddr, bind: Option<IpAddr>) -> Result<TcpStream, io::Error> {
+    let Some(bind) = bind else {
+        return TcpStream::connect(address).await;
+    };
+
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.bind(SocketAddr::new(bind, 0))?;
+    socket.connect(address).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::interface;
+
+    #[test]
+    fn lists_loopback() {
+        let interfaces = interface::interfaces().unwrap();
+
+        assert!(interfaces.iter().any(|interface| interface.address() == IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn connects_from_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let stream = interface::connect(address, Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        assert!(interface::connect(address, None).await.is_ok());
+
+        // an ipv6 source can't reach an ipv4 peer
+        assert!(interface::connect(address, Some("::1".parse().unwrap())).await.is_err());
+
+        assert_eq!(interface::listen_address(None, 6881), "0.0.0.0:6881".parse().unwrap());
+        assert_eq!(interface::listen_address(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), 6881), "127.0.0.1:6881".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn listens_on_both_families() {
+        let listener = interface::listen(None, 0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_ok());
+        assert!(TcpStream::connect(("::1", port)).await.is_ok());
+
+        let listener = interface::listen(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), 0).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
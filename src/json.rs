@@ -0,0 +1,117 @@
+use serde::{Serialize, Serializer};
+
+use crate::metainfo::MetaInfo;
+
+/// Lowercase hex of `bytes`, how info hashes are usually shown
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Serializes bytes as a hex string instead of an array of numbers
+pub fn hex<S: Serializer>(bytes: &impl AsRef<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(bytes.as_ref()))
+}
+
+pub fn hex_option<S: Serializer, const N: usize>(bytes: &Option<[u8; N]>, serializer: S) -> Result<S::Ok, S::Error> {
+    bytes.as_ref().map(|bytes| to_hex(bytes)).serialize(serializer)
+}
+
+pub fn hex_list<S: Serializer, const N: usize>(list: &[[u8; N]], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(list.iter().map(|bytes| to_hex(bytes)))
+}
+
+/// What `MetaInfo::to_json` exports, the fields tools usually look for
+#[derive(Serialize)]
+struct MetaInfoJson<'a> {
+    name: &'a str,
+    info_hash: String,
+    trackers: Vec<&'a str>,
+    length: u64,
+    piece_length: u32,
+    piece_count: usize,
+    files: Vec<FileJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pieces: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct FileJson {
+    path: String,
+    length: u64,
+}
+
+impl MetaInfo {
+    /// Name, hex info hash, trackers and files of the torrent as pretty printed json.
+    /// The piece hashes are only included with `pieces`, each as hex
+    pub fn to_json(&self, pieces: bool) -> String {
+        let info = self.info();
+
+        let mut trackers = vec![self.announce().as_str()];
+
+        for tracker in self.announce_list().into_iter().flatten().flatten() {
+            if !trackers.contains(&tracker.as_str()) {
+                trackers.push(tracker);
+            }
+        }
+
+        let files = info.file_paths().into_iter()
+            .zip(info.file_lengths())
+            .map(|(path, length)| FileJson { path: path.to_string_lossy().into_owned(), length })
+            .collect();
+
+        let json = MetaInfoJson {
+            name: info.name(),
+            info_hash: self.info_hash().to_hex(),
+            trackers,
+            length: info.total_length(),
+            piece_length: info.piece_length(),
+            piece_count: info.piece_count(),
+            files,
+            pieces: pieces.then(|| info.pieces().iter().map(|piece| to_hex(piece)).collect()),
+        };
+
+        // paths that aren't utf-8 would be the only failure, and they are converted lossily
+        serde_json::to_string_pretty(&json).expect("metainfo is always serializable")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use crate::bencode::FromBencode;
+    use crate::metainfo::MetaInfo;
+
+    fn metainfo() -> MetaInfo {
+        let mut torrent = b"d8:announce9:localhost13:announce-listll9:localhostel10:backup.orgee4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi5e4:pathl3:dir1:beee4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        torrent.extend_from_slice(&[0xab; 20]);
+        torrent.extend_from_slice(b"ee");
+
+        MetaInfo::from_bencode(&torrent).unwrap()
+    }
+
+    #[test]
+    fn exports_summary() {
+        let metainfo = metainfo();
+        let json: Value = serde_json::from_str(&metainfo.to_json(false)).unwrap();
+
+        assert_eq!(json["name"], "test");
+        assert_eq!(json["info_hash"], metainfo.info_hash().to_hex());
+        assert_eq!(json["trackers"], serde_json::json!(["localhost", "backup.org"]));
+        assert_eq!((json["length"].as_u64(), json["piece_length"].as_u64(), json["piece_count"].as_u64()), (Some(8), Some(16384), Some(1)));
+        assert_eq!(json["files"][1], serde_json::json!({ "path": "test/dir/b", "length": 5 }));
+        assert!(json.get("pieces").is_none());
+
+        let json: Value = serde_json::from_str(&metainfo.to_json(true)).unwrap();
+        assert_eq!(json["pieces"], serde_json::json!(["ab".repeat(20)]));
+    }
+
+    #[test]
+    fn derived_bytes_are_hex() {
+        let metainfo = metainfo();
+        let json = serde_json::to_value(&metainfo).unwrap();
+
+        assert_eq!(json["info_hash"].as_str().unwrap().len(), 40);
+        assert_eq!(json["info"]["pieces"], serde_json::json!(["ab".repeat(20)]));
+    }
+}
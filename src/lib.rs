@@ -5,4 +5,24 @@ pub mod client;
 pub mod torrent;
 pub mod bencode;
 pub mod tracker;
-pub mod peer;
\ No newline at end of file
+pub mod http_seed;
+pub mod peer;
+pub mod peer_id;
+pub mod info_hash;
+pub mod progress;
+pub mod file;
+pub mod hash;
+pub mod lsd;
+pub mod error;
+pub mod config;
+pub mod seed;
+pub mod state;
+pub mod scheduler;
+pub mod stats;
+pub mod blocklist;
+pub mod storage;
+pub mod pex;
+pub mod interface;
+pub mod proxy;
+#[cfg(feature = "serde")]
+pub mod json;
@@ -0,0 +1,147 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::from_utf8;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use crate::info_hash::InfoHash;
+
+/// Multicast group and port of Local Service Discovery (BEP 14) over IPv4
+pub const LSD_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// BEP 14 asks for at most one announce per minute per torrent
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A `BT-SEARCH` message, multicast by every client to tell the LAN which torrents it has
+#[derive(Debug, PartialEq, Eq)]
+pub struct Announce {
+    port: u16,
+    info_hashes: Vec<InfoHash>,
+    /// Lets a client recognize and skip its own announces
+    cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn new(port: u16, info_hashes: Vec<InfoHash>, cookie: Option<String>) -> Self {
+        Announce { port, info_hashes, cookie }
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn info_hashes(&self) -> &[InfoHash] {
+        &self.info_hashes
+    }
+
+    pub fn cookie(&self) -> Option<&str> {
+        self.cookie.as_deref()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", LSD_ADDRESS, self.port);
+
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", info_hash.to_hex()));
+        }
+
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Reads a `BT-SEARCH` message, header names are case insensitive and unknown ones ignored
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut lines = from_utf8(bytes).ok()?.split("\r\n");
+
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().ok()?),
+                "infohash" => info_hashes.push(InfoHash::from_hex(value)?),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => (),
+            }
+        }
+
+        Some(Announce { port: port?, info_hashes, cookie })
+    }
+}
+
+/// Announces `info_hash` on the LAN every few minutes and calls `on_peer` with the
+/// address of every other client announcing it. Only returns on socket errors,
+/// including when another program already has the LSD port
+pub async fn discover(info_hash: InfoHash, port: u16, mut on_peer: impl FnMut(SocketAddr)) -> Result<(), io::Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LSD_ADDRESS.port())).await?;
+    socket.join_multicast_v4(*LSD_ADDRESS.ip(), Ipv4Addr::UNSPECIFIED)?;
+
+    let cookie = format!("{:08x}", rand::random::<u32>());
+    let message = Announce::new(port, vec![info_hash], Some(cookie.clone())).to_bytes();
+
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    let mut buffer = [0u8; 1500];
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                socket.send_to(&message, LSD_ADDRESS).await?;
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (length, from) = received?;
+
+                let Some(announce) = Announce::parse(&buffer[..length]) else {
+                    continue;
+                };
+
+                // multicast loops our own announces back
+                if announce.cookie() != Some(cookie.as_str()) && announce.info_hashes().contains(&info_hash) {
+                    on_peer(SocketAddr::new(from.ip(), announce.port()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::info_hash::InfoHash;
+    use crate::lsd::Announce;
+
+    #[test]
+    fn announce_round_trip() {
+        let announce = Announce::new(6881, vec![InfoHash::new([0xab; 20]), InfoHash::new([1; 20])], Some(String::from("c00k1e")));
+        let bytes = announce.to_bytes();
+
+        assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abababab"));
+        assert_eq!(Announce::parse(&bytes), Some(announce));
+    }
+
+    #[test]
+    fn parse_other_clients() {
+        let message = b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT: 51413\r\nx-extra: 1\r\ninfohash: 0101010101010101010101010101010101010101\r\n\r\n\r\n";
+        let announce = Announce::parse(message).unwrap();
+
+        assert_eq!(announce.port(), 51413);
+        assert_eq!(announce.info_hashes(), &[InfoHash::new([1; 20])]);
+        assert_eq!(announce.cookie(), None);
+
+        assert_eq!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"), None);
+        assert_eq!(Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 0101\r\nPort: 1\r\n\r\n"), None);
+        assert_eq!(Announce::parse(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n\r\n"), None);
+    }
+}
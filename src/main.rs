@@ -1,15 +1,226 @@
-use clap::Parser;
-use torrent_client::args::Args;
-use torrent_client::client::Client;
+use std::io::{stdout, IsTerminal};
+
+use clap::{CommandFactory, Parser};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use tokio::sync::broadcast::{self, error::RecvError};
+use torrent_client::args::{Args, Command, Progress};
+use torrent_client::client::{self, Client};
+use torrent_client::config::ClientConfig;
+use torrent_client::error;
+use torrent_client::interface;
+use torrent_client::progress::ProgressEvent;
+use torrent_client::proxy::ProxyConfig;
+use torrent_client::stats::TorrentStats;
+use torrent_client::torrent::Torrent;
+
+/// Prints the protocol traces of peers to stderr
+#[cfg(feature = "trace")]
+struct StderrLogger;
+
+#[cfg(feature = "trace")]
+impl log::Log for StderrLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
 
 #[tokio::main]
 async fn main() {
+    #[cfg(feature = "trace")]
+    {
+        log::set_logger(&StderrLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+
     let args = Args::parse();
 
-    let client = Client::new();
+    let mut config = ClientConfig::new();
+    config.seed_after_complete = args.seed;
+    config.part_suffix = args.part;
+    config.check_disk_space = !args.skip_space_check;
+    config.bind_address = args.bind;
+    config.proxy = args.proxy.map(|address| {
+        let mut proxy = ProxyConfig::new(address);
+
+        // a username alone has an empty password
+        if let Some(auth) = args.proxy_auth.as_deref() {
+            let (username, password) = auth.split_once(':').unwrap_or((auth, ""));
+            proxy.set_credentials(username.to_string(), password.to_string());
+        }
+
+        proxy
+    });
+
+    let client = Client::with_config(config);
 
-    if let Err(err) = client.download(&args.torrent_file).await {
-        eprintln!("Error: {:?}", err);
+    let result = match (args.command, args.torrent_file) {
+        (Some(Command::Verify { torrent_file }), _) => {
+            client.verify(&torrent_file).await.map(|bitfield| {
+                let valid = bitfield.iter().filter(|&piece| piece).count();
+                println!("{}/{} pieces valid", valid, bitfield.len());
+            })
+        }
+        (Some(Command::Interfaces), _) => match interface::interfaces() {
+            Ok(interfaces) => {
+                for interface in interfaces {
+                    println!("{}\t{}", interface.name(), interface.address());
+                }
+
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("Error: Could not list the interfaces: {}", err);
+                std::process::exit(-1)
+            }
+        },
+        (None, Some(torrent_file)) => download(&client, &torrent_file, args.progress).await,
+        (None, None) => {
+            Args::command().print_help().unwrap();
+            std::process::exit(-1)
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", error::report(&err));
         std::process::exit(-1)
     }
 }
+
+/// Downloads like `Client::download`, showing the progress events of the torrent meanwhile
+async fn download(client: &Client, torrent_file: &str, progress: Progress) -> Result<(), client::Error> {
+    let torrent = Torrent::new(torrent_file, client.config().clone()).await?;
+    let events = torrent.subscribe();
+
+    let progress = match progress {
+        Progress::Auto if stdout().is_terminal() => Progress::Bar,
+        Progress::Auto => Progress::Lines,
+        progress => progress,
+    };
+
+    let mut display = Display::new(progress, torrent.metainfo().info().total_length());
+
+    // the events never end while the torrent is alive, the download decides when to stop.
+    // Interrupting lets the trackers know we left, which matters most when seeding
+    tokio::select! {
+        result = torrent.download() => result?,
+        () = display.show(events) => (),
+        _ = tokio::signal::ctrl_c() => {
+            torrent.pause().await?;
+            return Ok(());
+        }
+    }
+
+    display.finish();
+
+    Ok(())
+}
+
+enum Display {
+    Bar(ProgressBar),
+    Lines { last_pieces: Option<u32> },
+    None,
+}
+
+impl Display {
+    fn new(progress: Progress, total_length: u64) -> Self {
+        match progress {
+            Progress::Bar | Progress::Auto => {
+                let bar = ProgressBar::new(total_length);
+                bar.set_style(ProgressStyle::with_template("{wide_bar} {percent:>3}% {msg}").unwrap());
+
+                Display::Bar(bar)
+            }
+            Progress::Lines => Display::Lines { last_pieces: None },
+            Progress::None => Display::None,
+        }
+    }
+
+    async fn show(&mut self, mut events: broadcast::Receiver<ProgressEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(ProgressEvent::Stats(stats)) => self.update(&stats),
+                Ok(ProgressEvent::AlreadyComplete) => self.already_complete(),
+                Ok(ProgressEvent::PieceFailed { index, .. }) => self.message(&format!("Piece {} failed verification", index)),
+                Ok(ProgressEvent::PeerBanned(peer)) => self.message(&format!("Banned peer {}", peer)),
+                Ok(ProgressEvent::SeedingLimitReached) => self.message("Seeding limit reached"),
+                Ok(ProgressEvent::Warning(warning)) => self.warn(&warning),
+                Ok(_) | Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    fn update(&mut self, stats: &TorrentStats) {
+        match self {
+            Display::Bar(bar) => {
+                bar.set_position(bar.length().unwrap_or(0).saturating_sub(stats.left()));
+                bar.set_message(summary(stats));
+            }
+            Display::Lines { last_pieces } => {
+                if *last_pieces != Some(stats.pieces_complete()) {
+                    *last_pieces = Some(stats.pieces_complete());
+
+                    let percent = stats.pieces_complete() as f64 / stats.pieces_total().max(1) as f64 * 100.0;
+                    println!("{:.1}% {}/{} pieces, {}", percent, stats.pieces_complete(), stats.pieces_total(), summary(stats));
+                }
+            }
+            Display::None => (),
+        }
+    }
+
+    fn already_complete(&self) {
+        self.message("The files are already complete");
+    }
+
+    fn message(&self, message: &str) {
+        match self {
+            Display::Bar(bar) => bar.println(message),
+            Display::Lines { .. } => println!("{}", message),
+            Display::None => (),
+        }
+    }
+
+    /// Shown even without progress, on stderr unless it would break the bar
+    fn warn(&self, warning: &str) {
+        match self {
+            Display::Bar(bar) => bar.println(warning),
+            Display::Lines { .. } | Display::None => eprintln!("{}", warning),
+        }
+    }
+
+    /// Only called once the download succeeded, the last stats may be from before the last piece
+    fn finish(&self) {
+        match self {
+            Display::Bar(bar) => {
+                bar.set_position(bar.length().unwrap_or(0));
+                bar.finish();
+            }
+            Display::Lines { .. } => println!("Download finished"),
+            Display::None => (),
+        }
+    }
+}
+
+/// Speed, peers, time left and swarm size of a stats event
+fn summary(stats: &TorrentStats) -> String {
+    let eta = stats.eta().map_or(String::from("unknown"), |eta| HumanDuration(eta).to_string());
+
+    let mut summary = format!("{}/s, {} peers, ETA {}", HumanBytes(stats.download_rate() as u64), stats.connected_peers(), eta);
+
+    // trackers don't have to count the swarm
+    if let Some(seeders) = stats.seeders() {
+        summary.push_str(&format!(", {} seeders", seeders));
+    }
+
+    if let Some(leechers) = stats.leechers() {
+        summary.push_str(&format!(", {} leechers", leechers));
+    }
+
+    summary
+}
@@ -1,14 +1,23 @@
-use std::{fs, fmt};
-use std::path::PathBuf;
+use std::{fs, fmt, io};
 use std::io::Read;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 use std::str::from_utf8;
+use std::sync::Arc;
 
 use chrono::NaiveDateTime;
 use sha1::{Sha1, Digest};
+use sha2::Sha256;
 
-use crate::bencode::{self, FromBencode, Bedecode, Type, FromBencodeType};
+use crate::bencode::{self, Bedecode, Type, FromBencodeType};
+use crate::info_hash::InfoHash;
 use crate::input::TorrentType;
 
+/// Biggest piece length accepted unless configured otherwise, 16 MiB
+pub const DEFAULT_MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
 #[derive(Debug)]
 pub enum Error {
     MissingInfo,
@@ -19,19 +28,73 @@ pub enum Error {
     MalformedTimestamp,
     MissingLength,
     MissingPath,
+    /// A file name or path element that would leave the download directory, like `..`
+    InvalidPath(String),
+    /// A file length that is negative or doesn't fit in 64 bits
+    InvalidLength(String),
+    InvalidPieceLength { length: u64, max: u32 },
+    /// Byte length of `pieces`, which isn't a multiple of 20
+    InvalidPieces(usize),
+    InvalidMd5Sum,
+    /// Byte length of a piece layer, which isn't a multiple of 32
+    InvalidPieceLayer(usize),
+    /// Key of a string that must be utf-8 but isn't
+    NotUtf8(&'static str),
+    ConflictingLength { length: u64, files: u64 },
+    UnsupportedMetaVersion(u64),
+    InvalidPiecesRoot,
+    /// The input names a torrent some other way than a file, which can't be read yet
+    Unsupported(String),
+    /// The input is neither a torrent file nor a magnet link
+    InvalidInput(String),
+    Io(io::Error),
     DecodingError(bencode::Error)
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::MalformedTimestamp => write!(f, "Error: timestamp has the wrong format"),
-            _ => todo!(),
+            Self::MissingInfo => write!(f, "Missing info dictionary"),
+            Self::MissingPieceLength => write!(f, "Missing piece length"),
+            Self::MissingPieces => write!(f, "Missing pieces"),
+            Self::MissingName => write!(f, "Missing name"),
+            Self::MissingMd5Sum => write!(f, "Missing md5sum"),
+            Self::MalformedTimestamp => write!(f, "Timestamp has the wrong format"),
+            Self::MissingLength => write!(f, "Missing length"),
+            Self::MissingPath => write!(f, "Missing path"),
+            Self::InvalidPath(name) => write!(f, "Invalid file name {:?}", name),
+            Self::InvalidLength(length) => write!(f, "Invalid file length {}", length),
+            Self::InvalidPieceLength { length, max } => write!(f, "Piece length {} must be between 1 and {}", length, max),
+            Self::InvalidPieces(length) => write!(f, "Pieces must be a multiple of 20 bytes, not {}", length),
+            Self::InvalidMd5Sum => write!(f, "Md5sum must be 16 bytes or 32 hex digits"),
+            Self::InvalidPieceLayer(length) => write!(f, "Piece layer must be a multiple of 32 bytes, not {}", length),
+            Self::NotUtf8(key) => write!(f, "{} is not utf-8", key),
+            Self::ConflictingLength { length, files } => write!(f, "Length {} doesn't match the {} bytes of the files", length, files),
+            Self::UnsupportedMetaVersion(version) => write!(f, "Unsupported meta version {}", version),
+            Self::InvalidPiecesRoot => write!(f, "Pieces root must be 32 bytes"),
+            Self::Unsupported(feature) => write!(f, "Unsupported: {}", feature),
+            Self::InvalidInput(input) => write!(f, "{} is not a torrent file or magnet link", input),
+            Self::Io(_) => write!(f, "Could not read the torrent file"),
+            Self::DecodingError(_) => write!(f, "Could not decode metainfo"),
         }
     }
 }
 
-impl std::error::Error for Error { }
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::DecodingError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
 
 impl From<bencode::Error> for Error {
     fn from(value: bencode::Error) -> Self {
@@ -39,19 +102,30 @@ impl From<bencode::Error> for Error {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct CreationDate(NaiveDateTime);
 
-/// Represents a file of a multi-file info dictionary
-#[derive(Debug)]
+impl CreationDate {
+    pub const fn date_time(&self) -> &NaiveDateTime {
+        &self.0
+    }
+}
+
+/// Represents a file of a multi-file info dictionary or of a v2 file tree
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct File {
-    length: u32,
+    length: u64,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::json::hex_option"))]
     md5sum: Option<[u8; 16]>,
     path: PathBuf,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::json::hex_option"))]
+    pieces_root: Option<[u8; 32]>,
 }
 
 impl File {
-    pub const fn lenght(&self) -> u32 {
+    pub const fn lenght(&self) -> u64 {
         self.length
     }
 
@@ -62,6 +136,11 @@ impl File {
     pub const fn path(&self) -> &PathBuf {
         &self.path
     }
+
+    /// Root of the SHA-256 merkle tree of the file, only in v2 file trees
+    pub const fn pieces_root(&self) -> Option<&[u8; 32]> {
+        self.pieces_root.as_ref()
+    }
 }
 
 impl FromBencodeType for File {
@@ -73,59 +152,152 @@ impl FromBencodeType for File {
         let mut length = None;
         let mut md5sum = None;
         let mut path = None;
+        let mut path_utf8 = None;
 
         let iter = dict.iter();
 
         for (name, value) in iter {
-            let name = name.try_into_byte_string().unwrap().0;
+            let name = name.try_into_byte_string()?.0;
 
             match (name, value) {
                 (b"length", Type::Integer(int, _)) => {
-                    length = Some(int.parse().unwrap())
+                    length = Some(parse_length(int)?)
                 }
                 (b"md5sum", Type::String(bytes, _)) => {
-                    let mut arr = [0u8; 16];
-
-                    arr[..16].copy_from_slice(&bytes[..16]);
-
-                    md5sum = Some(arr);
+                    md5sum = Some(parse_md5sum(bytes)?);
                 }
                 (b"path", Type::List(list, _)) => {
-                    let mut path_buf = PathBuf::new();
-
-                    for elem in list {
-                        let elem = from_utf8(elem.try_into_byte_string()?.0).unwrap();
-                        path_buf.push(format!("/{}", elem));
-                    }
-                    
-                    path = Some(path_buf)
+                    path = Some(path_from_list(list)?);
                 }
-                _ => todo!(),
+                (b"path.utf-8", Type::List(list, _)) => {
+                    path_utf8 = Some(path_from_list(list)?);
+                }
+                _ => (),
             }
         }
 
         let length = length.ok_or(Error::MissingLength)?;
-        let path = path.ok_or(Error::MissingPath)?;
+        let path = path_utf8.or(path).ok_or(Error::MissingPath)?;
 
         Ok(File {
             length,
             md5sum,
-            path
+            path,
+            pieces_root: None,
         })
     }
 }
 
+/// Joins the elements of a `path` list. Elements that aren't utf-8, which older clients
+/// wrote in the encoding of the system, are converted lossily
+fn path_from_list(list: &[Type]) -> Result<PathBuf, Error> {
+    if list.is_empty() {
+        return Err(Error::MissingPath);
+    }
+
+    let mut path_buf = PathBuf::new();
+
+    for elem in list {
+        path_buf.push(path_component(String::from_utf8_lossy(elem.try_into_byte_string()?.0).into_owned())?);
+    }
+
+    Ok(path_buf)
+}
+
+/// Checks that `name` is a single file or directory name, so joining it can't leave the
+/// download directory. Separators, `.`, `..`, roots and prefixes like `C:` are rejected
+fn path_component(name: String) -> Result<String, Error> {
+    let mut components = Path::new(&name).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(name),
+        _ => Err(Error::InvalidPath(name)),
+    }
+}
+
+/// Parses the `length` of a file, which bencode allows to be negative or arbitrarily large
+fn parse_length(int: &str) -> Result<u64, Error> {
+    int.parse().map_err(|_| Error::InvalidLength(int.to_owned()))
+}
+
+/// Parses an `md5sum`, 32 hex digits as the spec says or the 16 bytes some clients write
+fn parse_md5sum(bytes: &[u8]) -> Result<[u8; 16], Error> {
+    if let Ok(md5sum) = <[u8; 16]>::try_from(bytes) {
+        return Ok(md5sum);
+    }
+
+    if bytes.len() != 32 {
+        return Err(Error::InvalidMd5Sum);
+    }
+
+    let digit = |digit: u8| char::from(digit).to_digit(16).ok_or(Error::InvalidMd5Sum);
+    let mut md5sum = [0u8; 16];
+
+    for (byte, &[high, low]) in md5sum.iter_mut().zip(bytes.as_chunks::<2>().0) {
+        *byte = (digit(high)? << 4 | digit(low)?) as u8;
+    }
+
+    Ok(md5sum)
+}
+
+/// Flattens a v2 `file tree` into `files`, a dictionary with an empty key holds a file
+/// and every other key is a directory or file name under `path`
+fn files_from_tree(tree: &Type, path: &Path, files: &mut Vec<File>) -> Result<(), Error> {
+    for (name, value) in tree.try_into_dict()?.0 {
+        let name = name.try_into_byte_string()?.0;
+
+        if !name.is_empty() {
+            let name = from_utf8(name).map_err(|_| Error::InvalidPath(String::from_utf8_lossy(name).into_owned()))?;
+            files_from_tree(value, &path.join(path_component(name.to_owned())?), files)?;
+            continue;
+        }
+
+        let mut length = None;
+        let mut pieces_root = None;
+
+        for (key, value) in value.try_into_dict()?.0 {
+            match (key.try_into_byte_string()?.0, value) {
+                (b"length", Type::Integer(int, _)) => {
+                    length = Some(parse_length(int)?);
+                }
+                (b"pieces root", Type::String(bytes, _)) => {
+                    pieces_root = Some(<[u8; 32]>::try_from(*bytes).map_err(|_| Error::InvalidPiecesRoot)?);
+                }
+                _ => (),
+            }
+        }
+
+        files.push(File {
+            length: length.ok_or(Error::MissingLength)?,
+            md5sum: None,
+            path: path.to_path_buf(),
+            pieces_root,
+        });
+    }
+
+    Ok(())
+}
+
+/// Cloning is cheap, the piece hashes are shared between clones
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Info {
     piece_length: u32,
-    pieces: Vec<[u8; 20]>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::json::hex_list"))]
+    pieces: Arc<[[u8; 20]]>,
     private: Option<bool>,
     name: String,
     mode: FileMode,
+    meta_version: u32,
+    file_tree: Vec<File>,
 }
 
 impl fmt::Debug for Info {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "piece_length: {}, pieces: Vec<[{}; 20]>, private: {:?}, name: {}, mode: {:?}", self.piece_length, self.pieces.len(), self.private, self.name, self.mode)
+        write!(f,
+            "piece_length: {}, pieces: Vec<[{}; 20]>, private: {:?}, name: {}, mode: {:?}, meta_version: {}, file_tree: {:?}",
+            self.piece_length, self.pieces.len(), self.private, self.name, self.mode, self.meta_version, self.file_tree
+        )
     }
 }
 
@@ -134,10 +306,26 @@ impl Info {
         self.piece_length
     }
 
-    pub const fn pieces(&self) -> &Vec<[u8; 20]> {
+    pub fn pieces(&self) -> &[[u8; 20]] {
         &self.pieces
     }
 
+    /// Number of pieces, counted from the files for pure v2 torrents which have no `pieces`.
+    /// Every v2 file starts a new piece
+    pub fn piece_count(&self) -> usize {
+        if !self.pieces.is_empty() || self.file_tree.is_empty() {
+            return self.pieces.len();
+        }
+
+        let piece_length = self.piece_length as u64;
+        self.file_tree.iter().map(|file| file.lenght().div_ceil(piece_length) as usize).sum()
+    }
+
+    /// A v2 torrent without the v1 keys of hybrid torrents
+    pub fn is_pure_v2(&self) -> bool {
+        self.meta_version == 2 && self.pieces.is_empty()
+    }
+
     pub const fn private(&self) -> &Option<bool> {
         &self.private
     }
@@ -149,6 +337,100 @@ impl Info {
     pub const fn mode(&self) -> &FileMode {
         &self.mode
     }
+
+    /// 1 for v1 torrents and 2 for v2 and hybrid ones
+    pub const fn meta_version(&self) -> u32 {
+        self.meta_version
+    }
+
+    /// Files of the v2 file tree with their pieces roots, empty for v1 torrents.
+    /// Hybrid torrents lay their data out by `mode` and may have padding files there
+    pub const fn file_tree(&self) -> &Vec<File> {
+        &self.file_tree
+    }
+
+    /// Length of every file in the torrent in order, a single-file torrent has exactly one
+    pub fn file_lengths(&self) -> Vec<u64> {
+        match &self.mode {
+            FileMode::SingleFile { length, .. } => vec![*length],
+            FileMode::MultipleFiles { files } => files.iter().map(|file| file.lenght()).collect(),
+        }
+    }
+
+    /// Where every file of the torrent is stored, relative to the download directory.
+    /// Multi-file torrents keep their files inside a directory called `name`
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        match &self.mode {
+            FileMode::SingleFile { .. } => vec![PathBuf::from(&self.name)],
+            FileMode::MultipleFiles { files } => files.iter().map(|file| Path::new(&self.name).join(file.path())).collect(),
+        }
+    }
+
+    /// Path and length of every file in the torrent, like `file_paths` and `file_lengths`
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        self.file_paths().into_iter().zip(self.file_lengths()).collect()
+    }
+
+    pub fn file_count(&self) -> usize {
+        match &self.mode {
+            FileMode::SingleFile { .. } => 1,
+            FileMode::MultipleFiles { files } => files.len(),
+        }
+    }
+
+    pub const fn is_single_file(&self) -> bool {
+        matches!(self.mode, FileMode::SingleFile { .. })
+    }
+
+    /// Sum of the lengths of all files in the torrent
+    pub fn total_length(&self) -> u64 {
+        self.file_lengths().iter().sum()
+    }
+
+    /// Translates an absolute torrent byte offset into `(file_index, offset_within_file)`
+    pub fn file_for_offset(&self, offset: u64) -> Option<(usize, u64)> {
+        let mut file_begin = 0;
+
+        for (i, length) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_begin + length;
+
+            if offset >= file_begin && offset < file_end {
+                return Some((i, offset - file_begin));
+            }
+
+            file_begin = file_end;
+        }
+
+        None
+    }
+
+    /// Splits the byte range `start..start + len` across the files it touches,
+    /// returning `(file_index, offset_within_file, length_within_file)` for each in order.
+    /// Zero-length files hold no bytes of any range, so they are never returned
+    pub fn files_in_range(&self, start: u64, len: u64) -> Vec<(usize, u64, u64)> {
+        let end = start + len;
+        let mut files = Vec::new();
+        let mut file_begin = 0;
+
+        for (i, length) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_begin + length;
+
+            if file_begin >= end {
+                break;
+            }
+
+            if length > 0 && file_end > start {
+                let local_begin = start.max(file_begin) - file_begin;
+                let local_end = end.min(file_end) - file_begin;
+
+                files.push((i, local_begin, local_end - local_begin));
+            }
+
+            file_begin = file_end;
+        }
+
+        files
+    }
 }
 
 impl FromBencodeType for Info {
@@ -161,51 +443,51 @@ impl FromBencodeType for Info {
         let mut pieces = None;
         let mut private = None;
         let mut name = None;
+        let mut name_utf8 = None;
         let mut length = None;
         let mut md5sum = None;
         let mut files = None;
+        let mut meta_version = 1;
+        let mut file_tree = Vec::new();
 
         for (field_name, value) in info_dic {
             let field_name = field_name.try_into_byte_string()?.0;
 
             match (field_name, value) {
                 (b"piece length", Type::Integer(int, _)) => {
-                    piece_length = Some(int.parse().unwrap());
-                }
-                (b"pieces", Type::String(bytes, _)) => {
-                    let mut vec = Vec::new();
+                    let length: u64 = int.parse().map_err(|_| Error::InvalidPieceLength { length: 0, max: u32::MAX })?;
 
-                    for sha1 in bytes.chunks(20) {
-                        let mut sha1_arr = [0u8; 20];
+                    if length == 0 || length > u32::MAX as u64 {
+                        return Err(Error::InvalidPieceLength { length, max: u32::MAX });
+                    }
 
-                        sha1_arr[..20].copy_from_slice(&sha1[..20]);
+                    piece_length = Some(length as u32);
+                }
+                (b"pieces", Type::String(bytes, _)) => {
+                    let (hashes, rest) = bytes.as_chunks::<20>();
 
-                        vec.push(sha1_arr);
+                    if !rest.is_empty() {
+                        return Err(Error::InvalidPieces(bytes.len()));
                     }
 
-                    pieces = Some(vec);
+                    pieces = Some(hashes.to_vec());
                 }
                 (b"private", Type::Integer(int, _)) => {
-                    let int: u32 = int.parse().unwrap();
-
-                    private = if let 0 = int {
-                        Some(false)
-                    } else {
-                        Some(true)
-                    }
+                    // any value but 0 marks it private, however large
+                    private = Some(*int != "0");
                 }
                 (b"name", Type::String(bytes, _)) => {
-                    name = Some(from_utf8(bytes).unwrap().to_string());
+                    name = Some(String::from_utf8_lossy(bytes).into_owned());
+                }
+                // the same name for sure in utf-8, when `name` may be in another encoding
+                (b"name.utf-8", Type::String(bytes, _)) => {
+                    name_utf8 = Some(String::from_utf8_lossy(bytes).into_owned());
                 }
                 (b"length", Type::Integer(int, _)) => {
-                    length = Some(int.parse().unwrap());
+                    length = Some(parse_length(int)?);
                 }
                 (b"md5sum", Type::String(bytes, _)) => {
-                    let mut arr = [0u8; 16];
-
-                    arr[..16].copy_from_slice(&bytes[..16]);
-
-                    md5sum = Some(arr);
+                    md5sum = Some(parse_md5sum(bytes)?);
                 }
                 (b"files", Type::List(list, _)) => {
                     let mut vec = Vec::new();
@@ -216,65 +498,108 @@ impl FromBencodeType for Info {
 
                     files = Some(vec);
                 }
-                _ => todo!(),
+                (b"meta version", Type::Integer(int, _)) => {
+                    meta_version = match int.parse() {
+                        Ok(2) => 2,
+                        _ => return Err(Error::UnsupportedMetaVersion(int.parse().unwrap_or(0))),
+                    };
+                }
+                (b"file tree", value) => {
+                    files_from_tree(value, Path::new(""), &mut file_tree)?;
+                }
+                // keys like `source` only matter for the info hash, which covers the whole dict
+                _ => (),
             }
         }
 
         let piece_length = piece_length.ok_or(Error::MissingPieceLength)?;
-        let pieces = pieces.ok_or(Error::MissingPieces)?;
-        let name = name.ok_or(Error::MissingName)?;
+        let name = path_component(name_utf8.or(name).ok_or(Error::MissingName)?)?;
+
+        // v2 only torrents hash their pieces per file in `piece layers` instead
+        let pieces = match pieces {
+            Some(pieces) => pieces,
+            None if meta_version == 2 => Vec::new(),
+            None => return Err(Error::MissingPieces),
+        };
 
+        // hybrid torrents may carry both, `files` describes the layout and `length` has to agree with it
         let mode = if let Some(files) = files {
-            FileMode::MultipleFiles { files }
-        } else {
-            let length = length.ok_or(Error::MissingLength)?;
+            let files_length = files.iter().map(|file| file.lenght()).sum();
 
+            match length {
+                Some(length) if length != files_length => return Err(Error::ConflictingLength { length, files: files_length }),
+                _ => FileMode::MultipleFiles { files },
+            }
+        } else if let Some(length) = length {
             FileMode::SingleFile { length, md5sum }
+        } else {
+            match file_tree.as_slice() {
+                [] => return Err(Error::MissingLength),
+                // a single file torrent's tree has one file called like the torrent
+                [file] if file.path() == Path::new(&name) => FileMode::SingleFile { length: file.lenght(), md5sum: None },
+                files => FileMode::MultipleFiles { files: files.to_vec() },
+            }
         };
 
         Ok(Info {
             piece_length,
-            pieces,
+            pieces: pieces.into(),
             private,
             name,
-            mode
+            mode,
+            meta_version,
+            file_tree,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum FileMode {
     MultipleFiles {
         files: Vec<File>,
     },
     SingleFile {
         length: u64,
+        #[cfg_attr(feature = "serde", serde(serialize_with = "crate::json::hex_option"))]
         md5sum: Option<[u8; 16]>,
     },
 }
 
+/// Cloning is cheap like for `Info`, the piece layers are shared too
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MetaInfo {
-    info_hash: [u8; 20],
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::json::hex"))]
+    info_hash: InfoHash,
     info: Info,
     announce: String,
     announce_list: Option<Vec<Vec<String>>>,
     creation_date: Option<CreationDate>,
     comment: Option<String>,
     created_by: Option<String>,
-    encoding: Option<String>
+    encoding: Option<String>,
+    /// SHA-256 hashes of the pieces of each v2 file, keyed by its pieces root.
+    /// Skipped when serializing, the keys aren't strings
+    #[cfg_attr(feature = "serde", serde(skip))]
+    piece_layers: Arc<HashMap<[u8; 32], Vec<[u8; 32]>>>,
+    /// DHT nodes to bootstrap from, given in `nodes` by trackerless torrents
+    nodes: Vec<SocketAddr>,
+    /// BEP 17 seed urls given in `httpseeds`
+    http_seeds: Vec<String>,
 }
 
 impl fmt::Debug for MetaInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f,
-            "info_hash: {:x?}, info: {:?}, announce: {}, announce_list: {:?}, creation_date: {:?}, comment: {:?}, created_by: {:?}, encoding: {:?}",
-            self.info_hash, self.info, self.announce, self.announce_list, self.creation_date, self.comment, self.created_by, self.encoding
+            "info_hash: {}, info: {:?}, announce: {}, announce_list: {:?}, creation_date: {:?}, comment: {:?}, created_by: {:?}, encoding: {:?}, piece_layers: {}, nodes: {:?}, http_seeds: {:?}",
+            self.info_hash, self.info, self.announce, self.announce_list, self.creation_date, self.comment, self.created_by, self.encoding, self.piece_layers.len(), self.nodes, self.http_seeds
         )
     }
 }
 
 impl MetaInfo {
-    pub const fn info_hash(&self) -> &[u8; 20] {
+    pub const fn info_hash(&self) -> &InfoHash {
         &self.info_hash
     }
 
@@ -306,12 +631,92 @@ impl MetaInfo {
         self.encoding.as_ref()
     }
 
-    fn from_file(path: &str) -> Result<MetaInfo, Error> {
-        // path validity has already been checked
-        let file = fs::File::open(path).unwrap();
-        let bytes = file.bytes().map(|byte| byte.unwrap()).collect::<Vec<u8>>();
+    /// Nodes to seed the DHT routing table with. Only nodes given by ip are kept, the ones
+    /// given by host name or with an invalid port are skipped
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    /// Urls of the BEP 17 seeds, which serve whole pieces through a script unlike the BEP 19
+    /// servers of `url-list`. Entries that aren't utf-8 strings are skipped
+    pub fn http_seeds(&self) -> &[String] {
+        &self.http_seeds
+    }
+
+    /// Piece hashes of the v2 file with `pieces_root`. Files no longer than a piece
+    /// have none, their pieces root is the hash of the whole file
+    pub fn piece_layer(&self, pieces_root: &[u8; 32]) -> Option<&[[u8; 32]]> {
+        self.piece_layers.get(pieces_root).map(Vec::as_slice)
+    }
+
+    /// See `Info::files`
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        self.info.files()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.info.file_count()
+    }
+
+    pub const fn is_single_file(&self) -> bool {
+        self.info.is_single_file()
+    }
+
+    /// Indices of the pieces holding at least one byte of the file at `file_index`.
+    ///
+    /// Pieces straddle file boundaries, so the first and last piece of the range may
+    /// also belong to the neighbouring files, and a file smaller than one piece still
+    /// maps to one (or two, if it crosses a boundary) pieces. Zero-length files map to
+    /// an empty range. Returns `None` if `file_index` is out of range
+    pub fn pieces_for_file(&self, file_index: usize) -> Option<Range<u32>> {
+        let lengths = self.info.file_lengths();
+        let length = *lengths.get(file_index)?;
+        let begin: u64 = lengths[..file_index].iter().sum();
+        let piece_length = self.info.piece_length() as u64;
+
+        if length == 0 {
+            let piece = (begin / piece_length) as u32;
+            return Some(piece..piece);
+        }
+
+        let first = begin / piece_length;
+        let last = (begin + length - 1) / piece_length;
+
+        Some(first as u32..(last + 1) as u32)
+    }
+
+    /// Parses the torrent file at `path`, rejecting piece lengths above `max_piece_length`
+    pub fn from_file(path: &str, max_piece_length: u32) -> Result<MetaInfo, Error> {
+        // read at once, the decoder borrows from the buffer so it can't stream
+        let bytes = fs::read(path)?;
+
+        MetaInfo::from_bencode_with_max_piece_length(&bytes, max_piece_length)
+    }
+
+    /// Parses a torrent read from `reader` until it ends. The decoder borrows from its input,
+    /// so the whole torrent is buffered before decoding
+    pub fn from_reader(mut reader: impl Read, max_piece_length: u32) -> Result<MetaInfo, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        MetaInfo::from_bencode_with_max_piece_length(&bytes, max_piece_length)
+    }
+
+    /// Same as `from_file` without blocking the runtime while the file is read
+    pub async fn from_file_async(path: &str, max_piece_length: u32) -> Result<MetaInfo, Error> {
+        let bytes = tokio::fs::read(path).await?;
+
+        MetaInfo::from_bencode_with_max_piece_length(&bytes, max_piece_length)
+    }
+
+    /// Decodes the metainfo, rejecting piece lengths above `max_piece_length`
+    pub fn from_bencode_with_max_piece_length(bytes: &[u8], max_piece_length: u32) -> Result<MetaInfo, Error> {
+        let metainfo = MetaInfo::decode(bytes)?;
+        let length = metainfo.info().piece_length();
 
-        let metainfo = MetaInfo::from_bencode(&bytes)?;
+        if length > max_piece_length {
+            return Err(Error::InvalidPieceLength { length: length as u64, max: max_piece_length });
+        }
 
         Ok(metainfo)
     }
@@ -321,17 +726,18 @@ impl TryFrom<&str> for MetaInfo {
     type Error = Error;
 
     fn try_from(input: &str) -> Result<Self, Error> {
-        if let Ok(torrent) = TorrentType::try_from(input) {
-            match torrent {
-                TorrentType::MagnetLink(_magnet) => todo!(),
-                TorrentType::InfoHash(_info_hash) => todo!(),
-                TorrentType::TorrentFile(file) => Ok(MetaInfo::from_file(&file)?),
-                TorrentType::Base32InfoHash(_b32_hash) => todo!(),
-                TorrentType::TorrentFileUrl(_url) => todo!(),
-            }
-        } else {
-            todo!()
-        }
+        MetaInfo::from_file(&torrent_file_path(input)?, DEFAULT_MAX_PIECE_LENGTH)
+    }
+}
+
+/// Path of the torrent file `input` names, the other kinds of input can't be read yet
+pub(crate) fn torrent_file_path(input: &str) -> Result<String, Error> {
+    match TorrentType::try_from(input) {
+        Ok(TorrentType::TorrentFile(path)) => Ok(path),
+        Ok(TorrentType::MagnetLink(_)) => Err(Error::Unsupported(String::from("magnet links"))),
+        Ok(TorrentType::InfoHash(_) | TorrentType::Base32InfoHash(_)) => Err(Error::Unsupported(String::from("info hashes"))),
+        Ok(TorrentType::TorrentFileUrl(_)) => Err(Error::Unsupported(String::from("torrent file urls"))),
+        Err(()) => Err(Error::InvalidInput(input.to_string())),
     }
 }
 
@@ -339,6 +745,46 @@ impl bencode::FromBencode for MetaInfo {
     type Error = Error;
 
     fn from_bencode(bytes: &[u8]) -> Result<Self, Self::Error> where Self: Sized {
+        MetaInfo::from_bencode_with_max_piece_length(bytes, DEFAULT_MAX_PIECE_LENGTH)
+    }
+}
+
+/// A `[host, port]` pair of `nodes`, if the host is an ip
+fn node(value: &Type) -> Option<SocketAddr> {
+    match value.try_into_list().ok()?.0.as_slice() {
+        [Type::String(host, _), Type::Integer(port, _)] => {
+            let ip: IpAddr = from_utf8(host).ok()?.parse().ok()?;
+
+            Some(SocketAddr::new(ip, port.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Tiers of tracker urls, each a list of strings. Urls that aren't utf-8 can't be announced
+/// to, so they are skipped
+fn decode_announce_list(list2d: &[Type]) -> Result<Vec<Vec<String>>, bencode::Error> {
+    let mut vec2d = Vec::new();
+
+    for list in list2d {
+        let mut vec = Vec::new();
+
+        for str in list.try_into_list()?.0 {
+            let str = str.try_into_byte_string()?.0;
+
+            if let Ok(url) = from_utf8(str) {
+                vec.push(url.to_string());
+            }
+        }
+
+        vec2d.push(vec);
+    }
+
+    Ok(vec2d)
+}
+
+impl MetaInfo {
+    fn decode(bytes: &[u8]) -> Result<MetaInfo, Error> {
         let map = bytes.try_into_dict()?.0;
 
         let mut info_hash = None;
@@ -349,6 +795,9 @@ impl bencode::FromBencode for MetaInfo {
         let mut comment = None;
         let mut created_by = None;
         let mut encoding = None;
+        let mut piece_layers = HashMap::new();
+        let mut nodes = Vec::new();
+        let mut http_seeds = Vec::new();
 
         let iter = map.iter();
 
@@ -359,50 +808,68 @@ impl bencode::FromBencode for MetaInfo {
                 (b"info", value) => {
                     let info_dict = value.try_into_dict()?;
 
-                    let mut hasher = Sha1::new();
-                    hasher.update(info_dict.1);
+                    let parsed = Info::from_bencode_type(value)?;
 
-                    let sha1: [u8; 20] = hasher.finalize().into();
-                    info_hash = Some(sha1);
+                    // pure v2 swarms are joined with the SHA-256 of the info truncated to 20 bytes
+                    let hash: [u8; 20] = if parsed.is_pure_v2() {
+                        let sha256: [u8; 32] = Sha256::digest(info_dict.1).into();
+                        std::array::from_fn(|i| sha256[i])
+                    } else {
+                        Sha1::digest(info_dict.1).into()
+                    };
 
-                    info = Some(Info::from_bencode_type(value)?);
+                    info_hash = Some(InfoHash::new(hash));
+                    info = Some(parsed);
                 }
                 (b"announce", Type::String(bytes, _)) => {
-                    announce = Some(from_utf8(bytes).unwrap().to_string());
+                    announce = Some(from_utf8(bytes).map_err(|_| Error::NotUtf8("announce"))?.to_string());
                 }
-                (b"announce-list", Type::List(list2d, _)) => {
-                    let mut vec2d = Vec::new();
-
-                    for list in list2d {
-                        let mut vec = Vec::new();
-
-                        let list = list.try_into_list()?.0;
-
-                        for str in list {
-                            let str = str.try_into_byte_string()?.0;
-                            vec.push(from_utf8(str).unwrap().to_string());
-                        }
-
-                        vec2d.push(vec);
-                    }
+                (b"announce-list", Type::List(list2d, raw)) => {
+                    let vec2d = decode_announce_list(list2d)
+                        .map_err(|err| err.in_key(name, bencode::offset(bytes, raw)))?;
 
                     announce_list = Some(vec2d);
                 }
                 (b"creation date", Type::Integer(int, _)) => {
-                    let secs = int.parse().unwrap();
+                    let time = int.parse().ok()
+                        .and_then(|secs| NaiveDateTime::from_timestamp_opt(secs, 0))
+                        .ok_or(Error::MalformedTimestamp)?;
 
-                    let time = CreationDate(NaiveDateTime::from_timestamp_opt(secs, 0).unwrap());
-                    
-                    creation_date = Some(time);
+                    creation_date = Some(CreationDate(time));
                 }
                 (b"comment", Type::String(bytes, _)) => {
-                    comment = Some(from_utf8(bytes).unwrap().to_string());
+                    comment = Some(String::from_utf8_lossy(bytes).into_owned());
                 }
                 (b"created by", Type::String(bytes, _)) => {
-                    created_by = Some(from_utf8(bytes).unwrap().to_string());
+                    created_by = Some(String::from_utf8_lossy(bytes).into_owned());
                 }
                 (b"encoding", Type::String(bytes, _)) => {
-                    encoding = Some(from_utf8(bytes).unwrap().to_string());
+                    encoding = Some(String::from_utf8_lossy(bytes).into_owned());
+                }
+                (b"piece layers", Type::Map(layers, _)) => {
+                    for (root, hashes) in layers {
+                        let root = <[u8; 32]>::try_from(root.try_into_byte_string()?.0).map_err(|_| Error::InvalidPiecesRoot)?;
+
+                        let bytes = hashes.try_into_byte_string()?.0;
+                        let (hashes, rest) = bytes.as_chunks::<32>();
+
+                        if !rest.is_empty() {
+                            return Err(Error::InvalidPieceLayer(bytes.len()));
+                        }
+
+                        let hashes = hashes.to_vec();
+
+                        piece_layers.insert(root, hashes);
+                    }
+                }
+                (b"nodes", Type::List(list, _)) => {
+                    nodes = list.iter().filter_map(node).collect();
+                }
+                (b"httpseeds", Type::List(list, _)) => {
+                    http_seeds = list.iter()
+                        .filter_map(|url| from_utf8(url.try_into_byte_string().ok()?.0).ok())
+                        .map(String::from)
+                        .collect();
                 }
                 _ => (),
             }
@@ -410,7 +877,7 @@ impl bencode::FromBencode for MetaInfo {
 
         let info = info.ok_or(Error::MissingInfo)?;
         let announce = announce.ok_or(Error::MissingInfo)?;
-        let info_hash = info_hash.unwrap(); // should be fine as long as info is cheked before
+        let info_hash = info_hash.ok_or(Error::MissingInfo)?;
 
         Ok(MetaInfo {
             info_hash,
@@ -420,7 +887,589 @@ impl bencode::FromBencode for MetaInfo {
             creation_date,
             comment,
             created_by,
-            encoding 
+            encoding,
+            piece_layers: Arc::new(piece_layers),
+            nodes,
+            http_seeds,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, Read};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use sha1::{Sha1, Digest};
+
+    use crate::bencode::{self, FromBencode};
+    use crate::info_hash::InfoHash;
+    use crate::metainfo::{Info, File, FileMode, MetaInfo, Error, DEFAULT_MAX_PIECE_LENGTH};
+
+    fn torrent_with_piece_length(piece_length: &str) -> Vec<u8> {
+        let mut bytes = format!("d8:announce9:localhost4:infod6:lengthi10e4:name4:test12:piece lengthi{}e6:pieces20:", piece_length).into_bytes();
+        bytes.extend_from_slice(&[0u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        bytes
+    }
+
+    fn multi_file_info(lengths: &[u64], piece_length: u32) -> Info {
+        let files = lengths.iter().enumerate().map(|(i, &length)| File {
+            length,
+            md5sum: None,
+            path: PathBuf::from(format!("file{}", i)),
+            pieces_root: None,
+        }).collect();
+
+        let total: u64 = lengths.iter().sum();
+        let num_pieces = total.div_ceil(piece_length as u64) as usize;
+
+        Info {
+            piece_length,
+            pieces: vec![[0u8; 20]; num_pieces].into(),
+            private: None,
+            name: String::from("test"),
+            mode: FileMode::MultipleFiles { files },
+            meta_version: 1,
+            file_tree: Vec::new(),
+        }
+    }
+
+    fn metainfo(info: Info) -> MetaInfo {
+        MetaInfo {
+            info_hash: InfoHash::new([0u8; 20]),
+            info,
+            announce: String::from("http://localhost/announce"),
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            piece_layers: Arc::default(),
+            nodes: Vec::new(),
+            http_seeds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn file_for_offset() {
+        let info = multi_file_info(&[10, 5, 20], 16);
+
+        assert_eq!(info.total_length(), 35);
+        assert_eq!(info.file_for_offset(0), Some((0, 0)));
+        assert_eq!(info.file_for_offset(9), Some((0, 9)));
+        assert_eq!(info.file_for_offset(10), Some((1, 0)));
+        assert_eq!(info.file_for_offset(15), Some((2, 0)));
+        assert_eq!(info.file_for_offset(34), Some((2, 19)));
+        assert_eq!(info.file_for_offset(35), None);
+    }
+
+    #[test]
+    fn files_in_range() {
+        let info = multi_file_info(&[10, 5, 20], 16);
+
+        assert_eq!(info.files_in_range(0, 10), vec![(0, 0, 10)]);
+        assert_eq!(info.files_in_range(2, 4), vec![(0, 2, 4)]);
+        assert_eq!(info.files_in_range(8, 4), vec![(0, 8, 2), (1, 0, 2)]);
+        assert_eq!(info.files_in_range(5, 20), vec![(0, 5, 5), (1, 0, 5), (2, 0, 10)]);
+        assert_eq!(info.files_in_range(0, 35), vec![(0, 0, 10), (1, 0, 5), (2, 0, 20)]);
+        assert_eq!(info.files_in_range(30, 10), vec![(2, 15, 5)]);
+        assert_eq!(info.files_in_range(35, 10), vec![]);
+    }
+
+    #[test]
+    fn files_in_range_skips_empty_files() {
+        let info = multi_file_info(&[0, 10, 0, 0, 5, 0], 8);
+
+        assert_eq!(info.total_length(), 15);
+        assert_eq!(info.files_in_range(0, 8), vec![(1, 0, 8)]);
+        assert_eq!(info.files_in_range(8, 7), vec![(1, 8, 2), (4, 0, 5)]);
+        assert_eq!(info.file_for_offset(0), Some((1, 0)));
+        assert_eq!(info.file_for_offset(10), Some((4, 0)));
+
+        let metainfo = metainfo(info);
+
+        assert_eq!(metainfo.pieces_for_file(0), Some(0..0));
+        assert_eq!(metainfo.pieces_for_file(2), Some(1..1));
+        assert_eq!(metainfo.pieces_for_file(4), Some(1..2));
+        assert_eq!(metainfo.pieces_for_file(5), Some(1..1));
+    }
+
+    #[test]
+    fn files_in_range_single_file() {
+        let info = Info {
+            piece_length: 16,
+            pieces: vec![[0u8; 20]; 2].into(),
+            private: None,
+            name: String::from("test"),
+            mode: FileMode::SingleFile { length: 20, md5sum: None },
+            meta_version: 1,
+            file_tree: Vec::new(),
+        };
+
+        assert_eq!(info.files_in_range(16, 16), vec![(0, 16, 4)]);
+        assert_eq!(info.file_for_offset(19), Some((0, 19)));
+    }
+
+    #[test]
+    fn files_of_both_modes() {
+        let single = metainfo(Info {
+            piece_length: 16,
+            pieces: vec![[0u8; 20]; 2].into(),
+            private: None,
+            name: String::from("test"),
+            mode: FileMode::SingleFile { length: 20, md5sum: None },
+            meta_version: 1,
+            file_tree: Vec::new(),
+        });
+
+        assert!(single.is_single_file());
+        assert_eq!(single.file_count(), 1);
+        assert_eq!(single.files(), vec![(PathBuf::from("test"), 20)]);
+
+        let multi = metainfo(multi_file_info(&[10, 5, 20], 16));
+
+        assert!(!multi.is_single_file());
+        assert_eq!(multi.file_count(), 3);
+        assert_eq!(multi.files(), vec![
+            (PathBuf::from("test/file0"), 10),
+            (PathBuf::from("test/file1"), 5),
+            (PathBuf::from("test/file2"), 20),
+        ]);
+    }
+
+    #[test]
+    fn pieces_for_file() {
+        let metainfo = metainfo(multi_file_info(&[10, 5, 20], 16));
+
+        assert_eq!(metainfo.pieces_for_file(0), Some(0..1));
+        assert_eq!(metainfo.pieces_for_file(1), Some(0..1));
+        assert_eq!(metainfo.pieces_for_file(2), Some(0..3));
+        assert_eq!(metainfo.pieces_for_file(3), None);
+    }
+
+    #[test]
+    fn pieces_for_file_smaller_than_piece() {
+        // second file straddles the boundary between piece 0 and 1
+        let metainfo = metainfo(multi_file_info(&[14, 4, 3, 11], 16));
+
+        assert_eq!(metainfo.pieces_for_file(0), Some(0..1));
+        assert_eq!(metainfo.pieces_for_file(1), Some(0..2));
+        assert_eq!(metainfo.pieces_for_file(2), Some(1..2));
+        assert_eq!(metainfo.pieces_for_file(3), Some(1..2));
+    }
+
+    #[test]
+    fn piece_length_limit() {
+        let absurd = torrent_with_piece_length("4294967296");
+        let above_default = torrent_with_piece_length("33554432");
+        let zero = torrent_with_piece_length("0");
+
+        assert!(matches!(MetaInfo::from_bencode(&absurd), Err(Error::InvalidPieceLength { length: 4294967296, .. })));
+        assert!(matches!(MetaInfo::from_bencode(&above_default), Err(Error::InvalidPieceLength { length: 33554432, max: 16777216 })));
+        assert!(matches!(MetaInfo::from_bencode(&zero), Err(Error::InvalidPieceLength { length: 0, .. })));
+
+        let metainfo = MetaInfo::from_bencode_with_max_piece_length(&above_default, 64 * 1024 * 1024).unwrap();
+        assert_eq!(metainfo.info().piece_length(), 33554432);
+    }
+
+    #[test]
+    fn info_hash_reference() {
+        let mut info = b"d6:lengthi1048576e4:name8:test.iso12:piece lengthi262144e6:pieces80:".to_vec();
+        info.extend(0..80);
+        info.extend_from_slice(b"7:privatei1ee");
+
+        // keys before and after the info dict so the hashed slice can't include its neighbours
+        let mut torrent = b"d8:announce31:http://tracker.example.com:80807:comment4:test13:creation datei1700000000e4:info".to_vec();
+        torrent.extend_from_slice(&info);
+        torrent.extend_from_slice(b"8:url-listl24:http://example.com/test/ee");
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+
+        // sha1 of the info dict as computed by other clients
+        assert_eq!(metainfo.info_hash().to_hex(), "a77f645163215cb37575465acdf35bddae71682e");
+    }
+
+    #[test]
+    fn info_source_key() {
+        let info = b"d6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000006:source3:abce";
+
+        let mut torrent = b"d8:announce9:localhost4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+
+        assert_eq!(metainfo.info().name(), "test");
+        assert_eq!(metainfo.info_hash(), &InfoHash::new(Sha1::digest(info).into()));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        // keys from other clients and BEPs at every level, `private` with an unexpected type is ignored too
+        let torrent = b"d8:announce9:localhost5:nodesll9:localhosti6881eee4:infod5:filesld4:attr1:p6:lengthi4e4:pathl1:ae10:path.utf-8l1:ae4:sha13:abcee\
+            4:name4:test10:name.utf-84:test12:piece lengthi16384e6:pieces20:000000000000000000007:private3:yes6:source3:abc12:x_cross_seed1:xe\
+            9:httpseedsl9:localhostee";
+
+        let metainfo = MetaInfo::from_bencode(torrent).unwrap();
+        let info = metainfo.info();
+
+        assert_eq!(info.file_paths(), vec![PathBuf::from("test/a")]);
+        assert_eq!(info.total_length(), 4);
+        assert_eq!(info.private(), &None);
+
+        // required keys are still checked
+        let missing_length = b"d8:announce9:localhost4:infod5:filesld4:attr1:p4:pathl1:aeee4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000ee";
+        assert!(matches!(MetaInfo::from_bencode(missing_length), Err(Error::MissingLength)));
+    }
+
+    #[test]
+    fn utf8_names() {
+        // `caf\xe9` is café in latin-1
+        let mut torrent = b"d8:announce9:localhost4:infod5:filesld6:lengthi4e4:pathl4:caf\xe9e10:path.utf-8l5:caf\xc3\xa9eed6:lengthi2e4:pathl4:caf\xe8eee".to_vec();
+        torrent.extend_from_slice(b"4:name4:caf\xe910:name.utf-85:caf\xc3\xa912:piece lengthi16384e6:pieces20:00000000000000000000ee");
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        let info = metainfo.info();
+
+        assert_eq!(info.name(), "café");
+        // without a utf-8 variant the legacy name is kept as well as it can be
+        assert_eq!(info.file_paths(), vec![PathBuf::from("café/café"), PathBuf::from("café/caf\u{fffd}")]);
+    }
+
+    #[test]
+    fn http_seeds() {
+        let torrent = b"d8:announce9:localhost9:httpseedsl24:http://seed.example/seedi1e2:\xff\xfe21:http://127.0.0.1/seede4:infod6:lengthi4e4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000ee";
+        let metainfo = MetaInfo::from_bencode(torrent).unwrap();
+
+        assert_eq!(metainfo.http_seeds(), ["http://seed.example/seed", "http://127.0.0.1/seed"]);
+        assert!(MetaInfo::from_bencode(&torrent_with_piece_length("16384")).unwrap().http_seeds().is_empty());
+    }
+
+    #[test]
+    fn dht_nodes() {
+        let torrent = b"d8:announce9:localhost5:nodesll9:127.0.0.1i6881eel11:2001:db8::1i6882eel8:dht.hosti6881eel8:10.0.0.1i70000eel8:10.0.0.2ee4:infod6:lengthi4e4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000ee";
+
+        let metainfo = MetaInfo::from_bencode(torrent).unwrap();
+
+        // host names, ports past u16 and pairs missing the port are skipped
+        assert_eq!(metainfo.nodes(), &["127.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:6882".parse().unwrap()][..]);
+        assert!(MetaInfo::from_bencode(&torrent_with_piece_length("16384")).unwrap().nodes().is_empty());
+    }
+
+    #[test]
+    fn decoding_errors_have_offsets() {
+        let truncated = b"d8:announce9:localhost4:infod4:name";
+        assert!(matches!(MetaInfo::from_bencode(truncated), Err(Error::DecodingError(bencode::Error::UnclosedMap(28)))));
+
+        let bad_tier = b"d8:announce9:localhost13:announce-listli1ee4:infodee";
+        let err = MetaInfo::from_bencode(bad_tier).unwrap_err();
+
+        assert!(matches!(&err, Error::DecodingError(bencode::Error::InKey { key, offset: 38, error })
+            if key == "announce-list" && **error == bencode::Error::ExpectedList));
+        assert_eq!(crate::error::report(&err), "Could not decode metainfo\n  caused by: Invalid value of key announce-list at byte 38\n  caused by: Expected a list");
+    }
+
+    #[test]
+    fn length_and_files() {
+        let torrent = |length: &str| format!(
+            "d8:announce9:localhost4:infod5:filesld6:lengthi4e4:pathl1:aeed6:lengthi6e4:pathl1:beee6:lengthi{}e4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000ee",
+            length,
+        ).into_bytes();
+
+        // files wins when length agrees with it
+        let metainfo = MetaInfo::from_bencode(&torrent("10")).unwrap();
+        assert!(matches!(metainfo.info().mode(), FileMode::MultipleFiles { files } if files.len() == 2));
+        assert_eq!(metainfo.info().total_length(), 10);
+
+        assert!(matches!(MetaInfo::from_bencode(&torrent("11")), Err(Error::ConflictingLength { length: 11, files: 10 })));
+    }
+
+    #[test]
+    fn v2_file_tree() {
+        let mut torrent = b"d8:announce9:localhost4:infod9:file treed1:ad0:d6:lengthi40000e11:pieces root32:".to_vec();
+        torrent.extend_from_slice(&[1; 32]);
+        torrent.extend_from_slice(b"ee3:dird1:bd0:d6:lengthi5eeeee12:meta versioni2e4:name4:test12:piece lengthi16384ee12:piece layersd32:");
+        torrent.extend_from_slice(&[1; 32]);
+        torrent.extend_from_slice(b"96:");
+        torrent.extend_from_slice(&[2; 96]);
+        torrent.extend_from_slice(b"ee");
+
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        let info = metainfo.info();
+
+        assert_eq!(info.meta_version(), 2);
+        assert!(info.pieces().is_empty());
+        assert_eq!(info.file_paths(), vec![PathBuf::from("test/a"), PathBuf::from("test/dir/b")]);
+        assert_eq!(info.file_lengths(), vec![40000, 5]);
+        // each file starts a piece, 3 for `a` and 1 for `b`
+        assert_eq!(info.piece_count(), 4);
+
+        assert_eq!(info.file_tree()[0].pieces_root(), Some(&[1; 32]));
+        assert_eq!(info.file_tree()[1].pieces_root(), None);
+        assert_eq!(metainfo.piece_layer(&[1; 32]), Some(&[[2; 32]; 3][..]));
+
+        let v1 = MetaInfo::from_bencode(&torrent_with_piece_length("16384")).unwrap();
+        assert_eq!(v1.info().meta_version(), 1);
+        assert!(v1.info().file_tree().is_empty());
+    }
+
+    #[test]
+    fn hybrid_and_v2_single_file() {
+        // the v1 keys describe the layout of hybrid torrents
+        let mut hybrid = b"d8:announce9:localhost4:infod9:file treed4:testd0:d6:lengthi10e11:pieces root32:".to_vec();
+        hybrid.extend_from_slice(&[1; 32]);
+        hybrid.extend_from_slice(b"eee6:lengthi10e12:meta versioni2e4:name4:test12:piece lengthi16384e6:pieces20:00000000000000000000ee");
+
+        let metainfo = MetaInfo::from_bencode(&hybrid).unwrap();
+        assert_eq!(metainfo.info().meta_version(), 2);
+        assert_eq!(metainfo.info().pieces().len(), 1);
+        assert_eq!(metainfo.info().file_tree().len(), 1);
+        assert!(matches!(metainfo.info().mode(), FileMode::SingleFile { length: 10, .. }));
+
+        // without them a tree holding only a file named like the torrent is a single file
+        let v2 = b"d8:announce9:localhost4:infod9:file treed4:testd0:d6:lengthi10eeee12:meta versioni2e4:name4:test12:piece lengthi16384eee";
+        let metainfo = MetaInfo::from_bencode(v2).unwrap();
+        assert!(matches!(metainfo.info().mode(), FileMode::SingleFile { length: 10, .. }));
+        assert_eq!(metainfo.info().file_paths(), vec![PathBuf::from("test")]);
+
+        let v3 = b"d8:announce9:localhost4:infod6:lengthi10e12:meta versioni3e4:name4:test12:piece lengthi16384eee";
+        assert!(matches!(MetaInfo::from_bencode(v3), Err(Error::UnsupportedMetaVersion(3))));
+    }
+
+    #[test]
+    fn from_file_io_error() {
+        let err = MetaInfo::from_file("does_not_exist.torrent", DEFAULT_MAX_PIECE_LENGTH).unwrap_err();
+
+        assert!(matches!(err, Error::Io(ref err) if err.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn unreadable_inputs() {
+        let magnet = MetaInfo::try_from("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert!(matches!(magnet, Err(Error::Unsupported(_))));
+
+        let info_hash = MetaInfo::try_from("c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert!(matches!(info_hash, Err(Error::Unsupported(_))));
+
+        let missing = MetaInfo::try_from("does_not_exist.torrent");
+        assert!(matches!(missing, Err(Error::InvalidInput(input)) if input == "does_not_exist.torrent"));
+    }
+
+    #[test]
+    fn rejects_paths_leaving_download_dir() {
+        let with_path = |path: &str| {
+            let mut torrent = format!("d8:announce9:localhost4:infod5:filesld6:lengthi1e4:path{}ee4:name4:test12:piece lengthi16384e6:pieces20:", path).into_bytes();
+            torrent.extend_from_slice(&[0; 20]);
+            torrent.extend_from_slice(b"ee");
+            MetaInfo::from_bencode(&torrent)
+        };
+
+        assert!(matches!(with_path("l2:..1:xe"), Err(Error::InvalidPath(name)) if name == ".."));
+        assert!(matches!(with_path("l4:../xe"), Err(Error::InvalidPath(name)) if name == "../x"));
+        assert!(matches!(with_path("l4:/etce"), Err(Error::InvalidPath(_))));
+        assert!(matches!(with_path("l0:e"), Err(Error::InvalidPath(_))));
+        assert!(matches!(with_path("le"), Err(Error::MissingPath)));
+        assert_eq!(with_path("l3:dir1:xe").unwrap().info().file_paths(), vec![PathBuf::from("test/dir/x")]);
+
+        for name in ["2:..", "1:.", "7:../test", "4:/etc", "0:"] {
+            let mut torrent = format!("d8:announce9:localhost4:infod6:lengthi10e4:name{}12:piece lengthi16384e6:pieces20:", name).into_bytes();
+            torrent.extend_from_slice(&[0; 20]);
+            torrent.extend_from_slice(b"ee");
+            assert!(matches!(MetaInfo::from_bencode(&torrent), Err(Error::InvalidPath(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_file_trees() {
+        let with_tree = |tree: &[u8]| {
+            let mut torrent = b"d8:announce9:localhost4:infod9:file tree".to_vec();
+            torrent.extend_from_slice(tree);
+            torrent.extend_from_slice(b"12:meta versioni2e4:name4:test12:piece lengthi16384eee");
+            MetaInfo::from_bencode(&torrent)
+        };
+
+        assert!(matches!(with_tree(b"d2:\xff\xfed0:d6:lengthi1eeee"), Err(Error::InvalidPath(_))));
+        assert!(matches!(with_tree(b"d2:..d1:ad0:d6:lengthi1eeeee"), Err(Error::InvalidPath(name)) if name == ".."));
+        assert!(matches!(with_tree(b"d3:a/bd0:d6:lengthi1eeee"), Err(Error::InvalidPath(name)) if name == "a/b"));
+        assert!(matches!(with_tree(b"d1:ad0:d6:lengthi-1eeee"), Err(Error::InvalidLength(length)) if length == "-1"));
+        assert!(matches!(with_tree(b"d1:ad0:d6:lengthi99999999999999999999eeee"), Err(Error::InvalidLength(_))));
+
+        let large = with_tree(b"d1:ad0:d6:lengthi4294967296eeee").unwrap();
+        assert_eq!(large.info().file_lengths(), vec![1 << 32]);
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        let with_info = |info: &str, pieces: &[u8]| {
+            let mut torrent = format!("d8:announce9:localhost4:infod{}12:piece lengthi16384e6:pieces{}:", info, pieces.len()).into_bytes();
+            torrent.extend_from_slice(pieces);
+            torrent.extend_from_slice(b"ee");
+            MetaInfo::from_bencode(&torrent)
+        };
+        let single = "6:lengthi10e4:name4:test";
+
+        assert!(matches!(with_info(single, &[0; 19]), Err(Error::InvalidPieces(19))));
+        assert!(matches!(with_info(single, &[0; 41]), Err(Error::InvalidPieces(41))));
+        assert!(matches!(with_info("6:lengthi-10e4:name4:test", &[0; 20]), Err(Error::InvalidLength(_))));
+        assert!(matches!(with_info("6:lengthi10e6:md5sum3:abc4:name4:test", &[0; 20]), Err(Error::InvalidMd5Sum)));
+        assert!(matches!(with_info("6:lengthi10e6:md5sum32:0123456789abcdef0123456789abcdeg4:name4:test", &[0; 20]), Err(Error::InvalidMd5Sum)));
+        assert!(matches!(with_info("5:filesld6:lengthi1e6:md5sum1:x4:pathl1:aeee4:name4:test", &[0; 20]), Err(Error::InvalidMd5Sum)));
+
+        let hex = with_info("6:lengthi10e6:md5sum32:000102030405060708090a0b0c0d0eFF4:name4:test", &[0; 20]).unwrap();
+        assert!(matches!(hex.info().mode, FileMode::SingleFile { md5sum: Some(md5sum), .. } if md5sum == [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 255]));
+
+        let private = with_info("6:lengthi10e4:name4:test7:privatei99999999999999999999e", &[0; 20]).unwrap();
+        assert_eq!(private.info().private, Some(true));
+
+        let mut torrent = b"d8:announce2:\xff\xfe".to_vec();
+        torrent.extend_from_slice(&torrent_with_piece_length("16384")[22..]);
+        assert!(matches!(MetaInfo::from_bencode(&torrent), Err(Error::NotUtf8("announce"))));
+
+        for date in ["i-99999999999999e", "i99999999999999999999e"] {
+            let mut torrent = format!("d8:announce9:localhost13:creation date{}", date).into_bytes();
+            torrent.extend_from_slice(&torrent_with_piece_length("16384")[22..]);
+            assert!(matches!(MetaInfo::from_bencode(&torrent), Err(Error::MalformedTimestamp)), "{}", date);
+        }
+
+        let mut torrent = b"d13:announce-listll9:localhost2:\xff\xfeee7:comment1:\xff".to_vec();
+        torrent.extend_from_slice(&torrent_with_piece_length("16384")[1..]);
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        assert_eq!(metainfo.announce_list, Some(vec![vec![String::from("localhost")]]));
+        assert_eq!(metainfo.comment.as_deref(), Some("\u{fffd}"));
+
+        let mut layer = torrent_with_piece_length("16384");
+        layer.pop();
+        layer.extend_from_slice(b"12:piece layersd32:");
+        layer.extend_from_slice(&[1; 32]);
+        layer.extend_from_slice(b"33:");
+        layer.extend_from_slice(&[2; 33]);
+        layer.extend_from_slice(b"ee");
+        assert!(matches!(MetaInfo::from_bencode(&layer), Err(Error::InvalidPieceLayer(33))));
+    }
+
+    /// xorshift64, enough to mutate torrents reproducibly without a dependency
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn mutated_torrents_dont_panic() {
+        let mut v1 = b"d8:announce9:localhost13:announce-listll9:localhostel3:udpee13:creation datei1700000000e7:comment2:hi10:created by4:test8:encoding5:UTF-84:infod5:filesld6:lengthi10e6:md5sum32:000102030405060708090a0b0c0d0e0f4:pathl3:dir1:aeed6:lengthi20e4:pathl1:beee4:name4:test12:piece lengthi16e7:privatei1e6:pieces40:".to_vec();
+        v1.extend_from_slice(&[7; 40]);
+        v1.extend_from_slice(b"ee");
+
+        let mut v2 = b"d8:announce9:localhost4:infod9:file treed1:ad0:d6:lengthi40000e11:pieces root32:".to_vec();
+        v2.extend_from_slice(&[1; 32]);
+        v2.extend_from_slice(b"ee3:dird1:bd0:d6:lengthi5eeeee12:meta versioni2e4:name4:test12:piece lengthi16384ee12:piece layersd32:");
+        v2.extend_from_slice(&[1; 32]);
+        v2.extend_from_slice(b"96:");
+        v2.extend_from_slice(&[2; 96]);
+        v2.extend_from_slice(b"ee");
+
+        assert!(MetaInfo::from_bencode(&v1).is_ok());
+        assert!(MetaInfo::from_bencode(&v2).is_ok());
+
+        // bytes that change what bencode and the fields mean: digits, signs, delimiters, separators
+        const INTERESTING: &[u8] = b"0123456789-:eildn./\\\xff";
+        let mut state = 0x9e3779b97f4a7c15;
+
+        for i in 0..20_000 {
+            let mut torrent = if i % 2 == 0 { v1.clone() } else { v2.clone() };
+
+            for _ in 0..1 + next_random(&mut state) % 4 {
+                if torrent.is_empty() {
+                    break;
+                }
+
+                let at = next_random(&mut state) as usize % torrent.len();
+                let byte = INTERESTING[next_random(&mut state) as usize % INTERESTING.len()];
+
+                match next_random(&mut state) % 4 {
+                    0 => torrent[at] = byte,
+                    1 => torrent.insert(at, byte),
+                    2 => { torrent.remove(at); }
+                    _ => torrent.truncate(at),
+                }
+            }
+
+            // any error is fine, only a panic fails the test
+            let _ = MetaInfo::from_bencode(&torrent);
+        }
+    }
+
+    /// Hands out at most `chunk` bytes per read, like a socket would
+    struct ChunkedReader {
+        bytes: Vec<u8>,
+        position: usize,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let end = self.bytes.len().min(self.position + self.chunk.min(buf.len()));
+            let read = end - self.position;
+
+            buf[..read].copy_from_slice(&self.bytes[self.position..end]);
+            self.position = end;
+
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn from_reader_larger_than_buffers() {
+        let mut torrent = b"d8:announce9:localhost4:infod5:filesl".to_vec();
+
+        for i in 0..10_000 {
+            let name = format!("file{}", i);
+            torrent.extend_from_slice(format!("d6:lengthi1e4:pathl{}:{}ee", name.len(), name).as_bytes());
+        }
+
+        torrent.extend_from_slice(b"e4:name4:test12:piece lengthi16384e6:pieces20:");
+        torrent.extend_from_slice(&[0; 20]);
+        torrent.extend_from_slice(b"ee");
+
+        // many times the 8 KiB of a BufReader
+        assert!(torrent.len() > 200_000);
+
+        let reader = BufReader::new(ChunkedReader { bytes: torrent.clone(), position: 0, chunk: 1000 });
+        let metainfo = MetaInfo::from_reader(reader, DEFAULT_MAX_PIECE_LENGTH).unwrap();
+
+        assert_eq!(metainfo.info().file_count(), 10_000);
+        assert_eq!(metainfo.info_hash(), MetaInfo::from_bencode(&torrent).unwrap().info_hash());
+
+        let truncated = ChunkedReader { bytes: torrent[..torrent.len() - 1].to_vec(), position: 0, chunk: 1000 };
+        assert!(matches!(MetaInfo::from_reader(truncated, DEFAULT_MAX_PIECE_LENGTH), Err(Error::DecodingError(_))));
+    }
+
+    #[tokio::test]
+    async fn from_file_async_matches_sync() {
+        let path = std::env::temp_dir().join(format!("torrent_client_metainfo_{}", std::process::id()));
+        std::fs::write(&path, torrent_with_piece_length("16384")).unwrap();
+        let path = path.to_str().unwrap();
+
+        let sync = MetaInfo::from_file(path, DEFAULT_MAX_PIECE_LENGTH).unwrap();
+        let from_async = MetaInfo::from_file_async(path, DEFAULT_MAX_PIECE_LENGTH).await.unwrap();
+        assert_eq!(sync.info_hash(), from_async.info_hash());
+
+        std::fs::remove_file(path).unwrap();
+
+        let err = MetaInfo::from_file_async(path, DEFAULT_MAX_PIECE_LENGTH).await.unwrap_err();
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn clones_share_pieces() {
+        let metainfo = MetaInfo::from_bencode(&torrent_with_piece_length("16384")).unwrap();
+        let clone = metainfo.clone();
+
+        assert_eq!(clone.info_hash(), metainfo.info_hash());
+        assert_eq!(clone.info().name(), metainfo.info().name());
+        assert!(std::ptr::eq(clone.info().pieces(), metainfo.info().pieces()));
+    }
+}
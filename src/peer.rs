@@ -1,30 +1,73 @@
 use std::fmt::Display;
-use std::io::{self, Cursor, Seek, Write};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use bit_vec::BitVec;
-use tokio::io::{BufReader, AsyncWriteExt, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+
+use crate::info_hash::InfoHash;
+use crate::peer_id::PeerId;
+
+/// Longest message accepted from a peer, far above the blocks we request
+const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// Capacity of the buffer in front of the read half unless `Peer::set_read_buffer_capacity`
+/// changes it, four blocks at a time instead of tokio's 8 KiB. Receiving 256 MiB of pieces
+/// over loopback took the same ~330ms with 8 KiB, 64 KiB and 256 KiB, parsing messages
+/// costs more than the reads do
+pub const DEFAULT_READ_BUFFER: usize = 64 * 1024;
+
+/// Time a peer has to answer our handshake unless `Peer::set_handshake_timeout` changes it
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Protocol string of every handshake unless `Peer::set_protocol` changes it
+pub const PROTOCOL: &[u8] = b"BitTorrent protocol";
 
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
     InvalidMessageId(u8),
     InvalidPayloadLength { expected: usize, actual: usize },
+    InvalidBitfieldLength { expected: usize, actual: usize },
+    InvalidBitfieldSpareBits,
+    /// A `Have` for a piece the torrent doesn't have
+    InvalidPieceIndex { index: u32, pieces: usize },
+    /// A protocol string has to fit its length in one byte
+    InvalidProtocolLength(usize),
+    /// The peer answered the handshake with another protocol string
+    UnexpectedProtocol(Vec<u8>),
+    /// The handshake wasn't exchanged in time, the peer sent nothing or too little
+    HandshakeTimeout,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::IoError(err) => write!(f, "{}", err),
+            Self::IoError(_) => write!(f, "Peer connection failed"),
             Self::InvalidMessageId(id) => write!(f, "Invalid message id: {}", id),
             Self::InvalidPayloadLength { expected, actual } =>
                 write!(f, "Expected payload of length {} but got {}", expected, actual),
+            Self::InvalidBitfieldLength { expected, actual } =>
+                write!(f, "Expected bitfield of {} bytes but got {}", expected, actual),
+            Self::InvalidBitfieldSpareBits => write!(f, "Bitfield has spare bits set"),
+            Self::InvalidPieceIndex { index, pieces } => write!(f, "Piece index {} is out of range of {} pieces", index, pieces),
+            Self::InvalidProtocolLength(length) => write!(f, "Protocol string of {} bytes is longer than 255", length),
+            Self::UnexpectedProtocol(protocol) => write!(f, "Peer speaks another protocol: {}", String::from_utf8_lossy(protocol)),
+            Self::HandshakeTimeout => write!(f, "Peer did not answer the handshake in time"),
         }
     }
 }
 
-impl std::error::Error for Error { }
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
@@ -57,6 +100,16 @@ impl WriteMessage {
     }
 }
 
+/// Sent by the torrent to steer a single peer task
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerCommand {
+    SendHave(u32),
+    Choke,
+    Unchoke,
+    Cancel { index: u32, begin: u32 },
+    Shutdown,
+}
+
 #[derive(Debug, PartialEq, PartialOrd)]
 pub enum Message {
     KeepAlive,
@@ -91,6 +144,46 @@ impl Display for Message {
 }
 
 impl Message {
+    /// Id of the message on the wire, keep alives have none
+    pub const fn id(&self) -> Option<u8> {
+        match self {
+            Self::KeepAlive => None,
+            Self::Choke => Some(0),
+            Self::Unchoke => Some(1),
+            Self::Interested => Some(2),
+            Self::NotInterested => Some(3),
+            Self::Have(_) => Some(4),
+            Self::Bitfield(_) => Some(5),
+            Self::Request { .. } => Some(6),
+            Self::Piece { .. } => Some(7),
+            Self::Cancel { .. } => Some(8),
+            Self::Extended(_) => Some(20),
+        }
+    }
+
+    /// Encodes the message with its length prefix as sent over the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (id, payload): (u8, Vec<u8>) = match self {
+            Self::KeepAlive => return vec![0, 0, 0, 0],
+            Self::Choke => (0, Vec::new()),
+            Self::Unchoke => (1, Vec::new()),
+            Self::Interested => (2, Vec::new()),
+            Self::NotInterested => (3, Vec::new()),
+            Self::Have(piece) => (4, piece.to_be_bytes().to_vec()),
+            Self::Bitfield(bitfield) => (5, bitfield.clone()),
+            Self::Request { index, begin, length } => (6, [index.to_be_bytes(), begin.to_be_bytes(), length.to_be_bytes()].concat()),
+            Self::Piece { index, begin, block } => (7, [&index.to_be_bytes()[..], &begin.to_be_bytes(), block].concat()),
+            Self::Cancel { index, begin, length } => (8, [index.to_be_bytes(), begin.to_be_bytes(), length.to_be_bytes()].concat()),
+            Self::Extended(payload) => (20, payload.clone()),
+        };
+
+        let mut bytes = (payload.len() as u32 + 1).to_be_bytes().to_vec();
+        bytes.push(id);
+        bytes.extend(payload);
+
+        bytes
+    }
+
     pub fn from_id(id: u8) -> Self {
         match id {
             0 => Self::Choke,
@@ -150,105 +243,242 @@ impl Message {
     }
 }
 
+/// What a peer answered our handshake with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    protocol: Vec<u8>,
+    reserved: [u8; 8],
+    info_hash: InfoHash,
+    peer_id: PeerId,
+}
+
+impl Handshake {
+    pub fn protocol(&self) -> &[u8] {
+        &self.protocol
+    }
+
+    /// Bits of the protocol extensions the peer supports
+    pub const fn reserved(&self) -> &[u8; 8] {
+        &self.reserved
+    }
+
+    pub const fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub const fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+}
+
+type Reader<'a> = Box<dyn AsyncRead + Send + Sync + Unpin + 'a>;
+type Writer<'a> = Box<dyn AsyncWrite + Send + Sync + Unpin + 'a>;
+
 pub struct Peer<'a> {
-    reader: BufReader<ReadHalf<'a>>,
-    writer: WriteHalf<'a>,
+    /// Only known for tcp streams, used to tell peers apart in traces
+    address: Option<SocketAddr>,
+    /// Sent in the handshake, `PROTOCOL` by default
+    protocol: Vec<u8>,
+    handshake_timeout: Duration,
+    reader: BufReader<Reader<'a>>,
+    writer: Writer<'a>,
+    read_buffer: Vec<u8>,
+    /// Messages sent with the `send_` methods until `flush` writes them
+    outgoing: Vec<Message>,
     is_choking: bool,
     is_interested: bool,
     am_choking: bool,
     am_interested: bool,
     bitfield: BitVec,
+    /// Ids of the messages `queue` drops, to find which one a peer mishandles
+    #[cfg(debug_assertions)]
+    disabled: Vec<u8>,
 }
 
 impl<'a> Peer<'a> {
     pub async fn new(stream: &'a mut TcpStream, num_pieces: usize) -> Result<Peer<'a>, Error> {
+        let address = stream.peer_addr().ok();
         let (reader, writer) = stream.split();
-        let reader = BufReader::new(reader);
-        
-        Ok(Peer {
-            reader,
+
+        let mut peer = Peer::from_halves(Box::new(reader), Box::new(writer), num_pieces);
+        peer.address = address;
+
+        Ok(peer)
+    }
+
+    /// Speaks the wire protocol over any stream, like the in-memory ones used in tests
+    pub fn with_stream(stream: impl AsyncRead + AsyncWrite + Send + Sync + Unpin + 'a, num_pieces: usize) -> Peer<'a> {
+        let (reader, writer) = tokio::io::split(stream);
+
+        Peer::from_halves(Box::new(reader), Box::new(writer), num_pieces)
+    }
+
+    fn from_halves(reader: Reader<'a>, writer: Writer<'a>, num_pieces: usize) -> Peer<'a> {
+        Peer {
+            address: None,
+            protocol: PROTOCOL.to_vec(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            reader: BufReader::with_capacity(DEFAULT_READ_BUFFER, reader),
             writer,
+            read_buffer: Vec::new(),
+            outgoing: Vec::new(),
             is_choking: true,
             is_interested: false,
             am_interested: false,
             am_choking: true,
             bitfield: BitVec::from_elem(num_pieces, false),
-        })
+            #[cfg(debug_assertions)]
+            disabled: Vec::new(),
+        }
     }
 
-    pub async fn handshake(&mut self, info_hash: [u8; 20], peer_id: [u8; 20]) -> Result<[u8; 68], Error> {
-        // prepare handshake
+    /// Bytes read from the stream at a time, `DEFAULT_READ_BUFFER` by default. Whatever
+    /// was buffered already is kept
+    pub fn set_read_buffer_capacity(&mut self, capacity: usize) {
+        self.read_buffer.extend_from_slice(self.reader.buffer());
 
-        let mut cursor = Cursor::new(vec![0u8; 68]);
-        cursor.seek(io::SeekFrom::Start(0))?;
+        let reader = std::mem::replace(&mut self.reader, BufReader::new(Box::new(tokio::io::empty())));
+        self.reader = BufReader::with_capacity(capacity, reader.into_inner());
+    }
 
-        write!(cursor, "{}BitTorrent protocol00000000", 19 as char)?;
+    /// Time `handshake` has to send ours and read the answer before failing with `HandshakeTimeout`
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
 
-        for byte in info_hash {
-            AsyncWriteExt::write_all(&mut cursor, &[byte]).await?;
+    /// Protocol string sent in the handshake instead of `PROTOCOL`, the peer has to answer
+    /// with the same one. Its length is sent in a single byte
+    pub fn set_protocol(&mut self, protocol: &[u8]) -> Result<(), Error> {
+        if protocol.len() > u8::MAX as usize {
+            return Err(Error::InvalidProtocolLength(protocol.len()));
         }
 
-        for byte in peer_id {
-            AsyncWriteExt::write_all(&mut cursor, &[byte]).await?;
-        }
+        self.protocol = protocol.to_vec();
 
-        // send handshake
+        Ok(())
+    }
 
-        self.writer.write_all(cursor.get_ref()).await?;
+    pub async fn handshake(&mut self, info_hash: InfoHash, peer_id: PeerId) -> Result<Handshake, Error> {
+        let mut handshake = vec![self.protocol.len() as u8];
+        handshake.extend_from_slice(&self.protocol);
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(info_hash.as_bytes());
+        handshake.extend_from_slice(peer_id.as_bytes());
 
-        // read response handshake
-        // loops until it connects or gives an error
+        #[cfg(feature = "trace")]
+        log::trace!("{} sent handshake {}", self.name(), hex(&handshake));
 
+        // one deadline for all of it, a peer trickling bytes can't keep it going
+        let exchange = async {
+            self.writer.write_all(&handshake).await?;
+
+            // read response handshake, it may arrive split in several segments
+            let length = self.reader.read_u8().await? as usize;
+            let mut rest = vec![0u8; length + 48];
+            self.reader.read_exact(&mut rest).await?;
+
+            Ok::<_, io::Error>(rest)
+        };
+
+        let rest = match tokio::time::timeout(self.handshake_timeout, exchange).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::HandshakeTimeout),
+        };
+
+        #[cfg(feature = "trace")]
+        log::trace!("{} received handshake {:02x}{}", self.name(), rest.len() - 48, hex(&rest));
+
+        let (protocol, rest) = rest.split_at(rest.len() - 48);
+
+        if protocol != self.protocol {
+            return Err(Error::UnexpectedProtocol(protocol.to_vec()));
+        }
+
+        Ok(Handshake {
+            protocol: protocol.to_vec(),
+            reserved: rest[..8].try_into().unwrap(),
+            info_hash: InfoHash::new(rest[8..28].try_into().unwrap()),
+            peer_id: PeerId::new(rest[28..].try_into().unwrap()),
+        })
+    }
+
+    /// Reads the next message. Partial messages are kept in `read_buffer`, so this is
+    /// cancel safe and can be raced against other futures in `tokio::select!`
+    pub async fn read_message(&mut self) -> Result<Message, Error> {
         loop {
-            let mut handshake = [0u8; 68];
+            if let Some(message) = self.buffered_message()? {
+                #[cfg(feature = "trace")]
+                log::trace!("{} received {}", self.name(), summary(&message));
+
+                return Ok(message);
+            }
 
-            match self.reader.read(&mut handshake).await {
-                Ok(received) if received == 68 => break Ok(handshake), 
-                Ok(_) => continue,
-                Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
-                Err(err) => break Err(err.into()),
+            if self.reader.read_buf(&mut self.read_buffer).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
             }
         }
     }
 
-    pub async fn read_message(&mut self) -> Result<Message, Error> {
+    /// Takes the first message out of `read_buffer` if all of it has been received
+    fn buffered_message(&mut self) -> Result<Option<Message>, Error> {
         // read length of message
-        let len = self.reader.read_u32().await?;
+        let Some(len) = self.read_buffer.get(..4) else {
+            return Ok(None);
+        };
+
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
 
         // If len is 0, it's a keep-alive message
         if len == 0 {
-            return Ok(Message::KeepAlive);
+            self.read_buffer.drain(..4);
+            return Ok(Some(Message::KeepAlive));
         }
 
-        // Read message id
-        let mut id = [0u8; 1];
-        self.reader.read_exact(&mut id).await?;
+        // checked before waiting for the rest so a bogus length can't exhaust memory,
+        // bitfields of huge torrents are the only messages allowed to be longer
+        let max_len = MAX_MESSAGE_LENGTH.max(self.bitfield.len().div_ceil(8) + 1);
 
-        let id = id[0];
+        if len > max_len {
+            return Err(Error::InvalidPayloadLength { expected: max_len, actual: len });
+        }
 
-        if id > 9 && id != 20 {
-            return Err(Error::InvalidMessageId(id));
+        if self.read_buffer.len() < 4 + len {
+            return Ok(None);
         }
 
-        // Calculate payload length and read payload if present
-        let payload_len = len as usize - 1;
+        let message: Vec<u8> = self.read_buffer.drain(..4 + len).skip(4).collect();
+        let id = message[0];
 
-        if payload_len > 0 {
-            let mut payload = vec![0; payload_len];
-            self.reader.read_exact(&mut payload).await?;
+        if id > 9 && id != 20 {
+            return Err(Error::InvalidMessageId(id));
+        }
 
-            // Construct and return the message
-            Ok(Message::from_id_and_payload(id, payload)?)
+        if id <= 3 {
+            // messages without a payload
+            Ok(Some(Message::from_id(id)))
         } else {
-            // If there's no payload, return a message with just the ID
-            Ok(Message::from_id(id))
+            Ok(Some(Message::from_id_and_payload(id, message[1..].to_vec())?))
         }
     }
 
+    pub fn send_keep_alive(&mut self) {
+        self.queue(Message::KeepAlive);
+    }
+
     pub fn bitfield(&self) -> &BitVec {
         &self.bitfield
     }
 
+    /// Pieces the peer has, unused bits of the bitfield are always zero
+    pub fn piece_count(&self) -> usize {
+        self.bitfield.blocks().map(|block| block.count_ones() as usize).sum()
+    }
+
+    /// Whether the peer has every piece, the bitfield is already truncated to the piece count
+    pub fn is_seed(&self) -> bool {
+        !self.bitfield.is_empty() && self.bitfield.all()
+    }
+
     pub fn set_is_choking(&mut self, bool: bool) {
         self.is_choking = bool;
     }
@@ -273,37 +503,560 @@ impl<'a> Peer<'a> {
         self.is_interested
     }
 
-    pub async fn send_unchoke(&mut self) -> Result<(), Error> {
-        self.writer.write_all(&[0, 0, 0, 1, 1]).await?;
+    pub fn send_unchoke(&mut self) {
+        self.queue(Message::Unchoke);
         self.am_choking = false;
+    }
 
-        Ok(())
+    pub fn send_choke(&mut self) {
+        self.queue(Message::Choke);
+        self.am_choking = true;
     }
 
-    pub async fn send_interested(&mut self) -> Result<(), Error> {
-        self.writer.write_all(&[0, 0, 0, 1, 2]).await?;
+    pub fn send_have(&mut self, index: u32) {
+        self.queue(Message::Have(index));
+    }
+
+    pub fn send_cancel(&mut self, index: u32, begin: u32, length: u32) {
+        self.queue(Message::Cancel { index, begin, length });
+    }
+
+    pub fn send_interested(&mut self) {
+        self.queue(Message::Interested);
         self.am_interested = true;
+    }
+
+    pub fn send_not_interested(&mut self) {
+        self.queue(Message::NotInterested);
+        self.am_interested = false;
+    }
+
+    pub fn send_request(&mut self, index: u32, begin: u32, length: u32) {
+        self.queue(Message::Request { index, begin, length });
+    }
+
+    pub fn send_piece(&mut self, index: u32, begin: u32, block: Vec<u8>) {
+        self.queue(Message::Piece { index, begin, block });
+    }
+
+    /// Only valid as the first message after the handshake
+    pub fn send_bitfield(&mut self, bitfield: &BitVec) {
+        self.queue(Message::Bitfield(bitfield.to_bytes()));
+    }
+
+    /// Never sends messages with these ids, the `send_` methods still update our state as if
+    /// they were sent. Only in debug builds, for testing how peers get along without them
+    #[cfg(debug_assertions)]
+    pub fn set_disabled_messages(&mut self, ids: &[u8]) {
+        self.disabled = ids.to_vec();
+    }
+
+    /// Whether messages are waiting for `flush`
+    pub fn has_queued(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Tells the peer we are done with it before the connection is dropped: not interested
+    /// anymore, and choked if we were uploading to it. Best effort, the connection may be
+    /// gone already
+    pub async fn close(&mut self) {
+        if self.am_interested {
+            self.send_not_interested();
+        }
+
+        if !self.am_choking {
+            self.send_choke();
+        }
+
+        let _ = self.flush().await;
+    }
+
+    /// Adds a message to the outgoing queue, dropping the ones it makes pointless
+    fn queue(&mut self, message: Message) {
+        #[cfg(debug_assertions)]
+        if message.id().is_some_and(|id| self.disabled.contains(&id)) {
+            return;
+        }
+
+        match &message {
+            // the request never left, so there is nothing to cancel
+            Message::Cancel { index, begin, length } => {
+                let request = Message::Request { index: *index, begin: *begin, length: *length };
+
+                if let Some(position) = self.outgoing.iter().position(|queued| *queued == request) {
+                    self.outgoing.remove(position);
+                    return;
+                }
+            }
+            Message::Choke | Message::Unchoke => self.outgoing.retain(|queued| !matches!(queued, Message::Choke | Message::Unchoke)),
+            Message::Interested | Message::NotInterested => self.outgoing.retain(|queued| !matches!(queued, Message::Interested | Message::NotInterested)),
+            // any other message keeps the connection alive as well
+            Message::KeepAlive if self.has_queued() => return,
+            _ => (),
+        }
+
+        self.outgoing.push(message);
+    }
+
+    /// Writes every queued message, cancels first so the peer doesn't start on blocks we
+    /// no longer want and pieces last since they are the largest. Messages of the same kind
+    /// keep their order. This is the only place messages are written after the handshake
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        if !self.has_queued() {
+            return Ok(());
+        }
+
+        let mut outgoing = std::mem::take(&mut self.outgoing);
+        outgoing.sort_by_key(send_priority);
+
+        #[cfg(feature = "trace")]
+        for message in &outgoing {
+            log::trace!("{} sent {}", self.name(), summary(message));
+        }
+
+        let bytes: Vec<u8> = outgoing.iter().flat_map(Message::to_bytes).collect();
+
+        self.writer.write_all(&bytes).await?;
+        self.writer.flush().await?;
 
         Ok(())
     }
 
-    pub async fn send_request(&mut self, index: u32, begin: u32, length: u32) -> Result<(), Error> {
-        let mut cursor = Cursor::new(vec![0, 0, 0, 13, 6]);
-        cursor.seek(io::SeekFrom::End(0)).unwrap();
-        AsyncWriteExt::write_all(&mut cursor, &index.to_be_bytes()).await?;
-        AsyncWriteExt::write_all(&mut cursor, &begin.to_be_bytes()).await?;
-        AsyncWriteExt::write_all(&mut cursor, &length.to_be_bytes()).await?;
+    /// How the peer is called in traces
+    #[cfg(feature = "trace")]
+    fn name(&self) -> String {
+        self.address.map_or(String::from("peer"), |address| format!("peer {}", address))
+    }
+
+    pub fn update_bitfield(&mut self, bitfield: Vec<u8>) -> Result<(), Error> {
+        self.bitfield = parse_bitfield(&bitfield, self.bitfield.len())?;
+
+        Ok(())
+    }
+
+    pub fn update_piece(&mut self, piece_index: u32) -> Result<(), Error> {
+        let pieces = self.bitfield.len();
+
+        if piece_index as usize >= pieces {
+            return Err(Error::InvalidPieceIndex { index: piece_index, pieces });
+        }
 
-        self.writer.write_all(cursor.get_ref()).await?;
+        self.bitfield.set(piece_index as usize, true);
 
         Ok(())
     }
+}
+
+/// Every field of the message for traces, but only the length of payloads
+#[cfg(feature = "trace")]
+fn summary(message: &Message) -> String {
+    match message {
+        Message::Bitfield(bitfield) => format!("Bitfield of {} bytes", bitfield.len()),
+        Message::Request { index, begin, length } => format!("Request {} offset {} length {}", index, begin, length),
+        Message::Piece { index, begin, block } => format!("Piece {} offset {} of {} bytes", index, begin, block.len()),
+        Message::Cancel { index, begin, length } => format!("Cancel {} offset {} length {}", index, begin, length),
+        Message::Extended(payload) => format!("Extended of {} bytes", payload.len()),
+        message => message.to_string(),
+    }
+}
+
+#[cfg(feature = "trace")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Lower goes first when flushing the outgoing queue
+fn send_priority(message: &Message) -> u8 {
+    match message {
+        Message::Cancel { .. } => 0,
+        Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 1,
+        Message::KeepAlive | Message::Have(_) | Message::Bitfield(_) | Message::Extended(_) => 2,
+        Message::Request { .. } => 3,
+        Message::Piece { .. } => 4,
+    }
+}
+
+/// Validates a received bitfield has exactly `ceil(num_pieces / 8)` bytes
+/// and that the spare bits after the last piece are zero
+fn parse_bitfield(bytes: &[u8], num_pieces: usize) -> Result<BitVec, Error> {
+    let expected = num_pieces.div_ceil(8);
+
+    if bytes.len() != expected {
+        return Err(Error::InvalidBitfieldLength { expected, actual: bytes.len() });
+    }
+
+    let mut bitfield = BitVec::from_bytes(bytes);
+
+    if bitfield.iter().skip(num_pieces).any(|bit| bit) {
+        return Err(Error::InvalidBitfieldSpareBits);
+    }
+
+    bitfield.truncate(num_pieces);
+
+    Ok(bitfield)
+}
+
+/// The remote end of a `Peer`, following a script of messages to send and expect
+#[cfg(test)]
+pub(crate) mod mock {
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use crate::info_hash::InfoHash;
+    use crate::peer::{Message, Peer};
+
+    pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+    impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+    pub enum Step {
+        /// Answers our handshake after checking its info hash
+        Handshake { info_hash: InfoHash },
+        Send(Message),
+        Expect(Message),
+    }
+
+    pub struct MockPeer {
+        stream: Box<dyn Stream>,
+    }
+
+    impl MockPeer {
+        /// Mock speaking over `stream`, such as an accepted `TcpStream`
+        pub fn new(stream: impl Stream + 'static) -> Self {
+            MockPeer { stream: Box::new(stream) }
+        }
+
+        /// A `Peer` for a torrent of `num_pieces` connected to the returned mock
+        pub fn pair(num_pieces: usize) -> (Peer<'static>, MockPeer) {
+            let (local, remote) = tokio::io::duplex(64 * 1024);
+
+            (Peer::with_stream(local, num_pieces), MockPeer::new(remote))
+        }
+
+        /// Runs every step in order, panicking on the first unexpected message
+        pub async fn run(mut self, steps: Vec<Step>) {
+            for step in steps {
+                match step {
+                    Step::Handshake { info_hash } => {
+                        // echoes whatever protocol string it's sent
+                        let length = self.stream.read_u8().await.unwrap() as usize;
+                        let mut handshake = vec![0u8; 1 + length + 48];
+                        handshake[0] = length as u8;
+                        self.stream.read_exact(&mut handshake[1..]).await.unwrap();
+
+                        let info_hash_start = 1 + length + 8;
+                        assert_eq!(handshake[info_hash_start..info_hash_start + 20], *info_hash.as_bytes());
+
+                        handshake[info_hash_start + 20..].copy_from_slice(b"-MOCK00-000000000000");
+                        self.stream.write_all(&handshake).await.unwrap();
+                    }
+                    Step::Send(message) => self.stream.write_all(&message.to_bytes()).await.unwrap(),
+                    Step::Expect(expected) => assert_eq!(self.receive().await, expected),
+                }
+            }
+        }
+
+        async fn receive(&mut self) -> Message {
+            let len = self.stream.read_u32().await.unwrap() as usize;
+
+            if len == 0 {
+                return Message::KeepAlive;
+            }
+
+            let mut message = vec![0u8; len];
+            self.stream.read_exact(&mut message).await.unwrap();
+
+            if len == 1 {
+                Message::from_id(message[0])
+            } else {
+                Message::from_id_and_payload(message[0], message[1..].to_vec()).unwrap()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::info_hash::InfoHash;
+    use crate::peer::{parse_bitfield, Error, Message, Peer};
+    use crate::peer::mock::{MockPeer, Step};
+    use crate::peer_id::PeerId;
+
+    #[test]
+    fn bitfield() {
+        let bitfield = parse_bitfield(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+
+        assert_eq!(bitfield.len(), 9);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![true, false, true, false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn invalid_bitfield() {
+        let too_long = parse_bitfield(&[0xff, 0x80, 0x00], 9);
+        let too_short = parse_bitfield(&[0xff], 9);
+        let spare_bits = parse_bitfield(&[0xff, 0xc0], 9);
+
+        assert!(matches!(too_long, Err(Error::InvalidBitfieldLength { expected: 2, actual: 3 })));
+        assert!(matches!(too_short, Err(Error::InvalidBitfieldLength { expected: 2, actual: 1 })));
+        assert!(matches!(spare_bits, Err(Error::InvalidBitfieldSpareBits)));
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn summaries_skip_payloads() {
+        let piece = Message::Piece { index: 1, begin: 16384, block: vec![7; 16384] };
+
+        assert_eq!(crate::peer::summary(&piece), "Piece 1 offset 16384 of 16384 bytes");
+        assert_eq!(crate::peer::summary(&Message::Have(3)), "Have 3");
+        assert_eq!(crate::peer::hex(&[19, 0xab]), "13ab");
+    }
+
+    #[test]
+    fn message_bytes() {
+        let messages = [
+            Message::Unchoke,
+            Message::Have(7),
+            Message::Bitfield(vec![0b1010_0000]),
+            Message::Request { index: 1, begin: 16384, length: 16384 },
+            Message::Piece { index: 2, begin: 0, block: vec![1, 2, 3] },
+        ];
+
+        for message in messages {
+            let bytes = message.to_bytes();
+            let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+            assert_eq!(len, bytes.len() - 4);
+
+            let decoded = if len == 1 {
+                Message::from_id(bytes[4])
+            } else {
+                Message::from_id_and_payload(bytes[4], bytes[5..].to_vec()).unwrap()
+            };
+
+            assert_eq!(decoded, message);
+        }
+
+        assert_eq!(Message::KeepAlive.to_bytes(), vec![0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn handshake_in_two_chunks() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        let mock = tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+
+            remote.write_all(&handshake[..30]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            remote.write_all(&handshake[30..]).await.unwrap();
+
+            // keeps the stream open until the handshake is read
+            let _ = remote.read(&mut [0]).await;
+        });
+
+        let handshake = peer.handshake(InfoHash::new([1; 20]), PeerId::new([2; 20])).await.unwrap();
+
+        assert_eq!(handshake.protocol(), b"BitTorrent protocol");
+        assert_eq!(handshake.info_hash(), &InfoHash::new([1; 20]));
+        assert_eq!(handshake.peer_id(), &PeerId::new([2; 20]));
+
+        drop(peer);
+        mock.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+        peer.set_handshake_timeout(Duration::from_secs(5));
+
+        // half of an answer, then nothing
+        remote.write_all(&[19, b'B', b'i']).await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        assert!(matches!(peer.handshake(InfoHash::new([1; 20]), PeerId::new([2; 20])).await, Err(Error::HandshakeTimeout)));
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn custom_protocol() {
+        let (mut peer, mock) = MockPeer::pair(8);
+
+        assert!(matches!(peer.set_protocol(&[b'a'; 256]), Err(Error::InvalidProtocolLength(256))));
+        peer.set_protocol(b"Custom").unwrap();
+
+        let mock = tokio::spawn(mock.run(vec![Step::Handshake { info_hash: InfoHash::new([1; 20]) }]));
+        let handshake = peer.handshake(InfoHash::new([1; 20]), PeerId::new([2; 20])).await.unwrap();
+        mock.await.unwrap();
+
+        assert_eq!(handshake.protocol(), b"Custom");
+        assert_eq!(handshake.info_hash(), &InfoHash::new([1; 20]));
+        assert_eq!(handshake.peer_id(), &PeerId::new(*b"-MOCK00-000000000000"));
+
+        // a peer answering with the standard protocol doesn't speak ours
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+        peer.set_protocol(b"Custom").unwrap();
+
+        let mut answer = vec![19];
+        answer.extend_from_slice(b"BitTorrent protocol");
+        answer.extend_from_slice(&[0; 48]);
+        remote.write_all(&answer).await.unwrap();
+
+        assert!(matches!(peer.handshake(InfoHash::new([1; 20]), PeerId::new([2; 20])).await, Err(Error::UnexpectedProtocol(protocol)) if protocol == b"BitTorrent protocol"));
+    }
+
+    #[tokio::test]
+    async fn oversized_message() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        remote.write_all(&[0xff, 0xff, 0xff, 0xff, 7]).await.unwrap();
+
+        assert!(matches!(peer.read_message().await, Err(Error::InvalidPayloadLength { actual: 0xffffffff, .. })));
+    }
+
+    #[tokio::test]
+    async fn read_message_cancel_safe() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        let have = Message::Have(3).to_bytes();
+        remote.write_all(&have[..6]).await.unwrap();
+
+        // gives up while only part of the message arrived
+        assert!(tokio::time::timeout(Duration::from_millis(10), peer.read_message()).await.is_err());
+
+        remote.write_all(&have[6..]).await.unwrap();
+        remote.write_all(&Message::Unchoke.to_bytes()).await.unwrap();
+
+        assert_eq!(peer.read_message().await.unwrap(), Message::Have(3));
+        assert_eq!(peer.read_message().await.unwrap(), Message::Unchoke);
+    }
 
-    pub fn update_bitfield(&mut self, bitfield: Vec<u8>) {
-        self.bitfield = BitVec::from_bytes(&bitfield);
+    #[tokio::test]
+    async fn read_buffer_capacity() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        remote.write_all(&[Message::Have(1).to_bytes(), Message::Have(2).to_bytes()].concat()).await.unwrap();
+        assert_eq!(peer.read_message().await.unwrap(), Message::Have(1));
+
+        // whatever of the second message the old buffer holds is kept
+        peer.set_read_buffer_capacity(1);
+        assert_eq!(peer.read_message().await.unwrap(), Message::Have(2));
+
+        remote.write_all(&Message::Have(3).to_bytes()).await.unwrap();
+        assert_eq!(peer.read_message().await.unwrap(), Message::Have(3));
     }
 
-    pub fn update_piece(&mut self, piece_index: usize) {
-        self.bitfield.set(piece_index, true);
+    #[tokio::test]
+    async fn outgoing_queue() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        peer.send_request(0, 0, 16384);
+        peer.send_request(0, 16384, 16384);
+        peer.send_interested();
+        peer.send_keep_alive();
+        peer.send_choke();
+        peer.send_unchoke();
+        // the second request is dropped instead of being cancelled after it is sent
+        peer.send_cancel(0, 16384, 16384);
+        peer.send_cancel(1, 0, 16384);
+
+        assert!(!peer.am_choking());
+        assert!(peer.has_queued());
+
+        peer.flush().await.unwrap();
+        assert!(!peer.has_queued());
+
+        let expected: Vec<u8> = [
+            Message::Cancel { index: 1, begin: 0, length: 16384 },
+            Message::Interested,
+            Message::Unchoke,
+            Message::Request { index: 0, begin: 0, length: 16384 },
+        ].iter().flat_map(Message::to_bytes).collect();
+
+        let mut received = vec![0u8; expected.len()];
+        remote.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        // nothing else was written
+        peer.flush().await.unwrap();
+        drop(peer);
+        assert_eq!(remote.read(&mut [0u8; 1]).await.unwrap(), 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn close() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        peer.send_interested();
+        peer.send_unchoke();
+        peer.flush().await.unwrap();
+        peer.close().await;
+        drop(peer);
+
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+
+        let expected: Vec<u8> = [Message::Interested, Message::Unchoke, Message::NotInterested, Message::Choke]
+            .iter().flat_map(Message::to_bytes).collect();
+        assert_eq!(received, expected);
+
+        // nothing to tell a peer we never were interested in nor uploaded to
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+        peer.close().await;
+        drop(peer);
+        assert_eq!(remote.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        // the connection being gone already isn't an error
+        let (local, remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+        drop(remote);
+        peer.send_interested();
+        peer.flush().await.unwrap_err();
+        peer.close().await;
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn disabled_messages() {
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut peer = Peer::with_stream(local, 8);
+
+        peer.set_disabled_messages(&[Message::Have(0).id().unwrap(), Message::Interested.id().unwrap()]);
+        peer.send_have(3);
+        peer.send_interested();
+        peer.send_request(0, 0, 16384);
+
+        assert!(peer.am_interested());
+
+        peer.flush().await.unwrap();
+        drop(peer);
+
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, Message::Request { index: 0, begin: 0, length: 16384 }.to_bytes());
+    }
+
+    #[test]
+    fn seed() {
+        let (local, _remote) = tokio::io::duplex(64);
+        let mut peer = Peer::with_stream(local, 9);
+
+        peer.update_bitfield(vec![0xff, 0x00]).unwrap();
+        assert!(!peer.is_seed());
+
+        // the spare bits of the last byte don't count
+        peer.update_bitfield(vec![0xff, 0x80]).unwrap();
+        assert!(peer.is_seed());
+    }
+}
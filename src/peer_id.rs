@@ -0,0 +1,191 @@
+use rand::Rng;
+
+/// Client code and version at the start of the peer ids of this client, Azureus style
+pub const CLIENT_PREFIX: &[u8; 8] = b"-CT0100-";
+
+/// Names of the two letter client codes of Azureus style peer ids, `-XX1234-`
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"CT", "cli-torrent-client"),
+    (b"qB", "qBittorrent"),
+    (b"TR", "Transmission"),
+    (b"DE", "Deluge"),
+    (b"LT", "libtorrent"),
+    (b"lt", "rTorrent"),
+    (b"UT", "\u{b5}Torrent"),
+    (b"UM", "\u{b5}Torrent Mac"),
+    (b"BT", "BitTorrent"),
+    (b"AZ", "Vuze"),
+    (b"BI", "BiglyBT"),
+    (b"BC", "BitComet"),
+    (b"KT", "KTorrent"),
+    (b"WW", "WebTorrent"),
+    (b"FD", "Free Download Manager"),
+];
+
+/// Names of the letters Shadow style peer ids start with, followed by up to five
+/// version characters and dashes
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaculture"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// The 20 bytes a peer identifies itself with in handshakes and announces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    pub const fn new(bytes: [u8; 20]) -> Self {
+        PeerId(bytes)
+    }
+
+    /// Our client prefix followed by random alphanumerics, so handshakes with ourselves
+    /// can be told apart from other peers running this client
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 20];
+        bytes[..CLIENT_PREFIX.len()].copy_from_slice(CLIENT_PREFIX);
+
+        for byte in &mut bytes[CLIENT_PREFIX.len()..] {
+            *byte = rand::thread_rng().sample(rand::distributions::Alphanumeric);
+        }
+
+        PeerId(bytes)
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Name and version of the client that made the id, when it's in the Azureus or
+    /// Shadow style of a client we know
+    pub fn client_name(&self) -> Option<String> {
+        self.azureus().or_else(|| self.shadow()).map(|(name, version)| format!("{} {}", name, version))
+    }
+
+    fn azureus(&self) -> Option<(&'static str, String)> {
+        let [b'-', code @ .., b'-'] = &self.0[..8] else { return None };
+        let (code, version) = code.split_at(2);
+
+        let (_, name) = AZUREUS_CLIENTS.iter().find(|(known, _)| *known == code)?;
+
+        Some((name, version_string(version)?))
+    }
+
+    fn shadow(&self) -> Option<(&'static str, String)> {
+        let (_, name) = SHADOW_CLIENTS.iter().find(|(letter, _)| *letter == self.0[0])?;
+
+        let length = self.0[1..6].iter().position(|&byte| byte == b'-').unwrap_or(5);
+        let version = &self.0[1..1 + length];
+
+        // at least two dashes end the version, which tells the style apart from random ids
+        if version.is_empty() || !self.0[1 + length..].starts_with(b"--") {
+            return None;
+        }
+
+        let parts: Vec<String> = version.iter().map(|&byte| shadow_digit(byte).map(|digit| digit.to_string())).collect::<Option<_>>()?;
+
+        Some((name, parts.join(".")))
+    }
+}
+
+/// Value of a Shadow style version character, 0-9 then A-Z then a-z and `.`
+fn shadow_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'Z' => Some(byte - b'A' + 10),
+        b'a'..=b'z' => Some(byte - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Version digits as a dotted version: leading zeros are dropped, and trailing ones
+/// while more than two parts are left, so `0100` is 1.0 and `4630` is 4.6.3. Letters
+/// past 9 count from 10 up
+fn version_string(digits: &[u8]) -> Option<String> {
+    let parts: Vec<u32> = digits.iter().map(|&digit| char::from(digit).to_digit(36)).collect::<Option<_>>()?;
+
+    let first = parts.iter().position(|&part| part != 0).unwrap_or(parts.len() - 1);
+    let mut parts = &parts[first..];
+
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts = &parts[..parts.len() - 1];
+    }
+
+    Some(parts.iter().map(u32::to_string).collect::<Vec<_>>().join("."))
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(value: [u8; 20]) -> Self {
+        PeerId(value)
+    }
+}
+
+impl TryFrom<&[u8]> for PeerId {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(PeerId(value.try_into()?))
+    }
+}
+
+/// The `client_name` of known ids, hex of the whole id otherwise
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.client_name() {
+            Some(name) => f.write_str(&name),
+            None => self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::peer_id::{PeerId, CLIENT_PREFIX};
+
+    #[test]
+    fn displays_client() {
+        assert_eq!(PeerId::new(*b"-CT0100-123456789012").to_string(), "cli-torrent-client 1.0");
+        assert_eq!(PeerId::new(*b"-CT2340-123456789012").to_string(), "cli-torrent-client 2.3.4");
+        assert_eq!(PeerId::generate().to_string(), "cli-torrent-client 1.0");
+
+        // unknown clients and ids that aren't azureus style
+        assert_eq!(PeerId::new(*b"-ZZ0100-123456789012").to_string(), "2d5a5a303130302d313233343536373839303132");
+        assert_eq!(PeerId::new([0xab; 20]).to_string(), "ab".repeat(20));
+    }
+
+    #[test]
+    fn recognizes_common_clients() {
+        let name = |id: &[u8; 20]| PeerId::new(*id).client_name();
+
+        assert_eq!(name(b"-qB4630-k8hj0wgej6ch").as_deref(), Some("qBittorrent 4.6.3"));
+        assert_eq!(name(b"-TR4050-8fe2ns9xs1tp").as_deref(), Some("Transmission 4.0.5"));
+        assert_eq!(name(b"-DE2110-abcdefghijkl").as_deref(), Some("Deluge 2.1.1"));
+        assert_eq!(name(b"-lt0D60-abcdefghijkl").as_deref(), Some("rTorrent 13.6"));
+        assert_eq!(name(b"-UT3550-abcdefghijkl").as_deref(), Some("\u{b5}Torrent 3.5.5"));
+        assert_eq!(name(b"S58B-----abcdefghijk").as_deref(), Some("Shadow's client 5.8.11"));
+        assert_eq!(name(b"T03I--00abcdefghijkl").as_deref(), Some("BitTornado 0.3.18"));
+
+        assert_eq!(name(b"-ZZ0100-123456789012"), None);
+        assert_eq!(name(b"Sabcdefghijklmnopqrs"), None);
+        assert_eq!(name(&[0; 20]), None);
+    }
+
+    #[test]
+    fn generated_ids_differ() {
+        let first = PeerId::generate();
+        let second = PeerId::generate();
+
+        assert!(first.as_bytes().starts_with(CLIENT_PREFIX));
+        assert_ne!(first, second);
+        assert_eq!(HashSet::from([first, second, first]).len(), 2);
+
+        assert!(PeerId::try_from(&b"short"[..]).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::SocketAddr;
+
+use crate::bencode::{self, Bedecode, FromBencode, Type};
+use crate::tracker::{compact_peers, compact_peers6};
+
+/// Set in `added.f` for peers that prefer encrypted connections
+pub const PREFERS_ENCRYPTION: u8 = 0x01;
+/// Set in `added.f` for peers that only upload
+pub const SEED: u8 = 0x02;
+/// Set in `added.f` for peers that support the uTP transport
+pub const SUPPORTS_UTP: u8 = 0x04;
+/// Set in `added.f` for peers that support the `ut_holepunch` extension
+pub const SUPPORTS_HOLEPUNCH: u8 = 0x08;
+/// Set in `added.f` for peers the sender could connect to itself
+pub const REACHABLE: u8 = 0x10;
+
+/// A peer added by a `ut_pex` message with the flags sent along with it, 0 if there were none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    address: SocketAddr,
+    flags: u8,
+}
+
+impl PexPeer {
+    pub const fn new(address: SocketAddr, flags: u8) -> Self {
+        PexPeer { address, flags }
+    }
+
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub const fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Encryption should be tried first with this peer when it's optional
+    pub const fn prefers_encryption(&self) -> bool {
+        self.flags & PREFERS_ENCRYPTION != 0
+    }
+
+    pub const fn is_seed(&self) -> bool {
+        self.flags & SEED != 0
+    }
+}
+
+/// The payload of a `ut_pex` extended message (BEP 11), peers the sender connected to
+/// or dropped since its previous one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PexMessage {
+    added: Vec<PexPeer>,
+    dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn added(&self) -> &[PexPeer] {
+        &self.added
+    }
+
+    pub fn dropped(&self) -> &[SocketAddr] {
+        &self.dropped
+    }
+
+    /// Addresses of the added peers, the ones preferring encryption first and otherwise
+    /// in the order they were sent
+    pub fn encrypted_first(&self) -> Vec<SocketAddr> {
+        let mut added = self.added.clone();
+        added.sort_by_key(|peer| !peer.prefers_encryption());
+
+        added.iter().map(PexPeer::address).collect()
+    }
+}
+
+/// Pairs compact addresses with their byte of a `.f` key, which may be missing or short
+fn with_flags(addresses: Vec<SocketAddr>, flags: &[u8]) -> impl Iterator<Item = PexPeer> + '_ {
+    addresses.into_iter()
+        .enumerate()
+        .map(|(i, address)| PexPeer::new(address, flags.get(i).copied().unwrap_or(0)))
+}
+
+impl FromBencode for PexMessage {
+    type Error = bencode::Error;
+
+    fn from_bencode(bytes: &[u8]) -> Result<Self, Self::Error> where Self: Sized {
+        let map = bytes.try_into_dict()?.0;
+
+        let mut added = Vec::new();
+        let mut added_flags: &[u8] = &[];
+        let mut added6 = Vec::new();
+        let mut added6_flags: &[u8] = &[];
+        let mut dropped = Vec::new();
+
+        for (name, value) in map.iter() {
+            match (name.try_into_byte_string()?.0, value) {
+                (b"added", Type::String(bytes, _)) => added = compact_peers(bytes),
+                (b"added.f", Type::String(bytes, _)) => added_flags = bytes,
+                (b"added6", Type::String(bytes, _)) => added6 = compact_peers6(bytes),
+                (b"added6.f", Type::String(bytes, _)) => added6_flags = bytes,
+                (b"dropped", Type::String(bytes, _)) => dropped.extend(compact_peers(bytes)),
+                (b"dropped6", Type::String(bytes, _)) => dropped.extend(compact_peers6(bytes)),
+                _ => (),
+            }
+        }
+
+        let added = with_flags(added, added_flags).chain(with_flags(added6, added6_flags)).collect();
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::bencode::FromBencode;
+    use crate::pex::{PexMessage, PexPeer, PREFERS_ENCRYPTION, SEED};
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_flags() {
+        let mut message = b"d5:added12:".to_vec();
+        message.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        message.extend_from_slice(b"7:added.f2:");
+        message.extend_from_slice(&[SEED, PREFERS_ENCRYPTION | SEED]);
+        message.extend_from_slice(b"6:added618:");
+        message.extend_from_slice(&[0; 15]);
+        message.extend_from_slice(&[1, 0x1a, 0xe1]);
+        message.extend_from_slice(b"7:dropped6:");
+        message.extend_from_slice(&[10, 0, 0, 3, 0x1a, 0xe1]);
+        message.push(b'e');
+
+        let message = PexMessage::from_bencode(&message).unwrap();
+
+        // added6 came without flags
+        assert_eq!(message.added(), &[
+            PexPeer::new(addr("10.0.0.1:6881"), SEED),
+            PexPeer::new(addr("10.0.0.2:6882"), PREFERS_ENCRYPTION | SEED),
+            PexPeer::new(addr("[::1]:6881"), 0),
+        ]);
+        assert_eq!(message.dropped(), &[addr("10.0.0.3:6881")]);
+
+        assert!(message.added()[1].prefers_encryption() && message.added()[1].is_seed());
+        assert_eq!(message.encrypted_first(), vec![addr("10.0.0.2:6882"), addr("10.0.0.1:6881"), addr("[::1]:6881")]);
+    }
+}
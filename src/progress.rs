@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+
+use bit_vec::BitVec;
+
+use crate::metainfo::Info;
+use crate::stats::TorrentStats;
+
+/// Events sent by a `Torrent` while it downloads, subscribe with `Torrent::subscribe`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    PieceCompleted(u32),
+    /// A downloaded piece didn't match its hash and will be downloaded again, `peers`
+    /// sent its blocks as far as known
+    PieceFailed { index: u32, peers: Vec<SocketAddr> },
+    FileProgress { file_index: usize, progress: FileProgress },
+    RecheckProgress { checked: u32, total: u32 },
+    /// Every file was found whole and valid on disk when the download started, nothing is
+    /// downloaded and nothing is left to do unless seeding
+    AlreadyComplete,
+    /// Storing downloaded blocks failed, with the disk full for example. The download
+    /// stops with the error
+    WriteFailed(String),
+    /// A tracker answered an announce, with the seeders and leechers it counts if it sent them
+    Announced { tracker: String, seeders: Option<u32>, leechers: Option<u32> },
+    /// The peer sent too many pieces failing verification and is never connected to again
+    PeerBanned(SocketAddr),
+    /// Seeding stopped at `ClientConfig::seed_ratio_limit` or `seed_time_limit`
+    SeedingLimitReached,
+    /// Something failed that the download goes on without, such as listening for peers
+    /// or an http seed
+    Warning(String),
+    /// Sent every second while downloading
+    Stats(TorrentStats),
+}
+
+/// Bytes of a single file that belong to verified pieces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileProgress {
+    downloaded: u64,
+    total: u64,
+}
+
+impl FileProgress {
+    pub const fn new(downloaded: u64, total: u64) -> Self {
+        FileProgress { downloaded, total }
+    }
+
+    pub const fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    pub const fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub const fn is_complete(&self) -> bool {
+        self.downloaded == self.total
+    }
+
+    /// Completion between 0 and 100, an empty file is always complete
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+
+        self.downloaded as f64 / self.total as f64 * 100.0
+    }
+}
+
+/// Length in bytes of the piece at `index`, only the last piece may be shorter
+pub fn piece_size(info: &Info, index: u32) -> u64 {
+    let piece_length = info.piece_length() as u64;
+    let begin = index as u64 * piece_length;
+
+    piece_length.min(info.total_length().saturating_sub(begin))
+}
+
+/// Computes the progress of every file from the pieces set in `bitfield`.
+///
+/// A piece straddling two files only counts the bytes that fall inside each file
+pub fn file_progress(info: &Info, bitfield: &BitVec) -> Vec<FileProgress> {
+    let mut progress: Vec<FileProgress> = info.file_lengths()
+        .into_iter()
+        .map(|length| FileProgress::new(0, length))
+        .collect();
+
+    for (index, complete) in bitfield.iter().enumerate() {
+        if !complete {
+            continue;
+        }
+
+        let begin = index as u64 * info.piece_length() as u64;
+
+        for (file_index, _, length) in info.files_in_range(begin, piece_size(info, index as u32)) {
+            progress[file_index].downloaded += length;
+        }
+    }
+
+    progress
+}
+
+#[cfg(test)]
+mod test {
+    use bit_vec::BitVec;
+
+    use crate::bencode::{Bedecode, FromBencodeType};
+    use crate::metainfo::Info;
+    use crate::progress::{file_progress, FileProgress};
+
+    /// info dict for files of 10 and 6 bytes split into 8 byte pieces
+    fn info() -> Info {
+        let mut bytes = b"d5:filesld6:lengthi10e4:pathl1:aeed6:lengthi6e4:pathl1:beee4:name4:test12:piece lengthi8e6:pieces40:".to_vec();
+        bytes.extend_from_slice(&[0u8; 40]);
+        bytes.push(b'e');
+
+        Info::from_bencode_type(&bytes.as_slice().bedecode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn file_progress_straddling_piece() {
+        let info = info();
+
+        // piece 1 holds bytes 8..16, two of file a and six of file b
+        let mut bitfield = BitVec::from_elem(2, false);
+        bitfield.set(1, true);
+
+        assert_eq!(file_progress(&info, &bitfield), vec![FileProgress::new(2, 10), FileProgress::new(6, 6)]);
+
+        bitfield.set(0, true);
+
+        assert_eq!(file_progress(&info, &bitfield), vec![FileProgress::new(10, 10), FileProgress::new(6, 6)]);
+    }
+
+    #[test]
+    fn file_progress_percent() {
+        assert_eq!(FileProgress::new(5, 10).percent(), 50.0);
+        assert_eq!(FileProgress::new(0, 0).percent(), 100.0);
+        assert!(FileProgress::new(0, 0).is_complete());
+    }
+}
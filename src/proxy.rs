@@ -0,0 +1,382 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Host;
+
+use crate::interface;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+/// Version of the username and password subnegotiation (RFC 1929)
+const AUTHENTICATION_VERSION: u8 = 1;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    /// The proxy answered with another protocol version
+    InvalidVersion(u8),
+    /// The proxy wants an authentication method we don't have, credentials are needed
+    /// when this comes from a proxy configured without them
+    NoAcceptableMethod,
+    AuthenticationFailed,
+    /// Usernames, passwords and domains are sent with a single length byte
+    TooLong,
+    /// The proxy could not open the connection, with its reply code
+    ConnectFailed(u8),
+    /// The proxy answered with an address of a type RFC 1928 doesn't have
+    InvalidAddressType(u8),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(_) => write!(f, "Proxy connection failed"),
+            Self::InvalidVersion(version) => write!(f, "Proxy answered with socks version {}", version),
+            Self::NoAcceptableMethod => write!(f, "Proxy accepts none of our authentication methods"),
+            Self::AuthenticationFailed => write!(f, "Proxy rejected the username and password"),
+            Self::TooLong => write!(f, "Proxy credentials or destination longer than 255 bytes"),
+            Self::ConnectFailed(reply) => write!(f, "Proxy could not connect: {}", reply_message(*reply)),
+            Self::InvalidAddressType(address_type) => write!(f, "Proxy answered with unknown address type {}", address_type),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+/// Peers only tell connection failures apart by io errors, the proxy's own failures
+/// become `Other` errors
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::IoError(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+/// What the reply codes of RFC 1928 mean
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "ttl expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// A SOCKS5 proxy (RFC 1928) peer and tracker connections are opened through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    address: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    pub const fn new(address: SocketAddr) -> Self {
+        ProxyConfig { address, credentials: None }
+    }
+
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.credentials.as_ref().map(|(username, _)| username.as_str())
+    }
+
+    /// Authenticate with a username and password (RFC 1929) when the proxy asks for them
+    pub fn set_credentials(&mut self, username: String, password: String) {
+        self.credentials = Some((username, password));
+    }
+}
+
+/// Opens a connection to `host` and `port` through the proxy, which is connected to from
+/// `bind` when it's set. Domains are resolved by the proxy, so they never reach our resolver
+pub async fn connect(proxy: &ProxyConfig, host: Host<&str>, port: u16, bind: Option<IpAddr>) -> Result<TcpStream, Error> {
+    let mut stream = interface::connect(proxy.address, bind).await?;
+
+    let method = if proxy.credentials.is_some() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+    stream.write_all(&[VERSION, 1, method]).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+
+    match reply {
+        [VERSION, NO_AUTHENTICATION] => (),
+        [VERSION, USERNAME_PASSWORD] if proxy.credentials.is_some() => authenticate(&mut stream, proxy).await?,
+        [VERSION, _] => return Err(Error::NoAcceptableMethod),
+        [version, _] => return Err(Error::InvalidVersion(version)),
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+
+    match host {
+        Host::Ipv4(ip) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Ipv6(ip) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Host::Domain(domain) => {
+            request.extend_from_slice(&[DOMAIN, short_length(domain.as_bytes())?]);
+            request.extend_from_slice(domain.as_bytes());
+        }
+    }
+
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+
+    match reply {
+        [VERSION, 0, _, _] => (),
+        [VERSION, code, _, _] => return Err(Error::ConnectFailed(code)),
+        [version, ..] => return Err(Error::InvalidVersion(version)),
+    }
+
+    // the address the proxy connected from isn't needed, but has to be read past
+    let bound_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => stream.read_u8().await? as usize,
+        address_type => return Err(Error::InvalidAddressType(address_type)),
+    };
+
+    let mut bound = vec![0; bound_length + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+/// Connects to the address of a peer through the proxy
+pub async fn connect_to(proxy: &ProxyConfig, address: SocketAddr, bind: Option<IpAddr>) -> Result<TcpStream, Error> {
+    let host = match address.ip() {
+        IpAddr::V4(ip) => Host::Ipv4(ip),
+        IpAddr::V6(ip) => Host::Ipv6(ip),
+    };
+
+    connect(proxy, host, address.port(), bind).await
+}
+
+async fn authenticate(stream: &mut TcpStream, proxy: &ProxyConfig) -> Result<(), Error> {
+    let Some((username, password)) = &proxy.credentials else {
+        return Err(Error::NoAcceptableMethod);
+    };
+
+    let mut request = vec![AUTHENTICATION_VERSION, short_length(username.as_bytes())?];
+    request.extend_from_slice(username.as_bytes());
+    request.push(short_length(password.as_bytes())?);
+    request.extend_from_slice(password.as_bytes());
+
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+
+    // any status but 0 is a failure
+    match reply {
+        [_, 0] => Ok(()),
+        _ => Err(Error::AuthenticationFailed),
+    }
+}
+
+fn short_length(bytes: &[u8]) -> Result<u8, Error> {
+    u8::try_from(bytes.len()).map_err(|_| Error::TooLong)
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    /// A SOCKS5 server accepting one client, which relays it to wherever it asks.
+    /// `credentials` are required when set, and the destination requested is returned
+    pub struct MockProxy {
+        pub address: std::net::SocketAddr,
+        pub handle: JoinHandle<Option<String>>,
+    }
+
+    impl MockProxy {
+        pub async fn new(credentials: Option<(&'static str, &'static str)>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+
+            let handle = tokio::spawn(async move {
+                let (mut client, _) = listener.accept().await.unwrap();
+
+                let mut greeting = [0; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0; greeting[1] as usize];
+                client.read_exact(&mut methods).await.unwrap();
+
+                let method = if credentials.is_some() { 2 } else { 0 };
+
+                if !methods.contains(&method) {
+                    client.write_all(&[5, 0xff]).await.unwrap();
+                    return None;
+                }
+
+                client.write_all(&[5, method]).await.unwrap();
+
+                if let Some((username, password)) = credentials {
+                    let mut sent = vec![0; 2];
+                    client.read_exact(&mut sent).await.unwrap();
+                    let mut sent_username = vec![0; sent[1] as usize];
+                    client.read_exact(&mut sent_username).await.unwrap();
+                    let mut sent_password = vec![0; client.read_u8().await.unwrap() as usize];
+                    client.read_exact(&mut sent_password).await.unwrap();
+
+                    if sent_username != username.as_bytes() || sent_password != password.as_bytes() {
+                        client.write_all(&[1, 1]).await.unwrap();
+                        return None;
+                    }
+
+                    client.write_all(&[1, 0]).await.unwrap();
+                }
+
+                let mut request = [0; 4];
+                client.read_exact(&mut request).await.unwrap();
+
+                let host = match request[3] {
+                    1 => {
+                        let mut ip = [0; 4];
+                        client.read_exact(&mut ip).await.unwrap();
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut domain = vec![0; client.read_u8().await.unwrap() as usize];
+                        client.read_exact(&mut domain).await.unwrap();
+                        String::from_utf8(domain).unwrap()
+                    }
+                    _ => {
+                        let mut ip = [0; 16];
+                        client.read_exact(&mut ip).await.unwrap();
+                        format!("[{}]", std::net::Ipv6Addr::from(ip))
+                    }
+                };
+
+                let port = client.read_u16().await.unwrap();
+                let destination = format!("{}:{}", host, port);
+
+                // domains of tests all point to the loopback
+                let target = destination.replacen(&host, "127.0.0.1", 1);
+
+                let Ok(mut server) = TcpStream::connect(&target).await else {
+                    client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    return Some(destination);
+                };
+
+                client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+
+                Some(destination)
+            });
+
+            MockProxy { address, handle }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Host;
+
+    use crate::proxy::{self, Error, ProxyConfig};
+    use crate::proxy::mock::MockProxy;
+
+    #[tokio::test]
+    async fn connects_through_proxy() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        let mock = MockProxy::new(Some(("user", "secret"))).await;
+        let mut config = ProxyConfig::new(mock.address);
+        config.set_credentials(String::from("user"), String::from("secret"));
+
+        let (stream, accepted) = tokio::join!(proxy::connect(&config, Host::Domain("tracker.test"), port, None), server.accept());
+        let mut stream = stream.unwrap();
+        let (mut accepted, _) = accepted.unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        drop((stream, accepted));
+        assert_eq!(mock.handle.await.unwrap(), Some(format!("tracker.test:{}", port)));
+    }
+
+    #[tokio::test]
+    async fn proxy_failures() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap();
+
+        // credentials were needed
+        let mock = MockProxy::new(Some(("user", "secret"))).await;
+        let result = proxy::connect_to(&ProxyConfig::new(mock.address), address, None).await;
+        assert!(matches!(result, Err(Error::NoAcceptableMethod)));
+
+        let mock = MockProxy::new(Some(("user", "secret"))).await;
+        let mut config = ProxyConfig::new(mock.address);
+        config.set_credentials(String::from("user"), String::from("wrong"));
+        assert!(matches!(proxy::connect_to(&config, address, None).await, Err(Error::AuthenticationFailed)));
+
+        drop(server);
+        let mock = MockProxy::new(None).await;
+        assert!(matches!(proxy::connect_to(&ProxyConfig::new(mock.address), address, None).await, Err(Error::ConnectFailed(5))));
+    }
+    #[tokio::test]
+    async fn invalid_address_type() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ProxyConfig::new(listener.local_addr().unwrap());
+
+        let answering = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            client.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            client.read_exact(&mut request).await.unwrap();
+            client.write_all(&[5, 0, 0, 9]).await.unwrap();
+        });
+
+        let result = proxy::connect_to(&config, "10.0.0.1:6881".parse().unwrap(), None).await;
+        assert!(matches!(result, Err(Error::InvalidAddressType(9))));
+
+        answering.await.unwrap();
+    }
+}
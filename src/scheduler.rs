@@ -0,0 +1,161 @@
+use std::fmt::Debug;
+
+use bit_vec::BitVec;
+
+use crate::state::DownloadState;
+
+/// Peers receiving at least this many bytes a second finish the pieces others left
+/// halfway, slower ones would hold them up again
+pub const FAST_PEER_RATE: f64 = 64.0 * 1024.0;
+
+/// What a scheduler knows about the peer asking for a piece
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulingPeer<'a> {
+    bitfield: &'a BitVec,
+    download_rate: f64,
+}
+
+impl<'a> SchedulingPeer<'a> {
+    pub const fn new(bitfield: &'a BitVec, download_rate: f64) -> Self {
+        SchedulingPeer { bitfield, download_rate }
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.get(index as usize) == Some(true)
+    }
+
+    pub fn is_seed(&self) -> bool {
+        !self.bitfield.is_empty() && self.bitfield.all()
+    }
+
+    /// Bytes a second received from the peer lately
+    pub const fn download_rate(&self) -> f64 {
+        self.download_rate
+    }
+
+    /// Pieces the peer has that are missing, in order
+    pub fn candidates(&self, state: &DownloadState) -> Vec<u32> {
+        (0..self.bitfield.len() as u32).filter(|&piece| self.has_piece(piece) && state.is_missing(piece)).collect()
+    }
+}
+
+/// Picks the piece every peer downloads next from the whole `DownloadState`, so each
+/// piece is downloaded from a single peer at a time and every peer from one piece at a
+/// time. Shared by all the peer tasks of a torrent, see `ClientConfig::scheduler`
+pub trait RequestScheduler: Debug + Send + Sync {
+    /// A missing piece the peer has, `None` when there is none it should download.
+    /// The piece is requested from the peer by the caller
+    fn next_piece(&self, state: &DownloadState, peer: &SchedulingPeer<'_>) -> Option<u32>;
+}
+
+/// Prioritized pieces in order, then the rarest ones. Fast peers and seeds finish partial
+/// pieces first, the others start fresh ones
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl RequestScheduler for RarestFirst {
+    fn next_piece(&self, state: &DownloadState, peer: &SchedulingPeer<'_>) -> Option<u32> {
+        let candidates = peer.candidates(state);
+
+        // someone is reading these, in order
+        if let Some(&piece) = candidates.iter().find(|&&piece| state.is_prioritized(piece)) {
+            return Some(piece);
+        }
+
+        let (partial, fresh): (Vec<u32>, Vec<u32>) = candidates.into_iter()
+            .partition(|&piece| state.partial_offset(piece).is_some());
+
+        // ties go to the lowest index
+        let rarest = |pieces: Vec<u32>| pieces.into_iter().min_by_key(|&piece| state.availability(piece));
+
+        if peer.is_seed() || peer.download_rate() >= FAST_PEER_RATE {
+            rarest(partial).or_else(|| rarest(fresh))
+        } else {
+            rarest(fresh).or_else(|| rarest(partial))
+        }
+    }
+}
+
+/// Prioritized pieces in order, then the lowest index the peer has. Downloads mostly in
+/// order, but rare pieces may be gone with their peers by the time they are reached
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstAvailable;
+
+impl RequestScheduler for FirstAvailable {
+    fn next_piece(&self, state: &DownloadState, peer: &SchedulingPeer<'_>) -> Option<u32> {
+        let candidates = peer.candidates(state);
+
+        candidates.iter().copied().find(|&piece| state.is_prioritized(piece)).or_else(|| candidates.first().copied())
+    }
+}
+
+/// The lowest index the peer has, ignoring priorities. None of the schedulers are random,
+/// but with this one the requests depend only on what the peers have, not on when
+/// `Torrent::read_range` is called, so tests can expect them in exact order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl RequestScheduler for Sequential {
+    fn next_piece(&self, state: &DownloadState, peer: &SchedulingPeer<'_>) -> Option<u32> {
+        peer.candidates(state).first().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bit_vec::BitVec;
+
+    use crate::scheduler::{FirstAvailable, RarestFirst, RequestScheduler, SchedulingPeer, Sequential, FAST_PEER_RATE};
+    use crate::state::DownloadState;
+
+    #[test]
+    fn first_available_ignores_rarity() {
+        let bitfield = BitVec::from_bytes(&[0b0111_0000]);
+        let peer = SchedulingPeer::new(&bitfield, 0.0);
+
+        let mut state = DownloadState::new(4);
+        state.add_available(1);
+        state.complete(2);
+
+        assert_eq!(FirstAvailable.next_piece(&state, &peer), Some(1));
+        assert_eq!(RarestFirst.next_piece(&state, &peer), Some(3));
+
+        state.prioritize(3);
+        assert_eq!(FirstAvailable.next_piece(&state, &peer), Some(3));
+
+        state.complete(1);
+        state.complete(3);
+        assert_eq!(FirstAvailable.next_piece(&state, &peer), None);
+    }
+
+    #[test]
+    fn sequential_ignores_priorities() {
+        let bitfield = BitVec::from_bytes(&[0b1011_0000]);
+        let peer = SchedulingPeer::new(&bitfield, 0.0);
+
+        let mut state = DownloadState::new(4);
+        state.add_available(0);
+        state.prioritize(3);
+
+        let mut requested = Vec::new();
+
+        while let Some(piece) = Sequential.next_piece(&state, &peer) {
+            requested.push(piece);
+            state.complete(piece);
+        }
+
+        assert_eq!(requested, [0, 2, 3]);
+    }
+
+    #[test]
+    fn fast_peers_take_partial_pieces() {
+        let bitfield = BitVec::from_bytes(&[0b1100_0000]);
+
+        let mut state = DownloadState::new(4);
+        state.request(1, "127.0.0.1:6881".parse().unwrap());
+        state.release(1, 16384);
+
+        assert_eq!(RarestFirst.next_piece(&state, &SchedulingPeer::new(&bitfield, 0.0)), Some(0));
+        assert_eq!(RarestFirst.next_piece(&state, &SchedulingPeer::new(&bitfield, FAST_PEER_RATE)), Some(1));
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use bit_vec::BitVec;
+
+/// Super seeding (BEP 16) for a torrent we are the only seed of.
+///
+/// Instead of a full bitfield every peer is offered a single piece it doesn't have,
+/// preferring the pieces offered to the fewest peers, and the next offer is only made
+/// once that piece was uploaded. Peers end up holding different pieces and trade them
+/// among themselves, so less data has to come from us; in exchange each peer downloads
+/// slower at first and the mode is wasteful once other seeds exist
+pub struct SuperSeed {
+    offers: HashMap<SocketAddr, u32>,
+    times_offered: Vec<u32>,
+}
+
+impl SuperSeed {
+    pub fn new(pieces: usize) -> Self {
+        SuperSeed { offers: HashMap::new(), times_offered: vec![0; pieces] }
+    }
+
+    /// Piece to advertise to `peer` with a `have`, None while its current offer is pending
+    /// or when it already has everything
+    pub fn next_offer(&mut self, peer: SocketAddr, bitfield: &BitVec) -> Option<u32> {
+        if let Some(&piece) = self.offers.get(&peer) {
+            if bitfield.get(piece as usize) != Some(true) {
+                return None;
+            }
+        }
+
+        let piece = (0..self.times_offered.len() as u32)
+            .filter(|&piece| bitfield.get(piece as usize) != Some(true))
+            .min_by_key(|&piece| self.times_offered[piece as usize])?;
+
+        self.times_offered[piece as usize] += 1;
+        self.offers.insert(peer, piece);
+
+        Some(piece)
+    }
+
+    /// Records that `piece` was uploaded to `peer`, returning the next piece to advertise
+    pub fn uploaded(&mut self, peer: SocketAddr, piece: u32, bitfield: &BitVec) -> Option<u32> {
+        if self.offers.get(&peer) == Some(&piece) {
+            self.offers.remove(&peer);
+        }
+
+        self.next_offer(peer, bitfield)
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.offers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bit_vec::BitVec;
+
+    use crate::seed::SuperSeed;
+
+    #[test]
+    fn one_offer_per_peer() {
+        let first: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:6882".parse().unwrap();
+        let mut super_seed = SuperSeed::new(3);
+
+        let mut first_bitfield = BitVec::from_elem(3, false);
+        let second_bitfield = BitVec::from_elem(3, false);
+
+        assert_eq!(super_seed.next_offer(first, &first_bitfield), Some(0));
+        assert_eq!(super_seed.next_offer(first, &first_bitfield), None);
+
+        // the other peer is offered a different piece
+        assert_eq!(super_seed.next_offer(second, &second_bitfield), Some(1));
+
+        first_bitfield.set(0, true);
+        assert_eq!(super_seed.uploaded(first, 0, &first_bitfield), Some(2));
+
+        first_bitfield.set(1, true);
+        first_bitfield.set(2, true);
+        assert_eq!(super_seed.uploaded(first, 2, &first_bitfield), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+use bit_vec::BitVec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Missing,
+    Requested { peer: SocketAddr },
+    Complete,
+    /// Not downloaded since it has no byte of a wanted file
+    Skipped,
+}
+
+/// What happened to every piece of a torrent, shared by the peer tasks that pick pieces
+/// and the writer that verifies them
+#[derive(Debug)]
+pub struct DownloadState {
+    pieces: Vec<PieceStatus>,
+    /// Pieces that are skipped instead of becoming missing again once a peer lets them go
+    wanted: BitVec,
+    /// Bytes already on disk of pieces a peer stopped downloading halfway
+    partial: HashMap<u32, u32>,
+    /// How many connected peers have each piece
+    availability: Vec<u16>,
+    /// Peers that sent blocks of each piece that isn't verified yet
+    contributors: HashMap<u32, Vec<SocketAddr>>,
+    /// Times each piece failed verification
+    failures: HashMap<u32, u32>,
+    /// Pieces failing verification that each peer sent
+    bad_pieces: HashMap<IpAddr, u32>,
+    /// Peers that are disconnected and never connected to again
+    banned: HashSet<IpAddr>,
+    /// Pieces someone is waiting for, picked before any other
+    prioritized: HashSet<u32>,
+}
+
+impl DownloadState {
+    pub fn new(pieces: usize) -> Self {
+        DownloadState {
+            pieces: vec![PieceStatus::Missing; pieces],
+            wanted: BitVec::from_elem(pieces, true),
+            partial: HashMap::new(),
+            availability: vec![0; pieces],
+            contributors: HashMap::new(),
+            failures: HashMap::new(),
+            bad_pieces: HashMap::new(),
+            banned: HashSet::new(),
+            prioritized: HashSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    pub fn status(&self, index: u32) -> Option<PieceStatus> {
+        self.pieces.get(index as usize).copied()
+    }
+
+    /// Whether the piece still has to be downloaded and no peer is doing it
+    pub fn is_missing(&self, index: u32) -> bool {
+        self.status(index) == Some(PieceStatus::Missing)
+    }
+
+    /// Whether every wanted piece is complete
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|status| matches!(status, PieceStatus::Complete | PieceStatus::Skipped))
+    }
+
+    /// Offset to resume the piece from, if a peer left it halfway
+    pub fn partial_offset(&self, index: u32) -> Option<u32> {
+        self.partial.get(&index).copied()
+    }
+
+    /// Pieces currently being downloaded from `peer`
+    pub fn requested_from(&self, peer: SocketAddr) -> Vec<u32> {
+        (0..self.pieces.len() as u32)
+            .filter(|&index| self.status(index) == Some(PieceStatus::Requested { peer }))
+            .collect()
+    }
+
+    /// Bitfield of the complete pieces
+    pub fn bitfield(&self) -> BitVec {
+        self.pieces.iter().map(|status| *status == PieceStatus::Complete).collect()
+    }
+
+    /// Assigns a missing piece to `peer`, returns false if it wasn't missing
+    pub fn request(&mut self, index: u32, peer: SocketAddr) -> bool {
+        if !self.is_missing(index) {
+            return false;
+        }
+
+        self.pieces[index as usize] = PieceStatus::Requested { peer };
+        true
+    }
+
+    /// Makes a requested piece missing again, remembering the first `offset` bytes are on disk
+    pub fn release(&mut self, index: u32, offset: u32) {
+        let Some(PieceStatus::Requested { peer }) = self.status(index) else {
+            return;
+        };
+
+        // the peer wrote blocks past where it started
+        if offset > self.partial_offset(index).unwrap_or(0) {
+            self.add_contributor(index, peer);
+        }
+
+        self.pieces[index as usize] = self.not_downloaded(index);
+
+        if offset > 0 {
+            self.partial.insert(index, offset);
+        } else {
+            self.partial.remove(&index);
+        }
+    }
+
+    /// The piece failed verification and has to be downloaded from scratch, returns
+    /// every peer that sent blocks of it. There is no telling which block was bad, so
+    /// each of them is counted as having sent a bad piece
+    pub fn fail(&mut self, index: u32) -> Vec<SocketAddr> {
+        if let PieceStatus::Requested { peer } = self.pieces[index as usize] {
+            self.add_contributor(index, peer);
+        }
+
+        let peers = self.contributors.remove(&index).unwrap_or_default();
+
+        self.pieces[index as usize] = self.not_downloaded(index);
+        self.partial.remove(&index);
+        *self.failures.entry(index).or_insert(0) += 1;
+
+        for peer in &peers {
+            *self.bad_pieces.entry(peer.ip()).or_insert(0) += 1;
+        }
+
+        peers
+    }
+
+    pub fn failures(&self, index: u32) -> u32 {
+        self.failures.get(&index).copied().unwrap_or(0)
+    }
+
+    /// Pieces sent by the peer that failed verification
+    pub fn bad_pieces(&self, peer: IpAddr) -> u32 {
+        self.bad_pieces.get(&peer).copied().unwrap_or(0)
+    }
+
+    /// Bans the peer for the rest of the session, it's disconnected when it sends another block
+    pub fn ban(&mut self, peer: IpAddr) {
+        self.banned.insert(peer);
+    }
+
+    pub fn is_banned(&self, peer: IpAddr) -> bool {
+        self.banned.contains(&peer)
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        self.pieces[index as usize] = PieceStatus::Complete;
+        self.partial.remove(&index);
+        self.contributors.remove(&index);
+        self.prioritized.remove(&index);
+    }
+
+    /// Downloads the piece before any other that isn't prioritized, even if it was skipped
+    pub fn prioritize(&mut self, index: u32) {
+        if self.status(index) == Some(PieceStatus::Complete) {
+            return;
+        }
+
+        self.prioritized.insert(index);
+        self.set_wanted(index, true);
+    }
+
+    pub fn is_prioritized(&self, index: u32) -> bool {
+        self.prioritized.contains(&index)
+    }
+
+    /// Skips or downloads a piece that isn't complete, a requested one finishes first
+    pub fn set_wanted(&mut self, index: u32, wanted: bool) {
+        self.wanted.set(index as usize, wanted);
+
+        if matches!(self.pieces[index as usize], PieceStatus::Missing | PieceStatus::Skipped) {
+            self.pieces[index as usize] = self.not_downloaded(index);
+        }
+    }
+
+    /// Connected peers that have the piece, pieces with the lowest count are picked first
+    pub fn availability(&self, index: u32) -> u16 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// A connected peer has the piece now
+    pub fn add_available(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// A peer that had the piece disconnected
+    pub fn remove_available(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Peers sharing an ip are blamed once, like a single peer
+    fn add_contributor(&mut self, index: u32, peer: SocketAddr) {
+        let contributors = self.contributors.entry(index).or_default();
+
+        if !contributors.iter().any(|contributor| contributor.ip() == peer.ip()) {
+            contributors.push(peer);
+        }
+    }
+
+    fn not_downloaded(&self, index: u32) -> PieceStatus {
+        if self.wanted[index as usize] {
+            PieceStatus::Missing
+        } else {
+            PieceStatus::Skipped
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use bit_vec::BitVec;
+
+    use crate::state::{DownloadState, PieceStatus};
+
+    #[test]
+    fn transitions() {
+        let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let mut state = DownloadState::new(2);
+
+        assert!(state.request(0, peer));
+        assert!(!state.request(0, peer));
+        assert_eq!(state.status(0), Some(PieceStatus::Requested { peer }));
+        assert_eq!(state.requested_from(peer), vec![0]);
+
+        state.release(0, 16384);
+        assert!(state.is_missing(0));
+        assert_eq!(state.partial_offset(0), Some(16384));
+
+        // a peer picking it up again resumes from the offset until the piece is done
+        assert!(state.request(0, peer));
+        assert_eq!(state.partial_offset(0), Some(16384));
+
+        state.complete(0);
+        assert_eq!(state.partial_offset(0), None);
+
+        // releasing a finished piece doesn't make it missing
+        state.release(0, 0);
+        assert_eq!(state.status(0), Some(PieceStatus::Complete));
+        assert!(!state.is_complete());
+
+        assert!(state.request(1, peer));
+        state.fail(1);
+        assert!(state.is_missing(1));
+
+        state.complete(1);
+        assert!(state.is_complete());
+        assert_eq!(state.bitfield(), BitVec::from_elem(2, true));
+    }
+
+    #[test]
+    fn skipped_pieces() {
+        let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let mut state = DownloadState::new(3);
+
+        state.set_wanted(0, false);
+        assert_eq!(state.status(0), Some(PieceStatus::Skipped));
+        assert!(!state.request(0, peer));
+
+        // an unwanted piece being downloaded is skipped once the peer lets go of it
+        assert!(state.request(1, peer));
+        state.set_wanted(1, false);
+        assert_eq!(state.status(1), Some(PieceStatus::Requested { peer }));
+        state.release(1, 0);
+        assert_eq!(state.status(1), Some(PieceStatus::Skipped));
+
+        state.complete(2);
+        state.set_wanted(2, false);
+        assert_eq!(state.status(2), Some(PieceStatus::Complete));
+        assert!(state.is_complete());
+
+        state.set_wanted(0, true);
+        assert!(state.is_missing(0));
+        assert!(!state.is_complete());
+    }
+
+    #[test]
+    fn prioritized_pieces() {
+        let mut state = DownloadState::new(3);
+
+        // reading a skipped piece downloads it
+        state.set_wanted(0, false);
+        state.prioritize(0);
+        assert!(state.is_missing(0) && state.is_prioritized(0));
+
+        state.complete(0);
+        assert!(!state.is_prioritized(0));
+
+        state.complete(1);
+        state.prioritize(1);
+        assert!(!state.is_prioritized(1));
+    }
+}
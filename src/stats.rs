@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::peer_id::PeerId;
+
+/// Rates are averaged over the bytes transferred in this window
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Time constant of the smoothed rate, bursts and stalls shorter than this barely move it
+const SMOOTHING: Duration = Duration::from_secs(20);
+
+/// Slower rates count as stalled, an ETA from them would be meaningless
+const STALLED_RATE: f64 = 1.0;
+
+/// Counts the bytes transferred in one direction and how fast they went lately
+#[derive(Debug)]
+pub struct RateMeter {
+    total: u64,
+    started: Instant,
+    /// When each recent transfer happened and its size, oldest first
+    samples: VecDeque<(Instant, u64)>,
+    /// Every recorded byte decayed exponentially by its age at `updated`
+    decayed: f64,
+    updated: Instant,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        let now = Instant::now();
+
+        RateMeter { total: 0, started: now, samples: VecDeque::new(), decayed: 0.0, updated: now }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+
+        self.total += bytes;
+        self.samples.push_back((now, bytes));
+
+        self.decayed = self.decayed_at(now) + bytes as f64;
+        self.updated = now;
+
+        while self.samples.front().is_some_and(|(time, _)| now.duration_since(*time) >= RATE_WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    pub const fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Bytes per second over the last few seconds, or since the meter was created if that's sooner
+    pub fn rate(&self) -> f64 {
+        let now = Instant::now();
+        let window = now.duration_since(self.started).min(RATE_WINDOW);
+
+        if window.is_zero() {
+            return 0.0;
+        }
+
+        let bytes: u64 = self.samples.iter()
+            .filter(|(time, _)| now.duration_since(*time) < window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        bytes as f64 / window.as_secs_f64()
+    }
+
+    /// Exponential moving average of the rate, steadier than `rate` so it's the one to
+    /// estimate with
+    pub fn smoothed_rate(&self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+
+        // early on the average hasn't seen a whole time constant of transfers yet
+        let warm_up = 1.0 - (-elapsed / SMOOTHING.as_secs_f64()).exp();
+
+        self.decayed_at(now) / SMOOTHING.as_secs_f64() / warm_up
+    }
+
+    fn decayed_at(&self, now: Instant) -> f64 {
+        let age = now.duration_since(self.updated).as_secs_f64();
+
+        self.decayed * (-age / SMOOTHING.as_secs_f64()).exp()
+    }
+}
+
+/// Time to transfer `left` bytes at `rate` bytes per second, `None` while stalled
+pub fn eta(left: u64, rate: f64) -> Option<Duration> {
+    if rate < STALLED_RATE {
+        return None;
+    }
+
+    Some(Duration::from_secs_f64(left as f64 / rate))
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter::new()
+    }
+}
+
+/// What a torrent is doing at one point in time, returned by `Torrent::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStats {
+    connected_peers: usize,
+    pieces_complete: u32,
+    pieces_total: u32,
+    downloaded: u64,
+    uploaded: u64,
+    download_rate: f64,
+    upload_rate: f64,
+    left: u64,
+    eta: Option<Duration>,
+    seeders: Option<u32>,
+    leechers: Option<u32>,
+}
+
+impl TorrentStats {
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(
+        connected_peers: usize,
+        pieces_complete: u32,
+        pieces_total: u32,
+        downloaded: u64,
+        uploaded: u64,
+        download_rate: f64,
+        upload_rate: f64,
+        left: u64,
+        eta: Option<Duration>,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+    ) -> Self {
+        TorrentStats { connected_peers, pieces_complete, pieces_total, downloaded, uploaded, download_rate, upload_rate, left, eta, seeders, leechers }
+    }
+
+    pub const fn connected_peers(&self) -> usize {
+        self.connected_peers
+    }
+
+    pub const fn pieces_complete(&self) -> u32 {
+        self.pieces_complete
+    }
+
+    pub const fn pieces_total(&self) -> u32 {
+        self.pieces_total
+    }
+
+    /// Bytes received from peers, including blocks of pieces that failed verification
+    pub const fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    pub const fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// Bytes per second
+    pub const fn download_rate(&self) -> f64 {
+        self.download_rate
+    }
+
+    /// Bytes per second
+    pub const fn upload_rate(&self) -> f64 {
+        self.upload_rate
+    }
+
+    /// Bytes of the pieces that aren't complete yet
+    pub const fn left(&self) -> u64 {
+        self.left
+    }
+
+    /// Same as `Torrent::eta` when the snapshot was taken
+    pub const fn eta(&self) -> Option<Duration> {
+        self.eta
+    }
+
+    /// Peers with every piece in the swarm, the most any tracker reported. `None` until
+    /// a tracker sends `complete`
+    pub const fn seeders(&self) -> Option<u32> {
+        self.seeders
+    }
+
+    /// Peers still downloading in the swarm, the most any tracker reported. `None` until
+    /// a tracker sends `incomplete`
+    pub const fn leechers(&self) -> Option<u32> {
+        self.leechers
+    }
+}
+
+/// One connected peer at one point in time, returned by `Torrent::peers`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    address: SocketAddr,
+    is_choking: bool,
+    am_interested: bool,
+    pieces: usize,
+    downloaded: u64,
+    download_rate: f64,
+    upload_rate: f64,
+    peer_id: Option<PeerId>,
+}
+
+impl PeerInfo {
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(
+        address: SocketAddr,
+        is_choking: bool,
+        am_interested: bool,
+        pieces: usize,
+        downloaded: u64,
+        download_rate: f64,
+        upload_rate: f64,
+        peer_id: Option<PeerId>,
+    ) -> Self {
+        PeerInfo { address, is_choking, am_interested, pieces, downloaded, download_rate, upload_rate, peer_id }
+    }
+
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Whether the peer refuses our requests
+    pub const fn is_choking(&self) -> bool {
+        self.is_choking
+    }
+
+    /// Whether we told the peer it has pieces we need
+    pub const fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    /// Pieces the peer advertised having
+    pub const fn pieces(&self) -> usize {
+        self.pieces
+    }
+
+    /// Bytes received from the peer since it was first connected, reconnections included
+    pub const fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Bytes per second
+    pub const fn download_rate(&self) -> f64 {
+        self.download_rate
+    }
+
+    /// Bytes per second
+    pub const fn upload_rate(&self) -> f64 {
+        self.upload_rate
+    }
+
+    /// `None` until the peer answered our handshake
+    pub const fn peer_id(&self) -> Option<&PeerId> {
+        self.peer_id.as_ref()
+    }
+
+    /// Name and version of the client the peer runs, see `PeerId::client_name`
+    pub fn client(&self) -> Option<String> {
+        self.peer_id.as_ref().and_then(PeerId::client_name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use crate::stats::{self, RateMeter};
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_over_window() {
+        let mut meter = RateMeter::new();
+        assert_eq!(meter.rate(), 0.0);
+
+        time::advance(Duration::from_secs(1)).await;
+        meter.record(1000);
+        assert_eq!(meter.rate(), 1000.0);
+
+        time::advance(Duration::from_secs(1)).await;
+        meter.record(3000);
+        assert_eq!(meter.rate(), 2000.0);
+
+        // only counts what happened in the last five seconds
+        time::advance(Duration::from_secs(4)).await;
+        assert_eq!(meter.rate(), 600.0);
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(meter.rate(), 0.0);
+        assert_eq!(meter.total(), 4000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn smoothed_rate() {
+        let mut meter = RateMeter::new();
+
+        for _ in 0..60 {
+            time::advance(Duration::from_secs(1)).await;
+            meter.record(1000);
+        }
+
+        let rate = meter.smoothed_rate();
+        assert!((950.0..1050.0).contains(&rate), "{}", rate);
+
+        // a stall as long as the time constant drops it to about a third
+        time::advance(Duration::from_secs(20)).await;
+
+        let rate = meter.smoothed_rate();
+        assert!((330.0..420.0).contains(&rate), "{}", rate);
+        assert_eq!(meter.rate(), 0.0);
+    }
+
+    #[test]
+    fn eta() {
+        assert_eq!(stats::eta(2048, 512.0), Some(Duration::from_secs(4)));
+        assert_eq!(stats::eta(0, 512.0), Some(Duration::ZERO));
+        assert_eq!(stats::eta(2048, 0.0), None);
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bit_vec::BitVec;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Where the files of a torrent are kept. Files are addressed by their index in the
+/// torrent, blocks crossing files are split by the caller
+pub trait Storage: Send {
+    /// Fills `buffer` from `offset` of the file, failing with `NotFound` or `UnexpectedEof`
+    /// if not all of it is stored
+    fn read_block(&mut self, file: usize, offset: u64, buffer: &mut [u8]) -> impl Future<Output = Result<(), io::Error>> + Send;
+
+    /// Stores `block` at `offset` of the file, creating it if needed
+    fn write_block(&mut self, file: usize, offset: u64, block: &[u8]) -> impl Future<Output = Result<(), io::Error>> + Send;
+
+    /// Truncates or extends the file to `length` bytes
+    fn set_len(&mut self, file: usize, length: u64) -> impl Future<Output = Result<(), io::Error>> + Send;
+
+    /// Waits until everything written to the file survives a crash
+    fn flush(&mut self, file: usize) -> impl Future<Output = Result<(), io::Error>> + Send;
+
+    /// Called once every piece of the file is verified. Nothing to do by default
+    fn complete(&mut self, _file: usize) -> impl Future<Output = Result<(), io::Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+struct OpenFile {
+    file: fs::File,
+    writable: bool,
+}
+
+/// Stores each file of the torrent at its path on disk. Files are opened the first
+/// time they are used, only for reading until something is written to them
+pub struct FileStorage {
+    /// Where files end up once complete
+    paths: Vec<PathBuf>,
+    /// Where files are while incomplete, the same as `paths` unless they have a part suffix
+    stored: Vec<PathBuf>,
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FileStorage {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        FileStorage::with_stored_paths(paths.clone(), paths)
+    }
+
+    /// Incomplete files are kept at `stored` and renamed to `paths` once complete
+    pub fn with_stored_paths(paths: Vec<PathBuf>, stored: Vec<PathBuf>) -> Self {
+        let files = paths.iter().map(|_| None).collect();
+
+        FileStorage { paths, stored, files }
+    }
+
+    /// Opens the files set in `needed` for writing right away, creating the missing ones,
+    /// so a bad path fails before anything is downloaded
+    pub async fn create(&mut self, needed: &BitVec) -> Result<(), io::Error> {
+        for (file, needed) in needed.iter().enumerate() {
+            if needed {
+                self.writable(file).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn writable(&mut self, file: usize) -> Result<&mut fs::File, io::Error> {
+        if !matches!(&self.files[file], Some(open) if open.writable) {
+            self.files[file] = Some(OpenFile { file: open_file(&self.stored[file]).await?, writable: true });
+        }
+
+        match &mut self.files[file] {
+            Some(open) => Ok(&mut open.file),
+            None => unreachable!(),
+        }
+    }
+
+    /// Checks the final path too, another storage of the same files may have renamed it
+    async fn readable(&mut self, file: usize) -> Result<&mut fs::File, io::Error> {
+        let open = match &mut self.files[file] {
+            Some(open) => open,
+            slot => {
+                let opened = match fs::File::open(&self.stored[file]).await {
+                    Err(err) if err.kind() == io::ErrorKind::NotFound && self.stored[file] != self.paths[file] => {
+                        fs::File::open(&self.paths[file]).await?
+                    }
+                    result => result?,
+                };
+
+                slot.insert(OpenFile { file: opened, writable: false })
+            }
+        };
+
+        Ok(&mut open.file)
+    }
+}
+
+impl Storage for FileStorage {
+    async fn read_block(&mut self, file: usize, offset: u64, buffer: &mut [u8]) -> Result<(), io::Error> {
+        let file = self.readable(file).await?;
+
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        file.read_exact(buffer).await?;
+
+        Ok(())
+    }
+
+    async fn write_block(&mut self, file: usize, offset: u64, block: &[u8]) -> Result<(), io::Error> {
+        let file = self.writable(file).await?;
+
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        file.write_all(block).await?;
+        // tokio finishes writes in the background, flushing makes them visible to other readers
+        file.flush().await
+    }
+
+    async fn set_len(&mut self, file: usize, length: u64) -> Result<(), io::Error> {
+        self.writable(file).await?.set_len(length).await
+    }
+
+    async fn flush(&mut self, file: usize) -> Result<(), io::Error> {
+        match &mut self.files[file] {
+            Some(open) if open.writable => open.file.sync_all().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the file to its final path, synced first so that path never holds a file
+    /// missing data
+    async fn complete(&mut self, file: usize) -> Result<(), io::Error> {
+        if self.stored[file] == self.paths[file] {
+            return Ok(());
+        }
+
+        self.flush(file).await?;
+
+        // the open file keeps working after being renamed
+        fs::rename(&self.stored[file], &self.paths[file]).await?;
+        self.stored[file] = self.paths[file].clone();
+
+        Ok(())
+    }
+}
+
+/// Opens a file, creating it (and its directories) if needed without truncating previous data
+async fn open_file(path: &Path) -> Result<fs::File, io::Error> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).await?;
+    }
+
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await
+}
+
+/// Blocks recently read or written through a `CachedStorage`, up to a number of bytes.
+/// Clones share the same blocks, so a block written by the `PieceWriter` is found by
+/// the readers of the same files
+#[derive(Debug, Clone)]
+pub struct PieceCache {
+    entries: Arc<Mutex<CacheEntries>>,
+}
+
+#[derive(Debug)]
+struct CacheEntries {
+    capacity: usize,
+    size: usize,
+    /// Block and last use of each cached block by file and offset, blocks never overlap
+    blocks: BTreeMap<(usize, u64), (Vec<u8>, u64)>,
+    /// Keys of `blocks` by last use, least recently used first
+    uses: BTreeMap<u64, (usize, u64)>,
+    next_use: u64,
+}
+
+impl PieceCache {
+    /// Cache of at most `capacity` bytes, 0 never keeps anything
+    pub fn new(capacity: usize) -> Self {
+        let entries = CacheEntries { capacity, size: 0, blocks: BTreeMap::new(), uses: BTreeMap::new(), next_use: 0 };
+
+        PieceCache { entries: Arc::new(Mutex::new(entries)) }
+    }
+
+    /// Bytes of the blocks cached right now
+    pub fn size(&self) -> usize {
+        self.entries.lock().unwrap().size
+    }
+
+    /// Fills `buffer` if a single cached block holds all of it
+    fn get(&self, file: usize, offset: u64, buffer: &mut [u8]) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let next_use = entries.next_use;
+
+        let Some((&key, (block, last_use))) = entries.blocks.range_mut(..=(file, offset)).next_back() else {
+            return false;
+        };
+
+        if key.0 != file || block.len() < (offset - key.1) as usize + buffer.len() {
+            return false;
+        }
+
+        let start = (offset - key.1) as usize;
+
+        buffer.copy_from_slice(&block[start..start + buffer.len()]);
+
+        let previous = std::mem::replace(last_use, next_use);
+        entries.uses.remove(&previous);
+        entries.uses.insert(next_use, key);
+        entries.next_use += 1;
+
+        true
+    }
+
+    /// Caches `block`, replacing whatever overlapped it and evicting the least recently
+    /// used blocks until everything fits
+    fn insert(&self, file: usize, offset: u64, block: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove_range(file, offset, offset + block.len() as u64);
+
+        if block.is_empty() || block.len() > entries.capacity {
+            return;
+        }
+
+        let next_use = entries.next_use;
+        entries.blocks.insert((file, offset), (block.to_vec(), next_use));
+        entries.uses.insert(next_use, (file, offset));
+        entries.next_use += 1;
+        entries.size += block.len();
+
+        while entries.size > entries.capacity {
+            let Some((_, key)) = entries.uses.pop_first() else {
+                break;
+            };
+
+            if let Some((block, _)) = entries.blocks.remove(&key) {
+                entries.size -= block.len();
+            }
+        }
+    }
+
+    /// Drops every block of the file
+    fn remove_file(&self, file: usize) {
+        self.entries.lock().unwrap().remove_range(file, 0, u64::MAX);
+    }
+}
+
+impl CacheEntries {
+    /// Drops the blocks of the file overlapping `start..end`
+    fn remove_range(&mut self, file: usize, start: u64, end: u64) {
+        // blocks don't overlap, so only the last one starting before `start` can reach into it
+        let before = self.blocks.range(..(file, start)).next_back()
+            .filter(|((block_file, offset), (block, _))| *block_file == file && offset + block.len() as u64 > start)
+            .map(|(&key, _)| key);
+
+        let inside: Vec<_> = self.blocks.range((file, start)..(file, end)).map(|(&key, _)| key).collect();
+
+        for key in before.into_iter().chain(inside) {
+            if let Some((block, last_use)) = self.blocks.remove(&key) {
+                self.uses.remove(&last_use);
+                self.size -= block.len();
+            }
+        }
+    }
+}
+
+/// Serves reads from a `PieceCache` when it holds the block, otherwise reads from `S`
+/// and caches the result. Written blocks are cached as well
+pub struct CachedStorage<S = FileStorage> {
+    storage: S,
+    cache: PieceCache,
+}
+
+impl<S: Storage> CachedStorage<S> {
+    pub const fn new(storage: S, cache: PieceCache) -> Self {
+        CachedStorage { storage, cache }
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    async fn read_block(&mut self, file: usize, offset: u64, buffer: &mut [u8]) -> Result<(), io::Error> {
+        if self.cache.get(file, offset, buffer) {
+            return Ok(());
+        }
+
+        self.storage.read_block(file, offset, buffer).await?;
+        self.cache.insert(file, offset, buffer);
+
+        Ok(())
+    }
+
+    async fn write_block(&mut self, file: usize, offset: u64, block: &[u8]) -> Result<(), io::Error> {
+        self.storage.write_block(file, offset, block).await?;
+        self.cache.insert(file, offset, block);
+
+        Ok(())
+    }
+
+    async fn set_len(&mut self, file: usize, length: u64) -> Result<(), io::Error> {
+        self.cache.remove_file(file);
+        self.storage.set_len(file, length).await
+    }
+
+    async fn flush(&mut self, file: usize) -> Result<(), io::Error> {
+        self.storage.flush(file).await
+    }
+
+    async fn complete(&mut self, file: usize) -> Result<(), io::Error> {
+        self.storage.complete(file).await
+    }
+}
+
+/// Keeps every file in memory, for tests. Clones share the same files, so one can be
+/// inspected while another is owned by a `PieceWriter`
+#[derive(Debug, Clone)]
+pub struct MemStorage {
+    files: Arc<Mutex<Vec<Option<Vec<u8>>>>>,
+}
+
+impl MemStorage {
+    /// Storage for `files` files, none of them stored yet
+    pub fn new(files: usize) -> Self {
+        MemStorage { files: Arc::new(Mutex::new(vec![None; files])) }
+    }
+
+    /// Contents of the file, `None` if nothing was written to it
+    pub fn file(&self, file: usize) -> Option<Vec<u8>> {
+        self.files.lock().unwrap()[file].clone()
+    }
+}
+
+impl Storage for MemStorage {
+    async fn read_block(&mut self, file: usize, offset: u64, buffer: &mut [u8]) -> Result<(), io::Error> {
+        let files = self.files.lock().unwrap();
+        let data = files[file].as_ref().ok_or(io::ErrorKind::NotFound)?;
+
+        let block = usize::try_from(offset).ok()
+            .and_then(|start| data.get(start..start.checked_add(buffer.len())?))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+        buffer.copy_from_slice(block);
+
+        Ok(())
+    }
+
+    async fn write_block(&mut self, file: usize, offset: u64, block: &[u8]) -> Result<(), io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let data = files[file].get_or_insert_with(Vec::new);
+
+        let start = offset as usize;
+        let end = start + block.len();
+
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+
+        data[start..end].copy_from_slice(block);
+
+        Ok(())
+    }
+
+    async fn set_len(&mut self, file: usize, length: u64) -> Result<(), io::Error> {
+        self.files.lock().unwrap()[file].get_or_insert_with(Vec::new).resize(length as usize, 0);
+
+        Ok(())
+    }
+
+    async fn flush(&mut self, _file: usize) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storage::{CachedStorage, MemStorage, PieceCache, Storage};
+
+    #[tokio::test]
+    async fn mem_storage() {
+        let mut storage = MemStorage::new(2);
+        let mut buffer = [0; 4];
+
+        let missing = storage.read_block(0, 0, &mut buffer).await.unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+
+        // writing past the end fills the gap with zeros
+        storage.clone().write_block(0, 2, &[1, 2, 3]).await.unwrap();
+        assert_eq!(storage.file(0).unwrap(), [0, 0, 1, 2, 3]);
+
+        storage.read_block(0, 1, &mut buffer).await.unwrap();
+        assert_eq!(buffer, [0, 1, 2, 3]);
+
+        let short = storage.read_block(0, 2, &mut buffer).await.unwrap_err();
+        assert_eq!(short.kind(), io::ErrorKind::UnexpectedEof);
+
+        storage.set_len(1, 3).await.unwrap();
+        assert_eq!(storage.file(1).unwrap(), [0; 3]);
+    }
+
+    #[tokio::test]
+    async fn cached_storage() {
+        let files = MemStorage::new(2);
+        let cache = PieceCache::new(8);
+        let mut storage = CachedStorage::new(files.clone(), cache.clone());
+        let mut buffer = [0; 2];
+
+        storage.write_block(0, 0, &[1, 2, 3, 4]).await.unwrap();
+        files.clone().write_block(0, 0, &[0; 4]).await.unwrap();
+
+        // served from the cache, the files underneath changed behind its back
+        storage.read_block(0, 1, &mut buffer).await.unwrap();
+        assert_eq!(buffer, [2, 3]);
+
+        // a write over part of a block replaces all of it
+        storage.write_block(0, 3, &[9]).await.unwrap();
+        storage.read_block(0, 2, &mut buffer).await.unwrap();
+        assert_eq!(buffer, [0, 9]);
+
+        // reads are cached too
+        files.clone().write_block(1, 0, &[5, 6]).await.unwrap();
+        storage.read_block(1, 0, &mut buffer).await.unwrap();
+        assert_eq!(cache.size(), 4);
+
+        storage.write_block(0, 4, &[7; 4]).await.unwrap();
+        storage.read_block(1, 0, &mut buffer).await.unwrap();
+
+        // the block at 2 of file 0 was the least recently used, evicted to fit another
+        storage.write_block(0, 8, &[8; 2]).await.unwrap();
+        files.clone().write_block(0, 3, &[0]).await.unwrap();
+        storage.read_block(0, 3, &mut buffer[..1]).await.unwrap();
+        assert_eq!(buffer[0], 0);
+
+        // the rest stayed
+        files.clone().write_block(1, 0, &[0; 2]).await.unwrap();
+        storage.read_block(1, 0, &mut buffer).await.unwrap();
+        assert_eq!(buffer, [5, 6]);
+        assert!(cache.size() <= 8);
+
+        // nothing is cached without room, and truncating drops the blocks of the file
+        let empty = PieceCache::new(0);
+        CachedStorage::new(files.clone(), empty.clone()).write_block(0, 0, &[1]).await.unwrap();
+        assert_eq!(empty.size(), 0);
+
+        storage.set_len(1, 0).await.unwrap();
+        assert!(storage.read_block(1, 0, &mut buffer).await.is_err());
+    }
+}
@@ -1,44 +1,136 @@
-use std::net::SocketAddr;
-use std::collections::HashSet;
-use std::io::{stdout, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::future;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use bit_vec::BitVec;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, Mutex, mpsc, broadcast, oneshot, watch};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
 use url::Url;
 
-use crate::metainfo::{self, MetaInfo, FileMode};
-use crate::tracker::{Tracker, self, TrackerRequest, Peers};
-use crate::peer::{Peer, self, Message, WriteMessage};
-
-static BLOCK_SIZE: u32 = 16384;
+use crate::metainfo::{self, MetaInfo};
+use crate::tracker::{Tracker, self, Event, TrackerRequest, TrackerResponse};
+use crate::http_seed::{self, HttpSeed};
+use crate::config::ClientConfig;
+use crate::peer::{Peer, self, Message, PeerCommand, WriteMessage};
+use crate::progress::{self, ProgressEvent, FileProgress};
+use crate::file::{self, PieceReader, PieceWriter};
+use crate::storage::{CachedStorage, FileStorage, PieceCache};
+use crate::lsd;
+use crate::peer_id::PeerId;
+use crate::info_hash::InfoHash;
+use crate::interface;
+use crate::proxy::{self, ProxyConfig};
+use crate::scheduler::{RequestScheduler, SchedulingPeer};
+use crate::seed::SuperSeed;
+use crate::state::{DownloadState, PieceStatus};
+use crate::stats::{self, PeerInfo, RateMeter, TorrentStats};
+use crate::error;
+
+pub(crate) static BLOCK_SIZE: u32 = 16384;
+
+/// Wait before announcing again to a tracker that couldn't be reached
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(30);
+
+/// Commands queued for a peer task before the torrent waits for it
+const PEER_COMMANDS: usize = 32;
+
+/// How often `ProgressEvent::Stats` is sent while downloading
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Port announced to trackers and LAN peers
+const LISTEN_PORT: u16 = 6881;
+
+/// Peers drop connections that stay silent for about two minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Blocks we didn't request that a peer can send before being disconnected, a few
+/// arrive legitimately after a cancel or a choke
+const MAX_UNREQUESTED_BLOCKS: u32 = 8;
+
+/// Wait before an http seed with nothing to download looks again, pieces become missing
+/// again when peers drop them or they fail verification
+const HTTP_SEED_IDLE: Duration = Duration::from_secs(5);
+
+/// Longest block uploaded for a request, bigger ones are ignored like most clients do
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
 
 #[derive(Debug)]
 pub enum Error {
     MetaInfoError(metainfo::Error),
     TrackerError(tracker::Error),
     PeerError(peer::Error),
+    IoError(io::Error),
+    Unsupported(String),
+    InvalidTorrent(String),
+    /// A setting of `ClientConfig` can't be used
+    InvalidConfig(String),
+    /// `reannounce` was called while the torrent isn't downloading or is paused
+    NotAnnouncing,
+    /// `reannounce` came before the `min interval` of every tracker, which ends after the duration
+    RateLimited(Duration),
+    /// The files that are left need `needed` bytes but the disk only has `available`
+    InsufficientSpace { needed: u64, available: u64 },
+    /// The torrent has no piece with this index
+    PieceOutOfRange(u32),
+    /// The piece isn't downloaded and verified yet
+    PieceIncomplete(u32),
+    /// `read_range` was given bytes past the end of the torrent
+    RangeOutOfBounds { start: u64, length: usize },
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::MetaInfoError(err) => write!(f, "{}", err),
-            Self::TrackerError(err) => write!(f, "{}", err),
-            Self::PeerError(err) => write!(f, "{}", err),
+            Self::MetaInfoError(_) => write!(f, "Invalid metainfo"),
+            Self::TrackerError(_) => write!(f, "Tracker error"),
+            Self::PeerError(_) => write!(f, "Peer error"),
+            Self::IoError(_) => write!(f, "Could not access the torrent files"),
+            Self::Unsupported(feature) => write!(f, "Unsupported: {}", feature),
+            Self::InvalidTorrent(reason) => write!(f, "Invalid torrent: {}", reason),
+            Self::InvalidConfig(reason) => write!(f, "Invalid config: {}", reason),
+            Self::NotAnnouncing => write!(f, "The torrent is not announcing to trackers"),
+            Self::RateLimited(wait) => write!(f, "Announced too recently, try again in {}s", wait.as_secs() + 1),
+            Self::InsufficientSpace { needed, available } => {
+                write!(f, "Not enough disk space, {} bytes are needed but only {} are available", needed, available)
+            }
+            Self::PieceOutOfRange(index) => write!(f, "The torrent has no piece {}", index),
+            Self::PieceIncomplete(index) => write!(f, "Piece {} is not downloaded yet", index),
+            Self::RangeOutOfBounds { start, length } => write!(f, "The range of {} bytes at {} is past the end of the torrent", length, start),
         }
     }
 }
 
-impl std::error::Error for Error { }
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::MetaInfoError(err) => Some(err),
+            Self::TrackerError(err) => Some(err),
+            Self::PeerError(err) => Some(err),
+            Self::IoError(err) => Some(err),
+            Self::Unsupported(_) | Self::InvalidTorrent(_) | Self::InvalidConfig(_) | Self::NotAnnouncing | Self::RateLimited(_) => None,
+            Self::InsufficientSpace { .. } | Self::PieceOutOfRange(_) | Self::PieceIncomplete(_) => None,
+            Self::RangeOutOfBounds { .. } => None,
+        }
+    }
+}
 
 impl From<metainfo::Error> for Error {
     fn from(value: metainfo::Error) -> Self {
-        Self::MetaInfoError(value)
+        // there is no metainfo to blame when the input isn't a torrent file
+        match value {
+            metainfo::Error::Unsupported(feature) => Self::Unsupported(feature),
+            metainfo::Error::InvalidInput(input) => Self::InvalidTorrent(format!("{} is not a torrent file or magnet link", input)),
+            value => Self::MetaInfoError(value),
+        }
     }
 }
 
@@ -54,398 +146,3329 @@ impl From<peer::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+
+/// Fails if the needed files don't fit on the disk, counting what is already stored of them.
+/// Every file is assumed to be on the disk of the first one
+async fn check_space(info: &metainfo::Info, paths: &[PathBuf], needed: &BitVec) -> Result<(), Error> {
+    let mut needed_bytes = 0;
+
+    for ((path, length), needed) in paths.iter().zip(info.file_lengths()).zip(needed) {
+        if !needed {
+            continue;
+        }
+
+        let stored = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        needed_bytes += length.saturating_sub(stored);
+    }
+
+    let Some(path) = paths.first() else {
+        return Ok(());
+    };
+
+    match file::available_space(path).await? {
+        Some(available) if available < needed_bytes => Err(Error::InsufficientSpace { needed: needed_bytes, available }),
+        _ => Ok(()),
+    }
+}
+
+/// Indices of the `slots` highest rates, the peers uploaded to by `Torrent::rechoke`
+fn upload_order(rates: impl IntoIterator<Item = f64>, slots: usize) -> Vec<usize> {
+    let mut order: Vec<(usize, f64)> = rates.into_iter().enumerate().collect();
+    order.sort_by(|(_, first), (_, second)| second.total_cmp(first));
+
+    order.into_iter().take(slots).map(|(index, _)| index).collect()
+}
+
+/// Whether every file is at its final path with its whole length, left by an earlier download
+/// or another client. Only then rechecking the files can find the torrent complete
+async fn is_stored_whole(info: &metainfo::Info, final_paths: &[PathBuf], paths: &[PathBuf]) -> Result<bool, Error> {
+    for ((path, final_path), length) in paths.iter().zip(final_paths).zip(info.file_lengths()) {
+        // empty files are created with the others
+        if length == 0 {
+            continue;
+        }
+
+        if path != final_path {
+            return Ok(false);
+        }
+
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.len() == length => (),
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether `address` is the listener of this client on this machine
+fn is_own_address(address: SocketAddr, bind: Option<IpAddr>) -> bool {
+    address.port() == LISTEN_PORT && (address.ip().is_loopback() || address.ip().is_unspecified() || Some(address.ip()) == bind)
+}
+
 struct DownloadingPiece {
     piece: Option<u32>,
     offset: u32,
-    available_pieces: Arc<RwLock<HashSet<u32>>>,
-    file_bitfield: Arc<RwLock<BitVec>>,
+    address: SocketAddr,
+    state: Arc<RwLock<DownloadState>>,
 }
 
 impl DownloadingPiece {
-    pub fn new(available_pieces: Arc<RwLock<HashSet<u32>>>, file_bitfield: Arc<RwLock<BitVec>>) -> Self {
-        Self { piece: None, offset: 0, available_pieces, file_bitfield }
+    pub fn new(address: SocketAddr, state: Arc<RwLock<DownloadState>>) -> Self {
+        Self { piece: None, offset: 0, address, state }
+    }
+
+    /// Makes the piece available to other peers again, unlike dropping
+    /// this is done by the time it returns
+    pub async fn release(&mut self) {
+        if let Some(piece) = self.piece.take() {
+            // the blocks before offset are already on disk, the next peer continues from there
+            self.state.write().await.release(piece, self.offset);
+        }
+    }
+
+    /// Starts downloading `piece`, resuming after the blocks a previous peer already got
+    pub async fn start(&mut self, piece: u32) {
+        self.piece = Some(piece);
+        self.offset = self.state.read().await.partial_offset(piece).unwrap_or(0);
     }
 }
 
 impl Drop for DownloadingPiece {
     fn drop(&mut self) {
         if let Some(piece) = self.piece {
-            println!("dropping {}", piece);
+            let state = Arc::clone(&self.state);
+            let offset = self.offset;
+
+            tokio::spawn(async move {
+                state.write().await.release(piece, offset);
+            });
+        }
+    }
+}
+
+/// Pieces of one peer counted in the availability of `DownloadState`, so exactly
+/// those are uncounted again when the peer disconnects
+struct PeerAvailability {
+    counted: BitVec,
+    state: Arc<RwLock<DownloadState>>,
+}
+
+impl PeerAvailability {
+    pub fn new(state: Arc<RwLock<DownloadState>>) -> Self {
+        Self { counted: BitVec::new(), state }
+    }
+
+    /// Counts a piece announced with `Have`, pieces past the end of the torrent are ignored
+    pub async fn add(&mut self, piece: u32) {
+        let index = piece as usize;
+
+        if self.counted.get(index) == Some(true) {
+            return;
+        }
+
+        let mut state = self.state.write().await;
+
+        if index >= state.len() {
+            return;
+        }
+
+        if index >= self.counted.len() {
+            self.counted.grow(index + 1 - self.counted.len(), false);
+        }
+
+        self.counted.set(index, true);
+        state.add_available(piece);
+    }
+
+    /// Makes the counted pieces match the whole bitfield of the peer
+    pub async fn update(&mut self, bitfield: &BitVec) {
+        let mut state = self.state.write().await;
+
+        if bitfield.len() > self.counted.len() {
+            self.counted.grow(bitfield.len() - self.counted.len(), false);
+        }
 
-            let available_pieces = Arc::clone(&self.available_pieces);
-            let file_bitfield = Arc::clone(&self.file_bitfield);
+        for index in 0..self.counted.len() {
+            let has = bitfield.get(index) == Some(true);
+
+            if has != self.counted[index] {
+                if has {
+                    state.add_available(index as u32);
+                } else {
+                    state.remove_available(index as u32);
+                }
+
+                self.counted.set(index, has);
+            }
+        }
+    }
+
+    /// Uncounts every piece, unlike dropping this is done by the time it returns
+    pub async fn clear(&mut self) {
+        self.update(&BitVec::new()).await;
+    }
+}
+
+impl Drop for PeerAvailability {
+    fn drop(&mut self) {
+        if self.counted.any() {
+            let counted = std::mem::take(&mut self.counted);
+            let state = Arc::clone(&self.state);
 
             tokio::spawn(async move {
-                if file_bitfield.read().await.get(piece as usize).is_none() {
-                    available_pieces.write().await.insert(piece);
+                let mut state = state.write().await;
+
+                for index in (0..counted.len()).filter(|&index| counted[index]) {
+                    state.remove_available(index as u32);
                 }
             });
-            
         }
     }
 }
 
+/// What a peer task shares about its connection, copied by `Torrent::peers`
+struct PeerStatus {
+    is_choking: bool,
+    am_interested: bool,
+    am_choking: bool,
+    is_interested: bool,
+    pieces: usize,
+    downloaded: RateMeter,
+    uploaded: RateMeter,
+    /// Known once the peer answered our handshake
+    peer_id: Option<PeerId>,
+}
+
+impl PeerStatus {
+    pub fn new() -> Self {
+        PeerStatus {
+            is_choking: true,
+            am_interested: false,
+            am_choking: true,
+            is_interested: false,
+            pieces: 0,
+            downloaded: RateMeter::new(),
+            uploaded: RateMeter::new(),
+            peer_id: None,
+        }
+    }
+
+    pub fn update(&mut self, peer: &Peer<'_>) {
+        self.is_choking = peer.is_choking();
+        self.am_interested = peer.am_interested();
+        self.am_choking = peer.am_choking();
+        self.is_interested = peer.is_interested();
+        self.pieces = peer.piece_count();
+    }
+}
+
+/// A running peer task, from the moment it's spawned until it ends
+struct ConnectedPeer {
+    commands: mpsc::Sender<PeerCommand>,
+    status: Arc<RwLock<PeerStatus>>,
+}
+
+/// When a tracker is announced to next. Regular announces, forced ones included, never
+/// come sooner than its `min interval` after the last one, only events are sent any time
+struct TrackerSchedule {
+    started: bool,
+    next: Instant,
+    earliest: Instant,
+    min_interval: Duration,
+}
+
+impl TrackerSchedule {
+    pub fn new(now: Instant) -> Self {
+        TrackerSchedule { started: false, next: now, earliest: now, min_interval: Duration::ZERO }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next <= now
+    }
+
+    /// `started` until the tracker answers once
+    pub fn event(&self) -> Option<Event> {
+        (!self.started).then_some(Event::Started)
+    }
+
+    pub fn answered(&mut self, now: Instant, response: &TrackerResponse) {
+        self.started = true;
+        self.min_interval = Duration::from_secs(response.min_interval().unwrap_or(0) as u64);
+        self.earliest = now + self.min_interval;
+        self.next = now + Duration::from_secs(response.interval() as u64).max(self.min_interval);
+    }
+
+    pub fn failed(&mut self, now: Instant) {
+        self.next = self.earliest.max(now + ANNOUNCE_RETRY);
+    }
+
+    /// The tracker was just sent `started` by `Torrent::resume`
+    pub fn resumed(&mut self, now: Instant) {
+        self.started = true;
+        self.earliest = now + self.min_interval;
+        self.next = self.earliest;
+    }
+
+    /// Whether a forced announce is allowed, or how long until it is
+    pub fn can_force(&self, now: Instant) -> Result<(), Duration> {
+        match self.earliest.checked_duration_since(now) {
+            Some(wait) if !wait.is_zero() => Err(wait),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn force(&mut self, now: Instant) {
+        if self.can_force(now).is_ok() {
+            self.next = now;
+        }
+    }
+}
+
+/// Seeders and leechers a tracker counts, when it sent them
+type SwarmSize = (Option<u32>, Option<u32>);
+
+/// Where the download loop sends the number of peers a `Torrent::reannounce` returned
+type ReannounceReply = oneshot::Sender<Result<usize, Error>>;
+
+/// State every peer task needs, cloned into each of them
+#[derive(Clone)]
+struct PeerContext {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    piece_length: u32,
+    last_piece_length: u32,
+    state: Arc<RwLock<DownloadState>>,
+    sender: mpsc::Sender<WriteMessage>,
+    reader: Arc<Mutex<PieceReader<CachedStorage>>>,
+    downloaded: Arc<RwLock<RateMeter>>,
+    uploaded: Arc<RwLock<RateMeter>>,
+    total_downloaded: Arc<AtomicU64>,
+    total_uploaded: Arc<AtomicU64>,
+    /// Peers with nothing we need stay connected to upload to them
+    seed: bool,
+    retries: u32,
+    retry_backoff: Duration,
+    read_buffer: usize,
+    handshake_timeout: Duration,
+    /// Where connections to peers come from, any interface when `None`
+    bind_address: Option<IpAddr>,
+    proxy: Option<ProxyConfig>,
+    scheduler: &'static dyn RequestScheduler,
+    /// Offers of every peer, only used when the torrent was complete as the peer connected
+    super_seed: Option<Arc<Mutex<SuperSeed>>>,
+    /// True while the torrent is paused, peers are neither downloaded from nor uploaded to
+    paused: watch::Receiver<bool>,
+    #[cfg(debug_assertions)]
+    disabled_messages: Arc<[u8]>,
+}
+
+impl PeerContext {
+    fn downloading_piece(&self, address: SocketAddr) -> DownloadingPiece {
+        DownloadingPiece::new(address, Arc::clone(&self.state))
+    }
+
+    fn peer_availability(&self) -> PeerAvailability {
+        PeerAvailability::new(Arc::clone(&self.state))
+    }
+}
+
 pub struct Torrent {
-    peer_id: [u8; 20],
-    metainfo: MetaInfo,
-    connected_peers: Arc<RwLock<HashSet<SocketAddr>>>,
-    file_bitfield: Arc<RwLock<BitVec>>,
-    available_pieces: Arc<RwLock<HashSet<u32>>>,
+    config: ClientConfig,
+    peer_id: PeerId,
+    metainfo: Arc<MetaInfo>,
+    /// Commands for every peer task that is running
+    connected_peers: Arc<RwLock<HashMap<SocketAddr, ConnectedPeer>>>,
+    /// Which pieces are missing, being downloaded and complete
+    state: Arc<RwLock<DownloadState>>,
+    events: broadcast::Sender<ProgressEvent>,
+    paused: watch::Sender<bool>,
+    /// Peers given with `add_peer`
+    added_peers: watch::Sender<HashSet<SocketAddr>>,
+    /// Requests of `reannounce`, the receiver is locked by `download` while it runs
+    reannounce: mpsc::Sender<ReannounceReply>,
+    reannounces: Mutex<mpsc::Receiver<ReannounceReply>>,
+    /// Last `tracker id` of each tracker url, sent back in every announce after it
+    tracker_ids: RwLock<HashMap<String, String>>,
+    /// Seeders and leechers in the last answer of each tracker url
+    swarm: RwLock<HashMap<String, SwarmSize>>,
+    /// Files chosen with `set_wanted_files`, all of them by default
+    wanted_files: RwLock<BitVec>,
+    downloaded: Arc<RwLock<RateMeter>>,
+    uploaded: Arc<RwLock<RateMeter>>,
+    /// Bytes of accepted blocks and of blocks sent this session, reported to trackers
+    total_downloaded: Arc<AtomicU64>,
+    total_uploaded: Arc<AtomicU64>,
+    /// Shared by the writer and every reader of the files, see `ClientConfig::piece_cache_size`
+    cache: PieceCache,
 }
 
 impl Torrent {
     /// Creates a new torrent and connects to the first tracker given by the metainfo
-    pub async fn new(torrent: &str) -> Result<Torrent, Error> {
-        let metainfo = MetaInfo::try_from(torrent)?;
+    pub async fn new(torrent: &str, config: ClientConfig) -> Result<Torrent, Error> {
+        if config.upload_slots == 0 {
+            return Err(Error::InvalidConfig(String::from("at least one upload slot is needed")));
+        }
 
-        // todo move this into download function
-        // calculate how many bytes the torrent needs to download
-        // TODO: increase limit (around 3GB right now)
-        let _length = match metainfo.info().mode() {
-            FileMode::SingleFile { length, .. } => {
+        if config.choke_interval.is_zero() {
+            return Err(Error::InvalidConfig(String::from("the choke interval can't be zero")));
+        }
 
-                *length as u128
-            }
-            FileMode::MultipleFiles { files } => {
-                let mut length = 0u128; // about 3GB max
+        let path = metainfo::torrent_file_path(torrent)?;
 
-                for file in files {
-                    length += file.lenght() as u128;
-                }
+        let metainfo = MetaInfo::from_file_async(&path, config.max_piece_length).await?;
 
-                length
-            }
-        };
+        let info = metainfo.info();
+
+        // without padding files the pieces of a multi-file v2 torrent don't line up with
+        // the files stored back to back
+        if info.is_pure_v2() && !info.is_single_file() {
+            return Err(Error::Unsupported(String::from("v2 torrents with multiple files")));
+        }
 
-        let peer_id_str = "-aa-aaaaaaaaaaaaaaaa".as_bytes();
-        let mut peer_id = [0u8; 20];
-        for (i, char) in peer_id_str.iter().enumerate() {
-            peer_id[i] = *char;
+        if info.piece_count() == 0 {
+            return Err(Error::Unsupported(String::from("torrents without pieces")));
         }
 
-        let file_bitfield = Arc::new(RwLock::new(BitVec::from_elem(metainfo.info().pieces().len(), false)));
+        let peer_id = PeerId::generate();
 
-        let mut available_pieces = HashSet::new();
+        let state = DownloadState::new(info.piece_count());
 
-        for i in 0..(metainfo.info().pieces().len() as u32) {
-            available_pieces.insert(i);
-        }
+        let (events, _) = broadcast::channel(1024);
+        let (reannounce, reannounces) = mpsc::channel(PEER_COMMANDS);
 
         Ok(Torrent {
+            cache: PieceCache::new(config.piece_cache_size),
+            config,
             peer_id,
-            metainfo,
-            connected_peers: Arc::new(RwLock::new(HashSet::new())),
-            file_bitfield,
-            available_pieces: Arc::new(RwLock::new(available_pieces)),
+            connected_peers: Arc::new(RwLock::new(HashMap::new())),
+            state: Arc::new(RwLock::new(state)),
+            events,
+            paused: watch::channel(false).0,
+            added_peers: watch::channel(HashSet::new()).0,
+            reannounce,
+            reannounces: Mutex::new(reannounces),
+            tracker_ids: RwLock::new(HashMap::new()),
+            swarm: RwLock::new(HashMap::new()),
+            wanted_files: RwLock::new(BitVec::from_elem(metainfo.info().file_count(), true)),
+            downloaded: Arc::new(RwLock::new(RateMeter::new())),
+            uploaded: Arc::new(RwLock::new(RateMeter::new())),
+            total_downloaded: Arc::new(AtomicU64::new(0)),
+            total_uploaded: Arc::new(AtomicU64::new(0)),
+            metainfo: Arc::new(metainfo),
         })
     }
 
-    pub async fn download(&mut self) {
-        let mut file_len = 0;
+    /// Downloads until every wanted piece is complete, seeding after that if configured.
+    /// Storing a block failing disconnects every peer and returns the error
+    pub async fn download(&self) -> Result<(), Error> {
+        let (sender, reciever) = mpsc::channel::<WriteMessage>(1000);
 
-        if let FileMode::SingleFile { length, .. } = self.metainfo.info().mode() {
-            println!("file len: {}", length);
-            file_len = *length;
-        }
+        // the files of a multi-file torrent are one stream of pieces, only the very last one is short
+        let last_piece = (self.metainfo.info().piece_count() as u32).saturating_sub(1);
+        let last_piece_length = progress::piece_size(self.metainfo.info(), last_piece) as u32;
 
-        let request = TrackerRequest::new(
-            *self.metainfo.info_hash(),
-            self.peer_id,
-            6881,
-            0,
-            0,
-            file_len.into(),
-            true,
-            false
-        );
+        let paths = self.stored_paths().await?;
 
-        let url = Url::parse(self.metainfo.announce()).unwrap();
-        let tracker_address = url.socket_addrs(|| None).unwrap()[0];
-        let mut tracker_stream = TcpStream::connect(tracker_address).await.unwrap();
+        // the download loop sees the torrent complete from the start, it never announces
+        // `completed` and either returns right away or goes on seeding
+        if !self.state.read().await.bitfield().any() && is_stored_whole(self.metainfo.info(), &self.file_paths(), &paths).await? {
+            let valid = file::recheck_files(&self.metainfo, &paths, &self.events).await?;
 
-        let mut tracker = Tracker::new(&mut tracker_stream, &url, &request).await.unwrap();
+            if valid.all() {
+                let mut state = self.state.write().await;
+                (0..state.len() as u32).for_each(|index| state.complete(index));
 
-        let (sender, mut reciever) = mpsc::channel::<WriteMessage>(1000);
+                let _ = self.events.send(ProgressEvent::AlreadyComplete);
+            }
+        }
 
-        println!("pieces: {}, piece length: {}", self.metainfo.info().pieces().len(), self.metainfo.info().piece_length());
-        
+        let needed = self.needed_files().await;
 
-        let num_of_pieces = self.metainfo.info().pieces().len();
+        if self.config.check_disk_space {
+            check_space(self.metainfo.info(), &paths, &needed).await?;
+        }
 
-        let last_piece_length = get_last_piece_length(file_len as usize, self.metainfo.info().pieces().len(), self.metainfo.info().piece_length() as usize);
+        let mut storage = FileStorage::with_stored_paths(self.file_paths(), paths.clone());
+        storage.create(&needed).await?;
 
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .open(self.metainfo.info().name())
-            .await
-            .unwrap();
+        let mut writer = PieceWriter::new(
+            Arc::clone(&self.metainfo),
+            CachedStorage::new(storage, self.cache.clone()),
+            Arc::clone(&self.state),
+            broadcast::Sender::clone(&self.events),
+        ).await;
 
-        let bitfield = Arc::clone(&self.file_bitfield);
+        writer.set_sync_pieces(self.config.sync_pieces);
+        writer.set_ban_limits(self.config.max_bad_pieces, self.config.max_piece_failures);
 
-        let piece_length = self.metainfo.info().piece_length();
+        let mut writing = tokio::spawn(writer.run(reciever));
 
-        tokio::spawn(async move {
-            let block_num = (piece_length + BLOCK_SIZE - 1) / BLOCK_SIZE; // rounds up
-            let last_block_num = (last_piece_length + BLOCK_SIZE - 1) / BLOCK_SIZE; // rounds up
+        // a file renamed by the writer is found at its final path
+        let reader = PieceReader::new(
+            Arc::clone(&self.metainfo),
+            CachedStorage::new(FileStorage::with_stored_paths(self.file_paths(), paths), self.cache.clone()),
+        );
 
-            let mut received_blocks = vec![BitVec::from_elem(block_num as usize, false); num_of_pieces - 1];
-            received_blocks.push(BitVec::from_elem(last_block_num as usize, false));
+        let context = PeerContext {
+            info_hash: *self.info_hash(),
+            peer_id: self.peer_id,
+            piece_length: self.metainfo.info().piece_length(),
+            last_piece_length,
+            state: Arc::clone(&self.state),
+            sender,
+            reader: Arc::new(Mutex::new(reader)),
+            downloaded: Arc::clone(&self.downloaded),
+            uploaded: Arc::clone(&self.uploaded),
+            total_downloaded: Arc::clone(&self.total_downloaded),
+            total_uploaded: Arc::clone(&self.total_uploaded),
+            seed: self.config.seed_after_complete,
+            retries: self.config.peer_retries,
+            retry_backoff: self.config.peer_retry_backoff,
+            read_buffer: self.config.peer_read_buffer,
+            handshake_timeout: self.config.handshake_timeout,
+            bind_address: self.config.bind_address,
+            proxy: self.config.proxy.clone(),
+            scheduler: self.config.scheduler,
+            super_seed: self.config.super_seed.then(|| Arc::new(Mutex::new(SuperSeed::new(self.metainfo.info().piece_count())))),
+            paused: self.paused.subscribe(),
+            #[cfg(debug_assertions)]
+            disabled_messages: Arc::from(self.config.disabled_messages.as_slice()),
+        };
 
-            let mut pieces = vec![Vec::new(); num_of_pieces];
+        let downloading = async {
+            let mut events = self.subscribe();
+            let mut paused = self.paused.subscribe();
+            let mut added_peers = self.added_peers.subscribe();
 
-            while let Some(write_message) = reciever.recv().await {
-                let piece_buffer = pieces.get_mut(write_message.index() as usize).unwrap();
+            let mut reannounces = self.reannounces.lock().await;
+            // answered with the peers of the next announce
+            let mut forced: Vec<ReannounceReply> = Vec::new();
 
-                // allocates needed size for slice copy
-                let begin = write_message.begin() as usize;
-                if piece_buffer.len() < begin + write_message.block().len() {
-                    piece_buffer.resize(begin + write_message.block().len(), 0);
-                }
-                piece_buffer[begin..begin + write_message.block().len()].copy_from_slice(write_message.block());
+            let trackers = self.trackers();
+            let mut schedules: Vec<TrackerSchedule> = trackers.iter().map(|_| TrackerSchedule::new(Instant::now())).collect();
 
-                let block_index = (write_message.begin() as u64 / BLOCK_SIZE as u64) as usize;
-                received_blocks.get_mut(write_message.index() as usize).unwrap().set(block_index, true);
+            // torrents complete from the start never announce `completed`
+            let mut completed = self.state.read().await.is_complete();
 
-                if received_blocks[write_message.index() as usize].all() {
-                    println!("piece {} completed", write_message.index());
-                    bitfield.write().await.set(write_message.index() as usize, true);
+            loop {
+                if self.state.read().await.is_complete() {
+                    if !completed {
+                        completed = true;
 
-                    // write to file
-                    let offset = write_message.index() as u64 * piece_length as u64;
+                        if let Err(err) = self.announce_event(Event::Completed).await {
+                            let _ = self.events.send(ProgressEvent::Warning(error::report(&err)));
+                        }
+                    }
 
-                    file.seek(std::io::SeekFrom::Start(offset)).await.unwrap();
-                    file.write_all(&pieces[write_message.index() as usize]).await.unwrap();
+                    if !self.config.seed_after_complete {
+                        break;
+                    }
                 }
-            }
-        });
 
-        'main: loop {
-            if self.file_bitfield.read().await.all() {
-                println!("Download finished");
-                break;
-            }
+                // peers were disconnected by pause, resume announces by itself
+                if *paused.borrow_and_update() {
+                    let _ = paused.wait_for(|paused| !paused).await;
+                    schedules.iter_mut().for_each(|schedule| schedule.resumed(Instant::now()));
+                    continue;
+                }
 
-            // todo handle errors
-            if let Err(_err) = tracker.announce().await {
-                continue;
-            }
+                // added peers don't depend on the tracker, the ones that disconnected are tried again every cycle
+                let added = added_peers.borrow_and_update().clone();
+                self.connect_peers(added, &context).await;
 
-            // handle each peer deparately in its own thread
-            match tracker.response().unwrap().peers() {
-                Peers::Binary(peers) => {
-                    for &addr in peers.iter() {
-                        if self.file_bitfield.read().await.all() {
-                            println!("Download finished");
-                            break 'main;
-                        }
+                let now = Instant::now();
+                let due: Vec<usize> = (0..trackers.len()).filter(|&tracker| schedules[tracker].is_due(now)).collect();
+                let announces = due.iter()
+                    .map(|&tracker| (trackers[tracker].clone(), schedules[tracker].event()))
+                    .collect();
 
-                        // skip if peer is already connected
-                        if self.connected_peers.read().await.contains(&addr) {
-                            continue;
-                        }
+                // trackers of the same torrent mostly know the same peers
+                let mut peers = HashSet::new();
 
-                        let connected_peers = Arc::clone(&self.connected_peers);
-                        let info_hash = *self.info_hash();
-                        let peer_id = self.peer_id;
-                        let piece_length = self.metainfo.info().piece_length();
-                        let file_bitfield = Arc::clone(&self.file_bitfield);
-                        let available_pieces = Arc::clone(&self.available_pieces);
-                        let sender = mpsc::Sender::clone(&sender);
-
-                        let connection = async move {
-                            match handle_peer(addr, info_hash, peer_id, piece_length, last_piece_length, file_bitfield, available_pieces, sender).await {
-                                Ok(()) => (),
-                                Err(Error::PeerError(peer::Error::IoError(_))) => (),
-                                Err(err) => {
-                                    let mut stdout = stdout().lock();
-                                    stdout.write_all(format!("{}\n", err).as_bytes()).unwrap();
-                                    stdout.flush().unwrap();
-                                },
-                            };
-
-                            connected_peers.write().await.remove(&addr);
-                        };
-
-                        self.connected_peers.write().await.insert(addr);
-                        tokio::spawn(connection);
+                for (&tracker, result) in due.iter().zip(self.announce(announces).await) {
+                    match result {
+                        Ok(response) => {
+                            schedules[tracker].answered(now, &response);
+                            peers.extend(response.peers().addresses());
+                        }
+                        Err(err) => {
+                            let _ = self.events.send(ProgressEvent::Warning(error::report(&err)));
+                            schedules[tracker].failed(now);
+                        }
                     }
-                },
-                Peers::Dictionary(_peers) => {
-                    todo!()
-                },
-            };
-        }
+                }
 
-        // send "completed" event to tracker
-    }
+                for reply in forced.drain(..) {
+                    let _ = reply.send(Ok(peers.len()));
+                }
 
-    pub const fn metainfo(&self) -> &MetaInfo {
-        &self.metainfo
-    }
+                self.connect_peers(peers, &context).await;
 
-    pub const fn info_hash(&self) -> &[u8; 20] {
-        self.metainfo.info_hash()
-    }
-}
+                let next = schedules.iter().map(|schedule| schedule.next).min().unwrap_or(now);
+                let wait = next.saturating_duration_since(Instant::now());
 
-async fn handle_peer(address: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20], piece_length: u32, last_piece_length: u32, file_bitfield: Arc<RwLock<BitVec>>, available_pieces: Arc<RwLock<HashSet<u32>>>, sender: mpsc::Sender<WriteMessage>) -> Result<(), Error> {
-    // connects and sends handshake
-    let pieces = available_pieces.read().await.len();
+                if let Some(reply) = self.wait_for_announce(wait, &context, &mut events, &mut paused, &mut added_peers, &mut reannounces).await {
+                    let now = Instant::now();
 
-    let mut stream = match TcpStream::connect(address).await {
-        Ok(stream) => stream,
-        Err(err) => return Err(peer::Error::IoError(err).into()),
-    };
+                    // trackers still in their min interval keep their schedule
+                    match schedules.iter().map(|schedule| schedule.can_force(now)).min() {
+                        Some(Err(wait)) => {
+                            let _ = reply.send(Err(Error::RateLimited(wait)));
+                        }
+                        _ => {
+                            schedules.iter_mut().for_each(|schedule| schedule.force(now));
+                            forced.push(reply);
+                        }
+                    }
+                }
+            }
+        };
 
-    let mut peer = Peer::new(&mut stream, pieces).await?;
+        // discovery never finishes, errors only leave the download to the tracker and added peers
+        let discovery = async {
+            if self.config.local_peer_discovery && self.metainfo.info().private() != &Some(true) {
+                if let Err(err) = lsd::discover(*self.info_hash(), LISTEN_PORT, |address| self.add_peer(address)).await {
+                    let _ = self.events.send(ProgressEvent::Warning(format!("Local peer discovery stopped: {}", err)));
+                }
+            }
 
-    let mut downloading_piece = DownloadingPiece::new(Arc::clone(&available_pieces), Arc::clone(&file_bitfield));
+            future::pending::<()>().await
+        };
 
-    let _peer_handshake = peer.handshake(info_hash, peer_id).await?;
+        let reporting = async {
+            let mut interval = time::interval(STATS_INTERVAL);
 
-    loop {
-        // possibly makes all slow when not handling stuck peers
-        let message = peer.read_message().await?;
-        // println!("piece: {:?}, offset: {:?}, message: {}", downloading_piece.piece, downloading_piece.offset, message);
+            loop {
+                interval.tick().await;
 
-        match message {
-            Message::KeepAlive => {
-                // closes connection if peer has no piece the file needs
-                if is_there_next_piece(&peer, &available_pieces).await {
-                    return Ok(());
-                }
-            },
-            Message::Choke => {
-                peer.set_is_choking(true);
+                // sending only fails when nobody is subscribed
+                let _ = self.events.send(ProgressEvent::Stats(self.stats().await));
             }
-            Message::Unchoke => {
-                // redundant message
-                if !peer.is_choking() {
-                    continue;
+        };
+
+        // like discovery, failing to listen leaves only the peers we connect to
+        let accepting = async {
+            match interface::listen(self.config.bind_address, LISTEN_PORT).await {
+                // connections that arrive while paused are closed right away by `spawn_peer`
+                Ok(listener) => loop {
+                    match listener.accept().await {
+                        Ok((stream, address)) => self.spawn_peer(address, Some(stream), &context).await,
+                        Err(err) => {
+                            let _ = self.events.send(ProgressEvent::Warning(format!("Could not accept a peer: {}", err)));
+                        }
+                    }
+                },
+                Err(err) => {
+                    let _ = self.events.send(ProgressEvent::Warning(format!("Not accepting peers: {}", err)));
                 }
+            }
 
-                peer.set_is_choking(false);
+            future::pending::<()>().await
+        };
 
-                if downloading_piece.piece.is_none() {
-                    if let Some(next_piece) = get_next_piece(&peer, &available_pieces).await {
-                        downloading_piece.piece = Some(next_piece);
+        // BEP 17 seeds download next to the peers, each task is aborted with the set
+        let http_seeding = async {
+            let mut seeds = JoinSet::new();
 
-                        peer.send_request(next_piece, downloading_piece.offset, BLOCK_SIZE).await?;
-                    } else {
-                        // no more pieces needed
-                        return Ok(());
-                    };
-                } else {
-                    let remaining_piece_size = if downloading_piece.piece.unwrap() as usize == pieces - 1 {
-                        last_piece_length - downloading_piece.offset
-                    } else {
-                        piece_length - downloading_piece.offset
-                    };
+            for url in self.metainfo.http_seeds() {
+                match HttpSeed::new(url) {
+                    Ok(mut seed) => {
+                        seed.set_bind_address(self.config.bind_address);
+                        seed.set_proxy(self.config.proxy.clone());
+                        seed.set_dns_timeout(self.config.dns_timeout);
 
-                    // sends request for smaller block size if needed
-                    if remaining_piece_size < BLOCK_SIZE {
-                        peer.send_request(downloading_piece.piece.unwrap(), downloading_piece.offset, remaining_piece_size).await?;
-                    } else {
-                        peer.send_request(downloading_piece.piece.unwrap(), downloading_piece.offset, BLOCK_SIZE).await?;
+                        seeds.spawn(download_from_http_seed(seed, context.clone(), self.paused.subscribe(), self.events.clone()));
+                    }
+                    Err(err) => {
+                        let _ = self.events.send(ProgressEvent::Warning(format!("Skipping http seed {}: {}", url, error::report(&err))));
                     }
                 }
             }
-            Message::Interested => {
-                // todo
-            }
-            Message::NotInterested => (),
-            Message::Have(piece_index) => {
-                peer.update_piece(piece_index as usize);
 
-                if !peer.am_interested() && is_there_next_piece(&peer, &available_pieces).await {
-                    peer.send_interested().await?;
-                }
-            }
-            Message::Bitfield(bitfield) => {
-                peer.update_bitfield(bitfield);
+            while seeds.join_next().await.is_some() {}
 
-                if !peer.am_interested() && is_there_next_piece(&peer, &available_pieces).await {
-                    peer.send_interested().await?;
-                }
+            future::pending::<()>().await
+        };
+
+        // picks the peers uploaded to every `choke_interval`
+        let choking = async {
+            let mut interval = time::interval(self.config.choke_interval);
+
+            loop {
+                interval.tick().await;
+                self.rechoke().await;
             }
-            Message::Request { index, begin, length } => (), // peer.send_piece(index, begin, length)?,
-            Message::Piece { index, begin, block } => {
-                sender.send(WriteMessage::new(index, begin, &block)).await.unwrap();
+        };
 
-                downloading_piece.offset += block.len() as u32;
+        // ends the download at a seeding limit, announcing `stopped` like a pause does
+        let seeding = async {
+            if !self.config.seed_after_complete || (self.config.seed_ratio_limit.is_none() && self.config.seed_time_limit.is_none()) {
+                return future::pending().await;
+            }
 
-                let remaining_piece_size = if index as usize == pieces - 1 {
-                    last_piece_length - downloading_piece.offset
-                } else {
-                    piece_length - downloading_piece.offset
-                };
+            let mut interval = time::interval(STATS_INTERVAL);
+            let mut seeding_since = None;
 
-                if remaining_piece_size == 0 {
-                    // Reset the offset to zero for the next piece
-                    downloading_piece.offset = 0;
+            loop {
+                interval.tick().await;
 
-                    // Request the next piece
-                    if let Some(next_piece) = get_next_piece(&peer, &available_pieces).await {
-                        downloading_piece.piece = Some(next_piece);
-  
-                        peer.send_request(next_piece, 0, BLOCK_SIZE).await?;
-                    } else {
-                        // no more pieces needed
-                        return Ok(());
-                    };
+                if !self.state.read().await.is_complete() {
+                    continue;
                 }
 
-                // Check if the remaining size is less than the block size
-                else if remaining_piece_size < BLOCK_SIZE {
-                    // request a smaller block to finish the piece
-                    peer.send_request(downloading_piece.piece.unwrap(), downloading_piece.offset, remaining_piece_size).await?;
-                } else {
-                    // Otherwise, request the next block as usual
-                    peer.send_request(downloading_piece.piece.unwrap(), downloading_piece.offset, BLOCK_SIZE).await?;
+                let since = *seeding_since.get_or_insert_with(Instant::now);
+
+                if self.seed_limit_reached(since.elapsed()).await {
+                    break;
                 }
             }
-            Message::Cancel { index, begin, length } => (), // todo (cancels previouslly requested piece)
-            _ => (),
+
+            let _ = self.events.send(ProgressEvent::SeedingLimitReached);
+
+            if let Err(err) = self.announce_event(Event::Stopped).await {
+                let _ = self.events.send(ProgressEvent::Warning(error::report(&err)));
+            }
+        };
+
+        let mut result = Ok(());
+
+        tokio::select! {
+            () = downloading => (),
+            () = discovery => (),
+            () = reporting => (),
+            () = accepting => (),
+            () = seeding => (),
+            () = choking => (),
+            () = http_seeding => (),
+            // the writer only ends while the context holds a sender when storing a block failed
+            joined = &mut writing => result = joined.expect("writer task panicked").map_err(Error::from),
         }
+
+        self.shutdown_peers().await;
+
+        result
     }
-}
 
-/// removes piece from `available_pieces set` if found
-async fn get_next_piece(peer: &Peer<'_>, available_pieces: &RwLock<HashSet<u32>>) -> Option<u32> {
-    let mut available_pieces = available_pieces.write().await;
+    /// Disconnects every peer and announces `stopped`, the downloaded pieces are kept
+    /// and `resume` continues from them. Only has an effect while `download` runs
+    pub async fn pause(&self) -> Result<(), Error> {
+        if self.paused.send_replace(true) {
+            return Ok(());
+        }
 
-    for (piece, exists) in peer.bitfield().iter().enumerate() {
-        let piece = piece as u32;
-        if exists && available_pieces.get(&piece).is_some() {
-            if piece == 396 {
-                std::process::exit(0);
-            }
+        self.shutdown_peers().await;
+        self.announce_event(Event::Stopped).await
+    }
 
-            // Remove the piece from the available pieces and return it.
-            available_pieces.remove(&piece);
-            return Some(piece);
+    /// Announces `started` again and lets `download` connect to peers
+    pub async fn resume(&self) -> Result<(), Error> {
+        if !self.paused.send_replace(false) {
+            return Ok(());
         }
+
+        self.announce_event(Event::Started).await
     }
 
-    // If no pieces meet the above conditions, return None.
-    None
-}
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
 
-async fn is_there_next_piece(peer: &Peer<'_>, available_pieces: &RwLock<HashSet<u32>>) -> bool {
-    let available_pieces = available_pieces.read().await;
+    /// Connects to `address` besides the peers the tracker returns, again after every
+    /// announce if it disconnected. Peers can be added before or while `download` runs
+    pub fn add_peer(&self, address: SocketAddr) {
+        self.added_peers.send_if_modified(|peers| peers.insert(address));
+    }
 
-    for &piece in available_pieces.iter() {
-        if peer.bitfield().get(piece as usize).is_some() {
-            return true;
+    /// Announces to every tracker right away instead of waiting for their interval and returns
+    /// how many peers they gave. Fails with `Error::RateLimited` while every tracker is still
+    /// in its `min interval`, those that aren't are announced to and the others keep their schedule
+    pub async fn reannounce(&self) -> Result<usize, Error> {
+        // the receiver is only free when no download is running
+        if self.is_paused() || self.reannounces.try_lock().is_ok() {
+            return Err(Error::NotAnnouncing);
         }
+
+        let (reply, peers) = oneshot::channel();
+        self.reannounce.send(reply).await.map_err(|_| Error::NotAnnouncing)?;
+
+        // dropped when the download finishes first
+        peers.await.unwrap_or(Err(Error::NotAnnouncing))
     }
 
-    false
-}
+    /// Downloads only the files at the given indices of `Info::file_lengths`. Pieces shared
+    /// with a wanted file are still downloaded whole, so the bytes of the unwanted file in
+    /// them are written too, other unwanted files aren't created at all
+    pub async fn set_wanted_files(&self, files: &[usize]) {
+        let info = self.metainfo.info();
+
+        let mut wanted = BitVec::from_elem(info.file_count(), false);
+
+        for &file in files.iter().filter(|&&file| file < info.file_count()) {
+            wanted.set(file, true);
+        }
+
+        let mut state = self.state.write().await;
 
-fn get_last_piece_length(file_length: usize, pieces: usize, piece_length: usize) -> u32 {
-    let length_without_last_piece = piece_length * (pieces - 1);
-    (file_length - length_without_last_piece) as u32
-}
\ No newline at end of file
+        for index in 0..state.len() as u32 {
+            let begin = index as u64 * info.piece_length() as u64;
+            let files_in_piece = info.files_in_range(begin, progress::piece_size(info, index));
+
+            state.set_wanted(index, files_in_piece.iter().any(|&(file, _, _)| wanted[file]));
+        }
+
+        *self.wanted_files.write().await = wanted;
+    }
+
+    /// Wanted files and the ones sharing a wanted piece with them,
+    /// since those pieces are verified by reading them back
+    async fn needed_files(&self) -> BitVec {
+        let state = self.state.read().await;
+        let mut needed = self.wanted_files.read().await.clone();
+
+        for file in 0..needed.len() {
+            let pieces = self.metainfo.pieces_for_file(file).unwrap_or_default();
+
+            if pieces.into_iter().any(|index| state.status(index) != Some(PieceStatus::Skipped)) {
+                needed.set(file, true);
+            }
+        }
+
+        needed
+    }
+
+    /// Spawns a task for each address that isn't blocked, banned or connected already, stopping early
+    /// when the download is complete or paused
+    async fn connect_peers(&self, addresses: impl IntoIterator<Item = SocketAddr>, context: &PeerContext) {
+        for addr in addresses {
+            if (self.state.read().await.is_complete() && !self.config.seed_after_complete) || self.is_paused() {
+                return;
+            }
+
+            self.spawn_peer(addr, None, context).await;
+        }
+    }
+
+    /// Starts the task of a peer, connecting to it unless `stream` is a connection it
+    /// opened to us. Blocked, banned and already connected peers are skipped, and so are
+    /// peers on an ip with `max_connections_per_ip` connections already
+    async fn spawn_peer(&self, addr: SocketAddr, stream: Option<TcpStream>, context: &PeerContext) {
+        if self.config.blocklist.is_blocked(addr.ip()) || self.state.read().await.is_banned(addr.ip()) {
+            return;
+        }
+
+        if is_own_address(addr, self.config.bind_address) {
+            return;
+        }
+
+        // checked and inserted under the same lock, peers of one ip can arrive together
+        let mut connected = self.connected_peers.write().await;
+
+        // `pause` shuts down the connected peers after setting the flag, a peer inserted
+        // past this check is among them
+        if self.is_paused() {
+            return;
+        }
+
+        // skip if peer is already connected
+        if connected.contains_key(&addr) {
+            return;
+        }
+
+        if connected.keys().filter(|connected| connected.ip() == addr.ip()).count() >= self.config.max_connections_per_ip {
+            return;
+        }
+
+        let connected_peers = Arc::clone(&self.connected_peers);
+        let events = self.events.clone();
+        let context = PeerContext::clone(context);
+        let (commands, mut receiver) = mpsc::channel(PEER_COMMANDS);
+        let status = Arc::new(RwLock::new(PeerStatus::new()));
+        let peer = ConnectedPeer { commands, status: Arc::clone(&status) };
+
+        let connection = async move {
+            let result = match stream {
+                Some(stream) => handle_inbound_peer(addr, stream, &context, &mut receiver, &status).await,
+                None => handle_peer_with_retries(addr, &context, &mut receiver, &status).await,
+            };
+
+            match result {
+                Ok(()) => (),
+                Err(Error::PeerError(peer::Error::IoError(_))) => (),
+                Err(err) => {
+                    let _ = events.send(ProgressEvent::Warning(format!("Peer {} disconnected: {}", addr, error::report(&err))));
+                }
+            };
+
+            connected_peers.write().await.remove(&addr);
+        };
+
+        connected.insert(addr, peer);
+        drop(connected);
+
+        tokio::spawn(connection);
+    }
+
+    async fn shutdown_peers(&self) {
+        for peer in self.connected_peers.read().await.values() {
+            // fails only if the task already ended
+            let _ = peer.commands.send(PeerCommand::Shutdown).await;
+        }
+    }
+
+    /// The main tracker, followed by the rest of `announce-list` when `announce_to_all` is set
+    fn trackers(&self) -> Vec<String> {
+        let mut trackers = vec![self.metainfo.announce().clone()];
+
+        if self.config.announce_to_all {
+            for url in self.metainfo.announce_list().into_iter().flatten().flatten() {
+                if !trackers.contains(url) {
+                    trackers.push(url.clone());
+                }
+            }
+        }
+
+        trackers
+    }
+
+    /// Announces `event` to every tracker, fails only if none of them answered
+    async fn announce_event(&self, event: Event) -> Result<(), Error> {
+        let announces = self.trackers().into_iter().map(|url| (url, Some(event))).collect();
+        let results = self.announce(announces).await;
+
+        if results.iter().any(Result::is_ok) {
+            return Ok(());
+        }
+
+        results.into_iter().next().map_or(Ok(()), |result| result.map(|_| ()))
+    }
+
+    /// Announces to each tracker url with its event concurrently, the results are in the same order
+    async fn announce(&self, announces: Vec<(String, Option<Event>)>) -> Vec<Result<TrackerResponse, Error>> {
+        let downloaded: u64 = self.file_progress().await.iter().map(FileProgress::downloaded).sum();
+
+        let request = TrackerRequest::new(
+            *self.metainfo.info_hash(),
+            self.peer_id,
+            LISTEN_PORT,
+            self.total_uploaded().into(),
+            self.total_downloaded().into(),
+            (self.metainfo.info().total_length() - downloaded).into(),
+            true,
+            false
+        );
+
+        let mut tasks = JoinSet::new();
+        let tracker_ids = self.tracker_ids.read().await.clone();
+        let dns_timeout = self.config.dns_timeout;
+        let bind_address = self.config.bind_address;
+        let proxy = self.config.proxy.clone();
+
+        for (index, (url, event)) in announces.into_iter().enumerate() {
+            let mut request = request.clone();
+            request.set_event(event);
+            request.set_trackerid(tracker_ids.get(&url).cloned());
+            let proxy = proxy.clone();
+
+            tasks.spawn(async move { (index, announce_to(&url, &request, dns_timeout, bind_address, proxy.as_ref()).await.map(|response| (url, response))) });
+        }
+
+        let mut results: Vec<_> = (0..tasks.len()).map(|_| None).collect();
+
+        while let Some(joined) = tasks.join_next().await {
+            // announcing doesn't panic
+            let (index, result) = joined.expect("announce task panicked");
+
+            // trackers may send the id only once, the last one is kept until another replaces it
+            if let Ok((url, response)) = &result {
+                if let Some(id) = response.tracker_id() {
+                    self.tracker_ids.write().await.insert(url.clone(), id.clone());
+                }
+
+                self.swarm.write().await.insert(url.clone(), (response.complete(), response.incomplete()));
+                let _ = self.events.send(ProgressEvent::Announced {
+                    tracker: url.clone(),
+                    seeders: response.complete(),
+                    leechers: response.incomplete(),
+                });
+            }
+
+            results[index] = Some(result.map(|(_, response)| response));
+        }
+
+        results.into_iter().flatten().collect()
+    }
+
+    /// Sleeps until the next announce is due, waking up early when the download
+    /// finishes or the torrent is paused or resumed. Peers added meanwhile are connected right away
+    /// Returns the request that woke it up if it was `reannounce`
+    async fn wait_for_announce(
+        &self,
+        interval: Duration,
+        context: &PeerContext,
+        events: &mut broadcast::Receiver<ProgressEvent>,
+        paused: &mut watch::Receiver<bool>,
+        added_peers: &mut watch::Receiver<HashSet<SocketAddr>>,
+        reannounces: &mut mpsc::Receiver<ReannounceReply>,
+    ) -> Option<ReannounceReply> {
+        let sleep = time::sleep(interval);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return None,
+                _ = paused.changed() => return None,
+                // the sender lives in self too
+                reply = reannounces.recv() => return reply,
+                // the sender lives in self, so this can't fail
+                _ = added_peers.changed() => {
+                    let added = added_peers.borrow_and_update().clone();
+                    self.connect_peers(added, context).await;
+                }
+                // lagging behind only means some events were skipped, after the download
+                // completed no piece does anymore so seeding isn't woken up every second
+                event = events.recv() => if matches!(event, Ok(ProgressEvent::PieceCompleted(_)) | Err(RecvError::Lagged(_)))
+                    && self.state.read().await.is_complete()
+                {
+                    return None;
+                },
+            }
+        }
+    }
+
+    pub const fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn metainfo(&self) -> &MetaInfo {
+        &self.metainfo
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        self.metainfo.info_hash()
+    }
+
+    /// Receives the `ProgressEvent`s sent from the moment of subscribing
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.events.subscribe()
+    }
+
+    /// Hashes the pieces already stored on disk and returns the bitfield of the valid ones
+    /// without downloading anything. Missing or short files count as missing pieces
+    pub async fn recheck(&self) -> Result<BitVec, Error> {
+        Ok(file::recheck_files(&self.metainfo, &self.stored_paths().await?, &self.events).await?)
+    }
+
+    /// Bytes of the piece at `index`, read back from disk. Only complete pieces can be
+    /// read, which can be done while the download continues
+    pub async fn read_piece(&self, index: u32) -> Result<Vec<u8>, Error> {
+        match self.state.read().await.status(index) {
+            Some(PieceStatus::Complete) => (),
+            Some(_) => return Err(Error::PieceIncomplete(index)),
+            None => return Err(Error::PieceOutOfRange(index)),
+        }
+
+        let storage = FileStorage::with_stored_paths(self.file_paths(), self.stored_paths().await?);
+        let mut reader = PieceReader::new(Arc::clone(&self.metainfo), CachedStorage::new(storage, self.cache.clone()));
+
+        let length = progress::piece_size(self.metainfo.info(), index) as u32;
+
+        Ok(reader.read_block(index, 0, length).await?)
+    }
+
+    /// Reads `length` bytes at `start` of the torrent, as if its files were one. Missing
+    /// pieces of the range are downloaded before any other, and waited for as long as
+    /// `ClientConfig::read_range_wait` allows. Without it they fail right away, still
+    /// prioritized for the next try
+    pub async fn read_range(&self, start: u64, length: usize) -> Result<Vec<u8>, Error> {
+        let info = self.metainfo.info();
+
+        if start.checked_add(length as u64).is_none_or(|end| end > info.total_length()) {
+            return Err(Error::RangeOutOfBounds { start, length });
+        }
+
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        let piece_length = info.piece_length() as u64;
+        let pieces = (start / piece_length) as u32..=((start + length as u64 - 1) / piece_length) as u32;
+
+        // subscribed before checking, so no piece completes unnoticed in between
+        let mut events = self.subscribe();
+
+        let missing = |state: &DownloadState| pieces.clone().find(|&index| state.status(index) != Some(PieceStatus::Complete));
+
+        // bound first, the temporary guard of an `if let` lives until the end of its block
+        let first_missing = missing(&*self.state.read().await);
+
+        if let Some(index) = first_missing {
+            {
+                let mut state = self.state.write().await;
+                pieces.clone().for_each(|index| state.prioritize(index));
+            }
+
+            let Some(wait) = self.config.read_range_wait else {
+                return Err(Error::PieceIncomplete(index));
+            };
+
+            let waiting = async {
+                loop {
+                    let Some(index) = missing(&*self.state.read().await) else {
+                        return Ok(());
+                    };
+
+                    match events.recv().await {
+                        Ok(_) | Err(RecvError::Lagged(_)) => (),
+                        Err(RecvError::Closed) => return Err(Error::PieceIncomplete(index)),
+                    }
+                }
+            };
+
+            match time::timeout(wait, waiting).await {
+                Ok(result) => result?,
+                Err(_) => return Err(Error::PieceIncomplete(missing(&*self.state.read().await).unwrap_or(*pieces.start()))),
+            }
+        }
+
+        let storage = FileStorage::with_stored_paths(self.file_paths(), self.stored_paths().await?);
+
+        Ok(PieceReader::new(Arc::clone(&self.metainfo), CachedStorage::new(storage, self.cache.clone())).read_range(start, length).await?)
+    }
+
+    /// Where each file is stored once complete, inside `ClientConfig::download_dir`
+    fn file_paths(&self) -> Vec<PathBuf> {
+        let paths = self.metainfo.info().file_paths();
+
+        match &self.config.download_dir {
+            Some(dir) => paths.into_iter().map(|path| dir.join(path)).collect(),
+            None => paths,
+        }
+    }
+
+    /// Where each file is stored until it's complete, see `ClientConfig::part_suffix`.
+    /// Empty files have nothing to download, they are created complete
+    async fn stored_paths(&self) -> Result<Vec<PathBuf>, io::Error> {
+        let mut paths = Vec::new();
+
+        for (path, length) in self.file_paths().into_iter().zip(self.metainfo.info().file_lengths()) {
+            paths.push(match length {
+                0 => path,
+                _ => file::stored_path(&path, self.config.part_suffix.as_deref()).await?,
+            });
+        }
+
+        Ok(paths)
+    }
+
+    /// Snapshot of the peers, pieces and transfer rates, cheap enough to poll every second
+    pub async fn stats(&self) -> TorrentStats {
+        let state = self.state.read().await;
+
+        let pieces_complete = (0..state.len() as u32)
+            .filter(|&index| state.status(index) == Some(PieceStatus::Complete))
+            .count() as u32;
+        let left = self.left(&state);
+
+        let downloaded = self.downloaded.read().await;
+        let uploaded = self.uploaded.read().await;
+        let swarm = self.swarm.read().await;
+
+        TorrentStats::new(
+            self.connected_peers.read().await.len(),
+            pieces_complete,
+            state.len() as u32,
+            self.total_downloaded(),
+            self.total_uploaded(),
+            downloaded.rate(),
+            uploaded.rate(),
+            left,
+            stats::eta(left, downloaded.smoothed_rate()),
+            swarm.values().filter_map(|(seeders, _)| *seeders).max(),
+            swarm.values().filter_map(|(_, leechers)| *leechers).max(),
+        )
+    }
+
+    /// Bytes of the blocks accepted from peers this session, failed pieces included
+    pub fn total_downloaded(&self) -> u64 {
+        self.total_downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the blocks sent to peers this session
+    pub fn total_uploaded(&self) -> u64 {
+        self.total_uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes uploaded for every byte downloaded this session, against the size of the
+    /// torrent when nothing was downloaded
+    pub async fn ratio(&self) -> f64 {
+        let downloaded = match self.total_downloaded() {
+            0 => self.metainfo.info().total_length(),
+            downloaded => downloaded,
+        };
+
+        self.total_uploaded() as f64 / downloaded.max(1) as f64
+    }
+
+    async fn seed_limit_reached(&self, seeded_for: Duration) -> bool {
+        if self.config.seed_time_limit.is_some_and(|limit| seeded_for >= limit) {
+            return true;
+        }
+
+        match self.config.seed_ratio_limit {
+            Some(limit) => self.ratio().await >= limit,
+            None => false,
+        }
+    }
+
+    /// Unchokes the `upload_slots` interested peers ranked first by `upload_order` and chokes
+    /// the other interested ones. Peers only wait for this to be choked, they are unchoked as
+    /// soon as they are interested so new ones get a chance before the next round
+    async fn rechoke(&self) {
+        let seeding = self.state.read().await.is_complete();
+        let mut interested = Vec::new();
+
+        for peer in self.connected_peers.read().await.values() {
+            let status = peer.status.read().await;
+
+            if status.is_interested {
+                // uploading to peers that don't send anything is all a seed can reward
+                let rate = if seeding { status.uploaded.rate() } else { status.downloaded.rate() };
+                interested.push((peer.commands.clone(), status.am_choking, rate));
+            }
+        }
+
+        let unchoked = upload_order(interested.iter().map(|&(_, _, rate)| rate), self.config.upload_slots);
+
+        for (index, (commands, am_choking, _)) in interested.into_iter().enumerate() {
+            let command = match (unchoked.contains(&index), am_choking) {
+                (true, true) => PeerCommand::Unchoke,
+                (false, false) => PeerCommand::Choke,
+                _ => continue,
+            };
+
+            // fails only if the task already ended
+            let _ = commands.send(command).await;
+        }
+    }
+
+    /// Snapshot of every connected peer in no particular order, peers still connecting
+    /// or waiting to reconnect are included
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        let connected_peers = self.connected_peers.read().await;
+        let mut peers = Vec::with_capacity(connected_peers.len());
+
+        for (address, peer) in connected_peers.iter() {
+            let status = peer.status.read().await;
+
+            peers.push(PeerInfo::new(
+                *address,
+                status.is_choking,
+                status.am_interested,
+                status.pieces,
+                status.downloaded.total(),
+                status.downloaded.rate(),
+                status.uploaded.rate(),
+                status.peer_id,
+            ));
+        }
+
+        peers
+    }
+
+    /// Estimated time to finish from the bytes left and the smoothed download rate,
+    /// `None` while nothing is being downloaded
+    pub async fn eta(&self) -> Option<Duration> {
+        let left = self.left(&*self.state.read().await);
+
+        stats::eta(left, self.downloaded.read().await.smoothed_rate())
+    }
+
+    /// Bytes of the wanted pieces that aren't complete
+    fn left(&self, state: &DownloadState) -> u64 {
+        let info = self.metainfo.info();
+
+        (0..state.len() as u32)
+            .filter(|&index| !matches!(state.status(index), Some(PieceStatus::Complete | PieceStatus::Skipped)))
+            .map(|index| progress::piece_size(info, index))
+            .sum()
+    }
+
+    /// Bytes downloaded and total bytes of every file in the torrent
+    pub async fn file_progress(&self) -> Vec<FileProgress> {
+        progress::file_progress(self.metainfo.info(), &self.state.read().await.bitfield())
+    }
+}
+
+/// Announces over a new connection, since trackers close it after answering
+async fn announce_to(
+    url: &str,
+    request: &TrackerRequest,
+    dns_timeout: Duration,
+    bind: Option<IpAddr>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<TrackerResponse, Error> {
+    let url = Url::parse(url).map_err(tracker::Error::from)?;
+
+    let mut tracker_stream = match proxy {
+        Some(proxy) => tracker::connect_proxied(&url, proxy, bind).await?,
+        None => {
+            let tracker_addresses = tracker::resolve(&url, dns_timeout).await?;
+
+            // the tracker may only listen on some of the addresses its name resolves to
+            tracker::connect(&tracker_addresses, bind).await.map_err(tracker::Error::from)?
+        }
+    };
+
+    let mut tracker = Tracker::new(&mut tracker_stream, &url, request).await?;
+    tracker.announce().await?;
+
+    Ok(tracker.into_response().ok_or(tracker::Error::EmptyResponse)?)
+}
+
+/// Downloads the pieces the scheduler picks from a BEP 17 seed, which has all of them, until
+/// none are missing. A busy seed is asked again as late as it says, other failures wait
+/// like peer retries do and give up after `context.retries` in a row or once it's banned.
+/// Failures are reported to `events` as warnings
+async fn download_from_http_seed(
+    seed: HttpSeed,
+    context: PeerContext,
+    mut paused: watch::Receiver<bool>,
+    events: broadcast::Sender<ProgressEvent>,
+) {
+    let address = match seed.address().await {
+        Ok(address) => address,
+        Err(err) => {
+            let _ = events.send(ProgressEvent::Warning(format!("Http seed {} stopped: {}", seed.url(), error::report(&err))));
+            return;
+        }
+    };
+
+    let pieces = context.state.read().await.len();
+    let bitfield = BitVec::from_elem(pieces, true);
+    let mut downloading_piece = context.downloading_piece(address);
+    let mut failures = 0;
+
+    loop {
+        // the peers are disconnected meanwhile, the seed stops asking as well
+        if *paused.borrow_and_update() {
+            let _ = paused.wait_for(|paused| !paused).await;
+        }
+
+        let piece = {
+            let mut state = context.state.write().await;
+
+            if state.is_complete() || state.is_banned(address.ip()) {
+                return;
+            }
+
+            context.scheduler.next_piece(&state, &SchedulingPeer::new(&bitfield, 0.0))
+                .filter(|&piece| state.request(piece, address))
+        };
+
+        let Some(piece) = piece else {
+            time::sleep(HTTP_SEED_IDLE).await;
+            continue;
+        };
+
+        downloading_piece.start(piece).await;
+
+        let length = if piece as usize == pieces - 1 { context.last_piece_length } else { context.piece_length };
+
+        match seed.download_piece(&context.info_hash, piece, length as usize).await {
+            Ok(data) => {
+                failures = 0;
+
+                context.downloaded.write().await.record(data.len() as u64);
+                context.total_downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+                // the writer keeps track of pieces in blocks, whoever sent them
+                for (block, begin) in data.chunks(BLOCK_SIZE as usize).zip((0..).step_by(BLOCK_SIZE as usize)) {
+                    // the writer failed, the download is stopping
+                    if context.sender.send(WriteMessage::new(piece, begin, block)).await.is_err() {
+                        return;
+                    }
+                }
+
+                // the writer completes the piece or makes it missing again
+                downloading_piece.piece = None;
+            }
+            Err(http_seed::Error::Busy(wait)) => {
+                downloading_piece.release().await;
+                time::sleep(wait).await;
+            }
+            Err(err) => {
+                downloading_piece.release().await;
+                let _ = events.send(ProgressEvent::Warning(format!("Http seed {} failed: {}", seed.url(), error::report(&err))));
+
+                if failures >= context.retries {
+                    return;
+                }
+
+                time::sleep(context.retry_backoff * 2u32.pow(failures)).await;
+                failures += 1;
+            }
+        }
+    }
+}
+
+/// Connects to the peer again when the connection drops while it still has pieces
+/// we need, doubling the wait after each attempt up to `context.retries` times
+async fn handle_peer_with_retries(
+    address: SocketAddr,
+    context: &PeerContext,
+    commands: &mut mpsc::Receiver<PeerCommand>,
+    status: &RwLock<PeerStatus>,
+) -> Result<(), Error> {
+    let mut attempt = 0;
+
+    loop {
+        let mut downloading_piece = context.downloading_piece(address);
+        let mut peer_bitfield = BitVec::new();
+
+        let result = handle_peer(address, context, commands, status, &mut downloading_piece, &mut peer_bitfield).await;
+
+        // the in-flight piece has to be available again before reconnecting
+        downloading_piece.release().await;
+
+        match result {
+            Err(Error::PeerError(peer::Error::IoError(_)))
+                if attempt < context.retries && has_needed_piece(&peer_bitfield, &context.state).await =>
+            {
+                tokio::select! {
+                    _ = time::sleep(context.retry_backoff * 2u32.pow(attempt)) => attempt += 1,
+                    // any command means the torrent is still steering this peer, only shutdowns matter while disconnected
+                    Some(PeerCommand::Shutdown) | None = commands.recv() => return Ok(()),
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Downloads from the peer until it has nothing else we need, `peer_bitfield` is
+/// left with the pieces the peer had when the connection ended
+async fn handle_peer(
+    address: SocketAddr,
+    context: &PeerContext,
+    commands: &mut mpsc::Receiver<PeerCommand>,
+    status: &RwLock<PeerStatus>,
+    downloading_piece: &mut DownloadingPiece,
+    peer_bitfield: &mut BitVec,
+) -> Result<(), Error> {
+    let connecting = async {
+        match &context.proxy {
+            Some(proxy) => Ok(proxy::connect_to(proxy, address, context.bind_address).await?),
+            None => interface::connect(address, context.bind_address).await,
+        }
+    };
+
+    let stream = match connecting.await {
+        Ok(stream) => stream,
+        Err(err) => return Err(peer::Error::IoError(err).into()),
+    };
+
+    handle_connection(stream, context, commands, status, downloading_piece, peer_bitfield).await
+}
+
+/// Uploads to and downloads from a peer that connected to us, unlike the peers we connect
+/// to it isn't reconnected to since it listens on another port than the one it used
+async fn handle_inbound_peer(
+    address: SocketAddr,
+    stream: TcpStream,
+    context: &PeerContext,
+    commands: &mut mpsc::Receiver<PeerCommand>,
+    status: &RwLock<PeerStatus>,
+) -> Result<(), Error> {
+    let mut downloading_piece = context.downloading_piece(address);
+
+    let result = handle_connection(stream, context, commands, status, &mut downloading_piece, &mut BitVec::new()).await;
+    downloading_piece.release().await;
+
+    result
+}
+
+/// Exchanges messages over an open connection, `peer_bitfield` is left with the pieces
+/// the peer had when it ended
+async fn handle_connection(
+    mut stream: TcpStream,
+    context: &PeerContext,
+    commands: &mut mpsc::Receiver<PeerCommand>,
+    status: &RwLock<PeerStatus>,
+    downloading_piece: &mut DownloadingPiece,
+    peer_bitfield: &mut BitVec,
+) -> Result<(), Error> {
+    let pieces = context.state.read().await.len();
+
+    let mut peer = Peer::new(&mut stream, pieces).await?;
+    peer.set_read_buffer_capacity(context.read_buffer);
+    peer.set_handshake_timeout(context.handshake_timeout);
+    #[cfg(debug_assertions)]
+    peer.set_disabled_messages(&context.disabled_messages);
+    let mut availability = context.peer_availability();
+
+    let result = exchange_messages(&mut peer, context, commands, status, downloading_piece, &mut availability).await;
+    peer.close().await;
+
+    if let Some(super_seed) = &context.super_seed {
+        super_seed.lock().await.remove_peer(&downloading_piece.address);
+    }
+
+    *peer_bitfield = peer.bitfield().clone();
+    availability.clear().await;
+
+    result
+}
+
+/// Sends the handshake and reacts to the peer's messages and the torrent's commands
+/// until the peer has nothing else we need, unless seeding, or a `PeerCommand::Shutdown` arrives.
+/// The pieces the peer has are counted in `availability`, its state is kept in `status`
+async fn exchange_messages(
+    peer: &mut Peer<'_>,
+    context: &PeerContext,
+    commands: &mut mpsc::Receiver<PeerCommand>,
+    status: &RwLock<PeerStatus>,
+    downloading_piece: &mut DownloadingPiece,
+    availability: &mut PeerAvailability,
+) -> Result<(), Error> {
+    let pieces = context.state.read().await.len();
+    let piece_length = context.piece_length;
+    let last_piece_length = context.last_piece_length;
+    let state = &context.state;
+
+    // a peer that never answers is dropped once `handshake_timeout` passes
+    let peer_handshake = peer.handshake(context.info_hash, context.peer_id).await?;
+
+    // a peer of another torrent
+    if *peer_handshake.info_hash() != context.info_hash {
+        return Ok(());
+    }
+
+    // ourselves, through an address of ours a tracker returned
+    if *peer_handshake.peer_id() == context.peer_id {
+        return Ok(());
+    }
+
+    // connected as the torrent was being paused, or reconnected by a retry after that
+    if *context.paused.borrow() {
+        return Ok(());
+    }
+
+    status.write().await.peer_id = Some(*peer_handshake.peer_id());
+
+    let bitfield = state.read().await.bitfield();
+    let address = downloading_piece.address;
+    // pieces are advertised one at a time instead of all at once
+    let super_seed = context.super_seed.as_deref().filter(|_| bitfield.all());
+    // the piece advertised last, how much of it was uploaded and which pieces went out whole
+    let mut offered: Option<u32> = None;
+    let mut offered_uploaded = 0;
+    let mut uploaded_pieces = BitVec::from_elem(pieces, false);
+    let piece_size = |piece: u32| if piece as usize + 1 == pieces { last_piece_length } else { piece_length };
+
+    if super_seed.is_some() {
+        peer.send_bitfield(&uploaded_pieces);
+        offered = offer_next_piece(peer, super_seed, address, &uploaded_pieces).await;
+    } else if bitfield.any() {
+        peer.send_bitfield(&bitfield);
+    }
+
+    let mut keep_alive = time::interval_at(Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
+    let mut unrequested_blocks = 0;
+    // a bitfield is only valid as the first message, keep-alives aside
+    let mut received_message = false;
+
+    loop {
+        // everything queued while handling the last command or message goes out in one write
+        peer.flush().await?;
+        status.write().await.update(peer);
+
+        // the next piece is only advertised once the whole offered one went out
+        if let Some((super_seed, piece)) = super_seed.zip(offered).filter(|&(_, piece)| offered_uploaded >= piece_size(piece)) {
+            uploaded_pieces.set(piece as usize, true);
+            offered_uploaded = 0;
+
+            let mut bitfield = peer.bitfield().clone();
+            bitfield.or(&uploaded_pieces);
+            offered = super_seed.lock().await.uploaded(address, piece, &bitfield);
+
+            if let Some(piece) = offered {
+                peer.send_have(piece);
+                peer.flush().await?;
+            }
+        }
+
+        // read_message is cancel safe, a tick in the middle of a message doesn't lose it
+        let message = tokio::select! {
+            // commands go first so a shutdown isn't delayed by a chatty peer
+            biased;
+
+            command = commands.recv() => {
+                match command {
+                    Some(PeerCommand::SendHave(index)) => peer.send_have(index),
+                    Some(PeerCommand::Choke) => peer.send_choke(),
+                    Some(PeerCommand::Unchoke) => peer.send_unchoke(),
+                    Some(PeerCommand::Cancel { index, begin }) => {
+                        let length = next_block_length(index, begin, pieces, piece_length, last_piece_length);
+                        peer.send_cancel(index, begin, length);
+                    }
+                    // the torrent is gone when every sender is dropped
+                    Some(PeerCommand::Shutdown) | None => return Ok(()),
+                }
+
+                continue;
+            }
+            message = peer.read_message() => message?,
+            _ = keep_alive.tick() => {
+                peer.send_keep_alive();
+                continue;
+            }
+        };
+
+        let first_message = !received_message;
+        received_message |= message != Message::KeepAlive;
+
+        match message {
+            // closes connection if peer has no piece the file needs
+            Message::KeepAlive if !is_there_next_piece(peer, state).await && is_done(peer, context).await => {
+                return Ok(());
+            },
+            Message::Choke => {
+                peer.set_is_choking(true);
+            }
+            Message::Unchoke => {
+                // redundant message
+                if !peer.is_choking() {
+                    continue;
+                }
+
+                peer.set_is_choking(false);
+
+                if downloading_piece.piece.is_none() {
+                    let download_rate = status.read().await.downloaded.rate();
+
+                    if let Some(next_piece) = get_next_piece(peer, downloading_piece.address, state, context.scheduler, download_rate).await {
+                        downloading_piece.start(next_piece).await;
+                    } else if is_done(peer, context).await {
+                        return Ok(());
+                    }
+                }
+
+                if let Some(piece) = downloading_piece.piece {
+                    let length = next_block_length(piece, downloading_piece.offset, pieces, piece_length, last_piece_length);
+                    peer.send_request(piece, downloading_piece.offset, length);
+                }
+            }
+            // uploaded to until the next round of `Torrent::rechoke` at least
+            Message::Interested => {
+                peer.set_is_interested(true);
+
+                if peer.am_choking() {
+                    peer.send_unchoke();
+                }
+            }
+            Message::NotInterested => peer.set_is_interested(false),
+            Message::Have(piece_index) => {
+                // a piece past the end ends the connection before anything counts it
+                peer.update_piece(piece_index)?;
+                availability.add(piece_index).await;
+                update_interest(peer, state).await;
+
+                // it may have gotten the offered piece from another peer
+                if let Some(piece) = offer_next_piece(peer, super_seed, address, &uploaded_pieces).await {
+                    offered = Some(piece);
+                    offered_uploaded = 0;
+                }
+            }
+            // it would replace the pieces the peer announced with `Have` since
+            Message::Bitfield(_) if !first_message => (),
+            Message::Bitfield(bitfield) => {
+                peer.update_bitfield(bitfield)?;
+                availability.update(peer.bitfield()).await;
+                update_interest(peer, state).await;
+
+                // the first offer was made before knowing what the peer has
+                if let Some(piece) = offer_next_piece(peer, super_seed, address, &uploaded_pieces).await {
+                    offered = Some(piece);
+                    offered_uploaded = 0;
+                }
+            }
+            Message::Request { index, begin, length } => {
+                let size = match index as usize {
+                    index if index + 1 == pieces => last_piece_length,
+                    index if index < pieces => piece_length,
+                    _ => continue,
+                };
+
+                // choked or invalid requests are dropped, the peer can tell from the missing block
+                if peer.am_choking() || length > MAX_REQUEST_LENGTH || begin as u64 + length as u64 > size as u64
+                    || state.read().await.status(index) != Some(PieceStatus::Complete)
+                {
+                    continue;
+                }
+
+                let block = context.reader.lock().await.read_block(index, begin, length).await?;
+
+                context.uploaded.write().await.record(block.len() as u64);
+                context.total_uploaded.fetch_add(block.len() as u64, Ordering::Relaxed);
+                status.write().await.uploaded.record(block.len() as u64);
+                peer.send_piece(index, begin, block);
+
+                if offered == Some(index) {
+                    offered_uploaded += length;
+                }
+            }
+            Message::Piece { index, begin, block } => {
+                // banned after an earlier piece failed verification, its blocks can't be trusted
+                if state.read().await.is_banned(downloading_piece.address.ip()) {
+                    return Ok(());
+                }
+
+                // only the block at the offset of the piece being downloaded is ever requested
+                let requested = downloading_piece.piece.filter(|&piece| piece == index && begin == downloading_piece.offset)
+                    .map(|piece| next_block_length(piece, begin, pieces, piece_length, last_piece_length));
+
+                if requested != Some(block.len() as u32) {
+                    unrequested_blocks += 1;
+
+                    if unrequested_blocks >= MAX_UNREQUESTED_BLOCKS {
+                        stop_downloading(peer).await;
+                        return Ok(());
+                    }
+
+                    continue;
+                }
+
+                context.downloaded.write().await.record(block.len() as u64);
+                context.total_downloaded.fetch_add(block.len() as u64, Ordering::Relaxed);
+                status.write().await.downloaded.record(block.len() as u64);
+                // the writer failed, the download is stopping every peer
+                if context.sender.send(WriteMessage::new(index, begin, &block)).await.is_err() {
+                    return Ok(());
+                }
+
+                downloading_piece.offset += block.len() as u32;
+
+                // the whole piece is written, request the next one
+                if next_block_length(index, downloading_piece.offset, pieces, piece_length, last_piece_length) == 0 {
+                    let download_rate = status.read().await.downloaded.rate();
+
+                    if let Some(next_piece) = get_next_piece(peer, downloading_piece.address, state, context.scheduler, download_rate).await {
+                        downloading_piece.start(next_piece).await;
+                    } else {
+                        // no more pieces needed
+                        downloading_piece.piece = None;
+
+                        if is_done(peer, context).await {
+                            return Ok(());
+                        }
+                    };
+                }
+
+                if let Some(piece) = downloading_piece.piece {
+                    let length = next_block_length(piece, downloading_piece.offset, pieces, piece_length, last_piece_length);
+                    peer.send_request(piece, downloading_piece.offset, length);
+                }
+            }
+            Message::Cancel { .. } => (), // todo (cancels previouslly requested piece)
+            _ => (),
+        }
+    }
+}
+
+/// Advertises the next piece of `super_seed` to the peer with a `have`, None while it
+/// hasn't got the piece offered before or when not super seeding. It has `uploaded`
+/// besides the pieces it announced
+async fn offer_next_piece(peer: &mut Peer<'_>, super_seed: Option<&Mutex<SuperSeed>>, address: SocketAddr, uploaded: &BitVec) -> Option<u32> {
+    let super_seed = super_seed?;
+    let mut bitfield = peer.bitfield().clone();
+    bitfield.or(uploaded);
+
+    let piece = super_seed.lock().await.next_offer(address, &bitfield)?;
+    peer.send_have(piece);
+
+    Some(piece)
+}
+
+/// Lets the peer know when it stopped or started having a piece we need
+async fn update_interest(peer: &mut Peer<'_>, state: &RwLock<DownloadState>) {
+    let interested = is_there_next_piece(peer, state).await;
+
+    if interested && !peer.am_interested() {
+        peer.send_interested();
+    } else if !interested && peer.am_interested() {
+        peer.send_not_interested();
+    }
+}
+
+/// Whether to disconnect from a peer that has nothing else we need. Seeding keeps it to
+/// upload to, only withdrawing our interest, until it has every piece too
+async fn is_done(peer: &mut Peer<'_>, context: &PeerContext) -> bool {
+    if !context.seed || peer.is_seed() {
+        stop_downloading(peer).await;
+        return true;
+    }
+
+    if peer.am_interested() {
+        peer.send_not_interested();
+    }
+
+    false
+}
+
+/// Withdraws our interest before disconnecting from a peer that has nothing else we need
+async fn stop_downloading(peer: &mut Peer<'_>) {
+    if peer.am_interested() {
+        peer.send_not_interested();
+    }
+
+    // the peer may have closed the connection already
+    let _ = peer.flush().await;
+}
+
+fn has_piece(bitfield: &BitVec, index: u32) -> bool {
+    bitfield.get(index as usize) == Some(true)
+}
+
+/// Requests the piece `scheduler` picks for the peer, which received `download_rate` bytes a second lately
+async fn get_next_piece(
+    peer: &Peer<'_>,
+    address: SocketAddr,
+    state: &RwLock<DownloadState>,
+    scheduler: &dyn RequestScheduler,
+    download_rate: f64,
+) -> Option<u32> {
+    let mut state = state.write().await;
+    let piece = scheduler.next_piece(&state, &SchedulingPeer::new(peer.bitfield(), download_rate))?;
+
+    // a piece that isn't missing is never downloaded twice, whatever the scheduler says
+    state.request(piece, address).then_some(piece)
+}
+
+async fn is_there_next_piece(peer: &Peer<'_>, state: &RwLock<DownloadState>) -> bool {
+    has_needed_piece(peer.bitfield(), state).await
+}
+
+/// Whether the bitfield has a piece that is missing and not being downloaded from another peer
+async fn has_needed_piece(bitfield: &BitVec, state: &RwLock<DownloadState>) -> bool {
+    let state = state.read().await;
+
+    (0..bitfield.len() as u32).any(|piece| has_piece(bitfield, piece) && state.is_missing(piece))
+}
+
+/// Length of the block to request at `offset` of `piece`, a smaller one finishes the piece
+/// and 0 means the piece has no blocks left
+fn next_block_length(piece: u32, offset: u32, pieces: usize, piece_length: u32, last_piece_length: u32) -> u32 {
+    let size = if piece as usize == pieces - 1 {
+        last_piece_length
+    } else {
+        piece_length
+    };
+
+    (size - offset).min(BLOCK_SIZE)
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use bit_vec::BitVec;
+    use sha1::{Digest, Sha1};
+    use sha2::Sha256;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{Mutex, RwLock, mpsc, watch};
+    use tokio::time::{self, Instant};
+
+    use crate::bencode::FromBencode;
+    use crate::config::ClientConfig;
+    use crate::error;
+    use crate::interface;
+    use crate::file::PieceReader;
+    use crate::metainfo::MetaInfo;
+    use crate::peer::{self, Message, Peer, PeerCommand, WriteMessage};
+    use crate::peer::mock::{MockPeer, Step};
+    use crate::info_hash::InfoHash;
+    use crate::peer_id::PeerId;
+    use crate::progress::ProgressEvent;
+    use crate::scheduler::RarestFirst;
+    use crate::seed::SuperSeed;
+    use crate::state::{DownloadState, PieceStatus};
+    use crate::stats::RateMeter;
+    use crate::storage::{CachedStorage, FileStorage, PieceCache};
+    use crate::tracker::{Event, TrackerResponse};
+    use crate::tracker::mock::MockTracker;
+    use crate::torrent::{BLOCK_SIZE, MAX_UNREQUESTED_BLOCKS, ConnectedPeer, Error, PeerAvailability, PeerContext, PeerStatus, Torrent, TrackerSchedule, exchange_messages, handle_inbound_peer, handle_peer_with_retries, is_own_address, is_there_next_piece, get_next_piece};
+
+    /// Both ends of a local connection, the first is used to build a `Peer`
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (remote, _) = listener.accept().await.unwrap();
+
+        (stream, remote)
+    }
+
+    /// Address the pieces of tests that don't connect anywhere are requested from
+    fn address() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    /// State of a torrent of `pieces` where only `needed` are missing
+    fn state(pieces: usize, needed: &[u32]) -> RwLock<DownloadState> {
+        let mut state = DownloadState::new(pieces);
+
+        for piece in (0..pieces as u32).filter(|piece| !needed.contains(piece)) {
+            state.complete(piece);
+        }
+
+        RwLock::new(state)
+    }
+
+    #[test]
+    fn error_source_chain() {
+        let err = Error::from(peer::Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer")));
+
+        let peer_err = err.source().unwrap();
+        assert!(peer_err.downcast_ref::<peer::Error>().is_some());
+
+        let io_err = peer_err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_err.kind(), io::ErrorKind::ConnectionReset);
+
+        assert_eq!(error::report(&err), "Peer error\n  caused by: Peer connection failed\n  caused by: reset by peer");
+    }
+
+    #[test]
+    fn schedule_respects_min_interval() {
+        let start = Instant::now();
+        let mut schedule = TrackerSchedule::new(start);
+
+        assert!(schedule.is_due(start));
+        assert_eq!(schedule.event(), Some(Event::Started));
+
+        // an interval shorter than the minimum is raised to it
+        let response = TrackerResponse::from_bencode(b"d8:intervali10e12:min intervali60e5:peers0:e").unwrap();
+        schedule.answered(start, &response);
+
+        assert_eq!(schedule.event(), None);
+        assert!(!schedule.is_due(start + Duration::from_secs(59)));
+        assert!(schedule.is_due(start + Duration::from_secs(60)));
+
+        // forced announces are refused before the minimum
+        assert_eq!(schedule.can_force(start + Duration::from_secs(5)), Err(Duration::from_secs(55)));
+        schedule.force(start + Duration::from_secs(5));
+        assert!(!schedule.is_due(start + Duration::from_secs(59)));
+
+        // failed retries can't come sooner either
+        schedule.failed(start + Duration::from_secs(1));
+        assert!(!schedule.is_due(start + Duration::from_secs(59)));
+        assert!(schedule.is_due(start + Duration::from_secs(60)));
+
+        // resume sent `started` itself, the next regular announce waits from then
+        let resumed = start + Duration::from_secs(100);
+        schedule.resumed(resumed);
+        assert!(!schedule.is_due(resumed + Duration::from_secs(59)));
+        assert!(schedule.is_due(resumed + Duration::from_secs(60)));
+
+        // past the minimum forcing is immediate
+        let response = TrackerResponse::from_bencode(b"d8:intervali1800e12:min intervali60e5:peers0:e").unwrap();
+        schedule.answered(resumed, &response);
+
+        let later = resumed + Duration::from_secs(90);
+        assert!(!schedule.is_due(later));
+        assert_eq!(schedule.can_force(later), Ok(()));
+        schedule.force(later);
+        assert!(schedule.is_due(later));
+    }
+
+    #[tokio::test]
+    async fn unsupported_torrents() {
+        let magnet = Torrent::new("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a", config()).await;
+        assert!(matches!(magnet, Err(Error::Unsupported(_))));
+
+        let missing = Torrent::new("does_not_exist.torrent", config()).await;
+        assert!(matches!(missing, Err(Error::InvalidTorrent(_))));
+
+        let path = std::env::temp_dir().join(format!("torrent_client_no_pieces_{}.torrent", std::process::id()));
+        std::fs::write(&path, b"d8:announce9:localhost4:infod6:lengthi0e4:name4:test12:piece lengthi16384e6:pieces0:ee").unwrap();
+
+        let empty = Torrent::new(path.to_str().unwrap(), config()).await;
+        assert!(matches!(empty, Err(Error::Unsupported(_))));
+
+        std::fs::write(&path, b"d8:announce9:localhost4:infod9:file treed1:ad0:d6:lengthi1eee1:bd0:d6:lengthi1eeee12:meta versioni2e4:name4:test12:piece lengthi16384eee").unwrap();
+
+        let v2 = Torrent::new(path.to_str().unwrap(), config()).await;
+        assert!(matches!(v2, Err(Error::Unsupported(_))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_next_piece_for_useless_peer() {
+        let (mut stream, _remote) = connection().await;
+        let mut peer = Peer::new(&mut stream, 10).await.unwrap();
+
+        // we only need pieces 2 and 3, the peer has 0 and 9
+        let state = state(10, &[2, 3]);
+        peer.update_bitfield(vec![0b1000_0000, 0b0100_0000]).unwrap();
+
+        assert!(!is_there_next_piece(&peer, &state).await);
+
+        peer.update_piece(3).unwrap();
+
+        assert!(is_there_next_piece(&peer, &state).await);
+
+        // another peer is already downloading it
+        state.write().await.request(3, "127.0.0.1:6882".parse().unwrap());
+
+        assert!(!is_there_next_piece(&peer, &state).await);
+    }
+
+    #[tokio::test]
+    async fn picks_rarest_piece() {
+        let (mut stream, _remote) = connection().await;
+        let mut peer = Peer::new(&mut stream, 4).await.unwrap();
+        peer.update_bitfield(vec![0b1110_0000]).unwrap();
+
+        let state = Arc::new(state(4, &[0, 1, 2, 3]));
+
+        // another peer has pieces 0 and 2, and sends 1 with a have
+        let mut other = PeerAvailability::new(Arc::clone(&state));
+        other.update(&BitVec::from_bytes(&[0b1010_0000])).await;
+        other.add(1).await;
+        other.add(1).await;
+        other.add(4).await;
+        other.add(u32::MAX).await;
+
+        let mut availability = PeerAvailability::new(Arc::clone(&state));
+        availability.update(peer.bitfield()).await;
+
+        let counts: Vec<u16> = {
+            let state = state.read().await;
+            (0..4).map(|piece| state.availability(piece)).collect()
+        };
+        assert_eq!(counts, vec![2, 2, 2, 0]);
+
+        other.update(&BitVec::from_bytes(&[0b1000_0000])).await;
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(1));
+
+        // dropping uncounts too, just not right away
+        drop(other);
+        availability.clear().await;
+        time::sleep(Duration::from_millis(10)).await;
+
+        let counts: Vec<u16> = {
+            let state = state.read().await;
+            (0..4).map(|piece| state.availability(piece)).collect()
+        };
+        assert_eq!(counts, vec![0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn next_piece_functions_agree() {
+        let (mut stream, _remote) = connection().await;
+        let mut peer = Peer::new(&mut stream, 16).await.unwrap();
+
+        // the peer has pieces 1, 6 and 12
+        peer.update_bitfield(vec![0b0100_0010, 0b0000_1000]).unwrap();
+
+        for needed in [vec![0, 2, 3], vec![6], vec![5, 12, 15], vec![]] {
+            let state = state(16, &needed);
+
+            let is_there = is_there_next_piece(&peer, &state).await;
+            let next = get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await;
+
+            assert_eq!(is_there, next.is_some());
+
+            if let Some(piece) = next {
+                assert!(needed.contains(&piece));
+                assert_eq!(state.read().await.status(piece), Some(PieceStatus::Requested { peer: address() }));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn seeds_take_partial_pieces() {
+        let (mut seed_stream, _seed_remote) = connection().await;
+        let mut seed = Peer::new(&mut seed_stream, 4).await.unwrap();
+        seed.update_bitfield(vec![0b1111_0000]).unwrap();
+
+        let (mut stream, _remote) = connection().await;
+        let mut peer = Peer::new(&mut stream, 4).await.unwrap();
+        peer.update_bitfield(vec![0b1110_0000]).unwrap();
+
+        assert!(seed.is_seed());
+        assert!(!peer.is_seed());
+
+        let state = state(4, &[0, 1, 2, 3]);
+
+        for piece in [0, 2] {
+            let mut state = state.write().await;
+            state.request(piece, address());
+            state.release(piece, BLOCK_SIZE);
+        }
+
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(1));
+        assert_eq!(get_next_piece(&seed, address(), &state, &RarestFirst, 0.0).await, Some(0));
+
+        // only partial pieces are left for the peer
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(2));
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, None);
+    }
+
+    #[tokio::test]
+    async fn prioritized_pieces_come_first() {
+        let (mut stream, _remote) = connection().await;
+        let mut peer = Peer::new(&mut stream, 4).await.unwrap();
+        peer.update_bitfield(vec![0b1111_0000]).unwrap();
+
+        let state = state(4, &[0, 1, 2, 3]);
+        state.write().await.add_available(0);
+
+        {
+            let mut state = state.write().await;
+            state.prioritize(3);
+            state.prioritize(2);
+        }
+
+        // in order, rarity doesn't matter
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(2));
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(3));
+        assert_eq!(get_next_piece(&peer, address(), &state, &RarestFirst, 0.0).await, Some(1));
+    }
+
+    /// Config storing the files of the torrents the tests write in the temporary directory
+    fn config() -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.download_dir = Some(std::env::temp_dir());
+
+        config
+    }
+
+    /// Writes a single piece torrent of `data` announcing to `announce`, returns
+    /// its path and the path the data is downloaded to
+    fn torrent_file(name: &str, announce: &str, data: &[u8]) -> (String, PathBuf) {
+        let download = std::env::temp_dir().join(format!("torrent_client_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&download);
+        let file_name = download.file_name().unwrap().to_str().unwrap();
+
+        let mut bytes = format!(
+            "d8:announce{}:{}4:infod6:lengthi{}e4:name{}:{}12:piece lengthi16384e6:pieces20:",
+            announce.len(), announce, data.len(), file_name.len(), file_name,
+        ).into_bytes();
+        bytes.extend_from_slice(&Sha1::digest(data));
+        bytes.extend_from_slice(b"ee");
+
+        let path = download.with_extension("torrent");
+        std::fs::write(&path, bytes).unwrap();
+
+        (path.to_str().unwrap().to_string(), download)
+    }
+
+    #[tokio::test]
+    async fn finishes_when_files_are_already_complete() {
+        let data: Vec<u8> = (0..10).collect();
+        let (path, download) = torrent_file("already_complete", "http://127.0.0.1:1/announce", &data);
+        std::fs::write(&download, &data).unwrap();
+
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+        let mut events = torrent.subscribe();
+
+        // nothing to download, not even the tracker is waited for
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+
+        assert!(torrent.state.read().await.is_complete());
+        assert_eq!(events.recv().await.unwrap(), ProgressEvent::RecheckProgress { checked: 1, total: 1 });
+        assert_eq!(events.recv().await.unwrap(), ProgressEvent::AlreadyComplete);
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_and_rechecks_v2_torrent() {
+        let block = BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..block + 100).map(|byte| byte as u8).collect();
+        let layer = [Sha256::digest(&data[..block]), Sha256::digest(&data[block..])].concat();
+        let root = Sha256::digest(&layer);
+
+        let seeder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\0\0\x01".to_vec();
+        body.extend_from_slice(&seeder.local_addr().unwrap().port().to_be_bytes());
+        body.push(b'e');
+        let tracker = MockTracker::start(&body).await;
+        let url = tracker.url().to_string();
+
+        let download = std::env::temp_dir().join(format!("torrent_client_v2_{}", std::process::id()));
+        let _ = std::fs::remove_file(&download);
+        let name = download.file_name().unwrap().to_str().unwrap();
+
+        // only the v2 keys, no `pieces` nor `length`
+        let mut info = format!("d9:file treed{}:{}d0:d6:lengthi{}e11:pieces root32:", name.len(), name, data.len()).into_bytes();
+        info.extend_from_slice(&root);
+        info.extend_from_slice(format!("eee12:meta versioni2e4:name{}:{}12:piece lengthi{}ee", name.len(), name, block).as_bytes());
+
+        let mut bytes = format!("d8:announce{}:{}4:info", url.len(), url).into_bytes();
+        bytes.extend_from_slice(&info);
+        bytes.extend_from_slice(b"12:piece layersd32:");
+        bytes.extend_from_slice(&root);
+        bytes.extend_from_slice(b"64:");
+        bytes.extend_from_slice(&layer);
+        bytes.extend_from_slice(b"ee");
+
+        let path = download.with_extension("torrent");
+        std::fs::write(&path, bytes).unwrap();
+        let path = path.to_str().unwrap();
+
+        let torrent = Torrent::new(path, config()).await.unwrap();
+        assert_eq!(torrent.info_hash().as_bytes()[..], Sha256::digest(&info)[..20]);
+        assert_eq!(torrent.state.read().await.len(), 2);
+
+        let info_hash = *torrent.info_hash();
+        let pieces = vec![(0, data[..block].to_vec()), (1, data[block..].to_vec())];
+
+        tokio::spawn(async move {
+            let (stream, _) = seeder.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1100_0000, pieces)).await;
+        });
+
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+
+        // verified again with the piece layers when started over
+        let torrent = Torrent::new(path, config()).await.unwrap();
+        let mut events = torrent.subscribe();
+
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+        assert_eq!(events.recv().await.unwrap(), ProgressEvent::RecheckProgress { checked: 1, total: 2 });
+        assert_eq!(events.recv().await.unwrap(), ProgressEvent::RecheckProgress { checked: 2, total: 2 });
+        assert_eq!(events.recv().await.unwrap(), ProgressEvent::AlreadyComplete);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_from_http_seeds() {
+        let data: Vec<u8> = (0..10000).map(|byte| byte as u8).collect();
+        let mut seed = MockTracker::start(&data).await;
+        let (path, download) = torrent_file("http_seed", "http://127.0.0.1:1/announce", &data);
+
+        // the seed goes between the announce and the info dict
+        let url = seed.url().to_string();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let info = bytes.windows(6).position(|key| key == b"4:info").unwrap();
+        bytes.splice(info..info, format!("9:httpseedsl{}:{}e", url.len(), url).into_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+        assert_eq!(torrent.metainfo().http_seeds(), [url]);
+        let mut events = torrent.subscribe();
+
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+
+        assert!(seed.next_request().await.contains("&piece=0 HTTP/1.1"));
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+
+        // the tracker is unreachable, which is reported instead of printed
+        let mut warnings = Vec::new();
+
+        while let Ok(event) = events.try_recv() {
+            if let ProgressEvent::Warning(warning) = event {
+                warnings.push(warning);
+            }
+        }
+
+        assert!(!warnings.is_empty());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_complete_pieces() {
+        let data: Vec<u8> = (0..10).collect();
+        let (path, download) = torrent_file("read_piece", "http://localhost", &data);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        assert!(matches!(torrent.read_piece(0).await, Err(Error::PieceIncomplete(0))));
+        assert!(matches!(torrent.read_piece(1).await, Err(Error::PieceOutOfRange(1))));
+
+        std::fs::write(&download, &data).unwrap();
+        torrent.state.write().await.complete(0);
+
+        assert_eq!(torrent.read_piece(0).await.unwrap(), data);
+
+        std::fs::remove_file(download).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_ranges() {
+        let data: Vec<u8> = (0..10).collect();
+        let (path, download) = torrent_file("read_range", "http://localhost", &data);
+        std::fs::write(&download, &data).unwrap();
+
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        assert!(matches!(torrent.read_range(8, 3).await, Err(Error::RangeOutOfBounds { start: 8, length: 3 })));
+
+        // missing pieces fail right away, prioritized meanwhile
+        assert!(matches!(torrent.read_range(2, 4).await, Err(Error::PieceIncomplete(0))));
+        assert!(torrent.state.read().await.is_prioritized(0));
+
+        let mut config = config();
+        config.read_range_wait = Some(Duration::from_secs(5));
+        let torrent = Arc::new(Torrent::new(&path, config).await.unwrap());
+
+        let completing = {
+            let torrent = Arc::clone(&torrent);
+
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(50)).await;
+                torrent.state.write().await.complete(0);
+                torrent.events.send(ProgressEvent::PieceCompleted(0)).unwrap();
+            })
+        };
+
+        assert_eq!(torrent.read_range(2, 4).await.unwrap(), &data[2..6]);
+        completing.await.unwrap();
+
+        std::fs::remove_file(download).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_disk_space() {
+        let download = std::env::temp_dir().join(format!("torrent_client_space_{}", std::process::id()));
+        let file_name = download.file_name().unwrap().to_str().unwrap();
+
+        // an exabyte, more than any disk running the tests
+        let mut bytes = format!(
+            "d8:announce16:http://localhost4:infod6:lengthi{}e4:name{}:{}12:piece lengthi16384e6:pieces20:",
+            1u64 << 60, file_name.len(), file_name,
+        ).into_bytes();
+        bytes.extend_from_slice(&[0; 20]);
+        bytes.extend_from_slice(b"ee");
+
+        let path = download.with_extension("torrent");
+        std::fs::write(&path, bytes).unwrap();
+
+        let torrent = Torrent::new(path.to_str().unwrap(), config()).await.unwrap();
+
+        match torrent.download().await {
+            Err(Error::InsufficientSpace { needed, available }) => assert!(needed == 1 << 60 && available < needed),
+            result => panic!("expected InsufficientSpace, got {:?}", result.map_err(|err| err.to_string())),
+        }
+
+        // nothing was created
+        assert!(!download.exists());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_announce() {
+        let mut tracker = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let (path, _) = torrent_file("pause", tracker.url().as_str(), &[0; 10]);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        torrent.pause().await.unwrap();
+        assert!(torrent.is_paused());
+        assert!(tracker.next_request().await.contains("&left=10&compact=1&event=stopped"));
+
+        // pausing twice doesn't announce again
+        torrent.pause().await.unwrap();
+        torrent.resume().await.unwrap();
+        assert!(!torrent.is_paused());
+        assert!(tracker.next_request().await.contains("&event=started"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn echoes_tracker_id() {
+        let mut tracker = MockTracker::start(b"d8:intervali1800e5:peers0:10:tracker id7:abc-123e").await;
+        let (path, _) = torrent_file("tracker_id", tracker.url().as_str(), &[0; 10]);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        torrent.pause().await.unwrap();
+        assert!(!tracker.next_request().await.contains("trackerid"));
+
+        torrent.resume().await.unwrap();
+        assert!(tracker.next_request().await.contains("&event=started&trackerid=abc-123"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_swarm_size() {
+        let tracker = MockTracker::start(b"d8:completei5e8:intervali1800e10:incompletei3e5:peers0:e").await;
+        let (path, _) = torrent_file("swarm_size", tracker.url().as_str(), &[0; 10]);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+        let mut events = torrent.subscribe();
+
+        let stats = torrent.stats().await;
+        assert_eq!((stats.seeders(), stats.leechers()), (None, None));
+
+        torrent.pause().await.unwrap();
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(event, ProgressEvent::Announced { tracker: tracker.url().to_string(), seeders: Some(5), leechers: Some(3) });
+
+        let stats = torrent.stats().await;
+        assert_eq!((stats.seeders(), stats.leechers()), (Some(5), Some(3)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reannounce_returns_peers() {
+        // accepted by the os but never answered, the download keeps running
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+
+        for (name, min_interval) in [("reannounce", ""), ("reannounce_limited", "12:min intervali1800e")] {
+            let mut body = format!("d8:intervali1800e{}5:peers6:", min_interval).into_bytes();
+            body.extend_from_slice(&[127, 0, 0, 1]);
+            body.extend_from_slice(&peer.port().to_be_bytes());
+            body.push(b'e');
+
+            let mut tracker = MockTracker::start(&body).await;
+            let (path, _) = torrent_file(name, tracker.url().as_str(), &[0; 10]);
+            let torrent = Torrent::new(&path, config()).await.unwrap();
+
+            assert!(matches!(torrent.reannounce().await, Err(Error::NotAnnouncing)));
+
+            tokio::select! {
+                _ = torrent.download() => panic!("the download can't finish"),
+                _ = async {
+                    assert!(tracker.next_request().await.contains("&event=started"));
+
+                    if min_interval.is_empty() {
+                        assert_eq!(torrent.reannounce().await.unwrap(), 1);
+                        assert!(!tracker.next_request().await.contains("&event="));
+                    } else {
+                        assert!(matches!(torrent.reannounce().await, Err(Error::RateLimited(wait)) if wait > Duration::from_secs(1790)));
+                    }
+                } => {}
+            }
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn seeds_after_completing() {
+        let data: Vec<u8> = (0..10).collect();
+
+        let seeder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let leecher = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let leecher_address = leecher.local_addr().unwrap();
+
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\0\0\x01".to_vec();
+        body.extend_from_slice(&seeder.local_addr().unwrap().port().to_be_bytes());
+        body.push(b'e');
+
+        let mut tracker = MockTracker::start(&body).await;
+        let (path, download) = torrent_file("seed", tracker.url().as_str(), &data);
+
+        let mut config = config();
+        config.seed_after_complete = true;
+        let torrent = Torrent::new(&path, config).await.unwrap();
+
+        let info_hash = *torrent.info_hash();
+        let block = data.clone();
+
+        tokio::spawn(async move {
+            let (stream, _) = seeder.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        let block = data.clone();
+
+        let leeching = tokio::spawn(async move {
+            let (stream, _) = leecher.accept().await.unwrap();
+
+            MockPeer::new(stream).run(vec![
+                Step::Handshake { info_hash },
+                Step::Expect(Message::Bitfield(vec![0b1000_0000])),
+                Step::Send(Message::Interested),
+                Step::Expect(Message::Unchoke),
+                Step::Send(Message::Request { index: 0, begin: 0, length: 10 }),
+                Step::Expect(Message::Piece { index: 0, begin: 0, block }),
+            ]).await;
+        });
+
+        tokio::select! {
+            _ = torrent.download() => panic!("seeding doesn't finish"),
+            _ = async {
+                assert!(tracker.next_request().await.contains("&event=started"));
+                assert!(tracker.next_request().await.contains("&left=0&compact=1&event=completed"));
+
+                // the download keeps running and uploads to peers found afterwards
+                torrent.add_peer(leecher_address);
+                time::timeout(Duration::from_secs(5), leeching).await.unwrap().unwrap();
+            } => {}
+        }
+
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+        assert_eq!(torrent.stats().await.uploaded(), 10);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_seeding_at_ratio_limit() {
+        let data: Vec<u8> = (0..10).collect();
+        let seeder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\0\0\x01".to_vec();
+        body.extend_from_slice(&seeder.local_addr().unwrap().port().to_be_bytes());
+        body.push(b'e');
+
+        let mut tracker = MockTracker::start(&body).await;
+        let (path, download) = torrent_file("seed_ratio", tracker.url().as_str(), &data);
+
+        let mut config = config();
+        config.seed_after_complete = true;
+        config.seed_ratio_limit = Some(1.0);
+        config.seed_time_limit = Some(Duration::from_secs(3600));
+        let torrent = Torrent::new(&path, config).await.unwrap();
+
+        let info_hash = *torrent.info_hash();
+        let block = data.clone();
+
+        tokio::spawn(async move {
+            let (stream, _) = seeder.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        let (result, ()) = tokio::join!(time::timeout(Duration::from_secs(10), torrent.download()), async {
+            assert!(tracker.next_request().await.contains("&event=started"));
+            assert!(tracker.next_request().await.contains("&uploaded=0&downloaded=10&left=0&compact=1&event=completed"));
+
+            // half of the 10 downloaded bytes
+            torrent.total_uploaded.fetch_add(5, Ordering::Relaxed);
+            assert_eq!(torrent.ratio().await, 0.5);
+            assert!(!torrent.seed_limit_reached(Duration::from_secs(60)).await);
+            assert!(torrent.seed_limit_reached(Duration::from_secs(3600)).await);
+
+            torrent.total_uploaded.fetch_add(6, Ordering::Relaxed);
+        });
+
+        result.unwrap().unwrap();
+        assert!(tracker.next_request().await.contains("&event=stopped"));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn announces_to_all_trackers() {
+        let data: Vec<u8> = (0..10).collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // only the backup tracker knows the peer
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\0\0\x01".to_vec();
+        body.extend_from_slice(&address.port().to_be_bytes());
+        body.push(b'e');
+
+        let mut main = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let mut backup = MockTracker::start(&body).await;
+        let (main_url, backup_url) = (main.url().to_string(), backup.url().to_string());
+
+        let (path, download) = torrent_file("all_trackers", &main_url, &data);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let list = format!("13:announce-listll{}:{}el{}:{}ee", main_url.len(), main_url, backup_url.len(), backup_url);
+        let info = bytes.windows(7).position(|window| window == b"4:infod").unwrap();
+        bytes.splice(info..info, list.into_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut config = config();
+        config.announce_to_all = true;
+        let torrent = Torrent::new(&path, config).await.unwrap();
+
+        // the main tracker is in the list too but only announced to once
+        assert_eq!(torrent.trackers(), vec![main_url, backup_url]);
+
+        let info_hash = *torrent.info_hash();
+        let block = data.clone();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+
+        assert!(main.next_request().await.contains("&event=started"));
+        assert!(backup.next_request().await.contains("&event=started"));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_connected_peers() {
+        let (path, _) = torrent_file("peers", "http://127.0.0.1:1/announce", &[0; 10]);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+        assert!(torrent.peers().await.is_empty());
+
+        let (mut peer, _mock) = MockPeer::pair(8);
+        peer.update_bitfield(vec![0b1011_0000]).unwrap();
+        peer.set_is_choking(false);
+
+        let mut status = PeerStatus::new();
+        status.update(&peer);
+        status.downloaded.record(100);
+
+        let (commands, _receiver) = mpsc::channel(1);
+        let peer = ConnectedPeer { commands, status: Arc::new(RwLock::new(status)) };
+        torrent.connected_peers.write().await.insert(address(), peer);
+
+        let peers = torrent.peers().await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!((peers[0].address(), peers[0].is_choking(), peers[0].am_interested()), (address(), false, false));
+        assert_eq!((peers[0].pieces(), peers[0].downloaded(), peers[0].upload_rate()), (3, 100, 0.0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rechoke_unchokes_fastest_interested_peers() {
+        let (path, _) = torrent_file("rechoke", "http://127.0.0.1:1/announce", &[0; 10]);
+
+        let mut config = config();
+        config.upload_slots = 1;
+        let torrent = Torrent::new(&path, config).await.unwrap();
+
+        let mut receivers = Vec::new();
+
+        // (interested, am choking, bytes downloaded from the peer)
+        for (port, (interested, choking, downloaded)) in [(true, true, 300), (true, false, 100), (false, false, 0)].into_iter().enumerate() {
+            let mut status = PeerStatus::new();
+            status.is_interested = interested;
+            status.am_choking = choking;
+            status.downloaded.record(downloaded);
+
+            let (commands, receiver) = mpsc::channel(1);
+            let peer = ConnectedPeer { commands, status: Arc::new(RwLock::new(status)) };
+            torrent.connected_peers.write().await.insert(SocketAddr::from(([127, 0, 0, 1], port as u16 + 1)), peer);
+            receivers.push(receiver);
+        }
+
+        torrent.rechoke().await;
+
+        assert!(matches!(receivers[0].try_recv(), Ok(PeerCommand::Unchoke)));
+        assert!(matches!(receivers[1].try_recv(), Ok(PeerCommand::Choke)));
+        assert!(receivers[2].try_recv().is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_choker_config() {
+        let (path, _) = torrent_file("no_slots", "http://127.0.0.1:1/announce", &[0; 10]);
+
+        let mut config = self::config();
+        config.upload_slots = 0;
+        assert!(matches!(Torrent::new(&path, config).await, Err(Error::InvalidConfig(_))));
+
+        let mut config = self::config();
+        config.choke_interval = Duration::ZERO;
+        assert!(matches!(Torrent::new(&path, config).await, Err(Error::InvalidConfig(_))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_from_added_peer() {
+        let data: Vec<u8> = (0..10).collect();
+
+        // the tracker knows no peers
+        let tracker = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let (path, download) = torrent_file("added_peer", tracker.url().as_str(), &data);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let info_hash = *torrent.info_hash();
+        let block = data.clone();
+
+        // accepts a single connection, so adding the peer twice must not connect twice
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        torrent.add_peer(address);
+        torrent.add_peer(address);
+
+        let stats = torrent.stats().await;
+        assert_eq!((stats.pieces_complete(), stats.pieces_total(), stats.left()), (0, 1, 10));
+        assert_eq!(stats.eta(), None);
+        assert_eq!(torrent.eta().await, None);
+
+        let mut events = torrent.subscribe();
+
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+
+        // the first stats are sent as soon as the download starts
+        let mut sent_stats = false;
+
+        while let Ok(event) = events.try_recv() {
+            sent_stats |= matches!(event, ProgressEvent::Stats(_));
+        }
+
+        assert!(sent_stats);
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+
+        let stats = torrent.stats().await;
+        assert_eq!((stats.pieces_complete(), stats.left(), stats.downloaded()), (1, 0, 10));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(download).unwrap();
+    }
+
+    /// Like `torrent_file` for a torrent of `files` named by their single letter, with pieces
+    /// of `piece_length`. Returns the torrent path and the directory the files go to
+    fn multi_file_torrent(name: &str, announce: &str, files: &[(char, &[u8])], piece_length: usize) -> (String, PathBuf) {
+        let download = std::env::temp_dir().join(format!("torrent_client_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&download);
+        let dir = download.file_name().unwrap().to_str().unwrap();
+
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.iter().copied()).collect();
+        let list: String = files.iter().map(|(name, data)| format!("d6:lengthi{}e4:pathl1:{}ee", data.len(), name)).collect();
+
+        let mut bytes = format!(
+            "d8:announce{}:{}4:infod5:filesl{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+            announce.len(), announce, list, dir.len(), dir, piece_length, data.len().div_ceil(piece_length) * 20,
+        ).into_bytes();
+
+        for piece in data.chunks(piece_length) {
+            bytes.extend_from_slice(&Sha1::digest(piece));
+        }
+
+        bytes.extend_from_slice(b"ee");
+
+        let path = download.with_extension("torrent");
+        std::fs::write(&path, bytes).unwrap();
+
+        (path.to_str().unwrap().to_string(), download)
+    }
+
+    #[tokio::test]
+    async fn downloads_wanted_files() {
+        // pieces of 8 bytes: `a` is in 0 and 1, `b` fills the rest of 1, `c` is in 2 and 3
+        let (a, b, c) = (vec![1u8; 10], vec![2u8; 6], vec![3u8; 16]);
+        let piece = [&a[8..], &b[..]].concat();
+
+        let tracker = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let (path, download) = multi_file_torrent("wanted", tracker.url().as_str(), &[('a', &a), ('b', &b), ('c', &c)], 8);
+
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+        torrent.set_wanted_files(&[1]).await;
+        assert_eq!(torrent.stats().await.left(), 8);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let info_hash = *torrent.info_hash();
+        let block = piece.clone();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1111_0000, vec![(1, block)])).await;
+        });
+
+        torrent.add_peer(address);
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+
+        // `a` shares the wanted piece so its part of it is written, `c` is never created
+        assert_eq!(std::fs::read(download.join("b")).unwrap(), b);
+        assert_eq!(std::fs::read(download.join("a")).unwrap()[8..], a[8..]);
+        assert!(!download.join("c").exists());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(download).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_short_last_piece_of_multiple_files() {
+        // 13 bytes in pieces of 8, the last piece is 5 bytes and all of it in `y`
+        let (x, y): (Vec<u8>, Vec<u8>) = ((0..6).collect(), (6..13).collect());
+        let data = [&x[..], &y[..]].concat();
+
+        let tracker = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let (path, download) = multi_file_torrent("short_last", tracker.url().as_str(), &[('x', &x), ('y', &y)], 8);
+        let torrent = Torrent::new(&path, config()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let info_hash = *torrent.info_hash();
+        let (first, last) = (data[..8].to_vec(), data[8..].to_vec());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(info_hash, 0b1100_0000, vec![(0, first), (1, last)])).await;
+        });
+
+        torrent.add_peer(address);
+        time::timeout(Duration::from_secs(5), torrent.download()).await.unwrap().unwrap();
+
+        assert_eq!(torrent.stats().await.left(), 0);
+        assert_eq!(std::fs::read(download.join("x")).unwrap(), x);
+        assert_eq!(std::fs::read(download.join("y")).unwrap(), y);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(download).unwrap();
+    }
+
+    /// Script of a peer with the pieces of `bitfield` that serves each of `pieces`, a piece
+    /// index and the block that is the whole piece, as we request them in order
+    fn serve_pieces(info_hash: InfoHash, bitfield: u8, pieces: Vec<(u32, Vec<u8>)>) -> Vec<Step> {
+        let mut steps = vec![
+            Step::Handshake { info_hash },
+            Step::Send(Message::Bitfield(vec![bitfield])),
+            Step::Expect(Message::Interested),
+            Step::Send(Message::Unchoke),
+        ];
+
+        for (index, block) in pieces {
+            steps.push(Step::Expect(Message::Request { index, begin: 0, length: block.len() as u32 }));
+            steps.push(Step::Send(Message::Piece { index, begin: 0, block }));
+        }
+
+        steps
+    }
+
+    /// Context for a torrent of `pieces` that are all missing, written blocks go to the returned receiver
+    fn context(pieces: u32, piece_length: u32, last_piece_length: u32) -> (PeerContext, mpsc::Receiver<WriteMessage>) {
+        let (sender, receiver) = mpsc::channel(16);
+
+        // only read from by tests uploading, which write the file themselves
+        let length = (pieces - 1) * piece_length + last_piece_length;
+        let mut torrent = format!("d8:announce9:localhost4:infod6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces{}:", length, piece_length, pieces * 20).into_bytes();
+        torrent.extend(std::iter::repeat_n(0, pieces as usize * 20));
+        torrent.extend_from_slice(b"ee");
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+        let paths = metainfo.info().file_paths();
+
+        let context = PeerContext {
+            info_hash: InfoHash::new([7; 20]),
+            peer_id: PeerId::new([0; 20]),
+            piece_length,
+            last_piece_length,
+            state: Arc::new(RwLock::new(DownloadState::new(pieces as usize))),
+            sender,
+            reader: Arc::new(Mutex::new(PieceReader::new(Arc::new(metainfo), CachedStorage::new(FileStorage::new(paths), PieceCache::new(0))))),
+            downloaded: Arc::new(RwLock::new(RateMeter::new())),
+            uploaded: Arc::new(RwLock::new(RateMeter::new())),
+            total_downloaded: Arc::new(AtomicU64::new(0)),
+            total_uploaded: Arc::new(AtomicU64::new(0)),
+            seed: false,
+            retries: 2,
+            retry_backoff: Duration::from_millis(1),
+            read_buffer: peer::DEFAULT_READ_BUFFER,
+            handshake_timeout: peer::DEFAULT_HANDSHAKE_TIMEOUT,
+            bind_address: None,
+            proxy: None,
+            scheduler: &RarestFirst,
+            super_seed: None,
+            paused: watch::channel(false).1,
+            #[cfg(debug_assertions)]
+            disabled_messages: Arc::from([]),
+        };
+
+        (context, receiver)
+    }
+
+    #[tokio::test]
+    async fn downloads_from_mock_peer() {
+        let data: Vec<u8> = (0..34).collect();
+        let (context, mut receiver) = context(2, 24, 10);
+
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let mut steps = serve_pieces(InfoHash::new([7; 20]), 0b1100_0000, vec![(0, data[..24].to_vec()), (1, data[24..].to_vec())]);
+        // the peer has nothing left we need
+        steps.push(Step::Expect(Message::NotInterested));
+        let script = mock.run(steps);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+        result.unwrap();
+        assert!(!peer.am_interested());
+
+        let status = status.read().await;
+        assert_eq!((status.is_choking, status.pieces, status.downloaded.total()), (false, 2, 34));
+
+        let mut downloaded = Vec::new();
+
+        while let Ok(message) = receiver.try_recv() {
+            downloaded.extend_from_slice(message.block());
+        }
+
+        assert_eq!(downloaded, data);
+
+        // both stay requested until the writer verifies them
+        assert_eq!(context.state.read().await.requested_from(address()), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn rejects_have_out_of_range() {
+        let (context, _receiver) = context(2, 24, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Send(Message::Have(1)),
+            Step::Expect(Message::Interested),
+            Step::Send(Message::Have(2)),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+
+        assert!(matches!(result, Err(Error::PeerError(peer::Error::InvalidPieceIndex { index: 2, pieces: 2 }))));
+        assert_eq!(peer.bitfield().len(), 2);
+
+        let state = context.state.read().await;
+        assert_eq!((state.availability(0), state.availability(1)), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn ignores_late_bitfield() {
+        let data: Vec<u8> = (0..10).collect();
+        let (context, mut receiver) = context(2, 24, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Send(Message::Have(1)),
+            Step::Expect(Message::Interested),
+            // would say the peer has piece 0 instead of 1
+            Step::Send(Message::Bitfield(vec![0b1000_0000])),
+            Step::Send(Message::Unchoke),
+            Step::Expect(Message::Request { index: 1, begin: 0, length: 10 }),
+            Step::Send(Message::Piece { index: 1, begin: 0, block: data.clone() }),
+            Step::Expect(Message::NotInterested),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+        result.unwrap();
+
+        assert_eq!(peer.bitfield().iter().collect::<Vec<_>>(), [false, true]);
+        assert_eq!(receiver.try_recv().unwrap().block(), &data);
+
+        let state = context.state.read().await;
+        assert_eq!((state.availability(0), state.availability(1)), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn drops_connections_to_ourselves() {
+        let (mut context, _receiver) = context(2, 24, 10);
+        context.peer_id = PeerId::new(*b"-MOCK00-000000000000");
+
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        // the bitfield would never be read
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Send(Message::Bitfield(vec![0b1100_0000])),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+        result.unwrap();
+        assert!(!peer.am_interested());
+
+        assert!(is_own_address("127.0.0.1:6881".parse().unwrap(), None));
+        assert!(!is_own_address("127.0.0.1:6882".parse().unwrap(), None));
+        assert!(!is_own_address("10.0.0.1:6881".parse().unwrap(), None));
+        assert!(is_own_address("10.0.0.1:6881".parse().unwrap(), Some("10.0.0.1".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_ip() {
+        let (path, _) = torrent_file("per_ip", "http://localhost:1/announce", &[0; 10]);
+        let mut config = config();
+        config.max_connections_per_ip = 2;
+        let torrent = Torrent::new(&path, config).await.unwrap();
+        let (context, _receiver) = context(2, 24, 10);
+
+        // accepted by the os but never answered, the peers stay connected
+        let mut listeners = Vec::new();
+
+        for ip in ["127.0.0.1", "127.0.0.1", "127.0.0.1", "127.0.0.2"] {
+            listeners.push(TcpListener::bind((ip, 0)).await.unwrap());
+        }
+
+        for listener in &listeners {
+            torrent.spawn_peer(listener.local_addr().unwrap(), None, &context).await;
+        }
+
+        let connected = torrent.connected_peers.read().await;
+        let mut ips: Vec<_> = connected.keys().map(SocketAddr::ip).collect();
+        ips.sort();
+
+        assert_eq!(ips, ["127.0.0.1".parse::<std::net::IpAddr>().unwrap(), "127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()]);
+        assert!(!connected.contains_key(&listeners[2].local_addr().unwrap()));
+        drop(connected);
+
+        torrent.shutdown_peers().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_unrequested_blocks() {
+        let data: Vec<u8> = (0..34).collect();
+        let (context, mut receiver) = context(2, 24, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Send(Message::Bitfield(vec![0b1100_0000])),
+            Step::Expect(Message::Interested),
+            Step::Send(Message::Unchoke),
+            Step::Expect(Message::Request { index: 0, begin: 0, length: 24 }),
+            // another piece, a wrong offset and a wrong length
+            Step::Send(Message::Piece { index: 1, begin: 0, block: vec![0xff; 10] }),
+            Step::Send(Message::Piece { index: 0, begin: 8, block: vec![0xff; 16] }),
+            Step::Send(Message::Piece { index: 0, begin: 0, block: vec![0xff; 8] }),
+            Step::Send(Message::Piece { index: 0, begin: 0, block: data[..24].to_vec() }),
+            Step::Expect(Message::Request { index: 1, begin: 0, length: 10 }),
+            Step::Send(Message::Piece { index: 1, begin: 0, block: data[24..].to_vec() }),
+            Step::Expect(Message::NotInterested),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+        result.unwrap();
+
+        let mut downloaded = Vec::new();
+
+        while let Ok(message) = receiver.try_recv() {
+            downloaded.extend_from_slice(message.block());
+        }
+
+        assert_eq!(downloaded, data);
+        assert_eq!(status.read().await.downloaded.total(), 34);
+    }
+
+    #[tokio::test]
+    async fn disconnects_peer_flooding_unrequested_blocks() {
+        let (context, mut receiver) = context(2, 10, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let mut steps = vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Send(Message::Bitfield(vec![0b1100_0000])),
+            Step::Expect(Message::Interested),
+            Step::Send(Message::Unchoke),
+            Step::Expect(Message::Request { index: 0, begin: 0, length: 10 }),
+        ];
+        steps.extend((0..MAX_UNREQUESTED_BLOCKS).map(|_| Step::Send(Message::Piece { index: 1, begin: 0, block: vec![0; 10] })));
+        steps.push(Step::Expect(Message::NotInterested));
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), mock.run(steps));
+        result.unwrap();
+
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn disconnects_banned_peer() {
+        let (context, mut receiver) = context(2, 10, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        // an earlier piece from this address failed verification
+        context.state.write().await.ban(address().ip());
+
+        let script = mock.run(serve_pieces(InfoHash::new([7; 20]), 0b1100_0000, vec![(0, vec![0; 10])]));
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+        result.unwrap();
+
+        // nothing it sent is written and its piece goes back to the pool
+        assert!(receiver.try_recv().is_err());
+        downloading_piece.release().await;
+        assert!(context.state.read().await.is_missing(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_keep_alive_while_idle() {
+        let (context, _receiver) = context(2, 24, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        // the peer never says anything after the handshake
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Expect(Message::KeepAlive),
+            Step::Expect(Message::KeepAlive),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let (result, ()) = tokio::join!(exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability), script);
+
+        // the mock hangs up after its script
+        assert!(matches!(result, Err(Error::PeerError(peer::Error::IoError(_)))));
+    }
+
+    #[tokio::test]
+    async fn downloads_over_ipv6() {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let data: Vec<u8> = (0..10).collect();
+        let block = data.clone();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(InfoHash::new([7; 20]), 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        let (inbound, mut writes) = context(1, 10, 10);
+        let (context, mut receiver) = context(1, 10, 10);
+        let (_sender, mut commands) = mpsc::channel(1);
+
+        handle_peer_with_retries(address, &context, &mut commands, &RwLock::new(PeerStatus::new())).await.unwrap();
+
+        assert_eq!(receiver.recv().await.unwrap().block(), &data);
+
+        // and from one connecting to us
+        let listener = interface::listen(None, 0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let block = data.clone();
+
+        tokio::spawn(async move {
+            let stream = TcpStream::connect(("::1", port)).await.unwrap();
+
+            MockPeer::new(stream).run(serve_pieces(InfoHash::new([7; 20]), 0b1000_0000, vec![(0, block)])).await;
+        });
+
+        let (stream, address) = listener.accept().await.unwrap();
+        assert!(address.is_ipv6());
+
+        handle_inbound_peer(address, stream, &inbound, &mut commands, &RwLock::new(PeerStatus::new())).await.unwrap();
+
+        assert_eq!(writes.recv().await.unwrap().block(), &data);
+    }
+
+    #[tokio::test]
+    async fn paused_torrent_drops_inbound_peers() {
+        let (mut context, mut receiver) = context(1, 10, 10);
+        let (_pause, paused) = watch::channel(true);
+        context.paused = paused;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let remote = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+
+            let mut handshake = [0u8; 68];
+            handshake[0] = 19;
+            handshake[1..20].copy_from_slice(b"BitTorrent protocol");
+            handshake[28..48].copy_from_slice(&[7; 20]);
+            handshake[48..].copy_from_slice(b"-MOCK00-000000000000");
+            stream.write_all(&handshake).await.unwrap();
+            stream.read_exact(&mut handshake).await.unwrap();
+
+            // offers the piece, the connection may already be closed
+            let _ = stream.write_all(&Message::Bitfield(vec![0b1000_0000]).to_bytes()).await;
+            let _ = stream.write_all(&Message::Unchoke.to_bytes()).await;
+
+            // nothing after the handshake, no interested nor request
+            let mut rest = [0u8; 4];
+            assert!(matches!(stream.read(&mut rest).await, Ok(0) | Err(_)));
+        });
+
+        let (stream, peer_address) = listener.accept().await.unwrap();
+        let (_sender, mut commands) = mpsc::channel(1);
+
+        handle_inbound_peer(peer_address, stream, &context, &mut commands, &RwLock::new(PeerStatus::new())).await.unwrap();
+        time::timeout(Duration::from_secs(5), remote).await.unwrap().unwrap();
+
+        assert!(receiver.try_recv().is_err());
+        assert!(context.state.read().await.requested_from(peer_address).is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_peer_dropping_mid_piece() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        // a peer with both pieces that disconnects as soon as a block is requested
+        let accepted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let (mut remote, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                let mut handshake = [0u8; 68];
+                remote.read_exact(&mut handshake).await.unwrap();
+                // echoed with our peer id it would look like connecting to ourselves
+                handshake[48..].copy_from_slice(b"-MOCK00-000000000000");
+                remote.write_all(&handshake).await.unwrap();
+                remote.write_all(&[0, 0, 0, 2, 5, 0b1100_0000, 0, 0, 0, 1, 1]).await.unwrap();
+
+                // interested and request
+                let mut messages = [0u8; 5 + 17];
+                remote.read_exact(&mut messages).await.unwrap();
+            }
+        });
+
+        let (context, _receiver) = context(2, 32768, 32768);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let result = handle_peer_with_retries(address, &context, &mut commands, &RwLock::new(PeerStatus::new())).await;
+
+        assert!(matches!(result, Err(Error::PeerError(peer::Error::IoError(_)))));
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        // the piece that was being downloaded is missing again
+        let state = context.state.read().await;
+        assert!(state.is_missing(0) && state.is_missing(1));
+    }
+
+    #[tokio::test]
+    async fn shutdown_command() {
+        let (context, _receiver) = context(2, 24, 10);
+        let (mut peer, mock) = MockPeer::pair(2);
+
+        let (sender, mut commands) = mpsc::channel(4);
+        sender.send(PeerCommand::Unchoke).await.unwrap();
+        sender.send(PeerCommand::SendHave(1)).await.unwrap();
+        sender.send(PeerCommand::Shutdown).await.unwrap();
+
+        let script = tokio::spawn(mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            Step::Expect(Message::Unchoke),
+            Step::Expect(Message::Have(1)),
+        ]));
+
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+        let result = time::timeout(Duration::from_secs(1), exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability)).await;
+
+        // the peer stays connected, only the command ends the task
+        assert!(matches!(result, Ok(Ok(()))));
+        script.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn super_seeds_one_piece_at_a_time() {
+        let data: Vec<u8> = (0..30).collect();
+        let download = std::env::temp_dir().join(format!("torrent_client_super_seed_{}", std::process::id()));
+        std::fs::write(&download, &data).unwrap();
+
+        let mut torrent = b"d8:announce9:localhost4:infod6:lengthi30e4:name4:test12:piece lengthi10e6:pieces60:".to_vec();
+        torrent.extend([0; 60]);
+        torrent.extend_from_slice(b"ee");
+        let metainfo = MetaInfo::from_bencode(&torrent).unwrap();
+
+        let (mut context, _receiver) = context(3, 10, 10);
+        context.reader = Arc::new(Mutex::new(PieceReader::new(Arc::new(metainfo), CachedStorage::new(FileStorage::new(vec![download.clone()]), PieceCache::new(0)))));
+        context.super_seed = Some(Arc::new(Mutex::new(SuperSeed::new(3))));
+        context.seed = true;
+
+        for piece in 0..3 {
+            context.state.write().await.complete(piece);
+        }
+
+        let (mut peer, mock) = MockPeer::pair(3);
+
+        let script = mock.run(vec![
+            Step::Handshake { info_hash: InfoHash::new([7; 20]) },
+            // none of the pieces but the offered one are advertised
+            Step::Expect(Message::Bitfield(vec![0])),
+            Step::Expect(Message::Have(0)),
+            Step::Send(Message::Interested),
+            Step::Expect(Message::Unchoke),
+            // half of the piece isn't enough for the next offer
+            Step::Send(Message::Request { index: 0, begin: 0, length: 5 }),
+            Step::Expect(Message::Piece { index: 0, begin: 0, block: data[..5].to_vec() }),
+            Step::Send(Message::Request { index: 0, begin: 5, length: 5 }),
+            Step::Expect(Message::Piece { index: 0, begin: 5, block: data[5..10].to_vec() }),
+            Step::Expect(Message::Have(1)),
+            Step::Send(Message::Request { index: 1, begin: 0, length: 10 }),
+            Step::Expect(Message::Piece { index: 1, begin: 0, block: data[10..20].to_vec() }),
+            Step::Expect(Message::Have(2)),
+        ]);
+
+        let (_sender, mut commands) = mpsc::channel(1);
+        let mut downloading_piece = context.downloading_piece(address());
+        let mut availability = context.peer_availability();
+        let status = RwLock::new(PeerStatus::new());
+
+        tokio::select! {
+            _ = exchange_messages(&mut peer, &context, &mut commands, &status, &mut downloading_piece, &mut availability) => panic!("seeding to a peer without every piece doesn't stop"),
+            _ = time::timeout(Duration::from_secs(5), script) => (),
+        }
+
+        assert_eq!(context.total_uploaded.load(Ordering::Relaxed), 20);
+
+        std::fs::remove_file(download).unwrap();
+    }
+}
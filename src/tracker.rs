@@ -1,13 +1,20 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, IpAddr};
-use std::io::{self, Write, Cursor};
+use std::io::{self, Write, Cursor, Read};
 use std::str::from_utf8;
+use std::time::Duration;
 
-use tokio::io::{AsyncWriteExt, AsyncReadExt, BufReader};
-use tokio::net::TcpStream;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, AsyncReadExt, BufReader};
+use tokio::net::{self as net, TcpStream};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
-use url::Url;
+use tokio::time;
+use url::{Host, Url};
 
 use crate::bencode::{FromBencode, self, Bedecode, Type, FromBencodeType};
+use crate::info_hash::InfoHash;
+use crate::interface;
+use crate::peer_id::PeerId;
+use crate::proxy::{self, ProxyConfig};
 
 
 #[derive(Debug)]
@@ -20,18 +27,47 @@ pub enum Error {
     MissingIncomplete,
     MissingPeers,
     MissingPeerId,
+    /// A peer id that isn't 20 bytes long
+    InvalidPeerId,
     MissingPeerIp,
     MissingPeerPort,
     EmptyResponse,
+    InvalidHttpResponse,
+    UnsupportedEncoding(String),
+    InvalidEncoding(io::Error),
+    Failure(String),
+    /// The tracker url has no host or port, or its host resolved to nothing
+    NoAddress(String),
+    /// Resolving the host of the tracker url took longer than allowed
+    DnsTimeout(String),
+    ProxyError(proxy::Error),
+    /// Only http trackers can be reached through a proxy
+    UnsupportedByProxy(String),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::IoError(err) => write!(f, "{}", err),
-            Self::ParseError(err) => write!(f, "{}", err),
-            Self::DecodingError(_err) => todo!(),
-            _ => todo!(),
+            Self::IoError(_) => write!(f, "Tracker connection failed"),
+            Self::ParseError(_) => write!(f, "Invalid tracker url"),
+            Self::DecodingError(_) => write!(f, "Could not decode tracker response"),
+            Self::MissingInterval => write!(f, "Tracker response is missing the interval"),
+            Self::MissingComplete => write!(f, "Tracker response is missing complete"),
+            Self::MissingIncomplete => write!(f, "Tracker response is missing incomplete"),
+            Self::MissingPeers => write!(f, "Tracker response is missing the peers"),
+            Self::MissingPeerId => write!(f, "Tracker peer is missing its peer id"),
+            Self::InvalidPeerId => write!(f, "Tracker peer has an invalid peer id"),
+            Self::MissingPeerIp => write!(f, "Tracker peer is missing its ip"),
+            Self::MissingPeerPort => write!(f, "Tracker peer is missing its port"),
+            Self::EmptyResponse => write!(f, "Tracker sent an empty response"),
+            Self::InvalidHttpResponse => write!(f, "Tracker sent an invalid http response"),
+            Self::UnsupportedEncoding(encoding) => write!(f, "Unsupported tracker response encoding: {}", encoding),
+            Self::InvalidEncoding(_) => write!(f, "Could not decompress tracker response"),
+            Self::Failure(reason) => write!(f, "Tracker refused the announce: {}", reason),
+            Self::NoAddress(url) => write!(f, "Could not resolve tracker {}", url),
+            Self::DnsTimeout(url) => write!(f, "Resolving tracker {} timed out", url),
+            Self::ProxyError(_) => write!(f, "Could not reach the tracker through the proxy"),
+            Self::UnsupportedByProxy(url) => write!(f, "Tracker {} can't be reached through a proxy", url),
         }
     }
 }
@@ -42,6 +78,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<proxy::Error> for Error {
+    fn from(value: proxy::Error) -> Self {
+        Self::ProxyError(value)
+    }
+}
+
 impl From<url::ParseError> for Error {
     fn from(value: url::ParseError) -> Self {
         Self::ParseError(value)
@@ -54,7 +96,18 @@ impl From<bencode::Error> for Error {
     }
 }
 
-impl std::error::Error for Error { }
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(err) => Some(err),
+            Self::ParseError(err) => Some(err),
+            Self::DecodingError(err) => Some(err),
+            Self::InvalidEncoding(err) => Some(err),
+            Self::ProxyError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 
 pub enum IpType {
@@ -62,15 +115,17 @@ pub enum IpType {
     Ipv6(Ipv6Addr),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Started,
     Stopped,
     Completed,
 }
 
+#[derive(Clone)]
 pub struct TrackerRequest {
-    info_hash: [u8; 20],
-    peer_id: [u8; 20],
+    info_hash: InfoHash,
+    peer_id: PeerId,
     port: u16,
     uploaded: u128,
     downloaded: u128,
@@ -85,7 +140,8 @@ pub struct TrackerRequest {
 }
 
 impl TrackerRequest {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], port: u16, uploaded: u128, downloaded: u128, left: u128, compact: bool, no_peer_id: bool) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(info_hash: InfoHash, peer_id: PeerId, port: u16, uploaded: u128, downloaded: u128, left: u128, compact: bool, no_peer_id: bool) -> Self {
         Self {
             info_hash,
             peer_id,
@@ -105,9 +161,17 @@ impl TrackerRequest {
 }
 
 impl TrackerRequest {
+    pub fn set_event(&mut self, event: Option<Event>) {
+        self.event = event;
+    }
+
+    pub fn set_trackerid(&mut self, trackerid: Option<String>) {
+        self.trackerid = trackerid;
+    }
+
     pub fn create_request(&self, path: &str, host: &str) -> Vec<u8> {   
-       let info_hash: String = url::form_urlencoded::byte_serialize(&self.info_hash).collect();
-       let peer_id: String = url::form_urlencoded::byte_serialize(&self.peer_id).collect();
+       let info_hash: String = url::form_urlencoded::byte_serialize(self.info_hash.as_bytes()).collect();
+       let peer_id: String = url::form_urlencoded::byte_serialize(self.peer_id.as_bytes()).collect();
 
        let mut request = Vec::new();
        let mut cursor = Cursor::new(&mut request);
@@ -148,7 +212,7 @@ impl TrackerRequest {
             write!(cursor, "&trackerid={}", trackerid).unwrap()
         }
 
-        write!(cursor, " HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\r\n", host).unwrap();
+        write!(cursor, " HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\nAccept-Encoding: gzip, deflate\r\nConnection: close\r\n\r\n", host).unwrap();
 
        request
     }
@@ -157,7 +221,41 @@ impl TrackerRequest {
 #[derive(Debug)]
 pub enum Peers {
     Binary(Vec<SocketAddr>),
-    Dictionary(Vec<(SocketAddr, String)>),
+    Dictionary(Vec<(SocketAddr, PeerId)>),
+}
+
+impl Peers {
+    /// Addresses of the peers regardless of the model the tracker used
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        match self {
+            Self::Binary(peers) => peers.clone(),
+            Self::Dictionary(peers) => peers.iter().map(|(addr, _)| *addr).collect(),
+        }
+    }
+}
+
+/// Parses the compact model of `peers`, 4 bytes of address and 2 of port per peer
+pub(crate) fn compact_peers(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks_exact(6)
+        .map(|addr_bytes| {
+            let ip = Ipv4Addr::new(addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3]);
+            let port = u16::from_be_bytes([addr_bytes[4], addr_bytes[5]]);
+
+            SocketAddr::new(IpAddr::V4(ip), port)
+        })
+        .collect()
+}
+
+/// Parses the `peers6` key, 16 bytes of address and 2 of port per peer
+pub(crate) fn compact_peers6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes.chunks_exact(18)
+        .map(|addr_bytes| {
+            let ip: [u8; 16] = addr_bytes[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addr_bytes[16], addr_bytes[17]]);
+
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)
+        })
+        .collect()
 }
 
 impl FromBencodeType for Peers {
@@ -166,13 +264,23 @@ impl FromBencodeType for Peers {
 
         // parse binary model
         if let Ok((bytes, _)) = value.try_into_byte_string() {
+            return Ok(Self::Binary(compact_peers(bytes)));
+        }
+
+        let list = value.try_into_list()?.0;
+
+        // some trackers send a list of compact peers, each its own string
+        if !list.is_empty() && list.iter().all(|peer| matches!(peer, Type::String(..))) {
             let mut vec = Vec::new();
 
-            for addr_bytes in bytes.chunks(6) {
-                let ip = Ipv4Addr::new(addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3]);
-                let port = u16::from_be_bytes([addr_bytes[4], addr_bytes[5]]);
-                let addr = SocketAddr::new(IpAddr::V4(ip), port);
-                vec.push(addr);
+            for peer in list {
+                if let Type::String(bytes, _) = peer {
+                    match bytes.len() {
+                        6 => vec.extend(compact_peers(bytes)),
+                        18 => vec.extend(compact_peers6(bytes)),
+                        _ => (),
+                    }
+                }
             }
 
             return Ok(Self::Binary(vec));
@@ -182,7 +290,7 @@ impl FromBencodeType for Peers {
 
         let mut vec = Vec::new();
 
-        for dict in value.try_into_list()?.0.iter() {
+        for dict in list.iter() {
             let mut peer_id = None;
             let mut ip = None;
             let mut port = None;
@@ -194,7 +302,7 @@ impl FromBencodeType for Peers {
 
                 match (name, value) {
                     (b"peer id", Type::String(bytes, _)) => {
-                        peer_id = Some(String::from_utf8(bytes.to_vec()).unwrap());
+                        peer_id = Some(PeerId::try_from(*bytes).map_err(|_| Error::InvalidPeerId)?);
                     }
                     (b"ip", Type::String(bytes, _)) => {
                         let string = String::from_utf8(bytes.to_vec()).unwrap();
@@ -264,18 +372,9 @@ impl TrackerResponse {
 impl FromBencode for TrackerResponse {
     type Error = Error;
 
+    /// Decodes the body of the http response, see `http_body`
     fn from_bencode(bytes: &[u8]) -> Result<Self, Self::Error> where Self: Sized {
-        let mut begin = 0;
-
-        // find where dictionary begins
-        for (i, pair) in bytes.windows(2).enumerate() {
-            if pair[0] == b'\n' && pair[1] == b'd' {
-                begin = i + 1;
-            }
-        }
-
-        // decode dictionary
-        let map = bytes[begin..].try_into_dict()?.0;
+        let map = bytes.try_into_dict()?.0;
 
         let mut warning_message = None;
         let mut interval = None;
@@ -284,6 +383,7 @@ impl FromBencode for TrackerResponse {
         let mut complete = None;
         let mut incomplete = None;
         let mut peers = None;
+        let mut peers6 = Vec::new();
 
         let iter = map.iter();
 
@@ -291,6 +391,10 @@ impl FromBencode for TrackerResponse {
             let name = name.try_into_byte_string()?.0;
 
             match (name, value) {
+                // no other key is guaranteed to be present with a failure
+                (b"failure reason", Type::String(bytes, _)) => {
+                    return Err(Error::Failure(String::from_utf8_lossy(bytes).into_owned()));
+                }
                 (b"warning message", Type::String(bytes, _)) => {
                     warning_message = Some(from_utf8(bytes).unwrap().to_string());
                 }
@@ -312,12 +416,25 @@ impl FromBencode for TrackerResponse {
                 (b"peers", value) => {
                     peers = Some(Peers::from_bencode_type(value)?);
                 }
+                (b"peers6", Type::String(bytes, _)) => {
+                    peers6 = compact_peers6(bytes);
+                }
                 _ => (),
             }
         }
 
         let interval = interval.ok_or(Error::MissingInterval)?;
-        let peers = peers.ok_or(Error::MissingPeers)?;
+
+        // ipv6 peers only come in the compact model
+        let peers = match peers {
+            Some(Peers::Binary(mut peers)) => {
+                peers.extend(peers6);
+                Peers::Binary(peers)
+            }
+            Some(peers) => peers,
+            None if !peers6.is_empty() => Peers::Binary(peers6),
+            None => return Err(Error::MissingPeers),
+        };
 
         if true {
             Ok(TrackerResponse { warning_message, interval, min_interval, tracker_id, complete, incomplete, peers })
@@ -327,6 +444,56 @@ impl FromBencode for TrackerResponse {
     }
 }
 
+/// Resolves the host of a tracker url without blocking the runtime, failing once `timeout`
+/// passes. Ip hosts are returned as they are
+pub async fn resolve(url: &Url, timeout: Duration) -> Result<Vec<SocketAddr>, Error> {
+    let no_address = || Error::NoAddress(url.to_string());
+    let port = url.port_or_known_default().ok_or_else(no_address)?;
+
+    let addresses: Vec<SocketAddr> = match url.host().ok_or_else(no_address)? {
+        Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        Host::Domain(domain) => time::timeout(timeout, net::lookup_host((domain, port))).await
+            .map_err(|_| Error::DnsTimeout(url.to_string()))??
+            .collect(),
+    };
+
+    if addresses.is_empty() {
+        return Err(no_address());
+    }
+
+    Ok(addresses)
+}
+
+/// Connects to the first of `addresses` that accepts, in order, failing with the error
+/// of the last one if none does. Connections come from `bind` when it's set
+pub async fn connect(addresses: &[SocketAddr], bind: Option<IpAddr>) -> Result<TcpStream, io::Error> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+
+    for address in addresses {
+        match interface::connect(*address, bind).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Connects to the tracker of `url` through `proxy`, which resolves its host instead of us
+pub async fn connect_proxied(url: &Url, proxy: &ProxyConfig, bind: Option<IpAddr>) -> Result<TcpStream, Error> {
+    // socks can relay udp, but the proxy may not and udp trackers aren't implemented anyway
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::UnsupportedByProxy(url.to_string()));
+    }
+
+    let no_address = || Error::NoAddress(url.to_string());
+    let port = url.port_or_known_default().ok_or_else(no_address)?;
+    let host = url.host().ok_or_else(no_address)?;
+
+    Ok(proxy::connect(proxy, host, port, bind).await?)
+}
+
 pub struct Tracker<'a> {
     reader: BufReader<ReadHalf<'a>>,
     writer: WriteHalf<'a>,
@@ -341,7 +508,6 @@ impl<'a> Tracker<'a> {
 
         // creates request
         let host = &format!("{}:{}", url.host_str().unwrap(), url.port_or_known_default().unwrap());
-        println!("host: {}", host);
         let request = request.create_request(url.path(), host);
 
         Ok(Tracker { reader, writer, response: None, request })
@@ -351,23 +517,9 @@ impl<'a> Tracker<'a> {
             // writes request
             self.writer.write_all(&self.request).await?;
 
-            // reads response
-            let mut response = Vec::new();
-            let result = self.reader.read_to_end(&mut response).await;
-
-            match result {
-                Ok(byte_count) if byte_count != 0 =>  {
-                    self.response = match TrackerResponse::from_bencode(&response) {
-                        Ok(response) => Some(response),
-                        Err(err) => {
-                            println!("error: {:?}", err);
-                            todo!()
-                        },
-                    };
-                },
-                Ok(_) => return Err(Error::EmptyResponse),
-                Err(err) => return Err(err.into()),
-            }
+            // reads response, trackers keeping the connection alive never send an eof
+            let (head, body) = read_response(&mut self.reader).await?;
+            self.response = Some(TrackerResponse::from_bencode(&http_body(&head, body)?)?);
 
         Ok(())
     }
@@ -375,9 +527,448 @@ impl<'a> Tracker<'a> {
     pub const fn response(&self) -> Option<&TrackerResponse> {
         self.response.as_ref()
     }
+
+    pub fn into_response(self) -> Option<TrackerResponse> {
+        self.response
+    }
+}
+
+/// Longest status line and headers accepted from a tracker
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+/// Reads an http response up to the end of its body, given by chunked encoding or
+/// `Content-Length`. Only a body with neither is read until the connection closes
+pub(crate) async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<(String, Vec<u8>), Error> {
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        let read = reader.read_until(b'\n', &mut head).await?;
+
+        if read == 0 && head.is_empty() {
+            return Err(Error::EmptyResponse);
+        }
+
+        if read == 0 || head.len() > MAX_HEAD_LENGTH {
+            return Err(Error::InvalidHttpResponse);
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| Error::InvalidHttpResponse)?;
+
+    if !head.starts_with("HTTP/") {
+        return Err(Error::InvalidHttpResponse);
+    }
+
+    let chunked = header_values(&head, "transfer-encoding").any(|encoding| encoding == "chunked");
+    let length = header_values(&head, "content-length").next();
+
+    let mut body = Vec::new();
+
+    if chunked {
+        read_chunks(reader, &mut body).await?;
+    } else if let Some(length) = length {
+        let length: usize = length.parse().map_err(|_| Error::InvalidHttpResponse)?;
+        body.resize(length, 0);
+        reader.read_exact(&mut body).await?;
+    } else {
+        reader.read_to_end(&mut body).await?;
+    }
+
+    Ok((head, body))
+}
+
+/// Reads a chunked body (RFC 9112 7.1) into `body`, together with the trailers after it
+async fn read_chunks<R: AsyncBufRead + Unpin>(reader: &mut R, body: &mut Vec<u8>) -> Result<(), Error> {
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+
+        // chunk extensions follow the size after a semicolon
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| Error::InvalidHttpResponse)?;
+
+        if size == 0 {
+            break;
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+
+        let mut line_break = String::new();
+        reader.read_line(&mut line_break).await?;
+    }
+
+    // trailers end with an empty line like the headers do
+    loop {
+        let mut trailer = String::new();
+
+        if reader.read_line(&mut trailer).await? == 0 || trailer.trim().is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Lowercase values of every header called `name` in `head`, split at commas
+fn header_values<'a>(head: &'a str, name: &'a str) -> impl Iterator<Item = String> + 'a {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|value| value.trim().to_ascii_lowercase())
+}
+
+/// Body of an http response, decompressed as the `Content-Encoding` of its `head` says
+pub(crate) fn http_body(head: &str, mut body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let encodings: Vec<String> = header_values(head, "content-encoding").collect();
+
+    // encodings are listed in the order they were applied
+    for encoding in encodings.into_iter().rev() {
+        body = match encoding.as_str() {
+            "gzip" | "x-gzip" => decompress(GzDecoder::new(&body[..]))?,
+            // servers disagree on whether deflate data has a zlib header
+            "deflate" => decompress(ZlibDecoder::new(&body[..])).or_else(|_| decompress(DeflateDecoder::new(&body[..])))?,
+            "identity" | "" => body,
+            _ => return Err(Error::UnsupportedEncoding(encoding)),
+        };
+    }
+
+    Ok(body)
+}
+
+fn decompress(mut decoder: impl Read) -> Result<Vec<u8>, Error> {
+    let mut decompressed = Vec::new();
+    decoder.read_to_end(&mut decompressed).map_err(Error::InvalidEncoding)?;
+
+    Ok(decompressed)
 }
 
 /// gives totally random peer id following no convention 
-pub fn random_peer_id() -> [u8; 20] {
-    rand::random()
-}
\ No newline at end of file
+pub fn random_peer_id() -> PeerId {
+    PeerId::new(rand::random())
+}
+
+/// Local HTTP tracker answering every announce with a canned response
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use url::Url;
+
+    use crate::tracker::{Error, Tracker, TrackerRequest, TrackerResponse};
+
+    pub struct MockTracker {
+        address: SocketAddr,
+        requests: mpsc::UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl MockTracker {
+        /// Starts listening, `body` is sent after the http headers
+        pub async fn start(body: &[u8]) -> Self {
+            MockTracker::with_headers("", body).await
+        }
+
+        /// Like `start`, also sending `headers`, each ending with a line break
+        pub async fn with_headers(headers: &str, body: &[u8]) -> Self {
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n", body.len(), headers).into_bytes();
+            response.extend_from_slice(body);
+
+            MockTracker::serve(response, false).await
+        }
+
+        /// Sends `response` as it is and leaves every connection open afterwards, like
+        /// trackers ignoring `Connection: close`
+        pub async fn keep_alive(response: &[u8]) -> Self {
+            MockTracker::serve(response.to_vec(), true).await
+        }
+
+        async fn serve(response: Vec<u8>, keep_alive: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let (sender, requests) = mpsc::unbounded_channel();
+
+            tokio::spawn(async move {
+                let mut open = Vec::new();
+
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8; 1];
+                        stream.read_exact(&mut byte).await.unwrap();
+                        request.push(byte[0]);
+                    }
+
+                    stream.write_all(&response).await.unwrap();
+
+                    if keep_alive {
+                        open.push(stream);
+                    }
+
+                    if sender.send(request).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            MockTracker { address, requests }
+        }
+
+        pub fn url(&self) -> Url {
+            Url::parse(&format!("http://{}/announce", self.address)).unwrap()
+        }
+
+        /// Http request of the next announce received
+        pub async fn next_request(&mut self) -> String {
+            String::from_utf8(self.requests.recv().await.unwrap()).unwrap()
+        }
+
+        /// Connects a `Tracker` to the mock and announces once, returning the result and the request the mock received
+        pub async fn announce(mut self, request: &TrackerRequest) -> (Result<Option<TrackerResponse>, Error>, String) {
+            let url = self.url();
+            let mut stream = TcpStream::connect(self.address).await.unwrap();
+            let mut tracker = Tracker::new(&mut stream, &url, request).await.unwrap();
+
+            let result = tracker.announce().await.map(|_| tracker.into_response());
+            let received = self.next_request().await;
+
+            (result, received)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    use crate::info_hash::InfoHash;
+    use crate::peer_id::PeerId;
+    use crate::proxy::ProxyConfig;
+    use crate::proxy::mock::MockProxy;
+    use crate::tracker::{self, Error, Peers, Tracker, TrackerRequest};
+    use crate::tracker::mock::MockTracker;
+
+    fn request() -> TrackerRequest {
+        TrackerRequest::new(InfoHash::new([0xAB; 20]), PeerId::new(*b"-TC0001-000000000000"), 6881, 0, 0, 100, true, false)
+    }
+
+    #[tokio::test]
+    async fn resolves_tracker_hosts() {
+        let resolve = |url: &str, timeout| {
+            let url = Url::parse(url).unwrap();
+            async move { tracker::resolve(&url, timeout).await }
+        };
+
+        let addresses = resolve("http://[::1]:8080/announce", Duration::ZERO).await.unwrap();
+        assert_eq!(addresses, vec!["[::1]:8080".parse::<SocketAddr>().unwrap()]);
+
+        let addresses = resolve("http://127.0.0.1/announce", Duration::ZERO).await.unwrap();
+        assert_eq!(addresses, vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+
+        let addresses = resolve("http://localhost:6969/announce", Duration::from_secs(10)).await.unwrap();
+        assert!(addresses.iter().all(|address| address.ip().is_loopback() && address.port() == 6969));
+
+        // udp has no known default port
+        assert!(matches!(resolve("udp://localhost/announce", Duration::ZERO).await, Err(Error::NoAddress(_))));
+    }
+
+    #[tokio::test]
+    async fn connects_to_any_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        // nothing listens there once the listener is dropped
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let dead6 = match TcpListener::bind("[::1]:0").await {
+            Ok(listener) => listener.local_addr().unwrap(),
+            Err(_) => dead,
+        };
+
+        let stream = tracker::connect(&[dead6, dead, live], None).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+
+        let err = tracker::connect(&[dead6, dead], None).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        assert_eq!(tracker::connect(&[], None).await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn announces_through_proxy() {
+        let mut mock = MockTracker::start(b"d8:intervali1800e5:peers0:e").await;
+        let proxy = MockProxy::new(None).await;
+        let config = ProxyConfig::new(proxy.address);
+
+        let url = Url::parse(&mock.url().as_str().replace("127.0.0.1", "tracker.test")).unwrap();
+        let mut stream = tracker::connect_proxied(&url, &config, None).await.unwrap();
+        let mut tracker = Tracker::new(&mut stream, &url, &request()).await.unwrap();
+        tracker.announce().await.unwrap();
+
+        assert_eq!(tracker.into_response().unwrap().interval(), 1800);
+        assert!(mock.next_request().await.starts_with("GET /announce?"));
+
+        // the proxy was asked for the domain, it was never resolved here
+        drop(stream);
+        assert_eq!(proxy.handle.await.unwrap(), Some(format!("tracker.test:{}", mock.url().port().unwrap())));
+
+        let url = Url::parse("udp://tracker.test:6969/announce").unwrap();
+        assert!(matches!(tracker::connect_proxied(&url, &config, None).await, Err(Error::UnsupportedByProxy(_))));
+    }
+
+    #[tokio::test]
+    async fn announce_compact_peers() {
+        let mut body = b"d8:intervali1800e5:peers12:".to_vec();
+        body.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0x1A, 0xE2]);
+        body.push(b'e');
+
+        let (result, request) = MockTracker::start(&body).await.announce(&request()).await;
+        let response = result.unwrap().unwrap();
+
+        assert_eq!(response.interval(), 1800);
+        assert!(matches!(response.peers(), Peers::Binary(_)));
+        assert_eq!(response.peers().addresses(), vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap(), "10.0.0.2:6882".parse().unwrap()]);
+
+        assert!(request.starts_with("GET /announce?info_hash=%AB%AB"));
+        assert!(request.contains("&port=6881&uploaded=0&downloaded=0&left=100&compact=1"));
+    }
+
+    #[tokio::test]
+    async fn announce_ipv6_peers() {
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+        body.extend_from_slice(b"6:peers618:");
+        body.extend_from_slice(&"::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0x1A, 0xE2]);
+        body.push(b'e');
+
+        let (result, _) = MockTracker::start(&body).await.announce(&request()).await;
+
+        assert_eq!(result.unwrap().unwrap().peers().addresses(), vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap(), "[::1]:6882".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn announce_dictionary_peers() {
+        let body = b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:-TC0001-1111111111114:porti6881eeee";
+
+        let (result, _) = MockTracker::start(body).await.announce(&request()).await;
+        let response = result.unwrap().unwrap();
+
+        match response.peers() {
+            Peers::Dictionary(peers) => assert_eq!(peers, &vec![("127.0.0.1:6881".parse().unwrap(), PeerId::new(*b"-TC0001-111111111111"))]),
+            peers => panic!("expected dictionary peers, got {:?}", peers),
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_list_of_compact_peers() {
+        let mut body = b"d8:intervali900e5:peersl6:".to_vec();
+        body.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend_from_slice(b"6:");
+        body.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+        body.extend_from_slice(b"ee");
+
+        let (result, _) = MockTracker::start(&body).await.announce(&request()).await;
+        let response = result.unwrap().unwrap();
+
+        match response.peers() {
+            Peers::Binary(peers) => assert_eq!(peers, &vec!["127.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse().unwrap()]),
+            peers => panic!("expected binary peers, got {:?}", peers),
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_failure() {
+        let body = b"d14:failure reason17:torrent not founde";
+
+        let (result, _) = MockTracker::start(body).await.announce(&request()).await;
+
+        assert!(matches!(result, Err(Error::Failure(reason)) if reason == "torrent not found"));
+    }
+
+    #[tokio::test]
+    async fn compressed_responses() {
+        // 10.100.0.1 has a line break followed by a `d` in its compact form
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend_from_slice(&[10, 100, 0, 1, 0x1A, 0xE1]);
+        body.push(b'e');
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&body).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&body).unwrap();
+        let zlib = zlib.finish().unwrap();
+
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(&body).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        for (encoding, body) in [("gzip", &gzip), ("Deflate", &zlib), ("deflate", &deflate), ("identity", &body)] {
+            let mock = MockTracker::with_headers(&format!("Content-Encoding: {}\r\n", encoding), body).await;
+            let (result, request) = mock.announce(&request()).await;
+
+            assert_eq!(result.unwrap().unwrap().peers().addresses(), vec!["10.100.0.1:6881".parse::<SocketAddr>().unwrap()], "{}", encoding);
+            assert!(request.contains("Accept-Encoding: gzip, deflate\r\n"));
+        }
+
+        let (result, _) = MockTracker::with_headers("content-encoding: br\r\n", &body).await.announce(&request()).await;
+        assert!(matches!(result, Err(Error::UnsupportedEncoding(encoding)) if encoding == "br"));
+
+        let (result, _) = MockTracker::with_headers("Content-Encoding: gzip\r\n", &body).await.announce(&request()).await;
+        assert!(matches!(result, Err(Error::InvalidEncoding(_))));
+    }
+
+    #[tokio::test]
+    async fn announce_over_kept_alive_connection() {
+        let body = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n", body.len()).into_bytes();
+        response.extend_from_slice(body);
+
+        // reading until eof would never return
+        let tracker_request = request();
+        let tracker = MockTracker::keep_alive(&response).await;
+        let announcing = tracker.announce(&tracker_request);
+        let (result, received) = tokio::time::timeout(Duration::from_secs(5), announcing).await.unwrap();
+
+        assert_eq!(result.unwrap().unwrap().peers().addresses(), vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        assert!(received.contains("Connection: close\r\n"));
+
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        response.extend_from_slice(b"11\r\nd8:intervali1800e\r\n");
+        response.extend_from_slice(b"b;name=value\r\n5:peers6:\x7f\x00\r\n");
+        response.extend_from_slice(b"5\r\n\x00\x01\x1a\xe1e\r\n0\r\nExpires: never\r\n\r\n");
+
+        let tracker = MockTracker::keep_alive(&response).await;
+        let announcing = tracker.announce(&tracker_request);
+        let (result, _) = tokio::time::timeout(Duration::from_secs(5), announcing).await.unwrap();
+
+        assert_eq!(result.unwrap().unwrap().peers().addresses(), vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+
+        let (result, _) = MockTracker::keep_alive(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").await.announce(&request()).await;
+        assert!(matches!(result, Err(Error::InvalidHttpResponse)));
+    }
+
+    #[tokio::test]
+    async fn announce_without_peers() {
+        let body = b"d8:intervali1800ee";
+
+        let (result, _) = MockTracker::start(body).await.announce(&request()).await;
+
+        assert!(matches!(result, Err(Error::MissingPeers)));
+    }
+}